@@ -24,11 +24,22 @@ use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 
+// Defaults for the persistent store configuration, see the comment in src/ctap/storage.rs.
+const DEFAULT_NUM_PAGES: usize = 20;
+const DEFAULT_MAX_SUPPORTED_RESIDENTIAL_KEYS: usize = 150;
+// Usable bytes per page and the maximum size of a residential key, as used in
+// src/ctap/storage.rs to estimate the number of counter increments.
+const PAGE_CAPACITY: usize = 4092;
+const MAX_RESIDENTIAL_KEY_SIZE: usize = 500;
+
 fn main() {
     println!("cargo:rerun-if-changed=crypto_data/opensk.key");
     println!("cargo:rerun-if-changed=crypto_data/opensk_cert.pem");
+    println!("cargo:rerun-if-env-changed=OPENSK_NUM_PAGES");
+    println!("cargo:rerun-if-env-changed=OPENSK_MAX_RESIDENTIAL_KEYS");
 
     let out_dir = env::var_os("OUT_DIR").unwrap();
+    write_storage_config(Path::new(&out_dir));
     let priv_key_bin_path = Path::new(&out_dir).join("opensk_pkey.bin");
     let cert_bin_path = Path::new(&out_dir).join("opensk_cert.bin");
     let aaguid_bin_path = Path::new(&out_dir).join("opensk_aaguid.bin");
@@ -91,3 +102,45 @@ fn main() {
     serial.resize(16, 0);
     aaguid_bin_file.write_all(&serial).unwrap();
 }
+
+fn read_env_usize(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a positive integer, got {}", name, value)),
+        Err(_) => default,
+    }
+}
+
+// The store occupies NUM_PAGES flash pages in the .app_state section, followed by the pages of the
+// counters. The linker fails if they do not fit in the FLASH region of the board layout together
+// with the code.
+fn write_storage_config(out_dir: &Path) {
+    let num_pages = read_env_usize("OPENSK_NUM_PAGES", DEFAULT_NUM_PAGES);
+    let max_keys = read_env_usize(
+        "OPENSK_MAX_RESIDENTIAL_KEYS",
+        DEFAULT_MAX_SUPPORTED_RESIDENTIAL_KEYS,
+    );
+    assert!(num_pages >= 2, "The store needs at least 2 pages");
+    assert!(
+        max_keys >= 1,
+        "At least 1 residential key must be supported"
+    );
+    // One page is always kept free for compaction, and the rest of the store must have space left
+    // for the other entries and the counter increments.
+    assert!(
+        max_keys * MAX_RESIDENTIAL_KEY_SIZE < (num_pages - 1) * PAGE_CAPACITY,
+        "{} residential keys do not fit in {} pages",
+        max_keys,
+        num_pages,
+    );
+
+    let storage_config_path = out_dir.join("storage_config.rs");
+    let mut storage_config_file = File::create(&storage_config_path).unwrap();
+    writeln!(
+        storage_config_file,
+        "const NUM_PAGES: usize = {};\nconst MAX_SUPPORTED_RESIDENTIAL_KEYS: usize = {};",
+        num_pages, max_keys
+    )
+    .unwrap();
+}
@@ -136,10 +136,10 @@ class OpenSKInstaller:
         port=None,
     )
 
-  def checked_command_output(self, cmd):
+  def checked_command_output(self, cmd, env=None):
     cmd_output = ""
     try:
-      cmd_output = subprocess.check_output(cmd)
+      cmd_output = subprocess.check_output(cmd, env=env)
     except subprocess.CalledProcessError as e:
       fatal("Failed to execute {}: {}".format(cmd[0], str(e)))
       # Unreachable because fatal() will exit
@@ -185,13 +185,20 @@ class OpenSKInstaller:
   def build_and_install_opensk(self):
     assert self.args.application
     info("Building OpenSK application")
-    self.checked_command_output([
+    # The persistent store configuration is read by build.rs.
+    env = os.environ.copy()
+    if self.args.num_pages is not None:
+      env["OPENSK_NUM_PAGES"] = str(self.args.num_pages)
+    if self.args.max_residential_keys is not None:
+      env["OPENSK_MAX_RESIDENTIAL_KEYS"] = str(self.args.max_residential_keys)
+    cargo_command = [
         "cargo",
         "build",
         "--release",
         "--target=thumbv7em-none-eabi",
         "--features={}".format(",".join(self.args.features)),
-    ])
+    ]
+    self.checked_command_output(cargo_command, env=env)
     self.install_elf_file(
         os.path.join("target/thumbv7em-none-eabi/release",
                      self.args.application))
@@ -393,6 +400,23 @@ if __name__ == '__main__':
             "(i.e. more debug messages will be sent over the console port "
             "such as hexdumps of packets)."),
   )
+  app_commands.add_argument(
+      "--num-pages",
+      type=int,
+      default=None,
+      dest="num_pages",
+      help=("Number of 4KiB flash pages reserved for the persistent store of "
+            "the OpenSK application (default: 20). Boards with more flash "
+            "can use a larger store."),
+  )
+  app_commands.add_argument(
+      "--max-residential-keys",
+      type=int,
+      default=None,
+      dest="max_residential_keys",
+      help=("Maximum number of residential keys the OpenSK application can "
+            "store (default: 150). They must fit in the persistent store."),
+  )
   apps_group = app_commands.add_mutually_exclusive_group()
   apps_group.add_argument(
       "--opensk",
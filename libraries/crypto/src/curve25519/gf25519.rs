@@ -0,0 +1,268 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::ops::{Add, Mul, Sub};
+use subtle::{Choice, ConditionallySelectable};
+
+// Element of the field GF(2^255 - 19), represented as 16 signed limbs of 16 bits each. Limbs may
+// temporarily exceed 16 bits after additions and subtractions, and are brought back into range by
+// the carry propagation after each multiplication.
+// All operations are constant time.
+#[derive(Clone, Copy)]
+pub struct GF25519 {
+    limbs: [i64; 16],
+}
+
+impl ConditionallySelectable for GF25519 {
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self {
+        let mut limbs = [0; 16];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = i64::conditional_select(&a.limbs[i], &b.limbs[i], choice);
+        }
+        GF25519 { limbs }
+    }
+}
+
+impl GF25519 {
+    pub const ZERO: GF25519 = GF25519 { limbs: [0; 16] };
+    pub const ONE: GF25519 = GF25519 {
+        limbs: [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    };
+
+    // Decodes a little-endian encoding, ignoring the most significant bit.
+    pub fn from_bytes(bytes: &[u8; 32]) -> GF25519 {
+        let mut limbs = [0; 16];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = bytes[2 * i] as i64 + ((bytes[2 * i + 1] as i64) << 8);
+        }
+        limbs[15] &= 0x7fff;
+        GF25519 { limbs }
+    }
+
+    // Encodes the canonical (i.e. fully reduced) value in little-endian.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut t = *self;
+        t.carry();
+        t.carry();
+        t.carry();
+        // At this point, t < 2p, so subtracting p at most twice yields the canonical value.
+        for _ in 0..2 {
+            let mut m = [0i64; 16];
+            m[0] = t.limbs[0] - 0xffed;
+            for i in 1..15 {
+                m[i] = t.limbs[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+                m[i - 1] &= 0xffff;
+            }
+            m[15] = t.limbs[15] - 0x7fff - ((m[14] >> 16) & 1);
+            let borrow = Choice::from(((m[15] >> 16) & 1) as u8);
+            m[14] &= 0xffff;
+            t = GF25519::conditional_select(&GF25519 { limbs: m }, &t, borrow);
+        }
+        let mut bytes = [0; 32];
+        for i in 0..16 {
+            bytes[2 * i] = t.limbs[i] as u8;
+            bytes[2 * i + 1] = (t.limbs[i] >> 8) as u8;
+        }
+        bytes
+    }
+
+    // Returns the least significant bit of the canonical encoding, i.e. the "sign" of the element.
+    pub fn parity(&self) -> u8 {
+        self.to_bytes()[0] & 1
+    }
+
+    pub fn square(&self) -> GF25519 {
+        self * self
+    }
+
+    // Computes the inverse as self^(p - 2). The inverse of zero is zero.
+    pub fn inv(&self) -> GF25519 {
+        let mut c = *self;
+        for i in (0..254).rev() {
+            c = c.square();
+            if i != 2 && i != 4 {
+                c = &c * self;
+            }
+        }
+        c
+    }
+
+    // Propagates the carries of all limbs, with the overflow of the top limb wrapping around as
+    // 2^256 = 38 mod p.
+    fn carry(&mut self) {
+        for i in 0..16 {
+            self.limbs[i] += 1 << 16;
+            let c = self.limbs[i] >> 16;
+            if i < 15 {
+                self.limbs[i + 1] += c - 1;
+            } else {
+                self.limbs[0] += 38 * (c - 1);
+            }
+            self.limbs[i] -= c << 16;
+        }
+    }
+}
+
+impl Add for &GF25519 {
+    type Output = GF25519;
+
+    fn add(self, other: &GF25519) -> GF25519 {
+        let mut limbs = [0; 16];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = self.limbs[i] + other.limbs[i];
+        }
+        GF25519 { limbs }
+    }
+}
+
+impl Sub for &GF25519 {
+    type Output = GF25519;
+
+    fn sub(self, other: &GF25519) -> GF25519 {
+        let mut limbs = [0; 16];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = self.limbs[i] - other.limbs[i];
+        }
+        GF25519 { limbs }
+    }
+}
+
+#[allow(clippy::suspicious_arithmetic_impl)]
+impl Mul for &GF25519 {
+    type Output = GF25519;
+
+    fn mul(self, other: &GF25519) -> GF25519 {
+        let mut t = [0i64; 31];
+        for i in 0..16 {
+            for j in 0..16 {
+                t[i + j] += self.limbs[i] * other.limbs[j];
+            }
+        }
+        // Fold the upper half, using 2^256 = 38 mod p.
+        for i in 0..15 {
+            t[i] += 38 * t[i + 16];
+        }
+        let mut result = GF25519 {
+            limbs: *array_ref![t, 0, 16],
+        };
+        result.carry();
+        result.carry();
+        result
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for GF25519 {
+    fn eq(&self, other: &GF25519) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+}
+
+#[cfg(test)]
+impl core::fmt::Debug for GF25519 {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "GF25519::from_bytes({:02x?})", self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn get_nontrivial_elements() -> Vec<GF25519> {
+        let mut p_minus_one = [0xff; 32];
+        p_minus_one[0] = 0xec;
+        p_minus_one[31] = 0x7f;
+        vec![
+            GF25519::ONE,
+            GF25519::from_bytes(&[0x02; 32]),
+            GF25519::from_bytes(&[0x7f; 32]),
+            GF25519::from_bytes(&p_minus_one),
+            GF25519::from_bytes(&[0xff; 32]),
+        ]
+    }
+
+    #[test]
+    fn test_to_bytes_is_canonical() {
+        // 2^255 - 1 = p + 18.
+        let mut bytes = [0xff; 32];
+        bytes[31] = 0x7f;
+        let mut expected = [0; 32];
+        expected[0] = 18;
+        assert_eq!(GF25519::from_bytes(&bytes).to_bytes(), expected);
+    }
+
+    #[test]
+    fn test_from_to_bytes() {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert_eq!(GF25519::from_bytes(&bytes).to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_add_sub() {
+        for x in &get_nontrivial_elements() {
+            for y in &get_nontrivial_elements() {
+                assert_eq!(&(x + y) - y, *x);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sub_self_is_zero() {
+        for (x, y) in get_nontrivial_elements()
+            .iter()
+            .zip(get_nontrivial_elements().iter())
+        {
+            assert_eq!(x - y, GF25519::ZERO);
+        }
+    }
+
+    #[test]
+    fn test_mul_is_commutative() {
+        for x in &get_nontrivial_elements() {
+            for y in &get_nontrivial_elements() {
+                assert_eq!(x * y, y * x);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mul_distributes() {
+        for x in &get_nontrivial_elements() {
+            for y in &get_nontrivial_elements() {
+                for z in &get_nontrivial_elements() {
+                    assert_eq!(x * &(y + z), &(x * y) + &(x * z));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_inv() {
+        for x in &get_nontrivial_elements() {
+            assert_eq!(x * &x.inv(), GF25519::ONE);
+        }
+        assert_eq!(GF25519::ZERO.inv(), GF25519::ZERO);
+    }
+
+    #[test]
+    fn test_minus_one_squared() {
+        let minus_one = &GF25519::ZERO - &GF25519::ONE;
+        assert_eq!(minus_one.square(), GF25519::ONE);
+        assert_eq!(minus_one.parity(), 0);
+    }
+}
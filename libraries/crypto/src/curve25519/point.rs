@@ -0,0 +1,216 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::gf25519::GF25519;
+use subtle::{Choice, ConditionallySelectable};
+
+// Little-endian encoding of 2*d, where d = -121665/121666 is the curve constant of
+// edwards25519: -x^2 + y^2 = 1 + d*x^2*y^2.
+const D2: [u8; 32] = [
+    0x59, 0xf1, 0xb2, 0x26, 0x94, 0x9b, 0xd6, 0xeb, 0x56, 0xb1, 0x83, 0x82, 0x9a, 0x14, 0xe0, 0x00,
+    0x30, 0xd1, 0xf3, 0xee, 0xf2, 0x80, 0x8e, 0x19, 0xe7, 0xfc, 0xdf, 0x56, 0xdc, 0xd9, 0x06, 0x24,
+];
+
+// Little-endian encodings of the coordinates of the base point B, as defined in RFC 8032.
+const BASE_X: [u8; 32] = [
+    0x1a, 0xd5, 0x25, 0x8f, 0x60, 0x2d, 0x56, 0xc9, 0xb2, 0xa7, 0x25, 0x95, 0x60, 0xc7, 0x2c, 0x69,
+    0x5c, 0xdc, 0xd6, 0xfd, 0x31, 0xe2, 0xa4, 0xc0, 0xfe, 0x53, 0x6e, 0xcd, 0xd3, 0x36, 0x69, 0x21,
+];
+const BASE_Y: [u8; 32] = [
+    0x58, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+    0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+];
+
+// A point on edwards25519, in extended homogeneous coordinates (X : Y : Z : T), with
+// x = X/Z, y = Y/Z and x*y = T/Z.
+#[derive(Clone, Copy)]
+pub struct PointEd25519 {
+    x: GF25519,
+    y: GF25519,
+    z: GF25519,
+    t: GF25519,
+}
+
+impl ConditionallySelectable for PointEd25519 {
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self {
+        PointEd25519 {
+            x: GF25519::conditional_select(&a.x, &b.x, choice),
+            y: GF25519::conditional_select(&a.y, &b.y, choice),
+            z: GF25519::conditional_select(&a.z, &b.z, choice),
+            t: GF25519::conditional_select(&a.t, &b.t, choice),
+        }
+    }
+}
+
+impl PointEd25519 {
+    const NEUTRAL: PointEd25519 = PointEd25519 {
+        x: GF25519::ZERO,
+        y: GF25519::ONE,
+        z: GF25519::ONE,
+        t: GF25519::ZERO,
+    };
+
+    fn base_point() -> PointEd25519 {
+        let x = GF25519::from_bytes(&BASE_X);
+        let y = GF25519::from_bytes(&BASE_Y);
+        PointEd25519 {
+            x,
+            y,
+            z: GF25519::ONE,
+            t: &x * &y,
+        }
+    }
+
+    // Computes scalar * B, where the scalar is given in little-endian. All 256 bits of the scalar
+    // are processed, in constant time.
+    pub fn base_point_mul(scalar: &[u8; 32]) -> PointEd25519 {
+        PointEd25519::base_point().mul(scalar)
+    }
+
+    // Constant-time double-and-add, based on the unified addition formula.
+    fn mul(&self, scalar: &[u8; 32]) -> PointEd25519 {
+        let mut p = PointEd25519::NEUTRAL;
+        let mut q = *self;
+        for i in (0..256).rev() {
+            let bit = Choice::from((scalar[i / 8] >> (i % 8)) & 1);
+            PointEd25519::conditional_swap(&mut p, &mut q, bit);
+            q = q.add(&p);
+            p = p.add(&p);
+            PointEd25519::conditional_swap(&mut p, &mut q, bit);
+        }
+        p
+    }
+
+    // Unified addition "add-2008-hwcd-3", which is complete on edwards25519 and therefore also
+    // valid for doubling.
+    #[allow(clippy::many_single_char_names)]
+    fn add(&self, other: &PointEd25519) -> PointEd25519 {
+        let a = &(&self.y - &self.x) * &(&other.y - &other.x);
+        let b = &(&self.y + &self.x) * &(&other.y + &other.x);
+        let c = &(&self.t * &other.t) * &GF25519::from_bytes(&D2);
+        let d = &self.z * &other.z;
+        let d = &d + &d;
+        let e = &b - &a;
+        let f = &d - &c;
+        let g = &d + &c;
+        let h = &b + &a;
+        PointEd25519 {
+            x: &e * &f,
+            y: &h * &g,
+            z: &g * &f,
+            t: &e * &h,
+        }
+    }
+
+    // Encodes the point as the little-endian y coordinate, with the most significant bit set to
+    // the parity of the x coordinate.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let z_inv = self.z.inv();
+        let x = &self.x * &z_inv;
+        let y = &self.y * &z_inv;
+        let mut bytes = y.to_bytes();
+        bytes[31] ^= x.parity() << 7;
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Little-endian encoding of d.
+    const D: [u8; 32] = [
+        0xa3, 0x78, 0x59, 0x13, 0xca, 0x4d, 0xeb, 0x75, 0xab, 0xd8, 0x41, 0x41, 0x4d, 0x0a, 0x70,
+        0x00, 0x98, 0xe8, 0x79, 0x77, 0x79, 0x40, 0xc7, 0x8c, 0x73, 0xfe, 0x6f, 0x2b, 0xee, 0x6c,
+        0x03, 0x52,
+    ];
+
+    // The order L of the base point, in little-endian.
+    const ORDER: [u8; 32] = [
+        0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde,
+        0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x10,
+    ];
+
+    fn is_on_curve(p: &PointEd25519) -> bool {
+        let z_inv = p.z.inv();
+        let x = &p.x * &z_inv;
+        let y = &p.y * &z_inv;
+        let x2 = x.square();
+        let y2 = y.square();
+        let d = GF25519::from_bytes(&D);
+        &y2 - &x2 == &GF25519::ONE + &(&d * &(&x2 * &y2)) && p.t == &(&p.x * &p.y) * &z_inv
+    }
+
+    #[test]
+    fn test_d2_is_twice_d() {
+        let d = GF25519::from_bytes(&D);
+        assert_eq!(&d + &d, GF25519::from_bytes(&D2));
+        // d = -121665/121666.
+        let mut bytes = [0; 32];
+        bytes[0] = 0x41;
+        bytes[1] = 0xdb;
+        bytes[2] = 0x01;
+        let n = GF25519::from_bytes(&bytes);
+        bytes[0] = 0x42;
+        let m = GF25519::from_bytes(&bytes);
+        assert_eq!(&(&d * &m) + &n, GF25519::ZERO);
+    }
+
+    #[test]
+    fn test_base_point_is_on_curve() {
+        assert!(is_on_curve(&PointEd25519::base_point()));
+        assert!(is_on_curve(&PointEd25519::NEUTRAL));
+    }
+
+    #[test]
+    fn test_base_point_encoding() {
+        let mut expected = BASE_Y;
+        // The x coordinate of B is "positive", i.e. even.
+        expected[31] &= 0x7f;
+        assert_eq!(PointEd25519::base_point().to_bytes(), expected);
+    }
+
+    #[test]
+    fn test_mul_by_small_scalars() {
+        let b = PointEd25519::base_point();
+        let mut scalar = [0; 32];
+        assert_eq!(
+            PointEd25519::base_point_mul(&scalar).to_bytes(),
+            PointEd25519::NEUTRAL.to_bytes()
+        );
+        scalar[0] = 1;
+        assert_eq!(
+            PointEd25519::base_point_mul(&scalar).to_bytes(),
+            b.to_bytes()
+        );
+        scalar[0] = 2;
+        let two_b = PointEd25519::base_point_mul(&scalar);
+        assert!(is_on_curve(&two_b));
+        assert_eq!(two_b.to_bytes(), b.add(&b).to_bytes());
+        scalar[0] = 3;
+        assert_eq!(
+            PointEd25519::base_point_mul(&scalar).to_bytes(),
+            two_b.add(&b).to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_mul_by_order_is_neutral() {
+        assert_eq!(
+            PointEd25519::base_point_mul(&ORDER).to_bytes(),
+            PointEd25519::NEUTRAL.to_bytes()
+        );
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Arithmetic modulo the order L = 2^252 + 27742317777372353535851937790883648493 of the
+// edwards25519 base point. Scalars are represented as little-endian byte arrays.
+
+// The order L, in little-endian.
+const ORDER: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10,
+];
+
+// Reduces a 512-bit little-endian value (e.g. the output of SHA-512) modulo L.
+pub fn reduce(bytes: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for (i, item) in x.iter_mut().enumerate() {
+        *item = bytes[i] as i64;
+    }
+    mod_order(&mut x)
+}
+
+// Computes (a * b + c) mod L, with a, b and c given as reduced scalars.
+pub fn mul_add(a: &[u8; 32], b: &[u8; 32], c: &[u8; 32]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for i in 0..32 {
+        x[i] = c[i] as i64;
+    }
+    for i in 0..32 {
+        for j in 0..32 {
+            x[i + j] += a[i] as i64 * b[j] as i64;
+        }
+    }
+    mod_order(&mut x)
+}
+
+// Reduces a value given as 64 signed "bytes" that may exceed 8 bits. Each input limb must be less
+// than 2^21 in absolute value.
+// The top limbs are successively folded into the lower ones using 2^256 = -16 * (L - 2^252) mod L,
+// then a final conditional subtraction yields the canonical value. This is constant time.
+fn mod_order(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        for j in (i - 32)..(i - 12) {
+            x[j] += carry - 16 * x[i] * ORDER[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+        }
+        x[i - 12] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * ORDER[j];
+        carry = x[j] >> 8;
+        x[j] &= 0xff;
+    }
+    for j in 0..32 {
+        x[j] -= carry * ORDER[j];
+    }
+    let mut result = [0; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        result[i] = x[i] as u8;
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn order_bytes() -> [u8; 32] {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = ORDER[i] as u8;
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_reduce_small_values() {
+        let mut bytes = [0; 64];
+        let mut expected = [0; 32];
+        assert_eq!(reduce(&bytes), expected);
+        bytes[0] = 42;
+        expected[0] = 42;
+        assert_eq!(reduce(&bytes), expected);
+    }
+
+    #[test]
+    fn test_reduce_order() {
+        let mut bytes = [0; 64];
+        bytes[..32].copy_from_slice(&order_bytes());
+        assert_eq!(reduce(&bytes), [0; 32]);
+        // L + 1 = 1 mod L.
+        bytes[0] += 1;
+        let mut expected = [0; 32];
+        expected[0] = 1;
+        assert_eq!(reduce(&bytes), expected);
+    }
+
+    #[test]
+    fn test_reduce_max() {
+        // 2^512 - 1 mod L, computed with an arbitrary precision calculator.
+        let expected = [
+            0x00, 0x0f, 0x9c, 0x44, 0xe3, 0x11, 0x06, 0xa4, 0x47, 0x93, 0x85, 0x68, 0xa7, 0x1b,
+            0x0e, 0xd0, 0x65, 0xbe, 0xf5, 0x17, 0xd2, 0x73, 0xec, 0xce, 0x3d, 0x9a, 0x30, 0x7c,
+            0x1b, 0x41, 0x99, 0x03,
+        ];
+        assert_eq!(reduce(&[0xff; 64]), expected);
+    }
+
+    #[test]
+    fn test_mul_add() {
+        let mut a = [0; 32];
+        let mut b = [0; 32];
+        let mut c = [0; 32];
+        a[0] = 3;
+        b[0] = 5;
+        c[0] = 7;
+        let mut expected = [0; 32];
+        expected[0] = 22;
+        assert_eq!(mul_add(&a, &b, &c), expected);
+
+        // (L - 1) * (L - 1) + 0 = 1 mod L.
+        let mut minus_one = order_bytes();
+        minus_one[0] -= 1;
+        expected[0] = 1;
+        assert_eq!(mul_add(&minus_one, &minus_one, &[0; 32]), expected);
+        // (L - 1) * 1 + 1 = 0 mod L.
+        let mut one = [0; 32];
+        one[0] = 1;
+        assert_eq!(mul_add(&minus_one, &one, &one), [0; 32]);
+    }
+}
@@ -62,13 +62,19 @@ impl SecKey {
         // - https://www.secg.org/sec1-v2.pdf
     }
 
-    // DH key agreement method defined in the FIDO2 specification, Section 5.5.4. "Getting
-    // sharedSecret from Authenticator"
-    pub fn exchange_x_sha256(&self, other: &PubKey) -> [u8; 32] {
+    // DH key agreement returning the x coordinate of the shared point, as used by the PIN/UV auth
+    // protocol 2 of FIDO 2.1 before applying its key derivation.
+    pub fn exchange_x(&self, other: &PubKey) -> [u8; 32] {
         let p = self.exchange_raw(other);
         let mut x: [u8; 32] = [Default::default(); 32];
         p.getx().to_int().to_bin(&mut x);
-        Sha256::hash(&x)
+        x
+    }
+
+    // DH key agreement method defined in the FIDO2 specification, Section 5.5.4. "Getting
+    // sharedSecret from Authenticator"
+    pub fn exchange_x_sha256(&self, other: &PubKey) -> [u8; 32] {
+        Sha256::hash(&self.exchange_x(other))
     }
 }
 
@@ -150,5 +156,22 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_exchange_x_is_symmetric() {
+        let mut rng = ThreadRng256 {};
+
+        for _ in 0..ITERATIONS {
+            let sk_a = SecKey::gensk(&mut rng);
+            let pk_a = sk_a.genpk();
+            let sk_b = SecKey::gensk(&mut rng);
+            let pk_b = sk_b.genpk();
+            assert_eq!(sk_a.exchange_x(&pk_b), sk_b.exchange_x(&pk_a));
+            assert_eq!(
+                Sha256::hash(&sk_a.exchange_x(&pk_b)),
+                sk_a.exchange_x_sha256(&pk_b)
+            );
+        }
+    }
+
     // TODO: tests with invalid public shares.
 }
@@ -237,6 +237,11 @@ impl PubKey {
         representation
     }
 
+    pub fn to_coordinates(&self, x: &mut [u8; int256::NBYTES], y: &mut [u8; int256::NBYTES]) {
+        self.p.getx().to_int().to_bin(x);
+        self.p.gety().to_int().to_bin(y);
+    }
+
     // Encodes the key according to CBOR Object Signing and Encryption, defined in RFC 8152.
     pub fn to_cose_key(&self) -> Option<Vec<u8>> {
         const EC2_KEY_TYPE: i64 = 2;
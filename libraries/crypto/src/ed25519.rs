@@ -0,0 +1,229 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::curve25519::point::PointEd25519;
+use super::curve25519::scalar;
+use super::rng256::Rng256;
+use super::sha512::Sha512;
+use alloc::vec::Vec;
+
+// Ed25519 signatures, as specified in RFC 8032 (PureEdDSA, without context or pre-hashing).
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "derive_debug", derive(Debug))]
+pub struct SecKey {
+    seed: [u8; 32],
+}
+
+pub struct Signature {
+    bytes: [u8; 64],
+}
+
+pub struct PubKey {
+    bytes: [u8; 32],
+}
+
+impl SecKey {
+    pub fn gensk<R>(rng: &mut R) -> SecKey
+    where
+        R: Rng256,
+    {
+        SecKey {
+            seed: rng.gen_uniform_u8x32(),
+        }
+    }
+
+    pub fn genpk(&self) -> PubKey {
+        let (a, _) = self.expand();
+        PubKey {
+            bytes: PointEd25519::base_point_mul(&a).to_bytes(),
+        }
+    }
+
+    // Ed25519 signatures are deterministic, so no randomness is needed.
+    #[allow(clippy::many_single_char_names)]
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        let (a, prefix) = self.expand();
+        let pk = PointEd25519::base_point_mul(&a).to_bytes();
+
+        let mut h = Sha512::new();
+        h.update(&prefix);
+        h.update(msg);
+        let r = scalar::reduce(&h.finalize());
+        let big_r = PointEd25519::base_point_mul(&r).to_bytes();
+
+        let mut h = Sha512::new();
+        h.update(&big_r);
+        h.update(&pk);
+        h.update(msg);
+        let k = scalar::reduce(&h.finalize());
+        // The clamped scalar is less than 2^255, which the reduction handles as well.
+        let s = scalar::mul_add(&k, &a, &r);
+
+        let mut bytes = [0; 64];
+        bytes[..32].copy_from_slice(&big_r);
+        bytes[32..].copy_from_slice(&s);
+        Signature { bytes }
+    }
+
+    // Any 32-byte string is a valid seed.
+    pub fn from_bytes(bytes: &[u8; 32]) -> SecKey {
+        SecKey { seed: *bytes }
+    }
+
+    pub fn to_bytes(&self, bytes: &mut [u8; 32]) {
+        bytes.copy_from_slice(&self.seed);
+    }
+
+    // Derives the clamped secret scalar and the nonce prefix from the seed.
+    fn expand(&self) -> ([u8; 32], [u8; 32]) {
+        let digest = Sha512::hash(&self.seed);
+        let mut a = *array_ref![digest, 0, 32];
+        a[0] &= 248;
+        a[31] &= 127;
+        a[31] |= 64;
+        (a, *array_ref![digest, 32, 32])
+    }
+}
+
+impl Signature {
+    pub fn to_bytes(&self) -> [u8; 64] {
+        self.bytes
+    }
+}
+
+impl PubKey {
+    pub const EDDSA_ALGORITHM: i64 = -8;
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.bytes
+    }
+
+    pub fn to_cose_key(&self) -> Option<Vec<u8>> {
+        const OKP_KEY_TYPE: i64 = 1;
+        const ED25519_CURVE: i64 = 6;
+        let x_byte_cbor: cbor::Value = cbor_bytes!(self.bytes.to_vec());
+        let cbor_value = cbor_map_options! {
+            1 => OKP_KEY_TYPE,
+            3 => PubKey::EDDSA_ALGORITHM,
+            -1 => ED25519_CURVE,
+            -2 => x_byte_cbor,
+        };
+        let mut encoded_key = Vec::new();
+        if cbor::write(cbor_value, &mut encoded_key) {
+            Some(encoded_key)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::rng256::ThreadRng256;
+    use super::*;
+    extern crate hex;
+    extern crate ring;
+    extern crate untrusted;
+
+    // Test vector "TEST 1" of RFC 8032, section 7.1.
+    #[test]
+    fn test_rfc8032_empty_message() {
+        let seed = hex::decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+            .unwrap();
+        let sk = SecKey::from_bytes(array_ref![seed, 0, 32]);
+        assert_eq!(
+            sk.genpk().to_bytes()[..],
+            hex::decode("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+                .unwrap()[..]
+        );
+        assert_eq!(
+            sk.sign(&[]).to_bytes()[..],
+            hex::decode(
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                 5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"
+            )
+            .unwrap()[..]
+        );
+    }
+
+    // Test vector "TEST 2" of RFC 8032, section 7.1.
+    #[test]
+    fn test_rfc8032_one_byte_message() {
+        let seed = hex::decode("4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb")
+            .unwrap();
+        let sk = SecKey::from_bytes(array_ref![seed, 0, 32]);
+        assert_eq!(
+            sk.genpk().to_bytes()[..],
+            hex::decode("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c")
+                .unwrap()[..]
+        );
+        assert_eq!(
+            sk.sign(&[0x72]).to_bytes()[..],
+            hex::decode(
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"
+            )
+            .unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn test_from_to_bytes() {
+        let mut rng = ThreadRng256 {};
+        for _ in 0..100 {
+            let sk = SecKey::gensk(&mut rng);
+            let mut bytes = [0; 32];
+            sk.to_bytes(&mut bytes);
+            assert_eq!(SecKey::from_bytes(&bytes), sk);
+        }
+    }
+
+    #[test]
+    fn test_sign_ring_compatibility() {
+        use ring::signature::KeyPair;
+
+        let mut rng = ThreadRng256 {};
+        for i in 0..20 {
+            let sk = SecKey::gensk(&mut rng);
+            let mut seed = [0; 32];
+            sk.to_bytes(&mut seed);
+            let key_pair = ring::signature::Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+            let pk = sk.genpk();
+            assert_eq!(pk.to_bytes()[..], key_pair.public_key().as_ref()[..]);
+
+            let msg = vec![i as u8; 13 * i];
+            let signature = sk.sign(&msg);
+            assert_eq!(signature.to_bytes()[..], key_pair.sign(&msg).as_ref()[..]);
+            let public_key =
+                ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, pk.to_bytes());
+            assert!(public_key.verify(&msg, &signature.to_bytes()).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_to_cose_key() {
+        let seed = [0x55; 32];
+        let pk = SecKey::from_bytes(&seed).genpk();
+        let cose_key = pk.to_cose_key().unwrap();
+        let expected_value = cbor_map! {
+            1 => 1,
+            3 => -8,
+            -1 => 6,
+            -2 => pk.to_bytes().to_vec(),
+        };
+        let mut expected_key = Vec::new();
+        assert!(cbor::write(expected_value, &mut expected_key));
+        assert_eq!(cose_key, expected_key);
+    }
+}
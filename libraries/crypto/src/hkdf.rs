@@ -0,0 +1,72 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::hmac::hmac_256;
+use super::{Hash256, HashBlockSize64Bytes};
+
+const HASH_SIZE: usize = 32;
+
+// HKDF as specified in RFC 5869, with an output length of exactly one hash, which is all FIDO2
+// needs. An empty salt is equivalent to a salt of HASH_SIZE zero bytes.
+pub fn hkdf_256<H>(ikm: &[u8], salt: &[u8], info: &[u8]) -> [u8; HASH_SIZE]
+where
+    H: Hash256 + HashBlockSize64Bytes,
+{
+    let prk = hmac_256::<H>(salt, ikm);
+    let mut t = info.to_vec();
+    t.push(0x01);
+    hmac_256::<H>(&prk, &t)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::sha256::Sha256;
+    use super::*;
+    extern crate hex;
+
+    // Test vectors of RFC 5869, appendix A, truncated to one hash length.
+    #[test]
+    fn test_hkdf_sha256_rfc5869_basic() {
+        let ikm = [0x0b; 22];
+        let salt = hex::decode("000102030405060708090a0b0c").unwrap();
+        let info = hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap();
+        assert_eq!(
+            hkdf_256::<Sha256>(&ikm, &salt, &info)[..],
+            hex::decode("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf")
+                .unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn test_hkdf_sha256_rfc5869_long_inputs() {
+        let ikm: Vec<u8> = (0x00..0x50).collect();
+        let salt: Vec<u8> = (0x60..0xb0).collect();
+        let info: Vec<u8> = (0xb0..=0xff).collect();
+        assert_eq!(
+            hkdf_256::<Sha256>(&ikm, &salt, &info)[..],
+            hex::decode("b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c")
+                .unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn test_hkdf_sha256_rfc5869_empty_salt_info() {
+        let ikm = [0x0b; 22];
+        let expected =
+            hex::decode("8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d")
+                .unwrap();
+        assert_eq!(hkdf_256::<Sha256>(&ikm, &[], &[])[..], expected[..]);
+        assert_eq!(hkdf_256::<Sha256>(&ikm, &[0; 32], &[])[..], expected[..]);
+    }
+}
@@ -27,12 +27,16 @@ extern crate cbor;
 
 pub mod aes256;
 pub mod cbc;
+mod curve25519;
 mod ec;
 pub mod ecdh;
 pub mod ecdsa;
+pub mod ed25519;
+pub mod hkdf;
 pub mod hmac;
 pub mod rng256;
 pub mod sha256;
+pub mod sha512;
 pub mod util;
 
 // Trait for hash functions that returns a 256-bit hash.
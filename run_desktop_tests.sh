@@ -33,6 +33,10 @@ cargo check --release --target=thumbv7em-none-eabi --features debug_ctap,with_ct
 echo "Checking that examples build properly..."
 cargo check --release --target=thumbv7em-none-eabi --examples
 
+echo "Checking that the simulator builds properly..."
+cargo check --release --features std --bin simulator
+cargo check --release --features std,with_ctap1 --bin simulator
+
 echo "Checking that CTAP2 builds and links properly (1 set of features)..."
 cargo build --release --target=thumbv7em-none-eabi --features with_ctap1
 
@@ -0,0 +1,342 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Runs the CTAP implementation of the firmware on a desktop, so that it can be tested without a
+// board. See docs/simulator.md for usage.
+
+#[macro_use]
+extern crate arrayref;
+extern crate crypto;
+extern crate ctap2;
+
+mod transport;
+
+use crypto::rng256::ThreadRng256;
+use ctap2::ctap;
+use ctap2::ctap::hid::{ChannelID, CtapHid, KeepaliveStatus, PendingPackets};
+use ctap2::ctap::platform::{self, Clock};
+use ctap2::ctap::status_code::Ctap2StatusCode;
+use ctap2::ctap::time::{self, ClockValue};
+use ctap2::ctap::CtapState;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use transport::HidTransport;
+
+const CLOCK_FREQUENCY_HZ: usize = 32768;
+const KEEPALIVE_DELAY_MS: isize = 100;
+const KEEPALIVE_DELAY: time::Duration<isize> = time::Duration::from_ms(KEEPALIVE_DELAY_MS);
+const STD_KEEPALIVE_DELAY: Duration = Duration::from_millis(KEEPALIVE_DELAY_MS as u64);
+const DEFAULT_UDP_ADDRESS: &str = "127.0.0.1:8111";
+
+const USAGE: &str = "Usage: simulator [OPTIONS]
+
+Options:
+    --udp ADDRESS          Exchange HID reports as UDP datagrams on ADDRESS (default: 127.0.0.1:8111)
+    --uhid                 Create a virtual USB HID device through /dev/uhid (Linux only)
+    --store PATH           Keep the persistent store in the file PATH, instead of RAM only
+    --user-presence MODE   How to answer user presence checks:
+                             always: the user is always present (default)
+                             never: the user never confirms, checks time out immediately
+                             stdin: type y or n on the standard input when asked";
+
+enum TransportOption {
+    Udp(String),
+    #[cfg(target_os = "linux")]
+    Uhid,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum UserPresenceMode {
+    Always,
+    Never,
+    Stdin,
+}
+
+struct Options {
+    transport: TransportOption,
+    store_path: Option<PathBuf>,
+    user_presence: UserPresenceMode,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        transport: TransportOption::Udp(String::from(DEFAULT_UDP_ADDRESS)),
+        store_path: None,
+        user_presence: UserPresenceMode::Always,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", name))
+        };
+        match arg.as_str() {
+            "--udp" => options.transport = TransportOption::Udp(value("--udp")?),
+            #[cfg(target_os = "linux")]
+            "--uhid" => options.transport = TransportOption::Uhid,
+            "--store" => options.store_path = Some(PathBuf::from(value("--store")?)),
+            "--user-presence" => {
+                options.user_presence = match value("--user-presence")?.as_str() {
+                    "always" => UserPresenceMode::Always,
+                    "never" => UserPresenceMode::Never,
+                    "stdin" => UserPresenceMode::Stdin,
+                    mode => return Err(format!("Unknown user presence mode {}", mode)),
+                }
+            }
+            "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+// Simulates the timer of the board, starting at boot.
+#[derive(Clone, Copy)]
+struct SimulatedClock {
+    boot: Instant,
+}
+
+impl SimulatedClock {
+    fn new() -> SimulatedClock {
+        SimulatedClock {
+            boot: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> ClockValue {
+        let ticks = self.boot.elapsed().as_micros() * CLOCK_FREQUENCY_HZ as u128 / 1_000_000;
+        ClockValue::new(ticks as isize, CLOCK_FREQUENCY_HZ)
+    }
+}
+
+// Plays the role of the button. Answers typed on the standard input are read by a background
+// thread, so that keepalives are still sent while waiting.
+struct UserPresence {
+    mode: UserPresenceMode,
+    answers: Option<Receiver<bool>>,
+}
+
+impl UserPresence {
+    fn new(mode: UserPresenceMode) -> UserPresence {
+        let answers = if mode == UserPresenceMode::Stdin {
+            let (sender, answers) = mpsc::channel();
+            thread::spawn(move || {
+                for line in io::stdin().lock().lines() {
+                    let answer = match line.as_ref().map(|l| l.trim()) {
+                        Ok("y") | Ok("yes") => true,
+                        Ok("n") | Ok("no") => false,
+                        Ok(_) => continue,
+                        Err(_) => break,
+                    };
+                    if sender.send(answer).is_err() {
+                        break;
+                    }
+                }
+            });
+            Some(answers)
+        } else {
+            None
+        };
+        UserPresence { mode, answers }
+    }
+
+    // Like pressing the button without being asked, which is how U2F registers user presence.
+    #[cfg(feature = "with_ctap1")]
+    fn is_button_touched(&self) -> bool {
+        match self.mode {
+            UserPresenceMode::Always => true,
+            UserPresenceMode::Never => false,
+            UserPresenceMode::Stdin => self.answers.as_ref().unwrap().try_iter().any(|a| a),
+        }
+    }
+
+    fn check(
+        &self,
+        transport: &HidTransport,
+        pending_packets: &PendingPackets,
+        cid: ChannelID,
+    ) -> Result<(), Ctap2StatusCode> {
+        let answers = match self.mode {
+            UserPresenceMode::Always => return Ok(()),
+            // No need to make tests wait for the real timeout.
+            UserPresenceMode::Never => return Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT),
+            UserPresenceMode::Stdin => self.answers.as_ref().unwrap(),
+        };
+        // Ignore answers typed before the question.
+        answers.try_iter().for_each(drop);
+        println!(
+            "User presence requested on channel {:02x?}, confirm? [y/n]",
+            cid
+        );
+
+        // The timeout is N times the keepalive delay, like on the board.
+        const TIMEOUT_ITERATIONS: isize = ctap::TOUCH_TIMEOUT_MS / KEEPALIVE_DELAY_MS;
+        for _ in 0..TIMEOUT_ITERATIONS {
+            CtapHid::send_keepalive(
+                transport,
+                pending_packets,
+                cid,
+                KeepaliveStatus::UpNeeded,
+                KEEPALIVE_DELAY,
+            )?;
+            match answers.recv_timeout(STD_KEEPALIVE_DELAY) {
+                Ok(true) => return Ok(()),
+                Ok(false) => return Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        println!("User presence check timed out");
+        Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+    }
+}
+
+// Lets the CTAP implementation ask the simulated user, and send keepalives on the transport.
+// Packets received in the meantime are queued for the main loop.
+struct SimulatedUserPresence<'a> {
+    user_presence: &'a UserPresence,
+    transport: &'a HidTransport,
+    pending_packets: PendingPackets,
+}
+
+impl platform::UserPresence for SimulatedUserPresence<'_> {
+    fn check(&self, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        self.user_presence
+            .check(self.transport, &self.pending_packets, cid)
+    }
+
+    fn processing(&self, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        CtapHid::send_keepalive(
+            self.transport,
+            &self.pending_packets,
+            cid,
+            KeepaliveStatus::Processing,
+            KEEPALIVE_DELAY,
+        )
+    }
+}
+
+fn read_store(path: &Path) -> io::Result<Option<Box<[u8]>>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content.into_boxed_slice())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Writes the whole store to a temporary file first, so that it is never half written.
+fn write_store(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    fs::write(&temporary_path, content)?;
+    fs::rename(&temporary_path, path)
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let transport = match &options.transport {
+        TransportOption::Udp(address) => {
+            println!("Listening for HID reports on UDP {}", address);
+            HidTransport::udp(address)?
+        }
+        #[cfg(target_os = "linux")]
+        TransportOption::Uhid => {
+            println!("Creating a virtual HID device with /dev/uhid");
+            HidTransport::uhid()?
+        }
+    };
+    let mut ctap_hid = CtapHid::new();
+    let user_presence = UserPresence::new(options.user_presence);
+    let check_user_presence = SimulatedUserPresence {
+        user_presence: &user_presence,
+        transport: &transport,
+        pending_packets: ctap_hid.pending_packets(),
+    };
+
+    let clock = SimulatedClock::new();
+    let mut rng = ThreadRng256 {};
+    let store_content = match &options.store_path {
+        Some(path) => read_store(path)?,
+        None => None,
+    };
+    let storage = match store_content {
+        Some(content) => {
+            if content.len() != ctap::STORE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The store file does not match the store size of this build",
+                ));
+            }
+            ctap::new_buffer_storage_with_content(content)
+        }
+        None => ctap::new_buffer_storage(),
+    };
+    let mut ctap_state =
+        CtapState::new(&mut rng, check_user_presence, storage, clock).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Cannot setup the persistent store: {:?}", e),
+            )
+        })?;
+
+    loop {
+        // Packets received while sending keepalives come first.
+        let packet = match ctap_hid.next_pending_packet() {
+            Some(packet) => Some(packet),
+            None => transport.recv_packet(STD_KEEPALIVE_DELAY)?,
+        };
+
+        let now = clock.now();
+        #[cfg(feature = "with_ctap1")]
+        {
+            if user_presence.is_button_touched() {
+                ctap_state.u2f_up_state.grant_up(now);
+            }
+        }
+        ctap_state.check_disable_reset();
+        ctap_hid.wink_permission = ctap_hid.wink_permission.check_expiration(now);
+
+        if let Some(packet) = packet {
+            let reply = ctap_hid.process_hid_packet(&packet, &mut ctap_state);
+            for pkt_reply in reply {
+                transport.send_packet(&pkt_reply)?;
+            }
+            if let Some(path) = &options.store_path {
+                write_store(path, &ctap_state.persistent_store_content())?;
+            }
+        }
+    }
+}
+
+fn main() {
+    #[cfg(feature = "debug_ctap")]
+    platform::set_debug_writer(|args| eprintln!("{}", args));
+    let options = parse_options().unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
+    if let Err(e) = run(options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
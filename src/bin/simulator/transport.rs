@@ -0,0 +1,306 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ctap2::ctap::hid::HidPacket;
+use ctap2::ctap::platform::{SendOrRecvStatus, Transport};
+use ctap2::ctap::time;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const PACKET_SIZE: usize = 64;
+
+// Carries 64-byte CTAPHID packets between the simulator and the host. Incoming packets are read by
+// a background thread, so that the main loop and the user presence check can wait with a timeout.
+pub struct HidTransport {
+    packets: Receiver<HidPacket>,
+    write_packet: Box<dyn Fn(&HidPacket) -> io::Result<()>>,
+}
+
+impl HidTransport {
+    // Each UDP datagram is one HID report. Replies go to the sender of the last received packet.
+    pub fn udp(address: &str) -> io::Result<HidTransport> {
+        let socket = Arc::new(UdpSocket::bind(address)?);
+        let peer: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+        let (sender, packets) = mpsc::channel();
+
+        let reader_socket = socket.clone();
+        let reader_peer = peer.clone();
+        thread::spawn(move || loop {
+            let mut packet = [0; PACKET_SIZE];
+            match reader_socket.recv_from(&mut packet) {
+                Ok((PACKET_SIZE, address)) => {
+                    *reader_peer.lock().unwrap() = Some(address);
+                    if sender.send(packet).is_err() {
+                        break;
+                    }
+                }
+                Ok((length, address)) => {
+                    eprintln!("Dropped datagram of {} bytes from {}", length, address)
+                }
+                Err(_) => break,
+            }
+        });
+
+        let write_packet = move |packet: &HidPacket| match *peer.lock().unwrap() {
+            Some(address) => socket.send_to(packet, address).map(|_| ()),
+            // Nobody talked to us yet, so there is nobody to answer to.
+            None => Ok(()),
+        };
+        Ok(HidTransport {
+            packets,
+            write_packet: Box::new(write_packet),
+        })
+    }
+
+    // Creates a virtual USB HID device through the Linux uhid driver. Hosts see it like a security
+    // key plugged in, so browsers and FIDO libraries can use it without modifications.
+    #[cfg(target_os = "linux")]
+    pub fn uhid() -> io::Result<HidTransport> {
+        use std::fs::OpenOptions;
+        use std::io::{Read, Write};
+
+        let mut device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/uhid")?;
+        device.write_all(&uhid::create2_event())?;
+        let (sender, packets) = mpsc::channel();
+
+        let mut reader_device = device.try_clone()?;
+        thread::spawn(move || loop {
+            let mut event = [0; uhid::EVENT_SIZE];
+            match reader_device.read(&mut event) {
+                Ok(length) => {
+                    if let Some(packet) = uhid::parse_output_event(&event[..length]) {
+                        if sender.send(packet).is_err() {
+                            break;
+                        }
+                    }
+                }
+                Err(_) => break,
+            }
+        });
+
+        // The device is destroyed when the file is closed.
+        let write_packet =
+            move |packet: &HidPacket| (&device).write_all(&uhid::input2_event(packet));
+        Ok(HidTransport {
+            packets,
+            write_packet: Box::new(write_packet),
+        })
+    }
+
+    // Returns the next packet, or None if none arrived in time.
+    pub fn recv_packet(&self, timeout: Duration) -> io::Result<Option<HidPacket>> {
+        match self.packets.recv_timeout(timeout) {
+            Ok(packet) => Ok(Some(packet)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The transport stopped receiving packets",
+            )),
+        }
+    }
+
+    pub fn send_packet(&self, packet: &HidPacket) -> io::Result<()> {
+        (self.write_packet)(packet)
+    }
+}
+
+impl Transport for HidTransport {
+    fn recv_with_timeout(
+        &self,
+        packet: &mut HidPacket,
+        timeout: time::Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        let timeout = Duration::from_millis(timeout.ms().max(0) as u64);
+        match self.recv_packet(timeout) {
+            Ok(Some(received)) => {
+                *packet = received;
+                Some(SendOrRecvStatus::Received)
+            }
+            Ok(None) => None,
+            Err(_) => Some(SendOrRecvStatus::Error),
+        }
+    }
+
+    // Sending does not wait for the host, so the only packet that can come first is one that
+    // already arrived.
+    fn send_or_recv_with_timeout(
+        &self,
+        packet: &mut HidPacket,
+        _timeout: time::Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        match self.packets.try_recv() {
+            Ok(received) => {
+                *packet = received;
+                Some(SendOrRecvStatus::Received)
+            }
+            Err(TryRecvError::Empty) => match self.send_packet(packet) {
+                Ok(()) => Some(SendOrRecvStatus::Sent),
+                Err(_) => Some(SendOrRecvStatus::Error),
+            },
+            Err(TryRecvError::Disconnected) => Some(SendOrRecvStatus::Error),
+        }
+    }
+}
+
+// Encoding of the events of the uhid driver, see include/uapi/linux/uhid.h in the Linux sources.
+// Events are a packed struct uhid_event, with a 4 bytes type followed by a union of payloads.
+#[cfg(target_os = "linux")]
+mod uhid {
+    use super::PACKET_SIZE;
+    use ctap2::ctap::hid::HidPacket;
+
+    pub const EVENT_SIZE: usize = 4380;
+    const DATA_MAX: usize = 4096;
+
+    const UHID_OUTPUT: u32 = 6;
+    const UHID_CREATE2: u32 = 11;
+    const UHID_INPUT2: u32 = 12;
+    const UHID_OUTPUT_REPORT: u8 = 1;
+
+    const BUS_USB: u16 = 0x03;
+    // We pretend to be the same device as the firmware.
+    const VENDOR_ID: u32 = 0x1915;
+    const PRODUCT_ID: u32 = 0x521F;
+    const DEVICE_NAME: &[u8] = b"OpenSK simulator";
+
+    // Same report descriptor as the USB HID interface of the firmware.
+    const CTAP_REPORT_DESCRIPTOR: [u8; 34] = [
+        0x06, 0xD0, 0xF1, // HID_UsagePage ( FIDO_USAGE_PAGE ),
+        0x09, 0x01, // HID_Usage ( FIDO_USAGE_CTAPHID ),
+        0xA1, 0x01, // HID_Collection ( HID_Application ),
+        0x09, 0x20, // HID_Usage ( FIDO_USAGE_DATA_IN ),
+        0x15, 0x00, // HID_LogicalMin ( 0 ),
+        0x26, 0xFF, 0x00, // HID_LogicalMaxS ( 0xff ),
+        0x75, 0x08, // HID_ReportSize ( 8 ),
+        0x95, 0x40, // HID_ReportCount ( HID_INPUT_REPORT_BYTES ),
+        0x81, 0x02, // HID_Input ( HID_Data | HID_Absolute | HID_Variable ),
+        0x09, 0x21, // HID_Usage ( FIDO_USAGE_DATA_OUT ),
+        0x15, 0x00, // HID_LogicalMin ( 0 ),
+        0x26, 0xFF, 0x00, // HID_LogicalMaxS ( 0xff ),
+        0x75, 0x08, // HID_ReportSize ( 8 ),
+        0x95, 0x40, // HID_ReportCount ( HID_OUTPUT_REPORT_BYTES ),
+        0x91, 0x02, // HID_Output ( HID_Data | HID_Absolute | HID_Variable ),
+        0xC0, // HID_EndCollection
+    ];
+
+    // struct uhid_create2_req: name[128], phys[64], uniq[64], rd_size, bus, vendor, product,
+    // version, country, rd_data[4096].
+    pub fn create2_event() -> Vec<u8> {
+        let mut event = vec![0; EVENT_SIZE];
+        event[0..4].copy_from_slice(&UHID_CREATE2.to_ne_bytes());
+        event[4..4 + DEVICE_NAME.len()].copy_from_slice(DEVICE_NAME);
+        let rd_size = CTAP_REPORT_DESCRIPTOR.len() as u16;
+        event[260..262].copy_from_slice(&rd_size.to_ne_bytes());
+        event[262..264].copy_from_slice(&BUS_USB.to_ne_bytes());
+        event[264..268].copy_from_slice(&VENDOR_ID.to_ne_bytes());
+        event[268..272].copy_from_slice(&PRODUCT_ID.to_ne_bytes());
+        event[280..280 + CTAP_REPORT_DESCRIPTOR.len()].copy_from_slice(&CTAP_REPORT_DESCRIPTOR);
+        event
+    }
+
+    // struct uhid_input2_req: size, data[4096].
+    pub fn input2_event(packet: &HidPacket) -> Vec<u8> {
+        let mut event = vec![0; 6 + PACKET_SIZE];
+        event[0..4].copy_from_slice(&UHID_INPUT2.to_ne_bytes());
+        event[4..6].copy_from_slice(&(PACKET_SIZE as u16).to_ne_bytes());
+        event[6..].copy_from_slice(packet);
+        event
+    }
+
+    // struct uhid_output_req: data[4096], size, rtype. Other events are ignored.
+    pub fn parse_output_event(event: &[u8]) -> Option<HidPacket> {
+        if event.len() < 4 + DATA_MAX + 3 {
+            return None;
+        }
+        let event_type = u32::from_ne_bytes(*array_ref!(event, 0, 4));
+        let size = u16::from_ne_bytes(*array_ref!(event, 4 + DATA_MAX, 2)) as usize;
+        let report_type = event[4 + DATA_MAX + 2];
+        if event_type != UHID_OUTPUT || report_type != UHID_OUTPUT_REPORT {
+            return None;
+        }
+        let mut data = &event[4..4 + size.min(DATA_MAX)];
+        // Hosts prefix the report with its ID, which is always 0 as we have a single report.
+        if data.len() == PACKET_SIZE + 1 {
+            data = &data[1..];
+        }
+        if data.len() != PACKET_SIZE {
+            return None;
+        }
+        Some(*array_ref!(data, 0, PACKET_SIZE))
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        fn output_event(data: &[u8], report_type: u8) -> Vec<u8> {
+            let mut event = vec![0; EVENT_SIZE];
+            event[0..4].copy_from_slice(&UHID_OUTPUT.to_ne_bytes());
+            event[4..4 + data.len()].copy_from_slice(data);
+            event[4 + DATA_MAX..4 + DATA_MAX + 2]
+                .copy_from_slice(&(data.len() as u16).to_ne_bytes());
+            event[4 + DATA_MAX + 2] = report_type;
+            event
+        }
+
+        #[test]
+        fn test_parse_output_event() {
+            let packet = [0x55; PACKET_SIZE];
+            let event = output_event(&packet, UHID_OUTPUT_REPORT);
+            assert_eq!(
+                parse_output_event(&event).map(|p| p.to_vec()),
+                Some(packet.to_vec())
+            );
+        }
+
+        #[test]
+        fn test_parse_output_event_with_report_id() {
+            let mut data = vec![0x00];
+            data.extend_from_slice(&[0x55; PACKET_SIZE]);
+            let event = output_event(&data, UHID_OUTPUT_REPORT);
+            assert_eq!(
+                parse_output_event(&event).map(|p| p.to_vec()),
+                Some(data[1..].to_vec())
+            );
+        }
+
+        #[test]
+        fn test_parse_output_event_ignores_other_events() {
+            let packet = [0x55; PACKET_SIZE];
+            let mut event = output_event(&packet, UHID_OUTPUT_REPORT);
+            event[0..4].copy_from_slice(&UHID_CREATE2.to_ne_bytes());
+            assert!(parse_output_event(&event).is_none());
+            let event = output_event(&packet, 0);
+            assert!(parse_output_event(&event).is_none());
+            let event = output_event(&packet[..10], UHID_OUTPUT_REPORT);
+            assert!(parse_output_event(&event).is_none());
+            assert!(parse_output_event(&event[..100]).is_none());
+        }
+
+        #[test]
+        fn test_input2_event() {
+            let event = input2_event(&[0x55; PACKET_SIZE]);
+            assert_eq!(event[0..4], UHID_INPUT2.to_ne_bytes());
+            assert_eq!(event[4..6], (PACKET_SIZE as u16).to_ne_bytes());
+            assert_eq!(event[6..], [0x55; PACKET_SIZE][..]);
+        }
+    }
+}
@@ -14,9 +14,11 @@
 
 use super::data_formats::{
     ok_or_missing, read_array, read_byte_string, read_integer, read_map, read_text_string,
-    read_unsigned, ClientPinSubCommand, CoseKey, Extensions, GetAssertionOptions,
-    MakeCredentialOptions, PublicKeyCredentialDescriptor, PublicKeyCredentialRpEntity,
-    PublicKeyCredentialType, PublicKeyCredentialUserEntity,
+    read_unsigned, ClientPinSubCommand, ConfigSubCommand, ConfigSubCommandParams, CoseKey,
+    CredentialManagementSubCommand, CredentialManagementSubCommandParameters, Extensions,
+    GetAssertionOptions, MakeCredentialOptions, PublicKeyCredentialDescriptor,
+    PublicKeyCredentialRpEntity, PublicKeyCredentialType, PublicKeyCredentialUserEntity,
+    SetMinPinLengthParams,
 };
 use super::status_code::Ctap2StatusCode;
 use alloc::string::String;
@@ -32,9 +34,23 @@ pub enum Command {
     AuthenticatorClientPin(AuthenticatorClientPinParameters),
     AuthenticatorReset,
     AuthenticatorGetNextAssertion,
+    AuthenticatorCredentialManagement(AuthenticatorCredentialManagementParameters),
+    AuthenticatorSelection,
+    AuthenticatorConfig(AuthenticatorConfigParameters),
     // TODO(kaczmarczyck) implement FIDO 2.1 commands (see below consts)
 }
 
+// Returns the CBOR encoding of a parsed value. The reader only accepts canonical CBOR, so this gives
+// back the bytes that the host sent, which pinUvAuthParams authenticate.
+fn encode_cbor(cbor_value: &cbor::Value) -> Result<Vec<u8>, Ctap2StatusCode> {
+    let mut encoded_cbor = Vec::new();
+    if cbor::write(cbor_value.clone(), &mut encoded_cbor) {
+        Ok(encoded_cbor)
+    } else {
+        Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+    }
+}
+
 impl From<cbor::reader::DecoderError> for Ctap2StatusCode {
     fn from(_: cbor::reader::DecoderError) -> Self {
         Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR
@@ -96,6 +112,22 @@ impl Command {
                 // Parameters are ignored.
                 Ok(Command::AuthenticatorGetNextAssertion)
             }
+            Command::AUTHENTICATOR_CREDENTIAL_MANAGEMENT => {
+                let decoded_cbor = cbor::read(&bytes[1..])?;
+                Ok(Command::AuthenticatorCredentialManagement(
+                    AuthenticatorCredentialManagementParameters::try_from(decoded_cbor)?,
+                ))
+            }
+            Command::AUTHENTICATOR_SELECTION => {
+                // Parameters are ignored.
+                Ok(Command::AuthenticatorSelection)
+            }
+            Command::AUTHENTICATOR_CONFIG => {
+                let decoded_cbor = cbor::read(&bytes[1..])?;
+                Ok(Command::AuthenticatorConfig(
+                    AuthenticatorConfigParameters::try_from(decoded_cbor)?,
+                ))
+            }
             _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND),
         }
     }
@@ -113,6 +145,7 @@ pub struct AuthenticatorMakeCredentialParameters {
     pub options: MakeCredentialOptions,
     pub pin_uv_auth_param: Option<Vec<u8>>,
     pub pin_uv_auth_protocol: Option<u64>,
+    pub enterprise_attestation: Option<u64>,
 }
 
 impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
@@ -177,6 +210,11 @@ impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
             .map(read_unsigned)
             .transpose()?;
 
+        let enterprise_attestation = param_map
+            .get(&cbor_unsigned!(10))
+            .map(read_unsigned)
+            .transpose()?;
+
         Ok(AuthenticatorMakeCredentialParameters {
             client_data_hash,
             rp,
@@ -187,6 +225,7 @@ impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
             options,
             pin_uv_auth_param,
             pin_uv_auth_protocol,
+            enterprise_attestation,
         })
     }
 }
@@ -268,6 +307,8 @@ pub struct AuthenticatorClientPinParameters {
     pub pin_auth: Option<Vec<u8>>,
     pub new_pin_enc: Option<Vec<u8>>,
     pub pin_hash_enc: Option<Vec<u8>>,
+    pub permissions: Option<u8>,
+    pub permissions_rp_id: Option<String>,
 }
 
 impl TryFrom<cbor::Value> for AuthenticatorClientPinParameters {
@@ -302,6 +343,18 @@ impl TryFrom<cbor::Value> for AuthenticatorClientPinParameters {
             .map(read_byte_string)
             .transpose()?;
 
+        // All permissions defined so far fit into one byte, higher bits are ignored.
+        let permissions = param_map
+            .get(&cbor_unsigned!(9))
+            .map(read_unsigned)
+            .transpose()?
+            .map(|permissions| permissions as u8);
+
+        let permissions_rp_id = param_map
+            .get(&cbor_unsigned!(10))
+            .map(read_text_string)
+            .transpose()?;
+
         Ok(AuthenticatorClientPinParameters {
             pin_protocol,
             sub_command,
@@ -309,6 +362,109 @@ impl TryFrom<cbor::Value> for AuthenticatorClientPinParameters {
             pin_auth,
             new_pin_enc,
             pin_hash_enc,
+            permissions,
+            permissions_rp_id,
+        })
+    }
+}
+
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct AuthenticatorCredentialManagementParameters {
+    pub sub_command: CredentialManagementSubCommand,
+    pub sub_command_params: Option<CredentialManagementSubCommandParameters>,
+    // The subcommand parameters as sent by the host, including fields that are not parsed.
+    pub raw_sub_command_params: Option<Vec<u8>>,
+    pub pin_protocol: Option<u64>,
+    pub pin_auth: Option<Vec<u8>>,
+}
+
+impl TryFrom<cbor::Value> for AuthenticatorCredentialManagementParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let param_map = read_map(&cbor_value)?;
+
+        let sub_command = CredentialManagementSubCommand::try_from(ok_or_missing(
+            param_map.get(&cbor_unsigned!(1)),
+        )?)?;
+
+        let sub_command_params = param_map
+            .get(&cbor_unsigned!(2))
+            .map(CredentialManagementSubCommandParameters::try_from)
+            .transpose()?;
+        let raw_sub_command_params = param_map
+            .get(&cbor_unsigned!(2))
+            .map(encode_cbor)
+            .transpose()?;
+
+        let pin_protocol = param_map
+            .get(&cbor_unsigned!(3))
+            .map(read_unsigned)
+            .transpose()?;
+
+        let pin_auth = param_map
+            .get(&cbor_unsigned!(4))
+            .map(read_byte_string)
+            .transpose()?;
+
+        Ok(AuthenticatorCredentialManagementParameters {
+            sub_command,
+            sub_command_params,
+            raw_sub_command_params,
+            pin_protocol,
+            pin_auth,
+        })
+    }
+}
+
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct AuthenticatorConfigParameters {
+    pub sub_command: ConfigSubCommand,
+    pub sub_command_params: Option<ConfigSubCommandParams>,
+    // The subcommand parameters as sent by the host, including fields that are not parsed.
+    pub raw_sub_command_params: Option<Vec<u8>>,
+    pub pin_uv_auth_param: Option<Vec<u8>>,
+    pub pin_uv_auth_protocol: Option<u64>,
+}
+
+impl TryFrom<cbor::Value> for AuthenticatorConfigParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let param_map = read_map(&cbor_value)?;
+
+        let sub_command =
+            ConfigSubCommand::try_from(ok_or_missing(param_map.get(&cbor_unsigned!(1)))?)?;
+
+        // The type of the parameters depends on the subcommand. Parameters of subcommands that
+        // don't expect any are ignored.
+        let sub_command_params = match (sub_command, param_map.get(&cbor_unsigned!(2))) {
+            (ConfigSubCommand::SetMinPinLength, Some(params)) => Some(
+                ConfigSubCommandParams::SetMinPinLength(SetMinPinLengthParams::try_from(params)?),
+            ),
+            _ => None,
+        };
+        let raw_sub_command_params = param_map
+            .get(&cbor_unsigned!(2))
+            .map(encode_cbor)
+            .transpose()?;
+
+        let pin_uv_auth_protocol = param_map
+            .get(&cbor_unsigned!(3))
+            .map(read_unsigned)
+            .transpose()?;
+
+        let pin_uv_auth_param = param_map
+            .get(&cbor_unsigned!(4))
+            .map(read_byte_string)
+            .transpose()?;
+
+        Ok(AuthenticatorConfigParameters {
+            sub_command,
+            sub_command_params,
+            raw_sub_command_params,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
         })
     }
 }
@@ -377,6 +533,7 @@ mod test {
             options,
             pin_uv_auth_param: Some(vec![0x12, 0x34]),
             pin_uv_auth_protocol: Some(1),
+            enterprise_attestation: None,
         };
 
         assert_eq!(
@@ -440,6 +597,8 @@ mod test {
             4 => vec! [0xBB],
             5 => vec! [0xCC],
             6 => vec! [0xDD],
+            9 => 0x03,
+            10 => "example.com",
         };
         let returned_pin_protocol_parameters =
             AuthenticatorClientPinParameters::try_from(cbor_value).unwrap();
@@ -451,6 +610,8 @@ mod test {
             pin_auth: Some(vec![0xBB]),
             new_pin_enc: Some(vec![0xCC]),
             pin_hash_enc: Some(vec![0xDD]),
+            permissions: Some(0x03),
+            permissions_rp_id: Some(String::from("example.com")),
         };
 
         assert_eq!(
@@ -480,4 +641,77 @@ mod test {
         let command = Command::deserialize(&cbor_bytes);
         assert_eq!(command, Ok(Command::AuthenticatorGetNextAssertion));
     }
+
+    #[test]
+    fn test_deserialize_selection() {
+        let cbor_bytes = [Command::AUTHENTICATOR_SELECTION];
+        let command = Command::deserialize(&cbor_bytes);
+        assert_eq!(command, Ok(Command::AuthenticatorSelection));
+    }
+
+    #[test]
+    fn test_deserialize_credential_management() {
+        let cbor_value = cbor_map! {
+            1 => CredentialManagementSubCommand::EnumerateCredentialsBegin,
+            2 => cbor_map! {
+                0x01 => vec![0x1D; 32],
+            },
+            3 => 1,
+            4 => vec![0x9A; 16],
+        };
+        let mut cbor_bytes = vec![Command::AUTHENTICATOR_CREDENTIAL_MANAGEMENT];
+        assert!(cbor::write(cbor_value, &mut cbor_bytes));
+        let command = Command::deserialize(&cbor_bytes);
+
+        let expected_parameters = AuthenticatorCredentialManagementParameters {
+            sub_command: CredentialManagementSubCommand::EnumerateCredentialsBegin,
+            sub_command_params: Some(CredentialManagementSubCommandParameters {
+                rp_id_hash: Some(vec![0x1D; 32]),
+                credential_id: None,
+                user: None,
+            }),
+            raw_sub_command_params: Some([&[0xA1, 0x01, 0x58, 0x20][..], &[0x1D; 32]].concat()),
+            pin_protocol: Some(1),
+            pin_auth: Some(vec![0x9A; 16]),
+        };
+        assert_eq!(
+            command,
+            Ok(Command::AuthenticatorCredentialManagement(
+                expected_parameters
+            ))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_config() {
+        let cbor_value = cbor_map! {
+            1 => ConfigSubCommand::SetMinPinLength,
+            2 => cbor_map! {
+                0x01 => 6,
+            },
+            3 => 1,
+            4 => vec![0x9A; 16],
+        };
+        let mut cbor_bytes = vec![Command::AUTHENTICATOR_CONFIG];
+        assert!(cbor::write(cbor_value, &mut cbor_bytes));
+        let command = Command::deserialize(&cbor_bytes);
+
+        let expected_parameters = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::SetMinPinLength,
+            sub_command_params: Some(ConfigSubCommandParams::SetMinPinLength(
+                SetMinPinLengthParams {
+                    new_min_pin_length: Some(6),
+                    min_pin_length_rp_ids: None,
+                    force_change_pin: None,
+                },
+            )),
+            raw_sub_command_params: Some(vec![0xA1, 0x01, 0x06]),
+            pin_uv_auth_param: Some(vec![0x9A; 16]),
+            pin_uv_auth_protocol: Some(1),
+        };
+        assert_eq!(
+            command,
+            Ok(Command::AuthenticatorConfig(expected_parameters))
+        );
+    }
 }
@@ -0,0 +1,495 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::command::AuthenticatorConfigParameters;
+use super::data_formats::{ConfigSubCommand, ConfigSubCommandParams, SetMinPinLengthParams};
+use super::pin_protocol::PinUvAuthProtocol;
+use super::platform::{Clock, PersistentStorage, UserPresence};
+use super::response::ResponseData;
+use super::status_code::Ctap2StatusCode;
+use super::token_state::PinPermission;
+use super::CtapState;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use crypto::rng256::Rng256;
+
+// The FIDO 2.1 specification authenticates config commands with this command byte, independent of
+// the command byte used for the preview version of the command.
+const AUTHENTICATOR_CONFIG_AUTH_BYTE: u8 = 0x0D;
+
+impl<'a, R, CheckUserPresence, S, C> CtapState<'a, R, CheckUserPresence, S, C>
+where
+    R: Rng256,
+    CheckUserPresence: UserPresence,
+    S: PersistentStorage,
+    C: Clock,
+{
+    // The pinUvAuthParam is computed over 32 bytes of 0xFF, the command byte, the subcommand byte
+    // and the subcommand parameters as sent by the host, if present.
+    fn check_config_pin_uv_auth_param(
+        &mut self,
+        sub_command: ConfigSubCommand,
+        raw_sub_command_params: Option<Vec<u8>>,
+        pin_uv_auth_param: Option<Vec<u8>>,
+        pin_uv_auth_protocol: Option<u64>,
+    ) -> Result<(), Ctap2StatusCode> {
+        let pin_uv_auth_param = pin_uv_auth_param.ok_or(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)?;
+        let pin_uv_auth_protocol =
+            pin_uv_auth_protocol.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?;
+        let pin_uv_auth_protocol = PinUvAuthProtocol::try_from(pin_uv_auth_protocol)?;
+        let mut auth_message = vec![0xFF; 32];
+        auth_message.push(AUTHENTICATOR_CONFIG_AUTH_BYTE);
+        auth_message.push(u8::from(sub_command));
+        if let Some(raw_sub_command_params) = raw_sub_command_params {
+            auth_message.extend(raw_sub_command_params);
+        }
+        if !self.check_pin_uv_auth_param(pin_uv_auth_protocol, &auth_message, &pin_uv_auth_param) {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+        }
+        self.pin_uv_auth_token_state
+            .has_permission(PinPermission::AuthenticatorConfiguration)?;
+        self.pin_uv_auth_token_state.mark_used();
+        Ok(())
+    }
+
+    fn process_set_min_pin_length(
+        &mut self,
+        params: SetMinPinLengthParams,
+    ) -> Result<(), Ctap2StatusCode> {
+        let SetMinPinLengthParams {
+            new_min_pin_length,
+            min_pin_length_rp_ids,
+            force_change_pin,
+        } = params;
+        let store_min_pin_length = self.persistent_store.min_pin_length();
+        let new_min_pin_length = new_min_pin_length.unwrap_or(store_min_pin_length);
+        // The minimum PIN length can only be decreased with a reset.
+        if new_min_pin_length < store_min_pin_length {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
+        }
+        let force_change_pin = force_change_pin.unwrap_or(false);
+        let has_pin = self.persistent_store.pin_hash().is_some();
+        if force_change_pin && !has_pin {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
+        }
+        if let Some(min_pin_length_rp_ids) = min_pin_length_rp_ids {
+            self.persistent_store
+                .set_min_pin_length_rp_ids(min_pin_length_rp_ids)?;
+        }
+        self.persistent_store
+            .set_min_pin_length(new_min_pin_length)?;
+        // We don't know the length of the current PIN, so it has to be changed whenever the
+        // minimum length increases.
+        if has_pin && (force_change_pin || new_min_pin_length > store_min_pin_length) {
+            self.persistent_store.set_force_pin_change(true)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn process_config(
+        &mut self,
+        config_params: AuthenticatorConfigParameters,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        let AuthenticatorConfigParameters {
+            sub_command,
+            sub_command_params,
+            raw_sub_command_params,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        } = config_params;
+
+        // Without any user verification configured, the authenticator can be configured without
+        // authentication.
+        if pin_uv_auth_param.is_some()
+            || self.persistent_store.pin_hash().is_some()
+            || self.persistent_store.always_uv()
+        {
+            self.check_config_pin_uv_auth_param(
+                sub_command,
+                raw_sub_command_params,
+                pin_uv_auth_param,
+                pin_uv_auth_protocol,
+            )?;
+        }
+
+        match sub_command {
+            ConfigSubCommand::EnableEnterpriseAttestation => {
+                self.persistent_store.enable_enterprise_attestation()?
+            }
+            ConfigSubCommand::ToggleAlwaysUv => self.persistent_store.toggle_always_uv()?,
+            ConfigSubCommand::SetMinPinLength => match sub_command_params {
+                Some(ConfigSubCommandParams::SetMinPinLength(params)) => {
+                    self.process_set_min_pin_length(params)?
+                }
+                None => return Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER),
+            },
+            // No vendor specific configuration is defined yet.
+            ConfigSubCommand::VendorPrototype => {
+                return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+            }
+        }
+        Ok(ResponseData::AuthenticatorConfig)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::platform::TestClock;
+    use super::super::time::ClockValue;
+    use super::super::{new_buffer_storage, PIN_AUTH_LENGTH};
+    use super::*;
+    use alloc::string::String;
+    use crypto::hmac::hmac_256;
+    use crypto::rng256::ThreadRng256;
+    use crypto::sha256::Sha256;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+    const PIN_UV_AUTH_TOKEN: [u8; 32] = [0x55; 32];
+
+    fn create_parameters(
+        sub_command: ConfigSubCommand,
+        sub_command_params: Option<ConfigSubCommandParams>,
+    ) -> AuthenticatorConfigParameters {
+        let mut auth_message = vec![0xFF; 32];
+        auth_message.push(AUTHENTICATOR_CONFIG_AUTH_BYTE);
+        auth_message.push(u8::from(sub_command));
+        let raw_sub_command_params = sub_command_params.clone().map(|sub_command_params| {
+            let mut raw_sub_command_params = Vec::new();
+            assert!(cbor::write(
+                sub_command_params.into(),
+                &mut raw_sub_command_params
+            ));
+            raw_sub_command_params
+        });
+        if let Some(raw_sub_command_params) = &raw_sub_command_params {
+            auth_message.extend(raw_sub_command_params);
+        }
+        let pin_uv_auth_param =
+            hmac_256::<Sha256>(&PIN_UV_AUTH_TOKEN, &auth_message)[..PIN_AUTH_LENGTH].to_vec();
+        AuthenticatorConfigParameters {
+            sub_command,
+            sub_command_params,
+            raw_sub_command_params,
+            pin_uv_auth_param: Some(pin_uv_auth_param),
+            pin_uv_auth_protocol: Some(1),
+        }
+    }
+
+    fn create_min_pin_length_parameters(
+        new_min_pin_length: Option<u8>,
+        min_pin_length_rp_ids: Option<Vec<String>>,
+        force_change_pin: Option<bool>,
+    ) -> AuthenticatorConfigParameters {
+        let params = SetMinPinLengthParams {
+            new_min_pin_length,
+            min_pin_length_rp_ids,
+            force_change_pin,
+        };
+        create_parameters(
+            ConfigSubCommand::SetMinPinLength,
+            Some(ConfigSubCommandParams::SetMinPinLength(params)),
+        )
+    }
+
+    #[test]
+    fn test_process_toggle_always_uv() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::AuthenticatorConfiguration as u8,
+                None,
+            );
+
+        // Without a PIN, no authentication is needed to enable alwaysUv.
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            sub_command_params: None,
+            raw_sub_command_params: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Ok(ResponseData::AuthenticatorConfig));
+        assert!(ctap_state.persistent_store.always_uv());
+
+        // With alwaysUv, authentication is always needed.
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            sub_command_params: None,
+            raw_sub_command_params: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED));
+        assert!(ctap_state.persistent_store.always_uv());
+
+        let config_params = create_parameters(ConfigSubCommand::ToggleAlwaysUv, None);
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Ok(ResponseData::AuthenticatorConfig));
+        assert!(!ctap_state.persistent_store.always_uv());
+    }
+
+    #[test]
+    fn test_process_config_pin_uv_auth_param() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::AuthenticatorConfiguration as u8,
+                None,
+            );
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0x88; 16])
+            .unwrap();
+
+        let mut config_params = create_parameters(ConfigSubCommand::ToggleAlwaysUv, None);
+        config_params.pin_uv_auth_param = None;
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED));
+
+        let mut config_params = create_parameters(ConfigSubCommand::ToggleAlwaysUv, None);
+        config_params.pin_uv_auth_protocol = Some(2);
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+
+        // The pinUvAuthParam of another subcommand is not valid.
+        let mut config_params = create_parameters(ConfigSubCommand::ToggleAlwaysUv, None);
+        config_params.sub_command = ConfigSubCommand::EnableEnterpriseAttestation;
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+        assert!(!ctap_state.persistent_store.always_uv());
+        assert!(!ctap_state.persistent_store.enterprise_attestation());
+    }
+
+    #[test]
+    fn test_process_config_permission() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0x88; 16])
+            .unwrap();
+
+        let config_params = create_parameters(ConfigSubCommand::ToggleAlwaysUv, None);
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+        assert!(!ctap_state.persistent_store.always_uv());
+    }
+
+    #[test]
+    fn test_process_enable_enterprise_attestation() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::AuthenticatorConfiguration as u8,
+                None,
+            );
+
+        let config_params = create_parameters(ConfigSubCommand::EnableEnterpriseAttestation, None);
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Ok(ResponseData::AuthenticatorConfig));
+        assert!(ctap_state.persistent_store.enterprise_attestation());
+    }
+
+    #[test]
+    fn test_process_set_min_pin_length() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::AuthenticatorConfiguration as u8,
+                None,
+            );
+
+        let rp_ids = vec![String::from("example.com")];
+        let config_params = create_min_pin_length_parameters(Some(6), Some(rp_ids.clone()), None);
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Ok(ResponseData::AuthenticatorConfig));
+        assert_eq!(ctap_state.persistent_store.min_pin_length(), 6);
+        assert_eq!(ctap_state.persistent_store.min_pin_length_rp_ids(), rp_ids);
+        assert!(!ctap_state.persistent_store.force_pin_change());
+
+        // The minimum PIN length can't decrease.
+        let config_params = create_min_pin_length_parameters(Some(5), None, None);
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(
+            response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)
+        );
+        assert_eq!(ctap_state.persistent_store.min_pin_length(), 6);
+
+        // A PIN change can only be forced if there is a PIN.
+        let config_params = create_min_pin_length_parameters(None, None, Some(true));
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET));
+
+        // Increasing the minimum PIN length forces a PIN change.
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0x88; 16])
+            .unwrap();
+        let config_params = create_min_pin_length_parameters(Some(6), None, None);
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Ok(ResponseData::AuthenticatorConfig));
+        assert!(!ctap_state.persistent_store.force_pin_change());
+        let config_params = create_min_pin_length_parameters(Some(8), None, None);
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Ok(ResponseData::AuthenticatorConfig));
+        assert_eq!(ctap_state.persistent_store.min_pin_length(), 8);
+        assert_eq!(ctap_state.persistent_store.min_pin_length_rp_ids(), rp_ids);
+        assert!(ctap_state.persistent_store.force_pin_change());
+    }
+
+    #[test]
+    fn test_process_set_min_pin_length_unknown_fields() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::AuthenticatorConfiguration as u8,
+                None,
+            );
+
+        // The pinUvAuthParam covers the parameters that are not parsed.
+        let sub_command_params = cbor_map! {
+            0x01 => 6,
+            0x7F => "unknown",
+        };
+        let mut auth_message = vec![0xFF; 32];
+        auth_message.push(AUTHENTICATOR_CONFIG_AUTH_BYTE);
+        auth_message.push(u8::from(ConfigSubCommand::SetMinPinLength));
+        assert!(cbor::write(sub_command_params.clone(), &mut auth_message));
+        let pin_uv_auth_param =
+            hmac_256::<Sha256>(&PIN_UV_AUTH_TOKEN, &auth_message)[..PIN_AUTH_LENGTH].to_vec();
+        let config_params = AuthenticatorConfigParameters::try_from(cbor_map! {
+            1 => ConfigSubCommand::SetMinPinLength,
+            2 => sub_command_params,
+            3 => 1,
+            4 => pin_uv_auth_param,
+        })
+        .unwrap();
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Ok(ResponseData::AuthenticatorConfig));
+        assert_eq!(ctap_state.persistent_store.min_pin_length(), 6);
+    }
+
+    #[test]
+    fn test_process_vendor_prototype() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::AuthenticatorConfiguration as u8,
+                None,
+            );
+
+        let config_params = create_parameters(ConfigSubCommand::VendorPrototype, None);
+        let response = ctap_state.process_config(config_params);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND));
+    }
+}
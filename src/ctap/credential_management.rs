@@ -0,0 +1,1052 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::command::AuthenticatorCredentialManagementParameters;
+use super::data_formats::{
+    CoseKey, CredentialManagementSubCommand, CredentialManagementSubCommandParameters,
+    PublicKeyCredentialDescriptor, PublicKeyCredentialRpEntity, PublicKeyCredentialSource,
+    PublicKeyCredentialUserEntity,
+};
+use super::hid::ChannelID;
+use super::pin_protocol::PinUvAuthProtocol;
+use super::platform::{Clock, PersistentStorage, UserPresence};
+use super::response::{AuthenticatorCredentialManagementResponse, ResponseData};
+use super::status_code::Ctap2StatusCode;
+use super::time::ClockValue;
+use super::token_state::PinPermission;
+use super::{truncate_to_char_boundary, CtapState, StatefulCommand};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use crypto::rng256::Rng256;
+use crypto::sha256::Sha256;
+use crypto::Hash256;
+
+// Generates the response for subcommands enumerating RPs.
+fn enumerate_rps_response(
+    rp_id: String,
+    total_rps: Option<u64>,
+) -> AuthenticatorCredentialManagementResponse {
+    let rp_id_hash = Sha256::hash(rp_id.as_bytes()).to_vec();
+    AuthenticatorCredentialManagementResponse {
+        rp: Some(PublicKeyCredentialRpEntity {
+            rp_id,
+            rp_name: None,
+            rp_icon: None,
+        }),
+        rp_id_hash: Some(rp_id_hash),
+        total_rps,
+        ..Default::default()
+    }
+}
+
+// Generates the response for subcommands enumerating credentials.
+fn enumerate_credentials_response(
+    credential: PublicKeyCredentialSource,
+    total_credentials: Option<u64>,
+) -> AuthenticatorCredentialManagementResponse {
+    let PublicKeyCredentialSource {
+        key_type,
+        credential_id,
+        private_key,
+        user_handle,
+        other_ui,
+        ..
+    } = credential;
+    AuthenticatorCredentialManagementResponse {
+        user: Some(PublicKeyCredentialUserEntity {
+            user_id: user_handle,
+            user_name: None,
+            user_display_name: other_ui,
+            user_icon: None,
+        }),
+        credential_id: Some(PublicKeyCredentialDescriptor {
+            key_type,
+            key_id: credential_id,
+            transports: None, // You can set USB as a hint here.
+        }),
+        public_key: Some(CoseKey::from(&private_key)),
+        total_credentials,
+        ..Default::default()
+    }
+}
+
+impl<'a, R, CheckUserPresence, S, C> CtapState<'a, R, CheckUserPresence, S, C>
+where
+    R: Rng256,
+    CheckUserPresence: UserPresence,
+    S: PersistentStorage,
+    C: Clock,
+{
+    // The pinAuth is computed over the subcommand byte, followed by the subcommand parameters as
+    // sent by the host, if present.
+    fn check_credential_management_pin_auth(
+        &mut self,
+        sub_command: CredentialManagementSubCommand,
+        raw_sub_command_params: Option<Vec<u8>>,
+        pin_protocol: Option<u64>,
+        pin_auth: Option<Vec<u8>>,
+    ) -> Result<(), Ctap2StatusCode> {
+        let pin_auth = pin_auth.ok_or(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)?;
+        let pin_protocol = pin_protocol.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?;
+        let pin_protocol = PinUvAuthProtocol::try_from(pin_protocol)?;
+        let mut auth_message = vec![u8::from(sub_command)];
+        if let Some(raw_sub_command_params) = raw_sub_command_params {
+            auth_message.extend(raw_sub_command_params);
+        }
+        if !self.check_pin_uv_auth_param(pin_protocol, &auth_message, &pin_auth) {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+        }
+        self.pin_uv_auth_token_state
+            .has_permission(PinPermission::CredentialManagement)
+    }
+
+    fn process_get_creds_metadata(
+        &self,
+    ) -> Result<AuthenticatorCredentialManagementResponse, Ctap2StatusCode> {
+        // Tokens scoped to a relying party can't access information about all credentials.
+        self.pin_uv_auth_token_state.has_no_permissions_rp_id()?;
+        Ok(AuthenticatorCredentialManagementResponse {
+            existing_resident_credentials_count: Some(
+                self.persistent_store.count_credentials() as u64
+            ),
+            max_possible_remaining_resident_credentials_count: Some(
+                self.persistent_store.remaining_credentials() as u64,
+            ),
+            ..Default::default()
+        })
+    }
+
+    fn process_enumerate_rps_begin(
+        &mut self,
+        cid: ChannelID,
+        now: ClockValue,
+    ) -> Result<AuthenticatorCredentialManagementResponse, Ctap2StatusCode> {
+        self.pin_uv_auth_token_state.has_no_permissions_rp_id()?;
+        let mut rp_ids = self.persistent_store.list_rp_ids();
+        let total_rps = rp_ids.len() as u64;
+        rp_ids.reverse();
+        let rp_id = rp_ids
+            .pop()
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?;
+        if !rp_ids.is_empty() {
+            self.start_stateful_command(StatefulCommand::EnumerateRps(rp_ids), cid, now);
+        }
+        Ok(enumerate_rps_response(rp_id, Some(total_rps)))
+    }
+
+    fn process_enumerate_rps_get_next_rp(
+        &mut self,
+        cid: ChannelID,
+        now: ClockValue,
+    ) -> Result<AuthenticatorCredentialManagementResponse, Ctap2StatusCode> {
+        let mut rp_ids = match self.continue_stateful_command(cid, now)? {
+            StatefulCommand::EnumerateRps(rp_ids) => rp_ids,
+            _ => return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED),
+        };
+        let rp_id = rp_ids.pop().ok_or(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)?;
+        if !rp_ids.is_empty() {
+            self.start_stateful_command(StatefulCommand::EnumerateRps(rp_ids), cid, now);
+        }
+        Ok(enumerate_rps_response(rp_id, None))
+    }
+
+    fn process_enumerate_credentials_begin(
+        &mut self,
+        sub_command_params: CredentialManagementSubCommandParameters,
+        cid: ChannelID,
+        now: ClockValue,
+    ) -> Result<AuthenticatorCredentialManagementResponse, Ctap2StatusCode> {
+        let rp_id_hash = sub_command_params
+            .rp_id_hash
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?;
+        self.pin_uv_auth_token_state
+            .has_permissions_rp_id_hash(&rp_id_hash)?;
+        // The store is indexed by RP ID, so we look for the RP ID matching the hash first.
+        let rp_id = self
+            .persistent_store
+            .list_rp_ids()
+            .into_iter()
+            .find(|rp_id| Sha256::hash(rp_id.as_bytes())[..] == rp_id_hash[..])
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?;
+        let mut credentials = self.persistent_store.filter_credential(&rp_id);
+        let total_credentials = credentials.len() as u64;
+        let credential = credentials
+            .pop()
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?;
+        if !credentials.is_empty() {
+            self.start_stateful_command(
+                StatefulCommand::EnumerateCredentials(credentials),
+                cid,
+                now,
+            );
+        }
+        Ok(enumerate_credentials_response(
+            credential,
+            Some(total_credentials),
+        ))
+    }
+
+    fn process_enumerate_credentials_get_next_credential(
+        &mut self,
+        cid: ChannelID,
+        now: ClockValue,
+    ) -> Result<AuthenticatorCredentialManagementResponse, Ctap2StatusCode> {
+        let mut credentials = match self.continue_stateful_command(cid, now)? {
+            StatefulCommand::EnumerateCredentials(credentials) => credentials,
+            _ => return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED),
+        };
+        let credential = credentials
+            .pop()
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)?;
+        if !credentials.is_empty() {
+            self.start_stateful_command(
+                StatefulCommand::EnumerateCredentials(credentials),
+                cid,
+                now,
+            );
+        }
+        Ok(enumerate_credentials_response(credential, None))
+    }
+
+    fn process_delete_credential(
+        &mut self,
+        sub_command_params: CredentialManagementSubCommandParameters,
+    ) -> Result<(), Ctap2StatusCode> {
+        let credential_id = sub_command_params
+            .credential_id
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?
+            .key_id;
+        let credential = self
+            .persistent_store
+            .find_credential_by_id(&credential_id)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?;
+        self.pin_uv_auth_token_state
+            .has_permissions_rp_id(&credential.rp_id)?;
+        self.persistent_store.delete_credential(&credential_id)
+    }
+
+    fn process_update_user_information(
+        &mut self,
+        sub_command_params: CredentialManagementSubCommandParameters,
+    ) -> Result<(), Ctap2StatusCode> {
+        let credential_id = sub_command_params
+            .credential_id
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?
+            .key_id;
+        let user = sub_command_params
+            .user
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?;
+        let mut credential = self
+            .persistent_store
+            .find_credential_by_id(&credential_id)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?;
+        self.pin_uv_auth_token_state
+            .has_permissions_rp_id(&credential.rp_id)?;
+        // The user handle identifies the credential in the store, it can't be changed.
+        if credential.user_handle != user.user_id {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+        // Missing or empty fields are removed from the credential.
+        credential.other_ui = user
+            .user_display_name
+            .filter(|name| !name.is_empty())
+            .map(|name| truncate_to_char_boundary(&name, 64).to_string());
+        self.persistent_store
+            .update_credential(self.rng, credential)
+    }
+
+    pub(super) fn process_credential_management(
+        &mut self,
+        credential_management_params: AuthenticatorCredentialManagementParameters,
+        cid: ChannelID,
+        now: ClockValue,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        let AuthenticatorCredentialManagementParameters {
+            sub_command,
+            sub_command_params,
+            raw_sub_command_params,
+            pin_protocol,
+            pin_auth,
+        } = credential_management_params;
+
+        let is_authenticated = match sub_command {
+            // The follow-up subcommands of enumerations are authorized by their begin subcommand.
+            CredentialManagementSubCommand::EnumerateRpsGetNextRp
+            | CredentialManagementSubCommand::EnumerateCredentialsGetNextCredential => false,
+            _ => {
+                self.stateful_command = None;
+                self.check_credential_management_pin_auth(
+                    sub_command,
+                    raw_sub_command_params,
+                    pin_protocol,
+                    pin_auth,
+                )?;
+                true
+            }
+        };
+
+        let response = match sub_command {
+            CredentialManagementSubCommand::GetCredsMetadata => {
+                Some(self.process_get_creds_metadata()?)
+            }
+            CredentialManagementSubCommand::EnumerateRpsBegin => {
+                Some(self.process_enumerate_rps_begin(cid, now)?)
+            }
+            CredentialManagementSubCommand::EnumerateRpsGetNextRp => {
+                Some(self.process_enumerate_rps_get_next_rp(cid, now)?)
+            }
+            CredentialManagementSubCommand::EnumerateCredentialsBegin => {
+                Some(self.process_enumerate_credentials_begin(
+                    sub_command_params.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
+                    cid,
+                    now,
+                )?)
+            }
+            CredentialManagementSubCommand::EnumerateCredentialsGetNextCredential => {
+                Some(self.process_enumerate_credentials_get_next_credential(cid, now)?)
+            }
+            CredentialManagementSubCommand::DeleteCredential => {
+                self.process_delete_credential(
+                    sub_command_params.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
+                )?;
+                None
+            }
+            CredentialManagementSubCommand::UpdateUserInformation => {
+                self.process_update_user_information(
+                    sub_command_params.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
+                )?;
+                None
+            }
+        };
+        // The subcommands check the permissions RP ID of the token, so it is only used now.
+        if is_authenticated {
+            self.pin_uv_auth_token_state.mark_used();
+        }
+        Ok(ResponseData::AuthenticatorCredentialManagement(response))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::command::AuthenticatorGetAssertionParameters;
+    use super::super::data_formats::{
+        GetAssertionOptions, PrivateKey, PublicKeyCredentialType, SignatureAlgorithm,
+    };
+    use super::super::platform::TestClock;
+    use super::super::{new_buffer_storage, PIN_AUTH_LENGTH};
+    use super::*;
+    use crypto::hmac::hmac_256;
+    use crypto::rng256::ThreadRng256;
+
+    const DUMMY_CHANNEL_ID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+    const PIN_UV_AUTH_TOKEN: [u8; 32] = [0x55; 32];
+
+    fn create_credential_source(
+        rng: &mut ThreadRng256,
+        rp_id: &str,
+        user_handle: Vec<u8>,
+    ) -> PublicKeyCredentialSource {
+        PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: rng.gen_uniform_u8x32().to_vec(),
+            private_key: PrivateKey::new(rng, SignatureAlgorithm::ES256),
+            rp_id: String::from(rp_id),
+            user_handle,
+            other_ui: None,
+            cred_random: None,
+            cred_protect_policy: None,
+            signature_counter: None,
+            creation_order: 0,
+        }
+    }
+
+    fn create_parameters(
+        sub_command: CredentialManagementSubCommand,
+        sub_command_params: Option<CredentialManagementSubCommandParameters>,
+    ) -> AuthenticatorCredentialManagementParameters {
+        let raw_sub_command_params = sub_command_params.clone().map(|sub_command_params| {
+            let mut raw_sub_command_params = Vec::new();
+            assert!(cbor::write(
+                sub_command_params.into(),
+                &mut raw_sub_command_params
+            ));
+            raw_sub_command_params
+        });
+        let mut auth_message = vec![u8::from(sub_command)];
+        if let Some(raw_sub_command_params) = &raw_sub_command_params {
+            auth_message.extend(raw_sub_command_params);
+        }
+        let pin_auth =
+            hmac_256::<Sha256>(&PIN_UV_AUTH_TOKEN, &auth_message)[..PIN_AUTH_LENGTH].to_vec();
+        AuthenticatorCredentialManagementParameters {
+            sub_command,
+            sub_command_params,
+            raw_sub_command_params,
+            pin_protocol: Some(1),
+            pin_auth: Some(pin_auth),
+        }
+    }
+
+    fn create_get_next_parameters(
+        sub_command: CredentialManagementSubCommand,
+    ) -> AuthenticatorCredentialManagementParameters {
+        AuthenticatorCredentialManagementParameters {
+            sub_command,
+            sub_command_params: None,
+            raw_sub_command_params: None,
+            pin_protocol: None,
+            pin_auth: None,
+        }
+    }
+
+    fn create_credential_id_parameters(
+        credential_id: Vec<u8>,
+        user: Option<PublicKeyCredentialUserEntity>,
+    ) -> CredentialManagementSubCommandParameters {
+        CredentialManagementSubCommandParameters {
+            rp_id_hash: None,
+            credential_id: Some(PublicKeyCredentialDescriptor {
+                key_type: PublicKeyCredentialType::PublicKey,
+                key_id: credential_id,
+                transports: None,
+            }),
+            user,
+        }
+    }
+
+    #[test]
+    fn test_process_get_creds_metadata() {
+        let mut rng = ThreadRng256 {};
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source)
+            .is_ok());
+        let remaining_credentials = ctap_state.persistent_store.remaining_credentials() as u64;
+
+        let params = create_parameters(CredentialManagementSubCommand::GetCredsMetadata, None);
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        let expected_response = AuthenticatorCredentialManagementResponse {
+            existing_resident_credentials_count: Some(1),
+            max_possible_remaining_resident_credentials_count: Some(remaining_credentials),
+            ..Default::default()
+        };
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorCredentialManagement(Some(
+                expected_response
+            )))
+        );
+    }
+
+    #[test]
+    fn test_process_credential_management_permissions_rp_id() {
+        let mut rng = ThreadRng256 {};
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let credential_id = credential_source.credential_id.clone();
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                Some(String::from("another.example.com")),
+            );
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source)
+            .is_ok());
+
+        // Tokens scoped to a relying party can't read the metadata of all credentials.
+        let params = create_parameters(CredentialManagementSubCommand::GetCredsMetadata, None);
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+
+        // Nor can they delete credentials of other relying parties.
+        let sub_command_params = create_credential_id_parameters(credential_id, None);
+        let params = create_parameters(
+            CredentialManagementSubCommand::DeleteCredential,
+            Some(sub_command_params),
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+        assert_eq!(ctap_state.persistent_store.count_credentials(), 1);
+    }
+
+    #[test]
+    fn test_process_credential_management_pin_auth() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+
+        let mut params = create_parameters(CredentialManagementSubCommand::GetCredsMetadata, None);
+        params.pin_auth = None;
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED));
+
+        let mut params = create_parameters(CredentialManagementSubCommand::GetCredsMetadata, None);
+        params.pin_protocol = Some(2);
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+
+        // The pinAuth of another subcommand is not valid.
+        let mut params = create_parameters(CredentialManagementSubCommand::GetCredsMetadata, None);
+        params.sub_command = CredentialManagementSubCommand::EnumerateRpsBegin;
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+    }
+
+    #[test]
+    fn test_process_enumerate_rps() {
+        let mut rng = ThreadRng256 {};
+        let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let credential_source2 =
+            create_credential_source(&mut rng, "another.example.com", vec![0x02]);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+
+        let params = create_parameters(CredentialManagementSubCommand::EnumerateRpsBegin, None);
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS));
+
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source1)
+            .is_ok());
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source2)
+            .is_ok());
+
+        let params = create_parameters(CredentialManagementSubCommand::EnumerateRpsBegin, None);
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        let first_rp_id = match response.unwrap() {
+            ResponseData::AuthenticatorCredentialManagement(Some(response)) => {
+                assert_eq!(response.total_rps, Some(2));
+                let rp_id = response.rp.unwrap().rp_id;
+                assert_eq!(
+                    response.rp_id_hash,
+                    Some(Sha256::hash(rp_id.as_bytes()).to_vec())
+                );
+                rp_id
+            }
+            _ => panic!("Invalid response type"),
+        };
+
+        let params =
+            create_get_next_parameters(CredentialManagementSubCommand::EnumerateRpsGetNextRp);
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        match response.unwrap() {
+            ResponseData::AuthenticatorCredentialManagement(Some(response)) => {
+                assert_eq!(response.total_rps, None);
+                assert_ne!(response.rp.unwrap().rp_id, first_rp_id);
+            }
+            _ => panic!("Invalid response type"),
+        };
+
+        let params =
+            create_get_next_parameters(CredentialManagementSubCommand::EnumerateRpsGetNextRp);
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED));
+    }
+
+    #[test]
+    fn test_process_enumerate_credentials() {
+        let mut rng = ThreadRng256 {};
+        let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let credential_source2 = create_credential_source(&mut rng, "example.com", vec![0x02]);
+        let credential_source3 =
+            create_credential_source(&mut rng, "another.example.com", vec![0x03]);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+        for credential_source in vec![credential_source1, credential_source2, credential_source3] {
+            assert!(ctap_state
+                .persistent_store
+                .store_credential(ctap_state.rng, credential_source)
+                .is_ok());
+        }
+
+        let sub_command_params = CredentialManagementSubCommandParameters {
+            rp_id_hash: Some(Sha256::hash(b"example.com").to_vec()),
+            credential_id: None,
+            user: None,
+        };
+        let params = create_parameters(
+            CredentialManagementSubCommand::EnumerateCredentialsBegin,
+            Some(sub_command_params),
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        let first_user_id = match response.unwrap() {
+            ResponseData::AuthenticatorCredentialManagement(Some(response)) => {
+                assert_eq!(response.total_credentials, Some(2));
+                assert!(response.public_key.is_some());
+                response.user.unwrap().user_id
+            }
+            _ => panic!("Invalid response type"),
+        };
+
+        let params = create_get_next_parameters(
+            CredentialManagementSubCommand::EnumerateCredentialsGetNextCredential,
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        match response.unwrap() {
+            ResponseData::AuthenticatorCredentialManagement(Some(response)) => {
+                assert_eq!(response.total_credentials, None);
+                let user_id = response.user.unwrap().user_id;
+                assert_ne!(user_id, first_user_id);
+                assert!(user_id == vec![0x01] || user_id == vec![0x02]);
+            }
+            _ => panic!("Invalid response type"),
+        };
+
+        let params = create_get_next_parameters(
+            CredentialManagementSubCommand::EnumerateCredentialsGetNextCredential,
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED));
+    }
+
+    #[test]
+    fn test_process_enumerate_invalidated() {
+        let mut rng = ThreadRng256 {};
+        let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let credential_source2 =
+            create_credential_source(&mut rng, "another.example.com", vec![0x02]);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source1)
+            .is_ok());
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source2)
+            .is_ok());
+
+        // The follow-up subcommand has to match the begin subcommand.
+        let params = create_parameters(CredentialManagementSubCommand::EnumerateRpsBegin, None);
+        assert!(ctap_state
+            .process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE)
+            .is_ok());
+        let params = create_get_next_parameters(
+            CredentialManagementSubCommand::EnumerateCredentialsGetNextCredential,
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED));
+
+        // Other subcommands discard the state.
+        let params = create_parameters(CredentialManagementSubCommand::EnumerateRpsBegin, None);
+        assert!(ctap_state
+            .process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE)
+            .is_ok());
+        let params = create_parameters(CredentialManagementSubCommand::GetCredsMetadata, None);
+        assert!(ctap_state
+            .process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE)
+            .is_ok());
+        let params =
+            create_get_next_parameters(CredentialManagementSubCommand::EnumerateRpsGetNextRp);
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED));
+
+        // Only the channel that started the enumeration may continue it.
+        let params = create_parameters(CredentialManagementSubCommand::EnumerateRpsBegin, None);
+        assert!(ctap_state
+            .process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE)
+            .is_ok());
+        let params =
+            create_get_next_parameters(CredentialManagementSubCommand::EnumerateRpsGetNextRp);
+        let response =
+            ctap_state.process_credential_management(params, [0x9A; 4], DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED));
+    }
+
+    #[test]
+    fn test_process_delete_credential() {
+        let mut rng = ThreadRng256 {};
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let credential_id = credential_source.credential_id.clone();
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source)
+            .is_ok());
+
+        let sub_command_params = create_credential_id_parameters(credential_id.clone(), None);
+        let params = create_parameters(
+            CredentialManagementSubCommand::DeleteCredential,
+            Some(sub_command_params),
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorCredentialManagement(None))
+        );
+        assert_eq!(ctap_state.persistent_store.count_credentials(), 0);
+
+        let sub_command_params = create_credential_id_parameters(credential_id, None);
+        let params = create_parameters(
+            CredentialManagementSubCommand::DeleteCredential,
+            Some(sub_command_params),
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS));
+    }
+
+    #[test]
+    fn test_process_update_user_information() {
+        let mut rng = ThreadRng256 {};
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let credential_id = credential_source.credential_id.clone();
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source)
+            .is_ok());
+
+        let user = PublicKeyCredentialUserEntity {
+            user_id: vec![0x01],
+            user_name: Some(String::from("foo")),
+            user_display_name: Some(String::from("bar")),
+            user_icon: None,
+        };
+        let sub_command_params = create_credential_id_parameters(credential_id.clone(), Some(user));
+        let params = create_parameters(
+            CredentialManagementSubCommand::UpdateUserInformation,
+            Some(sub_command_params),
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorCredentialManagement(None))
+        );
+        let credential = ctap_state
+            .persistent_store
+            .find_credential_by_id(&credential_id)
+            .unwrap();
+        assert_eq!(credential.other_ui, Some(String::from("bar")));
+        assert_eq!(ctap_state.persistent_store.count_credentials(), 1);
+
+        // The user ID must match the stored credential.
+        let user = PublicKeyCredentialUserEntity {
+            user_id: vec![0x02],
+            user_name: None,
+            user_display_name: None,
+            user_icon: None,
+        };
+        let sub_command_params = create_credential_id_parameters(credential_id, Some(user));
+        let params = create_parameters(
+            CredentialManagementSubCommand::UpdateUserInformation,
+            Some(sub_command_params),
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER));
+    }
+
+    #[test]
+    fn test_process_update_user_information_keeps_order() {
+        let mut rng = ThreadRng256 {};
+        let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let credential_source2 = create_credential_source(&mut rng, "example.com", vec![0x02]);
+        let credential_id1 = credential_source1.credential_id.clone();
+        let credential_id2 = credential_source2.credential_id.clone();
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source1)
+            .is_ok());
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source2)
+            .is_ok());
+
+        // Updates the older credential.
+        let user = PublicKeyCredentialUserEntity {
+            user_id: vec![0x01],
+            user_name: None,
+            user_display_name: Some(String::from("bar")),
+            user_icon: None,
+        };
+        let sub_command_params = create_credential_id_parameters(credential_id1, Some(user));
+        let params = create_parameters(
+            CredentialManagementSubCommand::UpdateUserInformation,
+            Some(sub_command_params),
+        );
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorCredentialManagement(None))
+        );
+
+        // The most recently created credential still comes first.
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        match response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(get_assertion_response) => {
+                assert_eq!(get_assertion_response.number_of_credentials, Some(2));
+                assert_eq!(
+                    get_assertion_response.credential.unwrap().key_id,
+                    credential_id2
+                );
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_process_update_user_information_unknown_fields() {
+        let mut rng = ThreadRng256 {};
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let credential_id = credential_source.credential_id.clone();
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state
+            .pin_protocol_v1
+            .set_pin_uv_auth_token(PIN_UV_AUTH_TOKEN);
+        ctap_state
+            .pin_uv_auth_token_state
+            .begin_using_pin_uv_auth_token(
+                DUMMY_CLOCK_VALUE,
+                PinPermission::CredentialManagement as u8,
+                None,
+            );
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source)
+            .is_ok());
+
+        // The pinAuth covers the members of the user entity that are not parsed.
+        let sub_command_params = cbor_map! {
+            0x02 => cbor_map! {
+                "id" => credential_id.clone(),
+                "type" => "public-key",
+            },
+            0x03 => cbor_map! {
+                "id" => vec![0x01],
+                "displayName" => "bar",
+                "unknownMember" => "baz",
+            },
+        };
+        let mut auth_message = vec![u8::from(
+            CredentialManagementSubCommand::UpdateUserInformation,
+        )];
+        assert!(cbor::write(sub_command_params.clone(), &mut auth_message));
+        let pin_auth =
+            hmac_256::<Sha256>(&PIN_UV_AUTH_TOKEN, &auth_message)[..PIN_AUTH_LENGTH].to_vec();
+        let params = AuthenticatorCredentialManagementParameters::try_from(cbor_map! {
+            1 => CredentialManagementSubCommand::UpdateUserInformation,
+            2 => sub_command_params,
+            3 => 1,
+            4 => pin_auth,
+        })
+        .unwrap();
+        let response =
+            ctap_state.process_credential_management(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorCredentialManagement(None))
+        );
+        let credential = ctap_state
+            .persistent_store
+            .find_credential_by_id(&credential_id)
+            .unwrap();
+        assert_eq!(credential.other_ui, Some(String::from("bar")));
+    }
+}
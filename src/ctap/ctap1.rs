@@ -12,11 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::hid::ChannelID;
+use super::data_formats::{PrivateKey, PublicKeyCredentialSource};
 use super::key_material::{ATTESTATION_CERTIFICATE, ATTESTATION_PRIVATE_KEY};
-use super::status_code::Ctap2StatusCode;
+use super::platform::{Clock, PersistentStorage, UserPresence};
+use super::time::ClockValue;
 use super::CtapState;
-use crate::timer::ClockValue;
 use alloc::vec::Vec;
 use core::convert::Into;
 use core::convert::TryFrom;
@@ -35,6 +35,8 @@ pub enum Ctap1StatusCode {
     SW_WRONG_LENGTH = 0x6700,
     SW_CLA_NOT_SUPPORTED = 0x6E00,
     SW_INS_NOT_SUPPORTED = 0x6D00,
+    // ISO 7816-4 memory failure, for errors of the persistent store.
+    SW_MEMERR = 0x6501,
     SW_VENDOR_KEY_HANDLE_TOO_LONG = 0xF000,
 }
 
@@ -49,6 +51,7 @@ impl TryFrom<u16> for Ctap1StatusCode {
             0x6700 => Ok(Ctap1StatusCode::SW_WRONG_LENGTH),
             0x6E00 => Ok(Ctap1StatusCode::SW_CLA_NOT_SUPPORTED),
             0x6D00 => Ok(Ctap1StatusCode::SW_INS_NOT_SUPPORTED),
+            0x6501 => Ok(Ctap1StatusCode::SW_MEMERR),
             0xF000 => Ok(Ctap1StatusCode::SW_VENDOR_KEY_HANDLE_TOO_LONG),
             _ => Err(()),
         }
@@ -214,15 +217,21 @@ impl Ctap1Command {
     const VENDOR_SPECIFIC_FIRST: u8 = 0x40;
     const VENDOR_SPECIFIC_LAST: u8 = 0xBF;
 
-    pub fn process_command<R, CheckUserPresence>(
+    pub fn process_command<R, CheckUserPresence, S, C>(
         message: &[u8],
-        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        ctap_state: &mut CtapState<R, CheckUserPresence, S, C>,
         clock_value: ClockValue,
     ) -> Result<Vec<u8>, Ctap1StatusCode>
     where
         R: Rng256,
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+        CheckUserPresence: UserPresence,
+        S: PersistentStorage,
+        C: Clock,
     {
+        // U2F has no user verification, so it is disabled with alwaysUv.
+        if ctap_state.persistent_store.always_uv() {
+            return Err(Ctap1StatusCode::SW_INS_NOT_SUPPORTED);
+        }
         let command = U2fCommand::try_from(message)?;
         match command {
             U2fCommand::Register {
@@ -275,18 +284,22 @@ impl Ctap1Command {
     // +------+-------------------+-----------------+------------+--------------------+
     // + 0x00 | application (32B) | challenge (32B) | key handle | User pub key (65B) |
     // +------+-------------------+-----------------+------------+--------------------+
-    fn process_register<R, CheckUserPresence>(
+    fn process_register<R, CheckUserPresence, S, C>(
         challenge: [u8; 32],
         application: [u8; 32],
-        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        ctap_state: &mut CtapState<R, CheckUserPresence, S, C>,
     ) -> Result<Vec<u8>, Ctap1StatusCode>
     where
         R: Rng256,
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+        CheckUserPresence: UserPresence,
+        S: PersistentStorage,
+        C: Clock,
     {
         let sk = crypto::ecdsa::SecKey::gensk(ctap_state.rng);
         let pk = sk.genpk();
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(PrivateKey::Ecdsa(sk), &application, None, None)
+            .map_err(|_| Ctap1StatusCode::SW_MEMERR)?;
         if key_handle.len() > 0xFF {
             // This is just being defensive with unreachable code.
             return Err(Ctap1StatusCode::SW_VENDOR_KEY_HANDLE_TOO_LONG);
@@ -330,29 +343,43 @@ impl Ctap1Command {
     // +-------------------+---------+--------------+-----------------+
     // + application (32B) | UP (1B) | Counter (4B) | challenge (32B) |
     // +-------------------+---------+--------------+-----------------+
-    fn process_authenticate<R, CheckUserPresence>(
+    fn process_authenticate<R, CheckUserPresence, S, C>(
         challenge: [u8; 32],
         application: [u8; 32],
         key_handle: Vec<u8>,
         flags: Ctap1Flags,
-        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        ctap_state: &mut CtapState<R, CheckUserPresence, S, C>,
     ) -> Result<Vec<u8>, Ctap1StatusCode>
     where
         R: Rng256,
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+        CheckUserPresence: UserPresence,
+        S: PersistentStorage,
+        C: Clock,
     {
-        let credential_source = ctap_state.decrypt_credential_source(key_handle, &application);
-        if let Some(credential_source) = credential_source {
+        // U2F has no user verification, so credentials requiring it are unknown here.
+        let credential_source = ctap_state
+            .decrypt_credential_source(key_handle, &application)
+            .map_err(|_| Ctap1StatusCode::SW_MEMERR)?
+            .filter(|credential| super::is_cred_protect_satisfied(credential, false, true));
+        // U2F only supports ECDSA, so key handles of other algorithms are unknown here.
+        if let Some(PublicKeyCredentialSource {
+            private_key: PrivateKey::Ecdsa(private_key),
+            ..
+        }) = credential_source
+        {
             if flags == Ctap1Flags::CheckOnly {
                 return Err(Ctap1StatusCode::SW_CONDITIONS_NOT_SATISFIED);
             }
-            ctap_state.increment_global_signature_counter();
-            let mut signature_data = ctap_state
-                .generate_auth_data(&application, Ctap1Command::USER_PRESENCE_INDICATOR_BYTE);
+            let signature_counter = ctap_state
+                .increment_global_signature_counter()
+                .map_err(|_| Ctap1StatusCode::SW_MEMERR)?;
+            let mut signature_data = ctap_state.generate_auth_data(
+                &application,
+                Ctap1Command::USER_PRESENCE_INDICATOR_BYTE,
+                signature_counter,
+            );
             signature_data.extend(&challenge);
-            let signature = credential_source
-                .private_key
-                .sign_rfc6979::<crypto::sha256::Sha256>(&signature_data);
+            let signature = private_key.sign_rfc6979::<crypto::sha256::Sha256>(&signature_data);
 
             let mut response = signature_data[application.len()..application.len() + 5].to_vec();
             response.extend(signature.to_asn1_der());
@@ -365,7 +392,9 @@ impl Ctap1Command {
 
 #[cfg(test)]
 mod test {
-    use super::super::{ENCRYPTED_CREDENTIAL_ID_SIZE, USE_SIGNATURE_COUNTER};
+    use super::super::data_formats::{CredentialProtectionPolicy, SignatureAlgorithm};
+    use super::super::platform::TestClock;
+    use super::super::{new_buffer_storage, ENCRYPTED_CREDENTIAL_ID_SIZE, USE_SIGNATURE_COUNTER};
     use super::*;
     use crypto::rng256::ThreadRng256;
     use crypto::Hash256;
@@ -405,12 +434,12 @@ mod test {
             0x00,
             0x00,
             0x00,
-            65 + ENCRYPTED_CREDENTIAL_ID_SIZE as u8,
+            65 + key_handle.len() as u8,
         ];
         let challenge = [0x0C; 32];
         message.extend(&challenge);
         message.extend(application);
-        message.push(ENCRYPTED_CREDENTIAL_ID_SIZE as u8);
+        message.push(key_handle.len() as u8);
         message.extend(key_handle);
         message
     }
@@ -419,7 +448,13 @@ mod test {
     fn test_process_register() {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let application = [0x0A; 32];
         let message = create_register_message(&application);
@@ -435,6 +470,7 @@ mod test {
                 response[67..67 + ENCRYPTED_CREDENTIAL_ID_SIZE].to_vec(),
                 &application
             )
+            .unwrap()
             .is_some());
         const CERT_START: usize = 67 + ENCRYPTED_CREDENTIAL_ID_SIZE;
         assert_eq!(
@@ -447,7 +483,13 @@ mod test {
     fn test_process_register_bad_message() {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let application = [0x0A; 32];
         let message = create_register_message(&application);
@@ -467,7 +509,13 @@ mod test {
 
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
         ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
@@ -476,16 +524,46 @@ mod test {
         assert_eq!(response, Err(Ctap1StatusCode::SW_CONDITIONS_NOT_SATISFIED));
     }
 
+    #[test]
+    fn test_process_register_always_uv() {
+        let application = [0x0A; 32];
+        let message = create_register_message(&application);
+
+        let mut rng = ThreadRng256 {};
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state.persistent_store.toggle_always_uv().unwrap();
+
+        ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
+        ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
+        let response = Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap1StatusCode::SW_INS_NOT_SUPPORTED));
+    }
+
     #[test]
     fn test_process_authenticate_check_only() {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(PrivateKey::Ecdsa(sk), &application, None, None)
+            .unwrap();
         let message = create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
 
         let response = Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE);
@@ -497,11 +575,19 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(PrivateKey::Ecdsa(sk), &application, None, None)
+            .unwrap();
         let application = [0x55; 32];
         let message = create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
 
@@ -509,16 +595,77 @@ mod test {
         assert_eq!(response, Err(Ctap1StatusCode::SW_WRONG_DATA));
     }
 
+    #[test]
+    fn test_process_authenticate_check_only_eddsa() {
+        let mut rng = ThreadRng256 {};
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let sk = PrivateKey::new(&mut rng, SignatureAlgorithm::EDDSA);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let rp_id = "example.com";
+        let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
+        let key_handle = ctap_state
+            .encrypt_key_handle(sk, &application, None, None)
+            .unwrap();
+        let message = create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
+
+        let response = Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap1StatusCode::SW_WRONG_DATA));
+    }
+
+    #[test]
+    fn test_process_authenticate_check_only_cred_protect() {
+        let mut rng = ThreadRng256 {};
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let rp_id = "example.com";
+        let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
+        let key_handle = ctap_state
+            .encrypt_key_handle(
+                PrivateKey::Ecdsa(sk),
+                &application,
+                None,
+                Some(CredentialProtectionPolicy::UserVerificationRequired),
+            )
+            .unwrap();
+        let message = create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
+
+        let response = Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap1StatusCode::SW_WRONG_DATA));
+    }
+
     #[test]
     fn test_process_authenticate_check_only_wrong_length() {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(PrivateKey::Ecdsa(sk), &application, None, None)
+            .unwrap();
         let mut message =
             create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
 
@@ -538,11 +685,19 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(PrivateKey::Ecdsa(sk), &application, None, None)
+            .unwrap();
         let mut message =
             create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
         message[0] = 0xEE;
@@ -556,11 +711,19 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(PrivateKey::Ecdsa(sk), &application, None, None)
+            .unwrap();
         let mut message =
             create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
         message[1] = 0xEE;
@@ -574,11 +737,19 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(PrivateKey::Ecdsa(sk), &application, None, None)
+            .unwrap();
         let mut message =
             create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
         message[2] = 0xEE;
@@ -592,11 +763,19 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(PrivateKey::Ecdsa(sk), &application, None, None)
+            .unwrap();
         let message =
             create_authenticate_message(&application, Ctap1Flags::EnforceUpAndSign, &key_handle);
 
@@ -605,11 +784,12 @@ mod test {
         let response =
             Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE).unwrap();
         assert_eq!(response[0], 0x01);
-        if USE_SIGNATURE_COUNTER {
-            assert_eq!(response[1..5], [0x00, 0x00, 0x00, 0x01]);
-        } else {
-            assert_eq!(response[1..5], [0x00, 0x00, 0x00, 0x00]);
-        }
+        let global_signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        assert_eq!(response[1..5], global_signature_counter.to_be_bytes());
+        assert_eq!(global_signature_counter > 0, USE_SIGNATURE_COUNTER);
     }
 
     #[test]
@@ -617,11 +797,19 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(PrivateKey::Ecdsa(sk), &application, None, None)
+            .unwrap();
         let message = create_authenticate_message(
             &application,
             Ctap1Flags::DontEnforceUpAndSign,
@@ -631,11 +819,12 @@ mod test {
         let response =
             Ctap1Command::process_command(&message, &mut ctap_state, TIMEOUT_CLOCK_VALUE).unwrap();
         assert_eq!(response[0], 0x01);
-        if USE_SIGNATURE_COUNTER {
-            assert_eq!(response[1..5], [0x00, 0x00, 0x00, 0x01]);
-        } else {
-            assert_eq!(response[1..5], [0x00, 0x00, 0x00, 0x00]);
-        }
+        let global_signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        assert_eq!(response[1..5], global_signature_counter.to_be_bytes());
+        assert_eq!(global_signature_counter > 0, USE_SIGNATURE_COUNTER);
     }
 
     #[test]
@@ -647,7 +836,13 @@ mod test {
 
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
         ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
@@ -664,7 +859,13 @@ mod test {
 
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            dummy_user_presence,
+            new_buffer_storage(),
+            TestClock::new(START_CLOCK_VALUE),
+        )
+        .unwrap();
 
         ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
         ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
@@ -17,7 +17,8 @@ use alloc::collections::BTreeMap;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use core::convert::TryFrom;
-use crypto::{ecdh, ecdsa};
+use crypto::rng256::Rng256;
+use crypto::{ecdh, ecdsa, ed25519};
 
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct PublicKeyCredentialRpEntity {
@@ -48,6 +49,17 @@ impl TryFrom<&cbor::Value> for PublicKeyCredentialRpEntity {
     }
 }
 
+impl From<PublicKeyCredentialRpEntity> for cbor::Value {
+    fn from(entity: PublicKeyCredentialRpEntity) -> Self {
+        cbor_map_options! {
+            "id" => entity.rp_id,
+            "name" => entity.rp_name,
+            "icon" => entity.rp_icon,
+        }
+    }
+}
+
+#[derive(Clone)]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct PublicKeyCredentialUserEntity {
     pub user_id: Vec<u8>,
@@ -121,6 +133,7 @@ impl TryFrom<&cbor::Value> for PublicKeyCredentialType {
     }
 }
 
+#[derive(Clone)]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub enum AuthenticatorTransport {
     Usb,
@@ -156,6 +169,7 @@ impl TryFrom<&cbor::Value> for AuthenticatorTransport {
     }
 }
 
+#[derive(Clone)]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct PublicKeyCredentialDescriptor {
     pub key_type: PublicKeyCredentialType,
@@ -220,6 +234,71 @@ impl TryFrom<&cbor::Value> for Extensions {
     }
 }
 
+impl Extensions {
+    // Returns whether the hmac-secret extension was requested in a MakeCredential.
+    pub fn make_credential_hmac_secret(&self) -> Result<bool, Ctap2StatusCode> {
+        self.0
+            .get("hmac-secret")
+            .map_or(Ok(false), |value| read_bool(value))
+    }
+
+    // Returns the credProtect policy requested in a MakeCredential, if present.
+    pub fn make_credential_cred_protect_policy(
+        &self,
+    ) -> Result<Option<CredentialProtectionPolicy>, Ctap2StatusCode> {
+        self.0
+            .get("credProtect")
+            .map(CredentialProtectionPolicy::try_from)
+            .transpose()
+    }
+
+    // Returns whether the minPinLength extension was requested in a MakeCredential.
+    pub fn make_credential_min_pin_length(&self) -> Result<bool, Ctap2StatusCode> {
+        self.0
+            .get("minPinLength")
+            .map_or(Ok(false), |value| read_bool(value))
+    }
+
+    // Returns the hmac-secret extension input of a GetAssertion, if present.
+    pub fn get_assertion_hmac_secret(
+        &self,
+    ) -> Result<Option<GetAssertionHmacSecretInput>, Ctap2StatusCode> {
+        self.0
+            .get("hmac-secret")
+            .map(GetAssertionHmacSecretInput::try_from)
+            .transpose()
+    }
+}
+
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct GetAssertionHmacSecretInput {
+    pub key_agreement: CoseKey,
+    pub salt_enc: Vec<u8>,
+    pub salt_auth: Vec<u8>,
+    pub pin_uv_auth_protocol: Option<u64>,
+}
+
+impl TryFrom<&cbor::Value> for GetAssertionHmacSecretInput {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let input_map = read_map(cbor_value)?;
+        let cose_key = read_map(ok_or_missing(input_map.get(&cbor_unsigned!(1)))?)?;
+        let salt_enc = read_byte_string(ok_or_missing(input_map.get(&cbor_unsigned!(2)))?)?;
+        let salt_auth = read_byte_string(ok_or_missing(input_map.get(&cbor_unsigned!(3)))?)?;
+        let pin_uv_auth_protocol = input_map
+            .get(&cbor_unsigned!(4))
+            .map(read_unsigned)
+            .transpose()?;
+        Ok(Self {
+            key_agreement: CoseKey(cose_key.clone()),
+            salt_enc,
+            salt_auth,
+            pin_uv_auth_protocol,
+        })
+    }
+}
+
 // Even though options are optional, we can use the default if not present.
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct MakeCredentialOptions {
@@ -297,10 +376,171 @@ impl From<PackedAttestationStatement> for cbor::Value {
     }
 }
 
-#[cfg_attr(test, derive(PartialEq))]
+#[derive(Clone, Copy, PartialEq)]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
 pub enum SignatureAlgorithm {
     ES256 = ecdsa::PubKey::ES256_ALGORITHM as isize,
+    EDDSA = ed25519::PubKey::EDDSA_ALGORITHM as isize,
+}
+
+impl From<SignatureAlgorithm> for cbor::Value {
+    fn from(alg: SignatureAlgorithm) -> Self {
+        (alg as i64).into()
+    }
+}
+
+impl TryFrom<i64> for SignatureAlgorithm {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(alg: i64) -> Result<Self, Ctap2StatusCode> {
+        match alg {
+            ecdsa::PubKey::ES256_ALGORITHM => Ok(SignatureAlgorithm::ES256),
+            ed25519::PubKey::EDDSA_ALGORITHM => Ok(SignatureAlgorithm::EDDSA),
+            _ => Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM),
+        }
+    }
+}
+
+impl TryFrom<&cbor::Value> for SignatureAlgorithm {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        SignatureAlgorithm::try_from(read_integer(cbor_value)?)
+    }
+}
+
+// The private key of a credential, tagged with its signature algorithm.
+#[derive(Clone)]
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum PrivateKey {
+    Ecdsa(ecdsa::SecKey),
+    Ed25519(ed25519::SecKey),
+}
+
+impl PrivateKey {
+    pub fn new(rng: &mut impl Rng256, alg: SignatureAlgorithm) -> Self {
+        match alg {
+            SignatureAlgorithm::ES256 => PrivateKey::Ecdsa(ecdsa::SecKey::gensk(rng)),
+            SignatureAlgorithm::EDDSA => PrivateKey::Ed25519(ed25519::SecKey::gensk(rng)),
+        }
+    }
+
+    pub fn from_bytes(alg: SignatureAlgorithm, bytes: &[u8; 32]) -> Option<Self> {
+        match alg {
+            SignatureAlgorithm::ES256 => ecdsa::SecKey::from_bytes(bytes).map(PrivateKey::Ecdsa),
+            SignatureAlgorithm::EDDSA => {
+                Some(PrivateKey::Ed25519(ed25519::SecKey::from_bytes(bytes)))
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0; 32];
+        match self {
+            PrivateKey::Ecdsa(ecdsa_key) => ecdsa_key.to_bytes(&mut bytes),
+            PrivateKey::Ed25519(ed25519_key) => ed25519_key.to_bytes(&mut bytes),
+        }
+        bytes
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        match self {
+            PrivateKey::Ecdsa(_) => SignatureAlgorithm::ES256,
+            PrivateKey::Ed25519(_) => SignatureAlgorithm::EDDSA,
+        }
+    }
+
+    // Signs the message and encodes the signature as expected in attestations and assertions:
+    // ASN.1 DER for ECDSA, and the raw 64 bytes for EdDSA.
+    pub fn sign_and_encode(&self, message: &[u8]) -> Vec<u8> {
+        match self {
+            PrivateKey::Ecdsa(ecdsa_key) => ecdsa_key
+                .sign_rfc6979::<crypto::sha256::Sha256>(message)
+                .to_asn1_der(),
+            PrivateKey::Ed25519(ed25519_key) => ed25519_key.sign(message).to_bytes().to_vec(),
+        }
+    }
+
+    // Returns the CBOR encoded COSE key of the public key, as used in authenticator data.
+    pub fn to_cose_key(&self) -> Option<Vec<u8>> {
+        match self {
+            PrivateKey::Ecdsa(ecdsa_key) => ecdsa_key.genpk().to_cose_key(),
+            PrivateKey::Ed25519(ed25519_key) => ed25519_key.genpk().to_cose_key(),
+        }
+    }
+}
+
+impl From<&PrivateKey> for CoseKey {
+    fn from(private_key: &PrivateKey) -> Self {
+        match private_key {
+            PrivateKey::Ecdsa(ecdsa_key) => CoseKey::from(ecdsa_key.genpk()),
+            PrivateKey::Ed25519(ed25519_key) => CoseKey::from(ed25519_key.genpk()),
+        }
+    }
+}
+
+// Private keys are stored with their algorithm. Before EdDSA support, only the ECDSA key bytes
+// were stored, which is still accepted when parsing.
+impl From<PrivateKey> for cbor::Value {
+    fn from(private_key: PrivateKey) -> Self {
+        cbor_array! {
+            private_key.algorithm(),
+            private_key.to_bytes().to_vec(),
+        }
+    }
+}
+
+impl TryFrom<&cbor::Value> for PrivateKey {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let (alg, key_bytes) = match cbor_value {
+            cbor::Value::Array(fields) => {
+                if fields.len() != 2 {
+                    return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
+                }
+                (
+                    SignatureAlgorithm::try_from(&fields[0])?,
+                    read_byte_string(&fields[1])?,
+                )
+            }
+            cbor_value => (SignatureAlgorithm::ES256, read_byte_string(cbor_value)?),
+        };
+        if key_bytes.len() != 32 {
+            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
+        }
+        PrivateKey::from_bytes(alg, array_ref!(key_bytes, 0, 32))
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+    }
+}
+
+// The levels of the credProtect extension.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum CredentialProtectionPolicy {
+    UserVerificationOptional = 0x01,
+    UserVerificationOptionalWithCredentialIdList = 0x02,
+    UserVerificationRequired = 0x03,
+}
+
+impl From<CredentialProtectionPolicy> for cbor::Value {
+    fn from(policy: CredentialProtectionPolicy) -> Self {
+        (policy as i64).into()
+    }
+}
+
+impl TryFrom<&cbor::Value> for CredentialProtectionPolicy {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        match read_integer(cbor_value)? {
+            0x01 => Ok(CredentialProtectionPolicy::UserVerificationOptional),
+            0x02 => Ok(CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList),
+            0x03 => Ok(CredentialProtectionPolicy::UserVerificationRequired),
+            _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -310,26 +550,59 @@ pub struct PublicKeyCredentialSource {
     // TODO function to convert to / from Vec<u8>
     pub key_type: PublicKeyCredentialType,
     pub credential_id: Vec<u8>,
-    pub private_key: ecdsa::SecKey, // TODO(kaczmarczyck) open for other algorithms
+    pub private_key: PrivateKey,
     pub rp_id: String,
     pub user_handle: Vec<u8>, // not optional, but nullable
     pub other_ui: Option<String>,
+    // The secret of the hmac-secret extension, only present if the extension was requested.
+    pub cred_random: Option<Vec<u8>>,
+    // The level of the credProtect extension, only present if the extension was requested.
+    pub cred_protect_policy: Option<CredentialProtectionPolicy>,
+    // The own signature counter of resident credentials. Credentials from key handles have none,
+    // they share the global signature counter.
+    pub signature_counter: Option<u32>,
+    // Orders resident credentials by creation, newer credentials have a greater value. The
+    // persistent store sets it when storing the credential. Credentials stored before it existed
+    // have 0.
+    pub creation_order: u64,
+}
+
+// The keys of the CBOR map of a PublicKeyCredentialSource. Fields are only ever added, and
+// unknown keys are ignored when parsing, so that credentials survive firmware updates.
+#[derive(Clone, Copy)]
+pub enum PublicKeyCredentialSourceField {
+    CredentialId = 0,
+    PrivateKey = 1,
+    RpId = 2,
+    UserHandle = 3,
+    OtherUi = 4,
+    CredRandom = 5,
+    CredProtectPolicy = 6,
+    // The persistent store replaces the private key and CredRandom by their sealed form.
+    SealedSecrets = 7,
+    SignatureCounter = 8,
+    CreationOrder = 9,
+}
+
+impl From<PublicKeyCredentialSourceField> for cbor::KeyType {
+    fn from(field: PublicKeyCredentialSourceField) -> cbor::KeyType {
+        (field as u64).into()
+    }
 }
 
 impl From<PublicKeyCredentialSource> for cbor::Value {
     fn from(credential: PublicKeyCredentialSource) -> cbor::Value {
-        let mut private_key = [0u8; 32];
-        credential.private_key.to_bytes(&mut private_key);
-        let other_ui = match credential.other_ui {
-            None => cbor_null!(),
-            Some(other_ui) => cbor_text!(other_ui),
-        };
-        cbor_array! {
-            credential.credential_id,
-            private_key,
-            credential.rp_id,
-            credential.user_handle,
-            other_ui,
+        use PublicKeyCredentialSourceField as Field;
+        cbor_map_options! {
+            Field::CredentialId => credential.credential_id,
+            Field::PrivateKey => credential.private_key,
+            Field::RpId => credential.rp_id,
+            Field::UserHandle => credential.user_handle,
+            Field::OtherUi => credential.other_ui,
+            Field::CredRandom => credential.cred_random,
+            Field::CredProtectPolicy => credential.cred_protect_policy,
+            Field::SignatureCounter => credential.signature_counter.map(u64::from),
+            Field::CreationOrder => credential.creation_order,
         }
     }
 }
@@ -338,25 +611,80 @@ impl TryFrom<cbor::Value> for PublicKeyCredentialSource {
     type Error = Ctap2StatusCode;
 
     fn try_from(cbor_value: cbor::Value) -> Result<PublicKeyCredentialSource, Ctap2StatusCode> {
+        match &cbor_value {
+            cbor::Value::Map(fields) => PublicKeyCredentialSource::try_from_map(fields),
+            cbor::Value::Array(fields) => PublicKeyCredentialSource::try_from_array(fields),
+            _ => Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+        }
+    }
+}
+
+impl PublicKeyCredentialSource {
+    fn try_from_map(
+        fields: &BTreeMap<cbor::KeyType, cbor::Value>,
+    ) -> Result<PublicKeyCredentialSource, Ctap2StatusCode> {
+        use PublicKeyCredentialSourceField as Field;
+        let field = |field: Field| fields.get(&cbor::KeyType::from(field));
+        let credential_id = read_byte_string(ok_or_missing(field(Field::CredentialId))?)?;
+        let private_key = PrivateKey::try_from(ok_or_missing(field(Field::PrivateKey))?)?;
+        let rp_id = read_text_string(ok_or_missing(field(Field::RpId))?)?;
+        let user_handle = read_byte_string(ok_or_missing(field(Field::UserHandle))?)?;
+        let other_ui = field(Field::OtherUi).map(read_text_string).transpose()?;
+        let cred_random = field(Field::CredRandom).map(read_byte_string).transpose()?;
+        let cred_protect_policy = field(Field::CredProtectPolicy)
+            .map(CredentialProtectionPolicy::try_from)
+            .transpose()?;
+        let signature_counter = match field(Field::SignatureCounter) {
+            None => None,
+            Some(cbor_value) => Some(
+                u32::try_from(read_unsigned(cbor_value)?)
+                    .map_err(|_| Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)?,
+            ),
+        };
+        let creation_order = field(Field::CreationOrder)
+            .map(read_unsigned)
+            .transpose()?
+            .unwrap_or(0);
+        Ok(PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id,
+            private_key,
+            rp_id,
+            user_handle,
+            other_ui,
+            cred_random,
+            cred_protect_policy,
+            signature_counter,
+            creation_order,
+        })
+    }
+
+    // Credentials used to be stored as arrays, which are still read to migrate them.
+    fn try_from_array(
+        fields: &[cbor::Value],
+    ) -> Result<PublicKeyCredentialSource, Ctap2StatusCode> {
         use cbor::{SimpleValue, Value};
 
-        let fields = read_array(&cbor_value)?;
-        if fields.len() != 5 {
+        // Credentials stored before the hmac-secret and credProtect extensions have fewer fields.
+        if fields.len() < 5 || fields.len() > 7 {
             return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
         }
         let credential_id = read_byte_string(&fields[0])?;
-        let private_key = read_byte_string(&fields[1])?;
-        if private_key.len() != 32 {
-            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
-        }
-        let private_key = ecdsa::SecKey::from_bytes(array_ref!(private_key, 0, 32))
-            .ok_or(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)?;
+        let private_key = PrivateKey::try_from(&fields[1])?;
         let rp_id = read_text_string(&fields[2])?;
         let user_handle = read_byte_string(&fields[3])?;
         let other_ui = match &fields[4] {
             Value::Simple(SimpleValue::NullValue) => None,
             cbor_value => Some(read_text_string(cbor_value)?),
         };
+        let cred_random = match fields.get(5) {
+            None | Some(Value::Simple(SimpleValue::NullValue)) => None,
+            Some(cbor_value) => Some(read_byte_string(cbor_value)?),
+        };
+        let cred_protect_policy = match fields.get(6) {
+            None | Some(Value::Simple(SimpleValue::NullValue)) => None,
+            Some(cbor_value) => Some(CredentialProtectionPolicy::try_from(cbor_value)?),
+        };
         Ok(PublicKeyCredentialSource {
             key_type: PublicKeyCredentialType::PublicKey,
             credential_id,
@@ -364,6 +692,10 @@ impl TryFrom<cbor::Value> for PublicKeyCredentialSource {
             rp_id,
             user_handle,
             other_ui,
+            cred_random,
+            cred_protect_policy,
+            signature_counter: None,
+            creation_order: 0,
         })
     }
 }
@@ -381,6 +713,8 @@ pub struct CoseKey(pub BTreeMap<cbor::KeyType, cbor::Value>);
 const ECDH_ALGORITHM: i64 = -25;
 const EC2_KEY_TYPE: i64 = 2;
 const P_256_CURVE: i64 = 1;
+const OKP_KEY_TYPE: i64 = 1;
+const ED25519_CURVE: i64 = 6;
 
 impl From<ecdh::PubKey> for CoseKey {
     fn from(pk: ecdh::PubKey) -> Self {
@@ -405,6 +739,45 @@ impl From<ecdh::PubKey> for CoseKey {
     }
 }
 
+impl From<ecdsa::PubKey> for CoseKey {
+    fn from(pk: ecdsa::PubKey) -> Self {
+        let mut x_bytes = [0; ecdh::NBYTES];
+        let mut y_bytes = [0; ecdh::NBYTES];
+        pk.to_coordinates(&mut x_bytes, &mut y_bytes);
+        let x_byte_cbor: cbor::Value = cbor_bytes_lit!(&x_bytes);
+        let y_byte_cbor: cbor::Value = cbor_bytes_lit!(&y_bytes);
+        let cose_cbor_value = cbor_map_options! {
+            1 => EC2_KEY_TYPE,
+            3 => ecdsa::PubKey::ES256_ALGORITHM,
+            -1 => P_256_CURVE,
+            -2 => x_byte_cbor,
+            -3 => y_byte_cbor,
+        };
+        if let cbor::Value::Map(cose_map) = cose_cbor_value {
+            CoseKey(cose_map)
+        } else {
+            unreachable!();
+        }
+    }
+}
+
+impl From<ed25519::PubKey> for CoseKey {
+    fn from(pk: ed25519::PubKey) -> Self {
+        let x_byte_cbor: cbor::Value = cbor_bytes_lit!(&pk.to_bytes());
+        let cose_cbor_value = cbor_map_options! {
+            1 => OKP_KEY_TYPE,
+            3 => ed25519::PubKey::EDDSA_ALGORITHM,
+            -1 => ED25519_CURVE,
+            -2 => x_byte_cbor,
+        };
+        if let cbor::Value::Map(cose_map) = cose_cbor_value {
+            CoseKey(cose_map)
+        } else {
+            unreachable!();
+        }
+    }
+}
+
 impl TryFrom<CoseKey> for ecdh::PubKey {
     type Error = Ctap2StatusCode;
 
@@ -445,6 +818,7 @@ pub enum ClientPinSubCommand {
     GetPinUvAuthTokenUsingPin,
     GetPinUvAuthTokenUsingUv,
     GetUvRetries,
+    GetPinUvAuthTokenUsingPinWithPermissions,
 }
 
 impl From<ClientPinSubCommand> for cbor::Value {
@@ -457,6 +831,7 @@ impl From<ClientPinSubCommand> for cbor::Value {
             ClientPinSubCommand::GetPinUvAuthTokenUsingPin => 0x05,
             ClientPinSubCommand::GetPinUvAuthTokenUsingUv => 0x06,
             ClientPinSubCommand::GetUvRetries => 0x07,
+            ClientPinSubCommand::GetPinUvAuthTokenUsingPinWithPermissions => 0x09,
         }
         .into()
     }
@@ -475,12 +850,222 @@ impl TryFrom<&cbor::Value> for ClientPinSubCommand {
             0x05 => Ok(ClientPinSubCommand::GetPinUvAuthTokenUsingPin),
             0x06 => Ok(ClientPinSubCommand::GetPinUvAuthTokenUsingUv),
             0x07 => Ok(ClientPinSubCommand::GetUvRetries),
+            0x09 => Ok(ClientPinSubCommand::GetPinUvAuthTokenUsingPinWithPermissions),
             // TODO(kaczmarczyck) what is the correct status code for this error?
             _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
         }
     }
 }
 
+#[derive(Clone, Copy)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub enum CredentialManagementSubCommand {
+    GetCredsMetadata,
+    EnumerateRpsBegin,
+    EnumerateRpsGetNextRp,
+    EnumerateCredentialsBegin,
+    EnumerateCredentialsGetNextCredential,
+    DeleteCredential,
+    UpdateUserInformation,
+}
+
+impl From<CredentialManagementSubCommand> for cbor::Value {
+    fn from(subcommand: CredentialManagementSubCommand) -> Self {
+        (u8::from(subcommand) as u64).into()
+    }
+}
+
+impl From<CredentialManagementSubCommand> for u8 {
+    fn from(subcommand: CredentialManagementSubCommand) -> Self {
+        match subcommand {
+            CredentialManagementSubCommand::GetCredsMetadata => 0x01,
+            CredentialManagementSubCommand::EnumerateRpsBegin => 0x02,
+            CredentialManagementSubCommand::EnumerateRpsGetNextRp => 0x03,
+            CredentialManagementSubCommand::EnumerateCredentialsBegin => 0x04,
+            CredentialManagementSubCommand::EnumerateCredentialsGetNextCredential => 0x05,
+            CredentialManagementSubCommand::DeleteCredential => 0x06,
+            CredentialManagementSubCommand::UpdateUserInformation => 0x07,
+        }
+    }
+}
+
+impl TryFrom<&cbor::Value> for CredentialManagementSubCommand {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let subcommand_int = read_unsigned(cbor_value)?;
+        match subcommand_int {
+            0x01 => Ok(CredentialManagementSubCommand::GetCredsMetadata),
+            0x02 => Ok(CredentialManagementSubCommand::EnumerateRpsBegin),
+            0x03 => Ok(CredentialManagementSubCommand::EnumerateRpsGetNextRp),
+            0x04 => Ok(CredentialManagementSubCommand::EnumerateCredentialsBegin),
+            0x05 => Ok(CredentialManagementSubCommand::EnumerateCredentialsGetNextCredential),
+            0x06 => Ok(CredentialManagementSubCommand::DeleteCredential),
+            0x07 => Ok(CredentialManagementSubCommand::UpdateUserInformation),
+            _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+        }
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct CredentialManagementSubCommandParameters {
+    pub rp_id_hash: Option<Vec<u8>>,
+    pub credential_id: Option<PublicKeyCredentialDescriptor>,
+    pub user: Option<PublicKeyCredentialUserEntity>,
+}
+
+impl TryFrom<&cbor::Value> for CredentialManagementSubCommandParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let param_map = read_map(cbor_value)?;
+        let rp_id_hash = param_map
+            .get(&cbor_unsigned!(0x01))
+            .map(read_byte_string)
+            .transpose()?;
+        let credential_id = param_map
+            .get(&cbor_unsigned!(0x02))
+            .map(PublicKeyCredentialDescriptor::try_from)
+            .transpose()?;
+        let user = param_map
+            .get(&cbor_unsigned!(0x03))
+            .map(PublicKeyCredentialUserEntity::try_from)
+            .transpose()?;
+        Ok(Self {
+            rp_id_hash,
+            credential_id,
+            user,
+        })
+    }
+}
+
+// The platform authenticates the subcommand parameters with their CBOR encoding. Since CTAP
+// requires canonical CBOR, we can recompute it from the parsed parameters.
+impl From<CredentialManagementSubCommandParameters> for cbor::Value {
+    fn from(sub_command_params: CredentialManagementSubCommandParameters) -> Self {
+        cbor_map_options! {
+            0x01 => sub_command_params.rp_id_hash,
+            0x02 => sub_command_params.credential_id,
+            0x03 => sub_command_params.user,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub enum ConfigSubCommand {
+    EnableEnterpriseAttestation,
+    ToggleAlwaysUv,
+    SetMinPinLength,
+    VendorPrototype,
+}
+
+impl From<ConfigSubCommand> for cbor::Value {
+    fn from(subcommand: ConfigSubCommand) -> Self {
+        (u8::from(subcommand) as u64).into()
+    }
+}
+
+impl From<ConfigSubCommand> for u8 {
+    fn from(subcommand: ConfigSubCommand) -> Self {
+        match subcommand {
+            ConfigSubCommand::EnableEnterpriseAttestation => 0x01,
+            ConfigSubCommand::ToggleAlwaysUv => 0x02,
+            ConfigSubCommand::SetMinPinLength => 0x03,
+            ConfigSubCommand::VendorPrototype => 0xFF,
+        }
+    }
+}
+
+impl TryFrom<&cbor::Value> for ConfigSubCommand {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let subcommand_int = read_unsigned(cbor_value)?;
+        match subcommand_int {
+            0x01 => Ok(ConfigSubCommand::EnableEnterpriseAttestation),
+            0x02 => Ok(ConfigSubCommand::ToggleAlwaysUv),
+            0x03 => Ok(ConfigSubCommand::SetMinPinLength),
+            0xFF => Ok(ConfigSubCommand::VendorPrototype),
+            _ => Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND),
+        }
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct SetMinPinLengthParams {
+    pub new_min_pin_length: Option<u8>,
+    pub min_pin_length_rp_ids: Option<Vec<String>>,
+    pub force_change_pin: Option<bool>,
+}
+
+impl TryFrom<&cbor::Value> for SetMinPinLengthParams {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let param_map = read_map(cbor_value)?;
+        let new_min_pin_length = param_map
+            .get(&cbor_unsigned!(0x01))
+            .map(read_unsigned)
+            .transpose()?
+            .map(|length| {
+                if length > core::u8::MAX as u64 {
+                    Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)
+                } else {
+                    Ok(length as u8)
+                }
+            })
+            .transpose()?;
+        let min_pin_length_rp_ids = param_map
+            .get(&cbor_unsigned!(0x02))
+            .map(|entry| {
+                read_array(entry)?
+                    .iter()
+                    .map(read_text_string)
+                    .collect::<Result<Vec<String>, Ctap2StatusCode>>()
+            })
+            .transpose()?;
+        let force_change_pin = param_map
+            .get(&cbor_unsigned!(0x03))
+            .map(read_bool)
+            .transpose()?;
+        Ok(Self {
+            new_min_pin_length,
+            min_pin_length_rp_ids,
+            force_change_pin,
+        })
+    }
+}
+
+impl From<SetMinPinLengthParams> for cbor::Value {
+    fn from(params: SetMinPinLengthParams) -> Self {
+        cbor_map_options! {
+            0x01 => params.new_min_pin_length.map(|length| length as u64),
+            0x02 => params.min_pin_length_rp_ids.map(|rp_ids| cbor_array_vec!(rp_ids)),
+            0x03 => params.force_change_pin,
+        }
+    }
+}
+
+// The parameters of config subcommands. Only setMinPINLength has parameters so far.
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub enum ConfigSubCommandParams {
+    SetMinPinLength(SetMinPinLengthParams),
+}
+
+impl From<ConfigSubCommandParams> for cbor::Value {
+    fn from(params: ConfigSubCommandParams) -> Self {
+        match params {
+            ConfigSubCommandParams::SetMinPinLength(set_min_pin_length_params) => {
+                set_min_pin_length_params.into()
+            }
+        }
+    }
+}
+
 pub(super) fn read_unsigned(cbor_value: &cbor::Value) -> Result<u64, Ctap2StatusCode> {
     match cbor_value {
         cbor::Value::KeyValue(cbor::KeyType::Unsigned(unsigned)) => Ok(*unsigned),
@@ -821,7 +1406,7 @@ mod test {
     }
 
     #[test]
-    fn test_from_public_key_credential_rp_entity() {
+    fn test_from_into_public_key_credential_rp_entity() {
         let cbor_rp_entity = cbor_map! {
             "id" => "example.com",
             "name" => "Example",
@@ -834,6 +1419,8 @@ mod test {
             rp_icon: Some("example.com/icon.png".to_string()),
         };
         assert_eq!(rp_entity, Ok(expected_rp_entity));
+        let created_cbor: cbor::Value = rp_entity.unwrap().into();
+        assert_eq!(created_cbor, cbor_rp_entity);
     }
 
     #[test]
@@ -912,6 +1499,122 @@ mod test {
         assert_eq!(extensions, Ok(expected_extensions));
     }
 
+    #[test]
+    fn test_make_credential_hmac_secret() {
+        let cbor_extensions = cbor_map! {
+            "hmac-secret" => true,
+        };
+        let extensions = Extensions::try_from(&cbor_extensions).unwrap();
+        assert_eq!(extensions.make_credential_hmac_secret(), Ok(true));
+
+        let cbor_extensions = cbor_map! {
+            "the_answer" => 42,
+        };
+        let extensions = Extensions::try_from(&cbor_extensions).unwrap();
+        assert_eq!(extensions.make_credential_hmac_secret(), Ok(false));
+
+        let cbor_extensions = cbor_map! {
+            "hmac-secret" => 42,
+        };
+        let extensions = Extensions::try_from(&cbor_extensions).unwrap();
+        assert_eq!(
+            extensions.make_credential_hmac_secret(),
+            Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+        );
+    }
+
+    #[test]
+    fn test_make_credential_min_pin_length() {
+        let cbor_extensions = cbor_map! {
+            "minPinLength" => true,
+        };
+        let extensions = Extensions::try_from(&cbor_extensions).unwrap();
+        assert_eq!(extensions.make_credential_min_pin_length(), Ok(true));
+
+        let extensions = Extensions::try_from(&cbor_map! {}).unwrap();
+        assert_eq!(extensions.make_credential_min_pin_length(), Ok(false));
+
+        let cbor_extensions = cbor_map! {
+            "minPinLength" => 6,
+        };
+        let extensions = Extensions::try_from(&cbor_extensions).unwrap();
+        assert_eq!(
+            extensions.make_credential_min_pin_length(),
+            Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+        );
+    }
+
+    #[test]
+    fn test_from_into_cred_protect_policy() {
+        let cbor_policy: cbor::Value = CredentialProtectionPolicy::UserVerificationOptional.into();
+        assert_eq!(cbor_policy, cbor_int!(0x01));
+        assert_eq!(
+            CredentialProtectionPolicy::try_from(&cbor_policy),
+            Ok(CredentialProtectionPolicy::UserVerificationOptional)
+        );
+        assert_eq!(
+            CredentialProtectionPolicy::try_from(&cbor_int!(0x04)),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_make_credential_cred_protect_policy() {
+        let cbor_extensions = cbor_map! {
+            "credProtect" => 0x02,
+        };
+        let extensions = Extensions::try_from(&cbor_extensions).unwrap();
+        assert_eq!(
+            extensions.make_credential_cred_protect_policy(),
+            Ok(Some(
+                CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList
+            ))
+        );
+
+        let extensions = Extensions::try_from(&cbor_map! {}).unwrap();
+        assert_eq!(extensions.make_credential_cred_protect_policy(), Ok(None));
+    }
+
+    #[test]
+    fn test_get_assertion_hmac_secret() {
+        use crypto::rng256::ThreadRng256;
+
+        let mut rng = ThreadRng256 {};
+        let sk = crypto::ecdh::SecKey::gensk(&mut rng);
+        let pk = sk.genpk();
+        let cose_key = CoseKey::from(pk);
+        let cbor_extensions = cbor_map! {
+            "hmac-secret" => cbor_map! {
+                1 => cbor::Value::Map(cose_key.0.clone()),
+                2 => vec![0x02; 32],
+                3 => vec![0x03; 16],
+            },
+        };
+        let extensions = Extensions::try_from(&cbor_extensions).unwrap();
+        let expected_input = GetAssertionHmacSecretInput {
+            key_agreement: cose_key,
+            salt_enc: vec![0x02; 32],
+            salt_auth: vec![0x03; 16],
+            pin_uv_auth_protocol: None,
+        };
+        assert_eq!(
+            extensions.get_assertion_hmac_secret(),
+            Ok(Some(expected_input))
+        );
+
+        let cbor_extensions = cbor_map! {
+            "hmac-secret" => cbor_map! {
+                2 => vec![0x02; 32],
+                3 => vec![0x03; 16],
+            },
+        };
+        let extensions = Extensions::try_from(&cbor_extensions).unwrap();
+        assert_eq!(
+            extensions.get_assertion_hmac_secret(),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+    }
+
     #[test]
     fn test_from_make_credential_options() {
         let cbor_make_options = cbor_map! {
@@ -981,6 +1684,137 @@ mod test {
         assert_eq!(created_cbor, cbor_sub_command);
     }
 
+    #[test]
+    fn test_from_ecdsa_into_cose_key() {
+        use crypto::rng256::ThreadRng256;
+
+        let mut rng = ThreadRng256 {};
+        let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let pk = sk.genpk();
+        let encoded_cose_key = cbor::read(&pk.to_cose_key().unwrap()).unwrap();
+        let cose_key = CoseKey::from(pk);
+        assert_eq!(cbor_map_btree!(cose_key.0), encoded_cose_key);
+    }
+
+    #[test]
+    fn test_from_ed25519_into_cose_key() {
+        use crypto::rng256::ThreadRng256;
+
+        let mut rng = ThreadRng256 {};
+        let sk = crypto::ed25519::SecKey::gensk(&mut rng);
+        let pk = sk.genpk();
+        let encoded_cose_key = cbor::read(&pk.to_cose_key().unwrap()).unwrap();
+        let cose_key = CoseKey::from(pk);
+        assert_eq!(cbor_map_btree!(cose_key.0), encoded_cose_key);
+    }
+
+    #[test]
+    fn test_private_key_into_cose_key() {
+        use crypto::rng256::ThreadRng256;
+
+        let mut rng = ThreadRng256 {};
+        for algorithm in &[SignatureAlgorithm::ES256, SignatureAlgorithm::EDDSA] {
+            let private_key = PrivateKey::new(&mut rng, *algorithm);
+            let encoded_cose_key = cbor::read(&private_key.to_cose_key().unwrap()).unwrap();
+            let cose_key = CoseKey::from(&private_key);
+            assert_eq!(cbor_map_btree!(cose_key.0), encoded_cose_key);
+        }
+    }
+
+    #[test]
+    fn test_from_into_credential_management_sub_command() {
+        let cbor_sub_command = cbor_int!(0x01);
+        let sub_command = CredentialManagementSubCommand::try_from(&cbor_sub_command);
+        let expected_sub_command = CredentialManagementSubCommand::GetCredsMetadata;
+        assert_eq!(sub_command, Ok(expected_sub_command));
+        let created_cbor: cbor::Value = sub_command.unwrap().into();
+        assert_eq!(created_cbor, cbor_sub_command);
+
+        let cbor_sub_command = cbor_int!(0x08);
+        let sub_command = CredentialManagementSubCommand::try_from(&cbor_sub_command);
+        assert_eq!(
+            sub_command,
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_from_into_credential_management_sub_command_parameters() {
+        let cbor_sub_command_params = cbor_map! {
+            0x01 => vec![0x1D; 32],
+            0x02 => cbor_map! {
+                "type" => "public-key",
+                "id" => vec![0x2D, 0x2D, 0x2D, 0x2D],
+            },
+            0x03 => cbor_map! {
+                "id" => vec![0x3D, 0x3D],
+                "displayName" => "bar",
+            },
+        };
+        let sub_command_params =
+            CredentialManagementSubCommandParameters::try_from(&cbor_sub_command_params);
+        let expected_sub_command_params = CredentialManagementSubCommandParameters {
+            rp_id_hash: Some(vec![0x1D; 32]),
+            credential_id: Some(PublicKeyCredentialDescriptor {
+                key_type: PublicKeyCredentialType::PublicKey,
+                key_id: vec![0x2D, 0x2D, 0x2D, 0x2D],
+                transports: None,
+            }),
+            user: Some(PublicKeyCredentialUserEntity {
+                user_id: vec![0x3D, 0x3D],
+                user_name: None,
+                user_display_name: Some("bar".to_string()),
+                user_icon: None,
+            }),
+        };
+        assert_eq!(sub_command_params, Ok(expected_sub_command_params));
+        let created_cbor: cbor::Value = sub_command_params.unwrap().into();
+        assert_eq!(created_cbor, cbor_sub_command_params);
+    }
+
+    #[test]
+    fn test_from_into_config_sub_command() {
+        let cbor_sub_command = cbor_int!(0x02);
+        let sub_command = ConfigSubCommand::try_from(&cbor_sub_command);
+        let expected_sub_command = ConfigSubCommand::ToggleAlwaysUv;
+        assert_eq!(sub_command, Ok(expected_sub_command));
+        let created_cbor: cbor::Value = sub_command.unwrap().into();
+        assert_eq!(created_cbor, cbor_sub_command);
+
+        let cbor_sub_command = cbor_int!(0x04);
+        let sub_command = ConfigSubCommand::try_from(&cbor_sub_command);
+        assert_eq!(
+            sub_command,
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+        );
+    }
+
+    #[test]
+    fn test_from_into_set_min_pin_length_params() {
+        let cbor_params = cbor_map! {
+            0x01 => 6,
+            0x02 => cbor_array!["example.com"],
+            0x03 => true,
+        };
+        let params = SetMinPinLengthParams::try_from(&cbor_params);
+        let expected_params = SetMinPinLengthParams {
+            new_min_pin_length: Some(6),
+            min_pin_length_rp_ids: Some(vec!["example.com".to_string()]),
+            force_change_pin: Some(true),
+        };
+        assert_eq!(params, Ok(expected_params));
+        let created_cbor: cbor::Value = params.unwrap().into();
+        assert_eq!(created_cbor, cbor_params);
+
+        let cbor_params = cbor_map! {
+            0x01 => 256,
+        };
+        assert_eq!(
+            SetMinPinLengthParams::try_from(&cbor_params),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)
+        );
+    }
+
     #[test]
     fn test_credential_source_cbor_round_trip() {
         use crypto::rng256::{Rng256, ThreadRng256};
@@ -989,10 +1823,14 @@ mod test {
         let credential = PublicKeyCredentialSource {
             key_type: PublicKeyCredentialType::PublicKey,
             credential_id: rng.gen_uniform_u8x32().to_vec(),
-            private_key: crypto::ecdsa::SecKey::gensk(&mut rng),
+            private_key: PrivateKey::new(&mut rng, SignatureAlgorithm::ES256),
             rp_id: "example.com".to_string(),
             user_handle: b"foo".to_vec(),
             other_ui: None,
+            cred_random: None,
+            cred_protect_policy: None,
+            signature_counter: None,
+            creation_order: 0,
         };
 
         assert_eq!(
@@ -1005,16 +1843,190 @@ mod test {
             ..credential
         };
 
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor::Value::from(credential.clone())),
+            Ok(credential.clone())
+        );
+
+        let credential = PublicKeyCredentialSource {
+            cred_random: Some(vec![0x1C; 32]),
+            ..credential
+        };
+
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor::Value::from(credential.clone())),
+            Ok(credential.clone())
+        );
+
+        let credential = PublicKeyCredentialSource {
+            cred_protect_policy: Some(CredentialProtectionPolicy::UserVerificationRequired),
+            ..credential
+        };
+
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor::Value::from(credential.clone())),
+            Ok(credential.clone())
+        );
+
+        let credential = PublicKeyCredentialSource {
+            signature_counter: Some(0x0102_0304),
+            ..credential
+        };
+
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor::Value::from(credential.clone())),
+            Ok(credential.clone())
+        );
+
+        let credential = PublicKeyCredentialSource {
+            private_key: PrivateKey::new(&mut rng, SignatureAlgorithm::EDDSA),
+            ..credential
+        };
+
         assert_eq!(
             PublicKeyCredentialSource::try_from(cbor::Value::from(credential.clone())),
             Ok(credential)
         );
     }
 
+    #[test]
+    fn test_credential_source_unknown_fields() {
+        use crypto::rng256::ThreadRng256;
+
+        let mut rng = ThreadRng256 {};
+        let credential = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: vec![0x01, 0x02],
+            private_key: PrivateKey::new(&mut rng, SignatureAlgorithm::ES256),
+            rp_id: "example.com".to_string(),
+            user_handle: b"foo".to_vec(),
+            other_ui: None,
+            cred_random: None,
+            cred_protect_policy: None,
+            signature_counter: None,
+            creation_order: 0,
+        };
+        let mut fields = match cbor::Value::from(credential.clone()) {
+            cbor::Value::Map(fields) => fields,
+            _ => panic!("Credentials are serialized as maps"),
+        };
+        // Fields added by newer firmwares are ignored.
+        fields.insert(cbor_unsigned!(0x42), cbor_text!("new field"));
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor::Value::Map(fields.clone())),
+            Ok(credential)
+        );
+
+        fields.remove(&cbor::KeyType::from(
+            PublicKeyCredentialSourceField::PrivateKey,
+        ));
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor::Value::Map(fields)),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_credential_source_without_cred_random() {
+        use crypto::rng256::ThreadRng256;
+
+        let mut rng = ThreadRng256 {};
+        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let mut private_key_bytes = [0u8; 32];
+        private_key.to_bytes(&mut private_key_bytes);
+        // Credentials stored before hmac-secret support have 5 fields.
+        let cbor_value = cbor_array! {
+            vec![0x01, 0x02],
+            private_key_bytes,
+            "example.com",
+            b"foo".to_vec(),
+            cbor_null!(),
+        };
+        let expected_credential = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: vec![0x01, 0x02],
+            private_key: PrivateKey::Ecdsa(private_key),
+            rp_id: "example.com".to_string(),
+            user_handle: b"foo".to_vec(),
+            other_ui: None,
+            cred_random: None,
+            cred_protect_policy: None,
+            signature_counter: None,
+            creation_order: 0,
+        };
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor_value),
+            Ok(expected_credential)
+        );
+    }
+
+    #[test]
+    fn test_from_into_signature_algorithm() {
+        let cbor_algorithm = cbor_int!(-8);
+        let algorithm = SignatureAlgorithm::try_from(&cbor_algorithm);
+        assert_eq!(algorithm, Ok(SignatureAlgorithm::EDDSA));
+        let created_cbor: cbor::Value = algorithm.unwrap().into();
+        assert_eq!(created_cbor, cbor_algorithm);
+
+        assert_eq!(
+            SignatureAlgorithm::try_from(-7),
+            Ok(SignatureAlgorithm::ES256)
+        );
+        assert_eq!(
+            SignatureAlgorithm::try_from(-257),
+            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)
+        );
+    }
+
+    #[test]
+    fn test_private_key_cbor_round_trip() {
+        use crypto::rng256::ThreadRng256;
+
+        let mut rng = ThreadRng256 {};
+        for algorithm in &[SignatureAlgorithm::ES256, SignatureAlgorithm::EDDSA] {
+            let private_key = PrivateKey::new(&mut rng, *algorithm);
+            assert_eq!(private_key.algorithm(), *algorithm);
+            let cbor_value = cbor::Value::from(private_key.clone());
+            assert_eq!(PrivateKey::try_from(&cbor_value), Ok(private_key.clone()));
+            assert_eq!(
+                PrivateKey::from_bytes(*algorithm, &private_key.to_bytes()),
+                Some(private_key)
+            );
+        }
+    }
+
+    #[test]
+    fn test_private_key_invalid_cbor() {
+        let key_bytes = vec![0x55; 32];
+        assert!(PrivateKey::try_from(&cbor_bytes!(key_bytes.clone())).is_ok());
+        assert_eq!(
+            PrivateKey::try_from(&cbor_array![-257, key_bytes.clone()]),
+            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)
+        );
+        assert_eq!(
+            PrivateKey::try_from(&cbor_array![-8, vec![0x55; 31]]),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+        );
+        assert_eq!(
+            PrivateKey::try_from(&cbor_array![-8]),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+        );
+        assert_eq!(
+            PrivateKey::try_from(&cbor_array![-8, key_bytes, cbor_null!()]),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+        );
+        // The zero scalar is not a valid ECDSA key.
+        assert_eq!(
+            PrivateKey::try_from(&cbor_array![-7, vec![0x00; 32]]),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+        );
+    }
+
     #[test]
     fn test_credential_source_invalid_cbor() {
         assert!(PublicKeyCredentialSource::try_from(cbor_false!()).is_err());
         assert!(PublicKeyCredentialSource::try_from(cbor_array!(false)).is_err());
         assert!(PublicKeyCredentialSource::try_from(cbor_array!(b"foo".to_vec())).is_err());
+        assert!(PublicKeyCredentialSource::try_from(cbor_map! {}).is_err());
     }
 }
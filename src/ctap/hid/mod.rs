@@ -19,19 +19,20 @@ use self::receive::MessageAssembler;
 use self::send::HidPacketIterator;
 #[cfg(feature = "with_ctap1")]
 use super::ctap1;
+#[cfg(feature = "debug_ctap")]
+use super::platform::debug_writeln;
+use super::platform::{Clock, PersistentStorage, SendOrRecvStatus, Transport, UserPresence};
 use super::status_code::Ctap2StatusCode;
+use super::time::{Duration, Timestamp};
 use super::timed_permission::TimedPermission;
 use super::CtapState;
-use crate::timer::{ClockValue, Duration, Timestamp};
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
-#[cfg(feature = "debug_ctap")]
-use core::fmt::Write;
+use core::cell::RefCell;
 use crypto::rng256::Rng256;
-#[cfg(feature = "debug_ctap")]
-use libtock::console::Console;
 
 // CTAP specification (version 20190130) section 8.1
-// TODO: Channel allocation, section 8.1.3?
 // TODO: Transaction timeout, section 8.1.5.2
 
 pub type HidPacket = [u8; 64];
@@ -64,15 +65,58 @@ pub struct CtapHid {
     assembler: MessageAssembler,
     // The specification (version 20190130) only requires unique CIDs ; the allocation algorithm is
     // vendor specific.
-    // We allocate them incrementally, that is all `cid` such that 1 <= cid <= allocated_cids are
-    // allocated.
-    // In packets, the ids are then encoded with the native endianness (with the
-    // u32::to/from_ne_bytes methods).
-    allocated_cids: usize,
+    // We allocate them randomly, so that an application can't guess the channel of another
+    // application to inject messages into it. At most MAX_CHANNELS are allocated, ordered from the
+    // least to the most recently used. A new channel evicts the least recently used one.
+    channels: Vec<ChannelID>,
     pub wink_permission: TimedPermission,
+    // The channel that holds the lock, if it is granted. Other channels are busy until then.
+    lock_cid: ChannelID,
+    lock_permission: TimedPermission,
+    // Packets received while sending a reply or a keepalive, to be processed after it.
+    pending_packets: PendingPackets,
 }
 
-#[allow(dead_code)]
+// Packets received while sending to the host, to be processed afterwards. CtapHid shares the
+// queue with the UserPresence implementation, which sends keepalives while a command is ongoing,
+// see CtapHid::pending_packets.
+#[derive(Clone, Default)]
+pub struct PendingPackets(Rc<RefCell<VecDeque<HidPacket>>>);
+
+impl PendingPackets {
+    pub fn new() -> PendingPackets {
+        PendingPackets::default()
+    }
+
+    fn push(&self, packet: HidPacket) {
+        let mut packets = self.0.borrow_mut();
+        if packets.len() < CtapHid::MAX_PENDING_PACKETS {
+            packets.push_back(packet);
+        } else {
+            #[cfg(feature = "debug_ctap")]
+            debug_writeln(format_args!("Dropped a packet received while sending"));
+        }
+    }
+
+    fn pop(&self) -> Option<HidPacket> {
+        self.0.borrow_mut().pop_front()
+    }
+
+    fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+// Why a reply could not be sent, see CtapHid::send_reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportError {
+    // The host didn't read the reply in time.
+    Timeout,
+    // The transport itself failed, e.g. the USB driver.
+    Failure,
+}
+
+#[derive(Clone, Copy)]
 pub enum KeepaliveStatus {
     Processing,
     UpNeeded,
@@ -95,7 +139,6 @@ impl CtapHid {
     pub const COMMAND_CANCEL: u8 = 0x11;
     const COMMAND_KEEPALIVE: u8 = 0x3B;
     const COMMAND_ERROR: u8 = 0x3F;
-    // TODO: optional lock command
     const COMMAND_LOCK: u8 = 0x04;
     const COMMAND_WINK: u8 = 0x08;
     const COMMAND_VENDOR_FIRST: u8 = 0x40;
@@ -134,42 +177,70 @@ impl CtapHid {
     // TODO: Is this timeout duration specified?
     const TIMEOUT_DURATION: Duration<isize> = Duration::from_ms(100);
     const WINK_TIMEOUT_DURATION: Duration<isize> = Duration::from_ms(5000);
+    const MAX_CHANNELS: usize = 8;
+    // Bounds the memory used by packets received while sending. Later packets are dropped.
+    const MAX_PENDING_PACKETS: usize = 16;
+    // CTAP specification (version 20190130) section 8.1.9.2.2
+    const MAX_LOCK_DURATION_SECS: u8 = 10;
 
     pub fn new() -> CtapHid {
         CtapHid {
             assembler: MessageAssembler::new(),
-            allocated_cids: 0,
+            channels: Vec::new(),
             wink_permission: TimedPermission::waiting(),
+            lock_cid: CtapHid::CHANNEL_RESERVED,
+            lock_permission: TimedPermission::waiting(),
+            pending_packets: PendingPackets::new(),
         }
     }
 
+    // Returns the queue of packets received while sending. Keepalives must be sent with it, so
+    // that the packets they receive are processed after the ongoing command.
+    pub fn pending_packets(&self) -> PendingPackets {
+        self.pending_packets.clone()
+    }
+
     // Process an incoming USB HID packet, and optionally returns a list of outgoing packets to
-    // send as a reply.
-    pub fn process_hid_packet<R, CheckUserPresence>(
+    // send as a reply. The time is read from the clock of the CTAP state.
+    pub fn process_hid_packet<R, CheckUserPresence, S, C>(
         &mut self,
         packet: &HidPacket,
-        clock_value: ClockValue,
-        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        ctap_state: &mut CtapState<R, CheckUserPresence, S, C>,
     ) -> HidPacketIterator
     where
         R: Rng256,
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+        CheckUserPresence: UserPresence,
+        S: PersistentStorage,
+        C: Clock,
     {
-        // TODO: Send COMMAND_KEEPALIVE every 100ms?
+        let clock_value = ctap_state.clock.now();
+        // Packets are checked before assembly, so that unallocated channels don't hold any memory.
+        let (&cid, processed_packet) = CtapHid::process_single_packet(packet);
+        if !self.has_valid_channel(cid, &processed_packet) {
+            #[cfg(feature = "debug_ctap")]
+            debug_writeln(format_args!("Invalid channel: {:02x?}", cid));
+            return CtapHid::error_message(cid, CtapHid::ERR_INVALID_CHANNEL);
+        }
+        // Other channels can't start a message while the lock is held.
+        if let ProcessedPacket::InitPacket { .. } = processed_packet {
+            if self.lock_permission.is_granted(clock_value) && cid != self.lock_cid {
+                return CtapHid::error_message(cid, CtapHid::ERR_CHANNEL_BUSY);
+            }
+        }
         match self
             .assembler
             .parse_packet(packet, Timestamp::<isize>::from_clock_value(clock_value))
         {
             Ok(Some(message)) => {
                 #[cfg(feature = "debug_ctap")]
-                writeln!(&mut Console::new(), "Received message: {:02x?}", message).unwrap();
+                debug_writeln(format_args!("Received message: {:02x?}", message));
 
                 let cid = message.cid;
-                if !self.has_valid_channel(&message) {
-                    #[cfg(feature = "debug_ctap")]
-                    writeln!(&mut Console::new(), "Invalid channel: {:02x?}", cid).unwrap();
-                    return CtapHid::error_message(cid, CtapHid::ERR_INVALID_CHANNEL);
+                // Messages that started before the lock was granted are also busy.
+                if self.lock_permission.is_granted(clock_value) && cid != self.lock_cid {
+                    return CtapHid::error_message(cid, CtapHid::ERR_CHANNEL_BUSY);
                 }
+                self.use_channel(cid);
                 // If another command arrives, stop winking to prevent accidential button touches.
                 self.wink_permission = TimedPermission::waiting();
 
@@ -196,8 +267,8 @@ impl CtapHid {
                     CtapHid::COMMAND_CBOR => {
                         // CTAP specification (version 20190130) section 8.1.5.1
                         // Each transaction is atomic, so we process the command directly here and
-                        // don't handle any other packet in the meantime.
-                        // TODO: Send keep-alive packets in the meantime.
+                        // don't handle any other packet in the meantime. The command sends
+                        // keepalives and listens for CANCEL through its UserPresence.
                         let response = ctap_state.process_command(&message.payload, cid);
                         if let Some(iterator) = CtapHid::split_message(Message {
                             cid,
@@ -230,9 +301,7 @@ impl CtapHid {
                                 return CtapHid::error_message(cid, CtapHid::ERR_INVALID_LEN);
                             }
 
-                            // TODO: Prevent allocating 2^32 channels.
-                            self.allocated_cids += 1;
-                            let allocated_cid = (self.allocated_cids as u32).to_ne_bytes();
+                            let allocated_cid = self.allocate_channel(ctap_state.rng);
 
                             let mut payload = vec![0; 17];
                             payload[..8].copy_from_slice(&message.payload);
@@ -274,7 +343,8 @@ impl CtapHid {
                     // CTAP specification (version 20190130) section 8.1.9.1.5
                     CtapHid::COMMAND_CANCEL => {
                         // Authenticators MUST NOT reply to this message.
-                        // CANCEL is handled during user presence checks in main.
+                        // CANCEL is handled while sending keepalives during the command it
+                        // cancels, see send_keepalive. Here, no command is ongoing.
                         HidPacketIterator::none()
                     }
                     // Optional commands
@@ -293,7 +363,30 @@ impl CtapHid {
                         .unwrap()
                     }
                     // CTAP specification (version 20190130) section 8.1.9.2.2
-                    // TODO: implement LOCK
+                    CtapHid::COMMAND_LOCK => {
+                        if message.payload.len() != 1 {
+                            return CtapHid::error_message(cid, CtapHid::ERR_INVALID_LEN);
+                        }
+                        let lock_duration_secs = message.payload[0];
+                        if lock_duration_secs > CtapHid::MAX_LOCK_DURATION_SECS {
+                            return CtapHid::error_message(cid, CtapHid::ERR_INVALID_PAR);
+                        }
+                        // A zero duration releases the lock.
+                        self.lock_cid = cid;
+                        self.lock_permission = if lock_duration_secs == 0 {
+                            TimedPermission::waiting()
+                        } else {
+                            let lock_duration =
+                                Duration::from_ms(1000 * isize::from(lock_duration_secs));
+                            TimedPermission::granted(clock_value, lock_duration)
+                        };
+                        CtapHid::split_message(Message {
+                            cid,
+                            cmd: CtapHid::COMMAND_LOCK,
+                            payload: vec![],
+                        })
+                        .unwrap()
+                    }
                     _ => {
                         // Unknown or unsupported command.
                         CtapHid::error_message(cid, CtapHid::ERR_INVALID_CMD)
@@ -309,7 +402,7 @@ impl CtapHid {
                     CtapHid::error_message(cid, CtapHid::ERR_INVALID_CHANNEL)
                 } else {
                     match error {
-                        receive::Error::UnexpectedChannel => {
+                        receive::Error::ChannelBusy => {
                             CtapHid::error_message(cid, CtapHid::ERR_CHANNEL_BUSY)
                         }
                         receive::Error::UnexpectedInit => {
@@ -327,6 +420,9 @@ impl CtapHid {
                         receive::Error::UnexpectedSeq => {
                             CtapHid::error_message(cid, CtapHid::ERR_INVALID_SEQ)
                         }
+                        receive::Error::InvalidLength => {
+                            CtapHid::error_message(cid, CtapHid::ERR_INVALID_LEN)
+                        }
                         receive::Error::Timeout => {
                             CtapHid::error_message(cid, CtapHid::ERR_MSG_TIMEOUT)
                         }
@@ -336,17 +432,90 @@ impl CtapHid {
         }
     }
 
-    fn has_valid_channel(&self, message: &Message) -> bool {
-        match message.cid {
+    // Sends the packets of a reply. Packets from the host that arrive in the meantime are queued,
+    // see next_pending_packet. If the transport fails or times out, the rest of the reply is
+    // dropped.
+    pub fn send_reply(
+        &mut self,
+        transport: &impl Transport,
+        reply: HidPacketIterator,
+        timeout: Duration<isize>,
+    ) -> Result<(), TransportError> {
+        for packet in reply {
+            let mut buffer = packet;
+            loop {
+                match transport.send_or_recv_with_timeout(&mut buffer, timeout) {
+                    None => return Err(TransportError::Timeout),
+                    Some(SendOrRecvStatus::Error) => return Err(TransportError::Failure),
+                    Some(SendOrRecvStatus::Sent) => break,
+                    Some(SendOrRecvStatus::Received) => {
+                        // The host sent a packet instead of reading ours. Keep it for later, and
+                        // send our packet again.
+                        self.pending_packets.push(buffer);
+                        buffer = packet;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the oldest packet received while sending a reply or a keepalive. These should be
+    // processed before receiving new packets.
+    pub fn next_pending_packet(&mut self) -> Option<HidPacket> {
+        self.pending_packets.pop()
+    }
+
+    // Drops the messages being assembled and the pending packets. This is meant to recover from
+    // transport errors, after which the host has missed part of the conversation. Allocated
+    // channels are kept.
+    pub fn reset(&mut self) {
+        self.assembler.reset();
+        self.pending_packets.clear();
+    }
+
+    fn has_valid_channel(&self, cid: ChannelID, packet: &ProcessedPacket) -> bool {
+        match (cid, packet) {
             // Only INIT commands use the broadcast channel.
-            CtapHid::CHANNEL_BROADCAST => message.cmd == CtapHid::COMMAND_INIT,
+            (CtapHid::CHANNEL_BROADCAST, ProcessedPacket::InitPacket { cmd, .. }) => {
+                *cmd == CtapHid::COMMAND_INIT
+            }
+            (CtapHid::CHANNEL_BROADCAST, ProcessedPacket::ContinuationPacket { .. }) => true,
             // Check that the channel is allocated.
-            _ => self.is_allocated_channel(message.cid),
+            _ => self.is_allocated_channel(cid),
         }
     }
 
     fn is_allocated_channel(&self, cid: ChannelID) -> bool {
-        cid != CtapHid::CHANNEL_RESERVED && u32::from_ne_bytes(cid) as usize <= self.allocated_cids
+        self.channels.contains(&cid)
+    }
+
+    // Allocates a random channel, evicting the least recently used channel if too many are
+    // allocated.
+    fn allocate_channel(&mut self, rng: &mut impl Rng256) -> ChannelID {
+        let cid = loop {
+            let random = rng.gen_uniform_u8x32();
+            let cid = *array_ref!(random, 0, 4);
+            if cid != CtapHid::CHANNEL_RESERVED
+                && cid != CtapHid::CHANNEL_BROADCAST
+                && !self.is_allocated_channel(cid)
+            {
+                break cid;
+            }
+        };
+        if self.channels.len() >= CtapHid::MAX_CHANNELS {
+            self.channels.remove(0);
+        }
+        self.channels.push(cid);
+        cid
+    }
+
+    // Marks the channel as the most recently used, if it is allocated.
+    fn use_channel(&mut self, cid: ChannelID) {
+        if let Some(index) = self.channels.iter().position(|&channel| channel == cid) {
+            self.channels.remove(index);
+            self.channels.push(cid);
+        }
     }
 
     fn error_message(cid: ChannelID, error_code: u8) -> HidPacketIterator {
@@ -385,7 +554,7 @@ impl CtapHid {
 
     fn split_message(message: Message) -> Option<HidPacketIterator> {
         #[cfg(feature = "debug_ctap")]
-        writeln!(&mut Console::new(), "Sending message: {:02x?}", message).unwrap();
+        debug_writeln(format_args!("Sending message: {:02x?}", message));
         HidPacketIterator::new(message)
     }
 
@@ -403,6 +572,62 @@ impl CtapHid {
         .unwrap()
     }
 
+    // Sends a keepalive telling the host that the command is still being processed or that user
+    // presence is needed. Returns an error if the host cancelled the command on this channel in
+    // the meantime. Other packets received in the meantime are queued in pending_packets, like
+    // during a reply.
+    pub fn send_keepalive(
+        transport: &impl Transport,
+        pending_packets: &PendingPackets,
+        cid: ChannelID,
+        status: KeepaliveStatus,
+        timeout: Duration<isize>,
+    ) -> Result<(), Ctap2StatusCode> {
+        let keepalive_msg = CtapHid::keepalive(cid, status);
+        for packet in keepalive_msg {
+            let mut buffer = packet;
+            loop {
+                match transport.send_or_recv_with_timeout(&mut buffer, timeout) {
+                    None => {
+                        #[cfg(feature = "debug_ctap")]
+                        debug_writeln(format_args!("Sending a KEEPALIVE packet timed out"));
+                        // The host stopped listening, so nobody waits for the user anymore.
+                        return Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT);
+                    }
+                    Some(SendOrRecvStatus::Error) => {
+                        // The reply can't be sent either. The caller recovers the transport when
+                        // it fails to send it.
+                        #[cfg(feature = "debug_ctap")]
+                        debug_writeln(format_args!("Error sending KEEPALIVE packet"));
+                        return Err(Ctap2StatusCode::CTAP1_ERR_OTHER);
+                    }
+                    Some(SendOrRecvStatus::Sent) => {
+                        #[cfg(feature = "debug_ctap")]
+                        debug_writeln(format_args!("Sent KEEPALIVE packet"));
+                        break;
+                    }
+                    Some(SendOrRecvStatus::Received) => {
+                        let (received_cid, processed_packet) =
+                            CtapHid::process_single_packet(&buffer);
+                        if let ProcessedPacket::InitPacket { cmd, .. } = processed_packet {
+                            if received_cid == &cid && cmd == CtapHid::COMMAND_CANCEL {
+                                // We ignore the payload, we can't answer with an error code
+                                // anyway.
+                                #[cfg(feature = "debug_ctap")]
+                                debug_writeln(format_args!("Command cancelled"));
+                                return Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
+                            }
+                        }
+                        // Other channels may start commands, they are answered after this one.
+                        pending_packets.push(buffer);
+                        buffer = packet;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     #[cfg(feature = "with_ctap1")]
     fn ctap1_error_message(
         cid: ChannelID,
@@ -432,9 +657,20 @@ impl CtapHid {
     }
 }
 
+impl Default for CtapHid {
+    fn default() -> CtapHid {
+        CtapHid::new()
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::super::platform::TestClock;
+    use super::super::time::ClockValue;
+    use super::super::{new_buffer_storage, StorageRegions};
     use super::*;
+    use crate::embedded_flash::BufferStorage;
+    use core::cell::RefCell;
     use crypto::rng256::ThreadRng256;
 
     const CLOCK_FREQUENCY_HZ: usize = 32768;
@@ -442,21 +678,112 @@ mod test {
     const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
     const DUMMY_TIMESTAMP: Timestamp<isize> = Timestamp::from_ms(0);
 
+    const KEEPALIVE_DELAY: Duration<isize> = Duration::from_ms(100);
+
+    // Sends all packets without delay and receives the queued packets first. A broken transport
+    // fails to send.
+    struct TestTransport {
+        received: RefCell<Vec<HidPacket>>,
+        sent: RefCell<Vec<HidPacket>>,
+        is_broken: bool,
+        // The host doesn't read the packets, so sending times out.
+        is_unread: bool,
+    }
+
+    impl TestTransport {
+        fn new(received: Vec<HidPacket>) -> TestTransport {
+            TestTransport {
+                received: RefCell::new(received),
+                sent: RefCell::new(Vec::new()),
+                is_broken: false,
+                is_unread: false,
+            }
+        }
+
+        fn broken() -> TestTransport {
+            TestTransport {
+                is_broken: true,
+                ..TestTransport::new(vec![])
+            }
+        }
+
+        fn unread() -> TestTransport {
+            TestTransport {
+                is_unread: true,
+                ..TestTransport::new(vec![])
+            }
+        }
+    }
+
+    impl Transport for TestTransport {
+        fn recv_with_timeout(
+            &self,
+            packet: &mut HidPacket,
+            _timeout: Duration<isize>,
+        ) -> Option<SendOrRecvStatus> {
+            let mut received = self.received.borrow_mut();
+            if received.is_empty() {
+                return None;
+            }
+            *packet = received.remove(0);
+            Some(SendOrRecvStatus::Received)
+        }
+
+        fn send_or_recv_with_timeout(
+            &self,
+            packet: &mut HidPacket,
+            timeout: Duration<isize>,
+        ) -> Option<SendOrRecvStatus> {
+            if let Some(status) = self.recv_with_timeout(packet, timeout) {
+                return Some(status);
+            }
+            if self.is_broken {
+                return Some(SendOrRecvStatus::Error);
+            }
+            if self.is_unread {
+                return None;
+            }
+            self.sent.borrow_mut().push(*packet);
+            Some(SendOrRecvStatus::Sent)
+        }
+    }
+
     fn process_messages<CheckUserPresence>(
         ctap_hid: &mut CtapHid,
-        ctap_state: &mut CtapState<ThreadRng256, CheckUserPresence>,
+        ctap_state: &mut CtapState<
+            ThreadRng256,
+            CheckUserPresence,
+            StorageRegions<BufferStorage>,
+            TestClock,
+        >,
         request: Vec<Message>,
     ) -> Option<Vec<Message>>
     where
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+        CheckUserPresence: UserPresence,
     {
+        process_messages_at(ctap_hid, ctap_state, request, DUMMY_CLOCK_VALUE)
+    }
+
+    fn process_messages_at<CheckUserPresence>(
+        ctap_hid: &mut CtapHid,
+        ctap_state: &mut CtapState<
+            ThreadRng256,
+            CheckUserPresence,
+            StorageRegions<BufferStorage>,
+            TestClock,
+        >,
+        request: Vec<Message>,
+        clock_value: ClockValue,
+    ) -> Option<Vec<Message>>
+    where
+        CheckUserPresence: UserPresence,
+    {
+        ctap_state.clock.set(clock_value);
         let mut result = Vec::new();
         let mut assembler_reply = MessageAssembler::new();
         for msg_request in request {
             for pkt_request in HidPacketIterator::new(msg_request).unwrap() {
-                for pkt_reply in
-                    ctap_hid.process_hid_packet(&pkt_request, DUMMY_CLOCK_VALUE, ctap_state)
-                {
+                for pkt_reply in ctap_hid.process_hid_packet(&pkt_request, ctap_state) {
                     match assembler_reply.parse_packet(&pkt_reply, DUMMY_TIMESTAMP) {
                         Ok(Some(message)) => result.push(message),
                         Ok(None) => (),
@@ -470,10 +797,15 @@ mod test {
 
     fn cid_from_init<CheckUserPresence>(
         ctap_hid: &mut CtapHid,
-        ctap_state: &mut CtapState<ThreadRng256, CheckUserPresence>,
+        ctap_state: &mut CtapState<
+            ThreadRng256,
+            CheckUserPresence,
+            StorageRegions<BufferStorage>,
+            TestClock,
+        >,
     ) -> ChannelID
     where
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+        CheckUserPresence: UserPresence,
     {
         let nonce = vec![0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0];
         let reply = process_messages(
@@ -525,7 +857,13 @@ mod test {
     fn test_command_init() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
         let mut ctap_hid = CtapHid::new();
 
         let reply = process_messages(
@@ -538,6 +876,10 @@ mod test {
             }],
         );
 
+        // The allocated CID is random.
+        let allocated_cid = *array_ref!(reply.as_ref().unwrap()[0].payload, 8, 4);
+        assert_ne!(allocated_cid, CtapHid::CHANNEL_RESERVED);
+        assert_ne!(allocated_cid, CtapHid::CHANNEL_BROADCAST);
         assert_eq!(
             reply,
             Some(vec![Message {
@@ -552,10 +894,10 @@ mod test {
                     0xBC,
                     0xDE,
                     0xF0,
-                    0x01, // Allocated CID
-                    0x00,
-                    0x00,
-                    0x00,
+                    allocated_cid[0], // Allocated CID
+                    allocated_cid[1],
+                    allocated_cid[2],
+                    allocated_cid[3],
                     0x02, // Protocol version
                     0x00, // Device version
                     0x00,
@@ -566,11 +908,121 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_channel_eviction() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut ctap_hid = CtapHid::new();
+        let cids: Vec<ChannelID> = (0..CtapHid::MAX_CHANNELS)
+            .map(|_| cid_from_init(&mut ctap_hid, &mut ctap_state))
+            .collect();
+
+        // Using the first channel makes the second one the least recently used.
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![ping_message(cids[0])]);
+        assert_eq!(reply, Some(vec![ping_message(cids[0])]));
+        cid_from_init(&mut ctap_hid, &mut ctap_state);
+
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![ping_message(cids[1])]);
+        assert_eq!(
+            reply,
+            Some(vec![error_message(cids[1], CtapHid::ERR_INVALID_CHANNEL)])
+        );
+        for &cid in cids.iter().filter(|&&cid| cid != cids[1]) {
+            let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![ping_message(cid)]);
+            assert_eq!(reply, Some(vec![ping_message(cid)]));
+        }
+    }
+
+    #[test]
+    fn test_unallocated_channel() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        // Sequential IDs next to an allocated channel are not allocated.
+        let guessed_cid = (u32::from_ne_bytes(cid).wrapping_add(1)).to_ne_bytes();
+
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![ping_message(guessed_cid)],
+        );
+        assert_eq!(
+            reply,
+            Some(vec![error_message(
+                guessed_cid,
+                CtapHid::ERR_INVALID_CHANNEL
+            )])
+        );
+    }
+
+    #[test]
+    fn test_unallocated_channels_hold_no_message() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let long_ping = |cid| Message {
+            cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x99; 100],
+        };
+
+        // Unallocated channels start long messages and never finish them.
+        for i in 0..CtapHid::MAX_CHANNELS {
+            let guessed_cid = (u32::from_ne_bytes(cid).wrapping_add(1 + i as u32)).to_ne_bytes();
+            let init_packet = HidPacketIterator::new(long_ping(guessed_cid))
+                .unwrap()
+                .next()
+                .unwrap();
+            let reply: Vec<Vec<u8>> = ctap_hid
+                .process_hid_packet(&init_packet, &mut ctap_state)
+                .map(|packet| packet.to_vec())
+                .collect();
+            let expected: Vec<Vec<u8>> =
+                CtapHid::error_message(guessed_cid, CtapHid::ERR_INVALID_CHANNEL)
+                    .map(|packet| packet.to_vec())
+                    .collect();
+            assert_eq!(reply, expected);
+        }
+
+        // The allocated channel can still send a long message.
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![long_ping(cid)]);
+        assert_eq!(reply, Some(vec![long_ping(cid)]));
+    }
+
     #[test]
     fn test_command_ping() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
         let mut ctap_hid = CtapHid::new();
         let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
 
@@ -593,4 +1045,504 @@ mod test {
             }])
         );
     }
+
+    fn lock_message(cid: ChannelID, lock_duration_secs: u8) -> Message {
+        Message {
+            cid,
+            cmd: CtapHid::COMMAND_LOCK,
+            payload: vec![lock_duration_secs],
+        }
+    }
+
+    fn ping_message(cid: ChannelID) -> Message {
+        Message {
+            cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x99, 0x99],
+        }
+    }
+
+    fn error_message(cid: ChannelID, error_code: u8) -> Message {
+        Message {
+            cid,
+            cmd: CtapHid::COMMAND_ERROR,
+            payload: vec![error_code],
+        }
+    }
+
+    #[test]
+    fn test_command_lock() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut ctap_hid = CtapHid::new();
+        let cid1 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let cid2 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![lock_message(cid1, 10)]);
+        let locked = Message {
+            cid: cid1,
+            cmd: CtapHid::COMMAND_LOCK,
+            payload: vec![],
+        };
+        assert_eq!(reply, Some(vec![locked.clone()]));
+
+        // Only the channel holding the lock is served.
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![ping_message(cid2), ping_message(cid1)],
+        );
+        assert_eq!(
+            reply,
+            Some(vec![
+                error_message(cid2, CtapHid::ERR_CHANNEL_BUSY),
+                ping_message(cid1)
+            ])
+        );
+
+        // A zero lock releases the lock.
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![lock_message(cid1, 0)]);
+        assert_eq!(reply, Some(vec![locked]));
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![ping_message(cid2)]);
+        assert_eq!(reply, Some(vec![ping_message(cid2)]));
+    }
+
+    #[test]
+    fn test_command_lock_rejects_init_packet() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut ctap_hid = CtapHid::new();
+        let cid1 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let cid2 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        process_messages(&mut ctap_hid, &mut ctap_state, vec![lock_message(cid1, 10)]);
+
+        // The first packet of a long message is already busy, before it is assembled.
+        let init_packet = HidPacketIterator::new(Message {
+            cid: cid2,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x99; 100],
+        })
+        .unwrap()
+        .next()
+        .unwrap();
+        let reply: Vec<Vec<u8>> = ctap_hid
+            .process_hid_packet(&init_packet, &mut ctap_state)
+            .map(|packet| packet.to_vec())
+            .collect();
+        let expected: Vec<Vec<u8>> = CtapHid::error_message(cid2, CtapHid::ERR_CHANNEL_BUSY)
+            .map(|packet| packet.to_vec())
+            .collect();
+        assert_eq!(reply, expected);
+    }
+
+    #[test]
+    fn test_command_lock_timeout() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut ctap_hid = CtapHid::new();
+        let cid1 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let cid2 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+
+        process_messages(&mut ctap_hid, &mut ctap_state, vec![lock_message(cid1, 1)]);
+        let half_a_second = ClockValue::new(CLOCK_FREQUENCY_HZ as isize / 2, CLOCK_FREQUENCY_HZ);
+        let reply = process_messages_at(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![ping_message(cid2)],
+            half_a_second,
+        );
+        assert_eq!(
+            reply,
+            Some(vec![error_message(cid2, CtapHid::ERR_CHANNEL_BUSY)])
+        );
+        let one_second = ClockValue::new(CLOCK_FREQUENCY_HZ as isize, CLOCK_FREQUENCY_HZ);
+        let reply = process_messages_at(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![ping_message(cid2)],
+            one_second,
+        );
+        assert_eq!(reply, Some(vec![ping_message(cid2)]));
+    }
+
+    #[test]
+    fn test_command_lock_invalid() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![lock_message(cid, 11)]);
+        assert_eq!(
+            reply,
+            Some(vec![error_message(cid, CtapHid::ERR_INVALID_PAR)])
+        );
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_LOCK,
+                payload: vec![],
+            }],
+        );
+        assert_eq!(
+            reply,
+            Some(vec![error_message(cid, CtapHid::ERR_INVALID_LEN)])
+        );
+    }
+
+    #[test]
+    fn test_interleaved_pings() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut ctap_hid = CtapHid::new();
+        let cid1 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let cid2 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let ping = |cid, byte| Message {
+            cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![byte; 100],
+        };
+        let packets1: Vec<HidPacket> = HidPacketIterator::new(ping(cid1, 0x11)).unwrap().collect();
+        let packets2: Vec<HidPacket> = HidPacketIterator::new(ping(cid2, 0x22)).unwrap().collect();
+
+        let mut replies = Vec::new();
+        let mut assembler_reply = MessageAssembler::new();
+        for (packet1, packet2) in packets1.iter().zip(packets2.iter()) {
+            for packet in &[packet1, packet2] {
+                for pkt_reply in ctap_hid.process_hid_packet(packet, &mut ctap_state) {
+                    if let Ok(Some(message)) =
+                        assembler_reply.parse_packet(&pkt_reply, DUMMY_TIMESTAMP)
+                    {
+                        replies.push(message);
+                    }
+                }
+            }
+        }
+        assert_eq!(replies, vec![ping(cid1, 0x11), ping(cid2, 0x22)]);
+    }
+
+    #[test]
+    fn test_send_reply_queues_received_packets() {
+        let cid = [0x12, 0x34, 0x56, 0x78];
+        let ping = |byte| Message {
+            cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![byte; 100],
+        };
+        let received: Vec<HidPacket> = HidPacketIterator::new(ping(0x11)).unwrap().collect();
+        let transport = TestTransport::new(received.clone());
+
+        let mut ctap_hid = CtapHid::new();
+        let reply = HidPacketIterator::new(ping(0x22)).unwrap();
+        assert_eq!(
+            ctap_hid.send_reply(&transport, reply, KEEPALIVE_DELAY),
+            Ok(())
+        );
+        let sent = transport.sent.borrow();
+        let expected_sent: Vec<HidPacket> = HidPacketIterator::new(ping(0x22)).unwrap().collect();
+        assert_eq!(sent.len(), expected_sent.len());
+        for (packet, expected_packet) in sent.iter().zip(expected_sent.iter()) {
+            assert_eq!(packet[..], expected_packet[..]);
+        }
+        for packet in received {
+            assert_eq!(ctap_hid.next_pending_packet().unwrap()[..], packet[..]);
+        }
+        assert!(ctap_hid.next_pending_packet().is_none());
+    }
+
+    #[test]
+    fn test_send_reply_transport_failure() {
+        let transport = TestTransport::broken();
+        let mut ctap_hid = CtapHid::new();
+        let reply = HidPacketIterator::new(ping_message([0x12, 0x34, 0x56, 0x78])).unwrap();
+        assert_eq!(
+            ctap_hid.send_reply(&transport, reply, KEEPALIVE_DELAY),
+            Err(TransportError::Failure)
+        );
+        assert!(transport.sent.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let request = Message {
+            cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x11; 100],
+        };
+        let mut packets = HidPacketIterator::new(request).unwrap();
+        let init_packet = packets.next().unwrap();
+        assert!(ctap_hid
+            .process_hid_packet(&init_packet, &mut ctap_state)
+            .next()
+            .is_none());
+        let transport = TestTransport::new(vec![init_packet]);
+        let reply = HidPacketIterator::new(ping_message(cid)).unwrap();
+        assert_eq!(
+            ctap_hid.send_reply(&transport, reply, KEEPALIVE_DELAY),
+            Ok(())
+        );
+
+        ctap_hid.reset();
+        assert!(ctap_hid.next_pending_packet().is_none());
+        // The assembler forgot the init packet, so the continuation packet is ignored.
+        let continuation_packet = packets.next().unwrap();
+        assert!(ctap_hid
+            .process_hid_packet(&continuation_packet, &mut ctap_state)
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn test_send_keepalive_up_needed() {
+        let cid = [0x12, 0x34, 0x56, 0x78];
+        let transport = TestTransport::new(vec![]);
+        assert_eq!(
+            CtapHid::send_keepalive(
+                &transport,
+                &PendingPackets::new(),
+                cid,
+                KeepaliveStatus::UpNeeded,
+                KEEPALIVE_DELAY
+            ),
+            Ok(())
+        );
+        let sent = transport.sent.borrow();
+        assert_eq!(sent.len(), 1);
+        let (sent_cid, processed_packet) = CtapHid::process_single_packet(&sent[0]);
+        assert_eq!(sent_cid, &cid);
+        match processed_packet {
+            ProcessedPacket::InitPacket { cmd, data, .. } => {
+                assert_eq!(cmd, CtapHid::COMMAND_KEEPALIVE);
+                assert_eq!(data[0], 2);
+            }
+            ProcessedPacket::ContinuationPacket { .. } => panic!("Expected an init packet"),
+        }
+    }
+
+    #[test]
+    fn test_send_keepalive_processing() {
+        let cid = [0x12, 0x34, 0x56, 0x78];
+        let transport = TestTransport::new(vec![]);
+        assert_eq!(
+            CtapHid::send_keepalive(
+                &transport,
+                &PendingPackets::new(),
+                cid,
+                KeepaliveStatus::Processing,
+                KEEPALIVE_DELAY
+            ),
+            Ok(())
+        );
+        let sent = transport.sent.borrow();
+        assert_eq!(sent.len(), 1);
+        match CtapHid::process_single_packet(&sent[0]).1 {
+            ProcessedPacket::InitPacket { cmd, data, .. } => {
+                assert_eq!(cmd, CtapHid::COMMAND_KEEPALIVE);
+                assert_eq!(data[0], 1);
+            }
+            ProcessedPacket::ContinuationPacket { .. } => panic!("Expected an init packet"),
+        }
+    }
+
+    #[test]
+    fn test_send_keepalive_transport_failure() {
+        let cid = [0x12, 0x34, 0x56, 0x78];
+        let transport = TestTransport::broken();
+        assert_eq!(
+            CtapHid::send_keepalive(
+                &transport,
+                &PendingPackets::new(),
+                cid,
+                KeepaliveStatus::UpNeeded,
+                KEEPALIVE_DELAY
+            ),
+            Err(Ctap2StatusCode::CTAP1_ERR_OTHER)
+        );
+    }
+
+    #[test]
+    fn test_send_keepalive_timeout() {
+        let cid = [0x12, 0x34, 0x56, 0x78];
+        let transport = TestTransport::unread();
+        assert_eq!(
+            CtapHid::send_keepalive(
+                &transport,
+                &PendingPackets::new(),
+                cid,
+                KeepaliveStatus::UpNeeded,
+                KEEPALIVE_DELAY
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+        );
+        assert!(transport.sent.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_send_keepalive_up_needed_cancelled() {
+        let cid = [0x12, 0x34, 0x56, 0x78];
+        let cancel = HidPacketIterator::new(Message {
+            cid,
+            cmd: CtapHid::COMMAND_CANCEL,
+            payload: vec![],
+        })
+        .unwrap()
+        .collect();
+        let transport = TestTransport::new(cancel);
+        assert_eq!(
+            CtapHid::send_keepalive(
+                &transport,
+                &PendingPackets::new(),
+                cid,
+                KeepaliveStatus::UpNeeded,
+                KEEPALIVE_DELAY
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL)
+        );
+        assert!(transport.sent.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_send_keepalive_up_needed_queues_received_packets() {
+        let cid = [0x12, 0x34, 0x56, 0x78];
+        let other_cancel = HidPacketIterator::new(Message {
+            cid: [0x9A, 0xBC, 0xDE, 0xF0],
+            cmd: CtapHid::COMMAND_CANCEL,
+            payload: vec![],
+        })
+        .unwrap();
+        let ping = HidPacketIterator::new(ping_message(cid)).unwrap();
+        let received: Vec<HidPacket> = other_cancel.chain(ping).collect();
+        let transport = TestTransport::new(received.clone());
+        let mut ctap_hid = CtapHid::new();
+        assert_eq!(
+            CtapHid::send_keepalive(
+                &transport,
+                &ctap_hid.pending_packets(),
+                cid,
+                KeepaliveStatus::UpNeeded,
+                KEEPALIVE_DELAY
+            ),
+            Ok(())
+        );
+        // The keepalive is sent after the received packets.
+        assert_eq!(transport.sent.borrow().len(), 1);
+        for packet in received {
+            assert_eq!(ctap_hid.next_pending_packet().unwrap()[..], packet[..]);
+        }
+        assert!(ctap_hid.next_pending_packet().is_none());
+    }
+
+    #[test]
+    fn test_interleaved_ping_during_user_presence() {
+        let mut rng = ThreadRng256 {};
+        let transport = TestTransport::new(vec![]);
+        let mut ctap_hid = CtapHid::new();
+        let pending_packets = ctap_hid.pending_packets();
+        let user_presence = |cid| {
+            CtapHid::send_keepalive(
+                &transport,
+                &pending_packets,
+                cid,
+                KeepaliveStatus::UpNeeded,
+                KEEPALIVE_DELAY,
+            )
+        };
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_presence,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let cid1 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let cid2 = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let ping = Message {
+            cid: cid2,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x22; 100],
+        };
+        // The second client pings while the first one waits for user presence.
+        transport
+            .received
+            .borrow_mut()
+            .extend(HidPacketIterator::new(ping.clone()).unwrap());
+
+        let selection = Message {
+            cid: cid1,
+            cmd: CtapHid::COMMAND_CBOR,
+            payload: vec![0xB0],
+        };
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![selection]);
+        assert_eq!(
+            reply,
+            Some(vec![Message {
+                cid: cid1,
+                cmd: CtapHid::COMMAND_CBOR,
+                payload: vec![0x00],
+            }])
+        );
+        let sent = transport.sent.borrow();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(CtapHid::process_single_packet(&sent[0]).0, &cid1);
+
+        let mut replies = Vec::new();
+        let mut assembler_reply = MessageAssembler::new();
+        while let Some(packet) = ctap_hid.next_pending_packet() {
+            for pkt_reply in ctap_hid.process_hid_packet(&packet, &mut ctap_state) {
+                if let Ok(Some(message)) = assembler_reply.parse_packet(&pkt_reply, DUMMY_TIMESTAMP)
+                {
+                    replies.push(message);
+                }
+            }
+        }
+        assert_eq!(replies, vec![ping]);
+    }
 }
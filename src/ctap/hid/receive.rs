@@ -13,38 +13,51 @@
 // limitations under the License.
 
 use super::{ChannelID, CtapHid, HidPacket, Message, ProcessedPacket};
-use crate::timer::Timestamp;
+use crate::ctap::time::Timestamp;
 use alloc::vec::Vec;
-use core::mem::swap;
 
-// A structure to assemble CTAPHID commands from a series of incoming USB HID packets.
-pub struct MessageAssembler {
-    // Whether this is waiting to receive an initialization packet.
-    idle: bool,
-    // Current channel ID.
+// CTAP specification (version 20190130) section 8.1.4
+// The longest payload that fits in an initialization packet and 128 continuation packets.
+const MAX_PAYLOAD_LENGTH: usize = 7609;
+// Bounds the memory used by the messages being assembled. This is enough for 2 messages of maximal
+// length, or more shorter ones.
+const MAX_ASSEMBLED_PAYLOAD_LENGTH: usize = 2 * MAX_PAYLOAD_LENGTH;
+
+// A message waiting for continuation packets on its channel.
+struct PartialMessage {
     cid: ChannelID,
-    // Timestamp of the last packet received on the current channel.
+    // Timestamp of the last packet received on this channel.
     last_timestamp: Timestamp<isize>,
-    // Current command.
     cmd: u8,
     // Sequence number expected for the next packet.
     seq: u8,
-    // Number of bytes left to fill the current message.
-    remaining_payload_len: usize,
-    // Buffer for the current payload.
+    // Length announced by the initialization packet.
+    payload_len: usize,
+    // Buffer for the payload received so far.
     payload: Vec<u8>,
 }
 
+// A structure to assemble CTAPHID commands from a series of incoming USB HID packets.
+// The packets of different channels may be interleaved, e.g. when 2 applications talk to the
+// authenticator at the same time. Each message is returned as soon as its last packet arrives, so a
+// long message doesn't hold back the short messages of other channels.
+pub struct MessageAssembler {
+    // The messages being assembled, at most one per channel.
+    partial_messages: Vec<PartialMessage>,
+}
+
 #[derive(PartialEq, Debug)]
 pub enum Error {
-    // Expected a continuation packet on a specific channel, got a packet on another channel.
-    UnexpectedChannel,
+    // Too many channels are sending a message, or their messages would take too much memory.
+    ChannelBusy,
     // Expected a continuation packet, got an init packet.
     UnexpectedInit,
     // Expected an init packet, got a continuation packet.
     UnexpectedContinuation,
     // Expected a continuation packet with a specific sequence number, got another sequence number.
     UnexpectedSeq,
+    // The announced payload length is longer than what the packets of a message can hold.
+    InvalidLength,
     // This packet arrived after a timeout.
     Timeout,
 }
@@ -52,26 +65,14 @@ pub enum Error {
 impl MessageAssembler {
     pub fn new() -> MessageAssembler {
         MessageAssembler {
-            idle: true,
-            cid: [0, 0, 0, 0],
-            last_timestamp: Timestamp::from_ms(0),
-            cmd: 0,
-            seq: 0,
-            remaining_payload_len: 0,
-            payload: Vec::new(),
+            partial_messages: Vec::new(),
         }
     }
 
-    // Resets the message assembler to the idle state.
-    // The caller can reset the assembler for example due to a timeout.
+    // Resets the message assembler to the idle state, dropping the messages of all channels.
+    // The caller can reset the assembler for example after a transport error.
     pub fn reset(&mut self) {
-        self.idle = true;
-        self.cid = [0, 0, 0, 0];
-        self.last_timestamp = Timestamp::from_ms(0);
-        self.cmd = 0;
-        self.seq = 0;
-        self.remaining_payload_len = 0;
-        self.payload.clear();
+        self.partial_messages.clear();
     }
 
     // Returns:
@@ -79,8 +80,6 @@ impl MessageAssembler {
     // full message was assembled after this packet, or None if more packets are needed to fill the
     // message.
     // - An Err() result if there was a parsing error.
-    // TODO: Implement timeouts. For example, have the caller pass us a timestamp of when this
-    // packet was received.
     pub fn parse_packet(
         &mut self,
         packet: &HidPacket,
@@ -89,64 +88,68 @@ impl MessageAssembler {
         // TODO: Support non-full-speed devices (i.e. packet len != 64)? This isn't recommended by
         // section 8.8.1
         let (cid, processed_packet) = CtapHid::process_single_packet(&packet);
-
-        if !self.idle && timestamp - self.last_timestamp >= CtapHid::TIMEOUT_DURATION {
-            // The current channel timed out.
-            // Save the channel ID and reset the state.
-            let current_cid = self.cid;
-            self.reset();
-
-            // If the packet is from the timed-out channel, send back a timeout error.
-            // Otherwise, proceed with processing the packet.
-            if *cid == current_cid {
-                return Err((*cid, Error::Timeout));
-            }
+        let cid = *cid;
+
+        // CTAP specification (version 20190130) section 8.1.5.2
+        // Drop the messages of the channels that timed out. If the packet is from one of them,
+        // send back a timeout error. Otherwise, proceed with processing the packet.
+        let mut is_timed_out = false;
+        self.partial_messages.retain(|message| {
+            let is_alive = timestamp - message.last_timestamp < CtapHid::TIMEOUT_DURATION;
+            is_timed_out |= !is_alive && message.cid == cid;
+            is_alive
+        });
+        if is_timed_out {
+            return Err((cid, Error::Timeout));
         }
 
-        if self.idle {
-            // Expecting an initialization packet.
-            match processed_packet {
-                ProcessedPacket::InitPacket { cmd, len, data } => {
-                    Ok(self.accept_init_packet(*cid, cmd, len, data, timestamp))
-                }
-                ProcessedPacket::ContinuationPacket { .. } => {
-                    // CTAP specification (version 20190130) section 8.1.5.4
-                    // Spurious continuation packets will be ignored.
-                    Err((*cid, Error::UnexpectedContinuation))
+        let position = self
+            .partial_messages
+            .iter()
+            .position(|message| message.cid == cid);
+        match (processed_packet, position) {
+            (ProcessedPacket::InitPacket { cmd, len, data }, None) => {
+                self.accept_init_packet(cid, cmd, len, data, timestamp)
+            }
+            // Unexpected initialization packet.
+            (ProcessedPacket::InitPacket { cmd, len, data }, Some(index)) => {
+                self.partial_messages.swap_remove(index);
+                if cmd == CtapHid::COMMAND_INIT {
+                    self.accept_init_packet(cid, cmd, len, data, timestamp)
+                } else {
+                    Err((cid, Error::UnexpectedInit))
                 }
             }
-        } else {
-            // Expecting a continuation packet from the current channel.
-
-            // CTAP specification (version 20190130) section 8.1.5.1
-            // Reject packets from other channels.
-            if *cid != self.cid {
-                return Err((*cid, Error::UnexpectedChannel));
+            (ProcessedPacket::ContinuationPacket { .. }, None) => {
+                // CTAP specification (version 20190130) section 8.1.5.4
+                // Spurious continuation packets will be ignored.
+                Err((cid, Error::UnexpectedContinuation))
             }
-
-            match processed_packet {
-                // Unexpected initialization packet.
-                ProcessedPacket::InitPacket { cmd, len, data } => {
-                    self.reset();
-                    if cmd == CtapHid::COMMAND_INIT {
-                        Ok(self.accept_init_packet(*cid, cmd, len, data, timestamp))
-                    } else {
-                        Err((*cid, Error::UnexpectedInit))
-                    }
+            (ProcessedPacket::ContinuationPacket { seq, data }, Some(index)) => {
+                let message = &mut self.partial_messages[index];
+                if seq != message.seq {
+                    // Reject packets with the wrong sequence number.
+                    self.partial_messages.swap_remove(index);
+                    return Err((cid, Error::UnexpectedSeq));
                 }
-                ProcessedPacket::ContinuationPacket { seq, data } => {
-                    if seq != self.seq {
-                        // Reject packets with the wrong sequence number.
-                        self.reset();
-                        Err((*cid, Error::UnexpectedSeq))
-                    } else {
-                        // Update the last timestamp.
-                        self.last_timestamp = timestamp;
-                        // Increment the sequence number for the next packet.
-                        self.seq += 1;
-                        Ok(self.append_payload(data))
-                    }
+                // Update the last timestamp.
+                message.last_timestamp = timestamp;
+                // Increment the sequence number for the next packet.
+                message.seq += 1;
+                let remaining_payload_len = message.payload_len - message.payload.len();
+                if data.len() < remaining_payload_len {
+                    message.payload.extend_from_slice(data);
+                    return Ok(None);
                 }
+                message
+                    .payload
+                    .extend_from_slice(&data[..remaining_payload_len]);
+                let message = self.partial_messages.swap_remove(index);
+                Ok(Some(Message {
+                    cid,
+                    cmd: message.cmd,
+                    payload: message.payload,
+                }))
             }
         }
     }
@@ -158,44 +161,48 @@ impl MessageAssembler {
         len: usize,
         data: &[u8],
         timestamp: Timestamp<isize>,
-    ) -> Option<Message> {
-        // TODO: Should invalid commands/payload lengths be rejected early, i.e. as soon as the
-        // initialization packet is received, or should we build a message and then catch the
-        // error?
+    ) -> Result<Option<Message>, (ChannelID, Error)> {
+        // TODO: Should invalid commands be rejected early, i.e. as soon as the initialization
+        // packet is received, or should we build a message and then catch the error?
         // The specification (version 20190130) isn't clear on this point.
-        self.cid = cid;
-        self.last_timestamp = timestamp;
-        self.cmd = cmd;
-        self.seq = 0;
-        self.remaining_payload_len = len;
-        self.append_payload(data)
-    }
-
-    fn append_payload(&mut self, data: &[u8]) -> Option<Message> {
-        if data.len() < self.remaining_payload_len {
-            self.payload.extend_from_slice(data);
-            self.idle = false;
-            self.remaining_payload_len -= data.len();
-            None
-        } else {
-            self.payload
-                .extend_from_slice(&data[..self.remaining_payload_len]);
-            self.idle = true;
-            let mut payload = Vec::new();
-            swap(&mut self.payload, &mut payload);
-            Some(Message {
-                cid: self.cid,
-                cmd: self.cmd,
-                payload,
-            })
+        if len > MAX_PAYLOAD_LENGTH {
+            return Err((cid, Error::InvalidLength));
+        }
+        if len <= data.len() {
+            return Ok(Some(Message {
+                cid,
+                cmd,
+                payload: data[..len].to_vec(),
+            }));
         }
+        let assembled_payload_len: usize = self
+            .partial_messages
+            .iter()
+            .map(|message| message.payload_len)
+            .sum();
+        if self.partial_messages.len() >= CtapHid::MAX_CHANNELS
+            || assembled_payload_len + len > MAX_ASSEMBLED_PAYLOAD_LENGTH
+        {
+            return Err((cid, Error::ChannelBusy));
+        }
+        let mut payload = Vec::with_capacity(len);
+        payload.extend_from_slice(data);
+        self.partial_messages.push(PartialMessage {
+            cid,
+            last_timestamp: timestamp,
+            cmd,
+            seq: 0,
+            payload_len: len,
+            payload,
+        });
+        Ok(None)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::timer::Duration;
+    use crate::ctap::time::Duration;
 
     // Except for tests that exercise timeouts, all packets are synchronized at the same dummy
     // timestamp.
@@ -428,29 +435,75 @@ mod test {
     }
 
     #[test]
-    fn test_unexpected_channel() {
+    fn test_interleaved_channels() {
+        // Check that the assembler can process messages from multiple channels, interleaved.
         let mut assembler = MessageAssembler::new();
+        for cid in 0x78..0x78 + CtapHid::MAX_CHANNELS as u8 {
+            assert_eq!(
+                assembler.parse_packet(
+                    &byte_extend(&[0x12, 0x34, 0x56, cid, 0x81, 0x00, 0x80], cid),
+                    DUMMY_TIMESTAMP
+                ),
+                Ok(None)
+            );
+        }
+        for cid in 0x78..0x78 + CtapHid::MAX_CHANNELS as u8 {
+            assert_eq!(
+                assembler.parse_packet(
+                    &byte_extend(&[0x12, 0x34, 0x56, cid, 0x00], cid),
+                    DUMMY_TIMESTAMP
+                ),
+                Ok(None)
+            );
+        }
+        // The messages complete in another order than they started.
+        for cid in (0x78..0x78 + CtapHid::MAX_CHANNELS as u8).rev() {
+            assert_eq!(
+                assembler.parse_packet(
+                    &byte_extend(&[0x12, 0x34, 0x56, cid, 0x01], cid),
+                    DUMMY_TIMESTAMP
+                ),
+                Ok(Some(Message {
+                    cid: [0x12, 0x34, 0x56, cid],
+                    cmd: 0x01,
+                    payload: vec![cid; 0x80]
+                }))
+            );
+        }
+    }
+
+    #[test]
+    fn test_too_many_channels() {
+        let mut assembler = MessageAssembler::new();
+        for cid in 0x78..0x78 + CtapHid::MAX_CHANNELS as u8 {
+            assert_eq!(
+                assembler.parse_packet(
+                    &zero_extend(&[0x12, 0x34, 0x56, cid, 0x81, 0x00, 0x40]),
+                    DUMMY_TIMESTAMP
+                ),
+                Ok(None)
+            );
+        }
         assert_eq!(
             assembler.parse_packet(
-                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x81, 0x00, 0x40]),
+                &zero_extend(&[0x12, 0x34, 0x56, 0x9A, 0x81, 0x00, 0x40]),
                 DUMMY_TIMESTAMP
             ),
-            Ok(None)
+            Err(([0x12, 0x34, 0x56, 0x9A], Error::ChannelBusy))
         );
-
-        // Check that many sorts of packets on another channel are ignored.
-        for cmd in 0..=0xFF {
-            for byte in 0..=0xFF {
-                assert_eq!(
-                    assembler.parse_packet(
-                        &byte_extend(&[0x12, 0x34, 0x56, 0x9A, cmd, 0x00], byte),
-                        DUMMY_TIMESTAMP
-                    ),
-                    Err(([0x12, 0x34, 0x56, 0x9A], Error::UnexpectedChannel))
-                );
-            }
-        }
-
+        // Messages that fit in one packet don't need a slot.
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x9A, 0x81, 0x00, 0x10]),
+                DUMMY_TIMESTAMP
+            ),
+            Ok(Some(Message {
+                cid: [0x12, 0x34, 0x56, 0x9A],
+                cmd: 0x01,
+                payload: vec![0x00; 0x10]
+            }))
+        );
+        // A completed message frees its slot.
         assert_eq!(
             assembler.parse_packet(
                 &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x00]),
@@ -462,6 +515,46 @@ mod test {
                 payload: vec![0x00; 0x40]
             }))
         );
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x9A, 0x81, 0x00, 0x40]),
+                DUMMY_TIMESTAMP
+            ),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_too_much_payload() {
+        let mut assembler = MessageAssembler::new();
+        for cid in 0x78..0x7A {
+            assert_eq!(
+                assembler.parse_packet(
+                    &zero_extend(&[0x12, 0x34, 0x56, cid, 0x81, 0x1D, 0xB9]),
+                    DUMMY_TIMESTAMP
+                ),
+                Ok(None)
+            );
+        }
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x9A, 0x81, 0x00, 0x40]),
+                DUMMY_TIMESTAMP
+            ),
+            Err(([0x12, 0x34, 0x56, 0x9A], Error::ChannelBusy))
+        );
+    }
+
+    #[test]
+    fn test_invalid_length() {
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x81, 0x1D, 0xBA]),
+                DUMMY_TIMESTAMP
+            ),
+            Err(([0x12, 0x34, 0x56, 0x78], Error::InvalidLength))
+        );
     }
 
     #[test]
@@ -586,5 +679,39 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_timeout_per_channel() {
+        let mut assembler = MessageAssembler::new();
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x81, 0x00, 0x40]),
+                DUMMY_TIMESTAMP
+            ),
+            Ok(None)
+        );
+        let timestamp = DUMMY_TIMESTAMP + (CtapHid::TIMEOUT_DURATION - Duration::from_ms(1));
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x9A, 0x81, 0x00, 0x40]),
+                timestamp
+            ),
+            Ok(None)
+        );
+        // The first channel timed out, but not the second.
+        let timestamp = DUMMY_TIMESTAMP + CtapHid::TIMEOUT_DURATION;
+        assert_eq!(
+            assembler.parse_packet(&zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x00]), timestamp),
+            Err(([0x12, 0x34, 0x56, 0x78], Error::Timeout))
+        );
+        assert_eq!(
+            assembler.parse_packet(&zero_extend(&[0x12, 0x34, 0x56, 0x9A, 0x00]), timestamp),
+            Ok(Some(Message {
+                cid: [0x12, 0x34, 0x56, 0x9A],
+                cmd: 0x01,
+                payload: vec![0x00; 0x40]
+            }))
+        );
+    }
+
     // TODO: more tests
 }
@@ -13,50 +13,62 @@
 // limitations under the License.
 
 pub mod command;
+mod config_command;
+mod credential_management;
 #[cfg(feature = "with_ctap1")]
 mod ctap1;
 pub mod data_formats;
 pub mod hid;
 mod key_material;
+mod pin_protocol;
+pub mod platform;
 pub mod response;
 pub mod status_code;
 mod storage;
+pub mod time;
 mod timed_permission;
+mod token_state;
 
 use self::command::{
     AuthenticatorClientPinParameters, AuthenticatorGetAssertionParameters,
     AuthenticatorMakeCredentialParameters, Command,
 };
 use self::data_formats::{
-    ClientPinSubCommand, CoseKey, PackedAttestationStatement, PublicKeyCredentialDescriptor,
+    ClientPinSubCommand, CoseKey, CredentialProtectionPolicy, GetAssertionHmacSecretInput,
+    PackedAttestationStatement, PrivateKey, PublicKeyCredentialDescriptor,
     PublicKeyCredentialSource, PublicKeyCredentialType, PublicKeyCredentialUserEntity,
     SignatureAlgorithm,
 };
 use self::hid::ChannelID;
 use self::key_material::{AAGUID, ATTESTATION_CERTIFICATE, ATTESTATION_PRIVATE_KEY};
+use self::pin_protocol::{verify_pin_uv_auth_token, PinProtocol, PinUvAuthProtocol, SharedSecret};
+use self::platform::{Clock, PersistentStorage, UserPresence};
 use self::response::{
     AuthenticatorClientPinResponse, AuthenticatorGetAssertionResponse,
     AuthenticatorGetInfoResponse, AuthenticatorMakeCredentialResponse, ResponseData,
 };
 use self::status_code::Ctap2StatusCode;
-use self::storage::PersistentStore;
+#[cfg(feature = "std")]
+pub use self::storage::{new_buffer_storage, new_buffer_storage_with_content};
+use self::storage::{PersistentStore, MAX_RP_IDS_LENGTH};
+pub use self::storage::{StorageRegions, COUNTERS_SIZE, STORE_SIZE};
+use self::time::{ClockValue, Duration, Timestamp};
+use self::timed_permission::TimedPermission;
 #[cfg(feature = "with_ctap1")]
 use self::timed_permission::U2fUserPresenceState;
+use self::token_state::{PinPermission, PinUvAuthTokenState};
+#[cfg(feature = "std")]
+use crate::embedded_flash::BufferStorage;
 use alloc::collections::BTreeMap;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use byteorder::{BigEndian, ByteOrder};
-use core::convert::TryInto;
-#[cfg(feature = "debug_ctap")]
-use core::fmt::Write;
+use core::convert::TryFrom;
 use crypto::cbc::{cbc_decrypt, cbc_encrypt};
-use crypto::hmac::{hmac_256, verify_hmac_256, verify_hmac_256_first_128bits};
+use crypto::hmac::{hmac_256, verify_hmac_256};
 use crypto::rng256::Rng256;
 use crypto::sha256::Sha256;
 use crypto::Hash256;
-#[cfg(feature = "debug_ctap")]
-use libtock::console::Console;
-use libtock::timer::{Duration, Timestamp};
 use subtle::ConstantTimeEq;
 
 // This flag enables or disables basic attestation for FIDO2. U2F is unaffected by
@@ -64,45 +76,85 @@ use subtle::ConstantTimeEq;
 // as a batch key. Turn it on if you want attestation. In this case, be aware that
 // it is your responsibility to generate your own key material and keep it secret.
 const USE_BATCH_ATTESTATION: bool = false;
-// The signature counter is currently implemented as a global counter, if you set
-// this flag to true. The spec strongly suggests to have per-credential-counters,
-// but it means you can't have an infinite amount of credentials anymore. Also,
-// since this is the only piece of information that needs writing often, we might
-// need a flash storage friendly way to implement this feature. The implemented
-// solution is a compromise to be compatible with U2F and not wasting storage.
+// The values of the enterpriseAttestation parameter of MakeCredential. Once enabled with
+// authenticatorConfig, platform-managed requests get the batch attestation, even without
+// USE_BATCH_ATTESTATION. There is no list of relying parties for vendor-facilitated enterprise
+// attestation, so those requests get the usual attestation.
+const ENTERPRISE_ATTESTATION_VENDOR_FACILITATED: u64 = 1;
+const ENTERPRISE_ATTESTATION_PLATFORM_MANAGED: u64 = 2;
+// The signature counter is incremented with each signature, if you set this flag to
+// true. As the spec suggests, resident credentials have their own counter, stored
+// with them. Key handles can't be updated, so they share a global counter, which is
+// also compatible with U2F. It grows by a random amount between 1 and
+// MAX_GLOBAL_SIGNATURE_COUNTER_INCREMENT, so that its value says less about how often
+// the authenticator was used with other relying parties.
 const USE_SIGNATURE_COUNTER: bool = true;
+const MAX_GLOBAL_SIGNATURE_COUNTER_INCREMENT: u8 = 16;
 // Those constants have to be multiples of 16, the AES block size.
 const PIN_AUTH_LENGTH: usize = 16;
-const PIN_TOKEN_LENGTH: usize = 32;
 const PIN_PADDED_LENGTH: usize = 64;
 // Our credential ID consists of
 // - 16 byte initialization vector for AES-256,
-// - 32 byte ECDSA private key for the credential,
+// - 32 byte private key for the credential,
 // - 32 byte relying party ID hashed with SHA256,
+// - 16 byte block with the signature algorithm and the credProtect policy, only for algorithms
+//   other than ES256 or credentials with a credProtect policy,
+// - 32 byte CredRandom, only for credentials with the hmac-secret extension,
 // - 32 byte HMAC-SHA256 over everything else.
+// ES256 credentials without CredRandom keep the U2F compatible size.
 pub const ENCRYPTED_CREDENTIAL_ID_SIZE: usize = 112;
+const ENCRYPTED_CREDENTIAL_ID_SIZE_WITH_CRED_RANDOM: usize = 144;
+const ALGORITHM_BLOCK_SIZE: usize = 16;
+// Advertised in getInfo as the maximum number of credentials in an allowList or excludeList. A
+// message of the maximum size fits about 5 descriptors of our largest credential IDs. Longer lists
+// are still processed.
+const MAX_CREDENTIAL_COUNT_IN_LIST: usize = 5;
 const UP_FLAG: u8 = 0x01;
 const UV_FLAG: u8 = 0x04;
 const AT_FLAG: u8 = 0x40;
+const ED_FLAG: u8 = 0x80;
 
 pub const TOUCH_TIMEOUT_MS: isize = 30000;
 #[cfg(feature = "with_ctap1")]
 const U2F_UP_PROMPT_TIMEOUT: Duration<isize> = Duration::from_ms(10000);
 const RESET_TIMEOUT_MS: isize = 10000;
+// CTAP specification (version 20190130) section 5.3
+// The state of a stateful command, like GetAssertion or the enumerations of the credential
+// management, is discarded if it is not continued within this time.
+const STATEFUL_COMMAND_TIMEOUT: Duration<isize> = Duration::from_ms(30000);
 
 pub const FIDO2_VERSION_STRING: &str = "FIDO_2_0";
 #[cfg(feature = "with_ctap1")]
 pub const U2F_VERSION_STRING: &str = "U2F_V2";
 
-fn check_pin_auth(hmac_key: &[u8], hmac_contents: &[u8], pin_auth: &[u8]) -> bool {
-    if pin_auth.len() != PIN_AUTH_LENGTH {
-        return false;
+// Checks the permissions a platform requests for a new pinUvAuthToken. Bio enrollment and large
+// blobs are not implemented, so their permissions can't be granted.
+fn check_requested_permissions(permissions: u8) -> Result<(), Ctap2StatusCode> {
+    if permissions == 0 {
+        return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+    }
+    let unsupported = PinPermission::BioEnrollment as u8 | PinPermission::LargeBlobWrite as u8;
+    if permissions & unsupported != 0 {
+        return Err(Ctap2StatusCode::CTAP2_ERR_UNAUTHORIZED_PERMISSION);
+    }
+    Ok(())
+}
+
+// Checks whether a credential's credProtect policy allows to use it. The policy only restricts
+// requests without user verification. Credentials without a policy are always usable.
+fn is_cred_protect_satisfied(
+    credential: &PublicKeyCredentialSource,
+    has_uv: bool,
+    is_listed: bool,
+) -> bool {
+    if has_uv {
+        return true;
+    }
+    match credential.cred_protect_policy {
+        None | Some(CredentialProtectionPolicy::UserVerificationOptional) => true,
+        Some(CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList) => is_listed,
+        Some(CredentialProtectionPolicy::UserVerificationRequired) => false,
     }
-    verify_hmac_256_first_128bits::<Sha256>(
-        hmac_key,
-        hmac_contents,
-        array_ref![pin_auth, 0, PIN_AUTH_LENGTH],
-    )
 }
 
 // This function is adapted from https://doc.rust-lang.org/nightly/src/core/str/mod.rs.html#2110
@@ -119,126 +171,236 @@ fn truncate_to_char_boundary(s: &str, mut max: usize) -> &str {
     }
 }
 
+// The decrypted salts of the hmac-secret extension, and the shared secret to encrypt its output.
+struct HmacSecretSalts {
+    shared_secret: SharedSecret,
+    // Contains one or two salts of 32 byte.
+    salts: Vec<u8>,
+}
+
+// The state of a GetAssertion call with multiple matching credentials. It is needed to answer the
+// following GetNextAssertion calls.
+struct AssertionState {
+    client_data_hash: Vec<u8>,
+    rp_id_hash: [u8; 32],
+    flags: u8,
+    hmac_secret_salts: Option<HmacSecretSalts>,
+    // The credentials that were not yet returned, in reverse order. The next credential to return
+    // is popped from the end.
+    next_credentials: Vec<PublicKeyCredentialSource>,
+}
+
+// Commands that return their results over multiple calls. The vectors hold the elements that were
+// not yet returned, in reverse order.
+enum StatefulCommand {
+    GetAssertion(AssertionState),
+    EnumerateRps(Vec<String>),
+    EnumerateCredentials(Vec<PublicKeyCredentialSource>),
+}
+
+struct StatefulCommandState {
+    // Only the channel that started the command may continue it.
+    cid: ChannelID,
+    // Reset on every successful call, the state is discarded when this permission expires.
+    timeout: TimedPermission,
+    command: StatefulCommand,
+}
+
 // This struct currently holds all state, not only the persistent memory. The persistent members are
 // in the persistent store field.
-pub struct CtapState<'a, R: Rng256, CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>>
+pub struct CtapState<'a, R: Rng256, CheckUserPresence: UserPresence, S: PersistentStorage, C: Clock>
 {
     rng: &'a mut R,
-    // A function to check user presence, ultimately returning true if user presence was detected,
-    // false otherwise.
+    // Checks user presence, ultimately returning Ok(()) if user presence was detected, an error
+    // otherwise.
     check_user_presence: CheckUserPresence,
-    persistent_store: PersistentStore,
-    key_agreement_key: crypto::ecdh::SecKey,
-    pin_uv_auth_token: [u8; PIN_TOKEN_LENGTH],
+    persistent_store: PersistentStore<S::Flash>,
+    clock: C,
+    pin_protocol_v1: PinProtocol,
+    pin_protocol_v2: PinProtocol,
+    // The permissions and lifetime of the pinUvAuthToken, shared by both PIN protocols.
+    pin_uv_auth_token_state: PinUvAuthTokenState,
     consecutive_pin_mismatches: u64,
     // This variable will be irreversibly set to false RESET_TIMEOUT_MS milliseconds after boot.
     accepts_reset: bool,
+    // Set by commands that return more results in follow-up calls.
+    stateful_command: Option<StatefulCommandState>,
     #[cfg(feature = "with_ctap1")]
     pub u2f_up_state: U2fUserPresenceState,
 }
 
-impl<'a, R, CheckUserPresence> CtapState<'a, R, CheckUserPresence>
+// Used when the store lives in a file, e.g. on a desktop.
+#[cfg(feature = "std")]
+impl<'a, R, CheckUserPresence, C>
+    CtapState<'a, R, CheckUserPresence, StorageRegions<BufferStorage>, C>
 where
     R: Rng256,
-    CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    CheckUserPresence: UserPresence,
+    C: Clock,
 {
-    pub const PIN_PROTOCOL_VERSION: u64 = 1;
+    // Returns the content of the persistent store, to restart from it with
+    // new_buffer_storage_with_content.
+    pub fn persistent_store_content(&self) -> Box<[u8]> {
+        self.persistent_store.content()
+    }
+}
 
+impl<'a, R, CheckUserPresence, S, C> CtapState<'a, R, CheckUserPresence, S, C>
+where
+    R: Rng256,
+    CheckUserPresence: UserPresence,
+    S: PersistentStorage,
+    C: Clock,
+{
+    // The storage holds the persistent store, see PersistentStore::new for its requirements and
+    // errors.
     pub fn new(
         rng: &'a mut R,
         check_user_presence: CheckUserPresence,
-    ) -> CtapState<'a, R, CheckUserPresence> {
-        let key_agreement_key = crypto::ecdh::SecKey::gensk(rng);
-        let pin_uv_auth_token = rng.gen_uniform_u8x32();
-        let persistent_store = PersistentStore::new(rng);
-        CtapState {
+        storage: S,
+        clock: C,
+    ) -> Result<CtapState<'a, R, CheckUserPresence, S, C>, Ctap2StatusCode> {
+        let persistent_store = PersistentStore::new(rng, storage.into_regions())?;
+        let pin_protocol_v1 = PinProtocol::new(rng);
+        let pin_protocol_v2 = PinProtocol::new(rng);
+        Ok(CtapState {
             rng,
             check_user_presence,
             persistent_store,
-            key_agreement_key,
-            pin_uv_auth_token,
+            clock,
+            pin_protocol_v1,
+            pin_protocol_v2,
+            pin_uv_auth_token_state: PinUvAuthTokenState::new(),
             consecutive_pin_mismatches: 0,
             accepts_reset: true,
+            stateful_command: None,
             #[cfg(feature = "with_ctap1")]
             u2f_up_state: U2fUserPresenceState::new(
                 U2F_UP_PROMPT_TIMEOUT,
                 Duration::from_ms(TOUCH_TIMEOUT_MS),
             ),
-        }
+        })
     }
 
-    pub fn check_disable_reset(&mut self, timestamp: Timestamp<isize>) {
+    pub fn check_disable_reset(&mut self) {
+        let timestamp = Timestamp::<isize>::from_clock_value(self.clock.now());
         if timestamp - Timestamp::<isize>::from_ms(0) > Duration::from_ms(RESET_TIMEOUT_MS) {
             self.accepts_reset = false;
         }
     }
 
-    pub fn increment_global_signature_counter(&mut self) {
-        if USE_SIGNATURE_COUNTER {
-            self.persistent_store.incr_global_signature_counter();
+    // Increments the signature counter of the credential and returns its new value. Only
+    // resident credentials have their own counter.
+    fn increment_signature_counter(
+        &mut self,
+        credential: &PublicKeyCredentialSource,
+    ) -> Result<u32, Ctap2StatusCode> {
+        if !USE_SIGNATURE_COUNTER {
+            return Ok(0);
+        }
+        match credential.signature_counter {
+            Some(_) => self
+                .persistent_store
+                .incr_credential_signature_counter(&credential.credential_id),
+            None => self.increment_global_signature_counter(),
+        }
+    }
+
+    // Increments the signature counter shared by key handles, and returns its new value.
+    pub fn increment_global_signature_counter(&mut self) -> Result<u32, Ctap2StatusCode> {
+        if !USE_SIGNATURE_COUNTER {
+            return Ok(0);
         }
+        let increment =
+            self.rng.gen_uniform_u8x32()[0] % MAX_GLOBAL_SIGNATURE_COUNTER_INCREMENT + 1;
+        self.persistent_store
+            .incr_global_signature_counter(increment as u32)
     }
 
     // Encrypts the private key and relying party ID hash into a credential ID. Other
     // information, such as a user name, are not stored, because encrypted credential IDs
     // are used for credentials stored server-side. Also, we want the key handle to be
-    // compatible with U2F.
+    // compatible with U2F. Only credentials with the hmac-secret extension also contain their
+    // CredRandom, and only credentials with algorithms other than ES256 contain their algorithm.
+    // Both make the credential ID longer.
     pub fn encrypt_key_handle(
         &mut self,
-        private_key: crypto::ecdsa::SecKey,
+        private_key: PrivateKey,
         application: &[u8; 32],
-    ) -> Vec<u8> {
-        let master_keys = self.persistent_store.master_keys();
+        cred_random: Option<&[u8; 32]>,
+        cred_protect_policy: Option<CredentialProtectionPolicy>,
+    ) -> Result<Vec<u8>, Ctap2StatusCode> {
+        let master_keys = self.persistent_store.master_keys()?;
         let aes_enc_key = crypto::aes256::EncryptionKey::new(master_keys.encryption);
-        let mut sk_bytes = [0; 32];
-        private_key.to_bytes(&mut sk_bytes);
+        let sk_bytes = private_key.to_bytes();
         let mut iv = [0; 16];
         iv.copy_from_slice(&self.rng.gen_uniform_u8x32()[..16]);
 
-        let mut blocks = [[0u8; 16]; 4];
+        let mut blocks = vec![[0u8; 16]; 4];
         blocks[0].copy_from_slice(&sk_bytes[..16]);
         blocks[1].copy_from_slice(&sk_bytes[16..]);
         blocks[2].copy_from_slice(&application[..16]);
         blocks[3].copy_from_slice(&application[16..]);
+        let algorithm = private_key.algorithm();
+        if algorithm != SignatureAlgorithm::ES256 || cred_protect_policy.is_some() {
+            // The policy is 0 if absent, so that older key handles have none.
+            let mut algorithm_block = [0u8; 16];
+            BigEndian::write_i64(&mut algorithm_block[..8], algorithm as i64);
+            algorithm_block[8] = cred_protect_policy.map_or(0, |policy| policy as u8);
+            blocks.push(algorithm_block);
+        }
+        if let Some(cred_random) = cred_random {
+            blocks.push(*array_ref![cred_random, 0, 16]);
+            blocks.push(*array_ref![cred_random, 16, 16]);
+        }
         cbc_encrypt(&aes_enc_key, iv, &mut blocks);
 
-        let mut encrypted_id = Vec::with_capacity(ENCRYPTED_CREDENTIAL_ID_SIZE);
+        let mut encrypted_id = Vec::with_capacity(
+            ENCRYPTED_CREDENTIAL_ID_SIZE_WITH_CRED_RANDOM + ALGORITHM_BLOCK_SIZE,
+        );
         encrypted_id.extend(&iv);
         for b in &blocks {
             encrypted_id.extend(b);
         }
         let id_hmac = hmac_256::<Sha256>(master_keys.hmac, &encrypted_id[..]);
         encrypted_id.extend(&id_hmac);
-        encrypted_id
+        Ok(encrypted_id)
     }
 
     // Decrypts a credential ID and writes the private key into a PublicKeyCredentialSource.
     // None is returned if the HMAC test fails or the relying party does not match the
-    // decrypted relying party ID hash.
+    // decrypted relying party ID hash. Errors come from reading the master keys.
     pub fn decrypt_credential_source(
         &self,
         credential_id: Vec<u8>,
         rp_id_hash: &[u8],
-    ) -> Option<PublicKeyCredentialSource> {
-        if credential_id.len() != ENCRYPTED_CREDENTIAL_ID_SIZE {
-            return None;
-        }
-        let master_keys = self.persistent_store.master_keys();
-        let payload_size = ENCRYPTED_CREDENTIAL_ID_SIZE - 32;
+    ) -> Result<Option<PublicKeyCredentialSource>, Ctap2StatusCode> {
+        let (has_algorithm, has_cred_random) = match credential_id.len() {
+            ENCRYPTED_CREDENTIAL_ID_SIZE => (false, false),
+            ENCRYPTED_CREDENTIAL_ID_SIZE_WITH_CRED_RANDOM => (false, true),
+            x if x == ENCRYPTED_CREDENTIAL_ID_SIZE + ALGORITHM_BLOCK_SIZE => (true, false),
+            x if x == ENCRYPTED_CREDENTIAL_ID_SIZE_WITH_CRED_RANDOM + ALGORITHM_BLOCK_SIZE => {
+                (true, true)
+            }
+            _ => return Ok(None),
+        };
+        let num_blocks = (credential_id.len() - 48) / 16;
+        let master_keys = self.persistent_store.master_keys()?;
+        let payload_size = credential_id.len() - 32;
         if !verify_hmac_256::<Sha256>(
             master_keys.hmac,
             &credential_id[..payload_size],
             array_ref![credential_id, payload_size, 32],
         ) {
-            return None;
+            return Ok(None);
         }
         let aes_enc_key = crypto::aes256::EncryptionKey::new(master_keys.encryption);
         let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
         let mut iv = [0; 16];
         iv.copy_from_slice(&credential_id[..16]);
-        let mut blocks = [[0u8; 16]; 4];
-        for i in 0..4 {
-            blocks[i].copy_from_slice(&credential_id[16 * (i + 1)..16 * (i + 2)]);
+        let mut blocks = vec![[0u8; 16]; num_blocks];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            block.copy_from_slice(&credential_id[16 * (i + 1)..16 * (i + 2)]);
         }
 
         cbc_decrypt(&aes_dec_key, iv, &mut blocks);
@@ -248,26 +410,57 @@ where
         decrypted_sk[16..].clone_from_slice(&blocks[1]);
         decrypted_rp_id_hash[..16].clone_from_slice(&blocks[2]);
         decrypted_rp_id_hash[16..].clone_from_slice(&blocks[3]);
+        let (algorithm, cred_protect_policy) = if has_algorithm {
+            let algorithm = match SignatureAlgorithm::try_from(BigEndian::read_i64(&blocks[4][..8]))
+            {
+                Ok(algorithm) => algorithm,
+                Err(_) => return Ok(None),
+            };
+            let cred_protect_policy = match blocks[4][8] {
+                0x00 => None,
+                0x01 => Some(CredentialProtectionPolicy::UserVerificationOptional),
+                0x02 => {
+                    Some(CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList)
+                }
+                0x03 => Some(CredentialProtectionPolicy::UserVerificationRequired),
+                _ => return Ok(None),
+            };
+            (algorithm, cred_protect_policy)
+        } else {
+            (SignatureAlgorithm::ES256, None)
+        };
+        let cred_random = if has_cred_random {
+            let mut decrypted_cred_random = blocks[num_blocks - 2].to_vec();
+            decrypted_cred_random.extend(&blocks[num_blocks - 1]);
+            Some(decrypted_cred_random)
+        } else {
+            None
+        };
 
         if rp_id_hash != decrypted_rp_id_hash {
-            return None;
+            return Ok(None);
         }
 
-        let sk_option = crypto::ecdsa::SecKey::from_bytes(&decrypted_sk);
-        sk_option.map(|sk| PublicKeyCredentialSource {
+        let sk_option = PrivateKey::from_bytes(algorithm, &decrypted_sk);
+        Ok(sk_option.map(|sk| PublicKeyCredentialSource {
             key_type: PublicKeyCredentialType::PublicKey,
             credential_id,
             private_key: sk,
             rp_id: String::from(""),
             user_handle: vec![],
             other_ui: None,
-        })
+            cred_random,
+            cred_protect_policy,
+            signature_counter: None,
+            creation_order: 0,
+        }))
     }
 
     pub fn process_command(&mut self, command_cbor: &[u8], cid: ChannelID) -> Vec<u8> {
+        let now = self.clock.now();
         let cmd = Command::deserialize(command_cbor);
         #[cfg(feature = "debug_ctap")]
-        writeln!(&mut Console::new(), "Received command: {:#?}", cmd).unwrap();
+        platform::debug_writeln(format_args!("Received command: {:#?}", cmd));
         match cmd {
             Ok(command) => {
                 // Correct behavior between CTAP1 and CTAP2 isn't defined yet. Just a guess.
@@ -278,21 +471,42 @@ where
                         Duration::from_ms(TOUCH_TIMEOUT_MS),
                     );
                 }
+                // CTAP specification (version 20190130) section 5.3
+                // Any command other than GetNextAssertion discards the state of the last
+                // GetAssertion call. The credential management decides depending on its
+                // subcommand.
+                if !matches!(
+                    command,
+                    Command::AuthenticatorGetNextAssertion
+                        | Command::AuthenticatorCredentialManagement(_)
+                ) {
+                    self.stateful_command = None;
+                }
+                self.pin_uv_auth_token_state
+                    .pin_uv_auth_token_usage_timer_observer(now);
                 let response = match command {
                     Command::AuthenticatorMakeCredential(params) => {
                         self.process_make_credential(params, cid)
                     }
                     Command::AuthenticatorGetAssertion(params) => {
-                        self.process_get_assertion(params, cid)
+                        self.process_get_assertion(params, cid, now)
+                    }
+                    Command::AuthenticatorGetNextAssertion => {
+                        self.process_get_next_assertion(cid, now)
                     }
                     Command::AuthenticatorGetInfo => self.process_get_info(),
-                    Command::AuthenticatorClientPin(params) => self.process_client_pin(params),
+                    Command::AuthenticatorClientPin(params) => {
+                        self.process_client_pin(params, cid, now)
+                    }
                     Command::AuthenticatorReset => self.process_reset(cid),
-                    // TODO(kaczmarczyck) implement GetNextAssertion and FIDO 2.1 commands
-                    _ => unimplemented!(),
+                    Command::AuthenticatorCredentialManagement(params) => {
+                        self.process_credential_management(params, cid, now)
+                    }
+                    Command::AuthenticatorSelection => self.process_selection(cid),
+                    Command::AuthenticatorConfig(params) => self.process_config(params),
                 };
                 #[cfg(feature = "debug_ctap")]
-                writeln!(&mut Console::new(), "Sending response: {:#?}", response).unwrap();
+                platform::debug_writeln(format_args!("Sending response: {:#?}", response));
                 match response {
                     Ok(response_data) => {
                         let mut response_vec = vec![0x00];
@@ -324,60 +538,53 @@ where
             user,
             pub_key_cred_params,
             exclude_list,
+            extensions,
             options,
             pin_uv_auth_param,
             pin_uv_auth_protocol,
-            ..
+            enterprise_attestation,
         } = make_credential_params;
 
-        if let Some(auth_param) = &pin_uv_auth_param {
-            // This case was added in FIDO 2.1.
-            if auth_param.is_empty() {
-                if self.persistent_store.pin_hash().is_none() {
-                    return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
-                } else {
-                    return Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID);
+        let pin_uv_auth_protocol = match &pin_uv_auth_param {
+            Some(auth_param) => {
+                // This case was added in FIDO 2.1.
+                if auth_param.is_empty() {
+                    if self.persistent_store.pin_hash().is_none() {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
+                    } else {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID);
+                    }
                 }
+                let protocol =
+                    pin_uv_auth_protocol.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?;
+                Some(PinUvAuthProtocol::try_from(protocol)?)
             }
+            None => None,
+        };
 
-            match pin_uv_auth_protocol {
-                Some(protocol) => {
-                    if protocol != CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION {
-                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
-                    }
-                }
-                None => return Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER),
+        let use_enterprise_attestation = match enterprise_attestation {
+            None => false,
+            Some(_) if !self.persistent_store.enterprise_attestation() => {
+                return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
             }
-        }
+            Some(ENTERPRISE_ATTESTATION_VENDOR_FACILITATED) => false,
+            Some(ENTERPRISE_ATTESTATION_PLATFORM_MANAGED) => true,
+            Some(_) => return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION),
+        };
 
-        let has_es_256 = pub_key_cred_params
+        // The parameters are ordered by the relying party's preference, we pick the first one
+        // we support.
+        let algorithm = pub_key_cred_params
             .iter()
-            .any(|(credential_type, algorithm)| {
+            .filter(|(credential_type, _)| {
                 // Even though there is only one type now, checking seems safer in
                 // case of extension so you can't forget to update here.
                 *credential_type == PublicKeyCredentialType::PublicKey
-                    && *algorithm == SignatureAlgorithm::ES256 as i64
-            });
-        if !has_es_256 {
-            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
-        }
+            })
+            .find_map(|(_, algorithm)| SignatureAlgorithm::try_from(*algorithm).ok())
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)?;
 
         let rp_id = rp.rp_id;
-        if let Some(exclude_list) = exclude_list {
-            for cred_desc in exclude_list {
-                if self
-                    .persistent_store
-                    .find_credential(&rp_id, &cred_desc.key_id)
-                    .is_some()
-                {
-                    // Perform this check, so bad actors can't brute force exclude_list
-                    // without user interaction. Discard the user presence check's outcome.
-                    let _ = (self.check_user_presence)(cid);
-                    return Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED);
-                }
-            }
-        }
-
         // MakeCredential always requires user presence.
         // User verification depends on the PIN auth inputs, which are checked here.
         let flags = match pin_uv_auth_param {
@@ -386,29 +593,92 @@ where
                     // Specification is unclear, could be CTAP2_ERR_INVALID_OPTION.
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
                 }
-                if !check_pin_auth(&self.pin_uv_auth_token, &client_data_hash, &pin_auth) {
+                let protocol =
+                    pin_uv_auth_protocol.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?;
+                if !self.check_pin_uv_auth_param(protocol, &client_data_hash, &pin_auth) {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
                 }
+                self.pin_uv_auth_token_state
+                    .has_permission(PinPermission::MakeCredential)?;
+                self.pin_uv_auth_token_state
+                    .ensure_permissions_rp_id(&rp_id)?;
+                self.pin_uv_auth_token_state.mark_used();
+                // Each token authorizes a single credential.
+                self.pin_uv_auth_token_state.clear_permissions_except_lbw();
                 UP_FLAG | UV_FLAG | AT_FLAG
             }
             None => {
                 if self.persistent_store.pin_hash().is_some() {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED);
                 }
+                if self.persistent_store.always_uv() {
+                    return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
+                }
                 if options.uv {
                     return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
                 }
                 UP_FLAG | AT_FLAG
             }
         };
+        let has_uv = flags & UV_FLAG != 0;
+
+        // Credentials that require user verification are not revealed by the exclude list.
+        if let Some(exclude_list) = exclude_list {
+            for cred_desc in exclude_list {
+                let is_excluded = self
+                    .persistent_store
+                    .find_credential(&rp_id, &cred_desc.key_id)
+                    .map_or(false, |credential| {
+                        is_cred_protect_satisfied(&credential, has_uv, true)
+                    });
+                if is_excluded {
+                    // Perform this check, so bad actors can't brute force exclude_list
+                    // without user interaction. Discard the user presence check's outcome.
+                    let _ = self.check_user_presence.check(cid);
+                    return Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED);
+                }
+            }
+        }
+
+        let (use_hmac_secret, cred_protect_policy, use_min_pin_length) = match &extensions {
+            Some(extensions) => (
+                extensions.make_credential_hmac_secret()?,
+                extensions.make_credential_cred_protect_policy()?,
+                extensions.make_credential_min_pin_length()?,
+            ),
+            None => (false, None, false),
+        };
+        // The minimum PIN length is only revealed to the RPs configured through setMinPINLength.
+        let min_pin_length = if use_min_pin_length
+            && self
+                .persistent_store
+                .min_pin_length_rp_ids()
+                .contains(&rp_id)
+        {
+            Some(self.persistent_store.min_pin_length() as u64)
+        } else {
+            None
+        };
+        let has_extensions_output =
+            use_hmac_secret || cred_protect_policy.is_some() || min_pin_length.is_some();
+        let flags = if has_extensions_output {
+            flags | ED_FLAG
+        } else {
+            flags
+        };
 
-        (self.check_user_presence)(cid)?;
+        self.check_user_presence.check(cid)?;
 
-        let sk = crypto::ecdsa::SecKey::gensk(self.rng);
-        let pk = sk.genpk();
+        self.check_user_presence.processing(cid)?;
+        let sk = PrivateKey::new(self.rng, algorithm);
+        let cred_random = if use_hmac_secret {
+            Some(self.rng.gen_uniform_u8x32())
+        } else {
+            None
+        };
 
         let rp_id_hash = Sha256::hash(rp_id.as_bytes());
-        let credential_id = if options.rk {
+        let (credential_id, signature_counter) = if options.rk {
             let random_id = self.rng.gen_uniform_u8x32().to_vec();
             let credential_source = PublicKeyCredentialSource {
                 key_type: PublicKeyCredentialType::PublicKey,
@@ -421,45 +691,73 @@ where
                 other_ui: user
                     .user_display_name
                     .map(|s| truncate_to_char_boundary(&s, 64).to_string()),
+                cred_random: cred_random.map(|cred_random| cred_random.to_vec()),
+                cred_protect_policy,
+                signature_counter: Some(0),
+                creation_order: 0,
             };
-            self.persistent_store.store_credential(credential_source)?;
-            random_id
+            self.persistent_store
+                .store_credential(self.rng, credential_source)?;
+            (random_id, 0)
         } else {
-            self.encrypt_key_handle(sk.clone(), &rp_id_hash)
+            let key_handle = self.encrypt_key_handle(
+                sk.clone(),
+                &rp_id_hash,
+                cred_random.as_ref(),
+                cred_protect_policy,
+            )?;
+            (
+                key_handle,
+                self.persistent_store.global_signature_counter()?,
+            )
         };
 
-        let mut auth_data = self.generate_auth_data(&rp_id_hash, flags);
+        let mut auth_data = self.generate_auth_data(&rp_id_hash, flags, signature_counter);
         auth_data.extend(AAGUID);
-        // The length is fixed to 0x20 or 0x70 and fits one byte.
+        // Resident credential IDs have 0x20 bytes, and key handles 0x70, 0x80, 0x90 or 0xA0 bytes,
+        // so the length fits one byte.
         if credential_id.len() > 0xFF {
             return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_TOO_LONG);
         }
         auth_data.extend(vec![0x00, credential_id.len() as u8]);
         auth_data.extend(&credential_id);
-        let cose_key = match pk.to_cose_key() {
+        let cose_key = match sk.to_cose_key() {
             Some(cose_key) => cose_key,
             None => return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR),
         };
         auth_data.extend(cose_key);
+        if has_extensions_output {
+            let hmac_secret_output = if use_hmac_secret { Some(true) } else { None };
+            let extensions_output = cbor_map_options! {
+                "credProtect" => cred_protect_policy,
+                "hmac-secret" => hmac_secret_output,
+                "minPinLength" => min_pin_length,
+            };
+            if !cbor::write(extensions_output, &mut auth_data) {
+                return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR);
+            }
+        }
 
         let mut signature_data = auth_data.clone();
         signature_data.extend(client_data_hash);
-        let (signature, x5c) = if USE_BATCH_ATTESTATION {
+        self.check_user_presence.processing(cid)?;
+        // The batch attestation key is always ES256, self attestation uses the credential key.
+        let (alg, signature, x5c) = if USE_BATCH_ATTESTATION || use_enterprise_attestation {
             let attestation_key =
                 crypto::ecdsa::SecKey::from_bytes(ATTESTATION_PRIVATE_KEY).unwrap();
             (
-                attestation_key.sign_rfc6979::<crypto::sha256::Sha256>(&signature_data),
+                SignatureAlgorithm::ES256,
+                attestation_key
+                    .sign_rfc6979::<crypto::sha256::Sha256>(&signature_data)
+                    .to_asn1_der(),
                 Some(vec![ATTESTATION_CERTIFICATE.to_vec()]),
             )
         } else {
-            (
-                sk.sign_rfc6979::<crypto::sha256::Sha256>(&signature_data),
-                None,
-            )
+            (sk.algorithm(), sk.sign_and_encode(&signature_data), None)
         };
         let attestation_statement = PackedAttestationStatement {
-            alg: SignatureAlgorithm::ES256 as i64,
-            sig: signature.to_asn1_der(),
+            alg: alg as i64,
+            sig: signature,
             x5c,
             ecdaa_key_id: None,
         };
@@ -468,6 +766,11 @@ where
                 fmt: String::from("packed"),
                 auth_data,
                 att_stmt: attestation_statement,
+                ep_att: if use_enterprise_attestation {
+                    Some(true)
+                } else {
+                    None
+                },
             },
         ))
     }
@@ -476,56 +779,34 @@ where
         &mut self,
         get_assertion_params: AuthenticatorGetAssertionParameters,
         cid: ChannelID,
+        now: ClockValue,
     ) -> Result<ResponseData, Ctap2StatusCode> {
         let AuthenticatorGetAssertionParameters {
             rp_id,
             client_data_hash,
             allow_list,
+            extensions,
             options,
             pin_uv_auth_param,
             pin_uv_auth_protocol,
-            ..
         } = get_assertion_params;
 
-        if let Some(auth_param) = &pin_uv_auth_param {
-            // This case was added in FIDO 2.1.
-            if auth_param.is_empty() {
-                if self.persistent_store.pin_hash().is_none() {
-                    return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
-                } else {
-                    return Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID);
-                }
-            }
-
-            match pin_uv_auth_protocol {
-                Some(protocol) => {
-                    if protocol != CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION {
-                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
-                    }
-                }
-                None => return Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER),
-            }
-        }
-
-        // This case was added in FIDO 2.1.
-        if pin_uv_auth_param == Some(vec![]) {
-            if self.persistent_store.pin_hash().is_none() {
-                return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
-            } else {
-                return Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID);
-            }
-        }
-
-        if pin_uv_auth_param.is_some() {
-            match pin_uv_auth_protocol {
-                Some(protocol) => {
-                    if protocol != CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION {
-                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+        let pin_uv_auth_protocol = match &pin_uv_auth_param {
+            Some(auth_param) => {
+                // This case was added in FIDO 2.1.
+                if auth_param.is_empty() {
+                    if self.persistent_store.pin_hash().is_none() {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
+                    } else {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID);
                     }
                 }
-                None => return Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER),
+                let protocol =
+                    pin_uv_auth_protocol.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?;
+                Some(PinUvAuthProtocol::try_from(protocol)?)
             }
-        }
+            None => None,
+        };
 
         // The user verification bit depends on the existance of PIN auth, whereas
         // user presence is requested as an option.
@@ -535,12 +816,28 @@ where
                     // Specification is unclear, could be CTAP2_ERR_UNSUPPORTED_OPTION.
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
                 }
-                if !check_pin_auth(&self.pin_uv_auth_token, &client_data_hash, &pin_auth) {
+                let protocol =
+                    pin_uv_auth_protocol.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?;
+                if !self.check_pin_uv_auth_param(protocol, &client_data_hash, &pin_auth) {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
                 }
+                self.pin_uv_auth_token_state
+                    .has_permission(PinPermission::GetAssertion)?;
+                self.pin_uv_auth_token_state
+                    .ensure_permissions_rp_id(&rp_id)?;
+                self.pin_uv_auth_token_state.mark_used();
+                // Each token authorizes a single assertion.
+                self.pin_uv_auth_token_state.clear_permissions_except_lbw();
                 UV_FLAG
             }
             None => {
+                if self.persistent_store.always_uv() {
+                    if self.persistent_store.pin_hash().is_some() {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED);
+                    } else {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
+                    }
+                }
                 if options.uv {
                     // The specification (inconsistently) wants CTAP2_ERR_UNSUPPORTED_OPTION.
                     return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
@@ -551,51 +848,252 @@ where
         if options.up {
             flags |= UP_FLAG;
         }
+        let hmac_secret_input = match &extensions {
+            Some(extensions) => extensions.get_assertion_hmac_secret()?,
+            None => None,
+        };
+        if hmac_secret_input.is_some() {
+            // The output is a secret of the host, e.g. to unlock a disk. It needs a touch.
+            if !options.up {
+                return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION);
+            }
+            self.check_user_presence.processing(cid)?;
+        }
+        let hmac_secret_salts = hmac_secret_input
+            .map(|input| self.decrypt_hmac_secret_salts(input))
+            .transpose()?;
 
+        let has_uv = flags & UV_FLAG != 0;
         let rp_id_hash = Sha256::hash(rp_id.as_bytes());
-        let mut decrypted_credential = None;
-        let credentials = if let Some(allow_list) = allow_list {
+        let (credential, next_credentials) = if let Some(allow_list) = allow_list {
             let mut found_credentials = vec![];
+            let mut decrypted_credential = None;
             for allowed_credential in allow_list {
                 match self
                     .persistent_store
                     .find_credential(&rp_id, &allowed_credential.key_id)
                 {
-                    Some(credential) => found_credentials.push(credential),
+                    Some(credential) => {
+                        if is_cred_protect_satisfied(&credential, has_uv, true) {
+                            found_credentials.push(credential);
+                        }
+                    }
                     None => {
                         if decrypted_credential.is_none() {
                             decrypted_credential = self
-                                .decrypt_credential_source(allowed_credential.key_id, &rp_id_hash);
+                                .decrypt_credential_source(allowed_credential.key_id, &rp_id_hash)?
+                                .filter(|credential| {
+                                    is_cred_protect_satisfied(credential, has_uv, true)
+                                });
                         }
                     }
                 }
             }
-            found_credentials
+            // With an allow list, the first match is used and no state is kept.
+            let credential = if found_credentials.is_empty() {
+                decrypted_credential.ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?
+            } else {
+                found_credentials.remove(0)
+            };
+            (credential, vec![])
         } else {
-            // TODO(kaczmarczyck) use GetNextAssertion
-            self.persistent_store.filter_credential(&rp_id)
+            let mut credentials = self.persistent_store.filter_credential(&rp_id);
+            credentials.retain(|credential| is_cred_protect_satisfied(credential, has_uv, false));
+            if credentials.is_empty() {
+                return Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS);
+            }
+            // The most recent credential comes first, the next ones are popped from the end.
+            credentials.sort_by_key(|credential| credential.creation_order);
+            let credential = credentials.pop().unwrap();
+            (credential, credentials)
         };
 
-        let credential = if let Some(credential) = credentials.first() {
-            credential
+        if options.up {
+            self.check_user_presence.check(cid)?;
+        }
+
+        let number_of_credentials = if next_credentials.is_empty() {
+            None
         } else {
-            decrypted_credential
-                .as_ref()
-                .ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?
+            Some(next_credentials.len() as u64 + 1)
         };
+        let response = self.assertion_response(
+            &credential,
+            &rp_id_hash,
+            flags,
+            &client_data_hash,
+            hmac_secret_salts.as_ref(),
+            number_of_credentials,
+        )?;
+        if !next_credentials.is_empty() {
+            let assertion_state = AssertionState {
+                client_data_hash,
+                rp_id_hash,
+                flags,
+                hmac_secret_salts,
+                next_credentials,
+            };
+            self.start_stateful_command(StatefulCommand::GetAssertion(assertion_state), cid, now);
+        }
+        Ok(ResponseData::AuthenticatorGetAssertion(response))
+    }
 
-        if options.up {
-            (self.check_user_presence)(cid)?;
+    fn process_get_next_assertion(
+        &mut self,
+        cid: ChannelID,
+        now: ClockValue,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        let mut assertion_state = match self.continue_stateful_command(cid, now)? {
+            StatefulCommand::GetAssertion(assertion_state) => assertion_state,
+            _ => return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED),
+        };
+        let credential = assertion_state
+            .next_credentials
+            .pop()
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)?;
+        let response = self.assertion_response(
+            &credential,
+            &assertion_state.rp_id_hash,
+            assertion_state.flags,
+            &assertion_state.client_data_hash,
+            assertion_state.hmac_secret_salts.as_ref(),
+            None,
+        )?;
+        if !assertion_state.next_credentials.is_empty() {
+            self.start_stateful_command(StatefulCommand::GetAssertion(assertion_state), cid, now);
+        }
+        Ok(ResponseData::AuthenticatorGetNextAssertion(response))
+    }
+
+    // Stores the state of a command that returns more results in follow-up calls. This replaces any
+    // other ongoing stateful command.
+    fn start_stateful_command(
+        &mut self,
+        command: StatefulCommand,
+        cid: ChannelID,
+        now: ClockValue,
+    ) {
+        self.stateful_command = Some(StatefulCommandState {
+            cid,
+            timeout: TimedPermission::granted(now, STATEFUL_COMMAND_TIMEOUT),
+            command,
+        });
+    }
+
+    // Takes the state of the ongoing stateful command. The caller has to restart it if there are
+    // more results left.
+    fn continue_stateful_command(
+        &mut self,
+        cid: ChannelID,
+        now: ClockValue,
+    ) -> Result<StatefulCommand, Ctap2StatusCode> {
+        // CTAP specification (version 20190130) section 5.3
+        // The state is only valid for the channel that started it, and for a limited time.
+        let state = self
+            .stateful_command
+            .take()
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)?;
+        if state.cid != cid || !state.timeout.is_granted(now) {
+            return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
+        }
+        Ok(state.command)
+    }
+
+    // Decrypts the salts of the hmac-secret extension, using the shared secret of the PIN
+    // protocol.
+    fn decrypt_hmac_secret_salts(
+        &self,
+        hmac_secret_input: GetAssertionHmacSecretInput,
+    ) -> Result<HmacSecretSalts, Ctap2StatusCode> {
+        let GetAssertionHmacSecretInput {
+            key_agreement,
+            salt_enc,
+            salt_auth,
+            pin_uv_auth_protocol,
+        } = hmac_secret_input;
+        // Platforms that predate FIDO 2.1 don't send a version and use the first one.
+        let protocol = match pin_uv_auth_protocol {
+            Some(protocol) => PinUvAuthProtocol::try_from(protocol)?,
+            None => PinUvAuthProtocol::V1,
+        };
+        let shared_secret = self
+            .pin_protocol(protocol)
+            .decapsulate(key_agreement, protocol)?;
+        // The specification asks for this error code if the salt authentication fails.
+        if !shared_secret.verify(&salt_enc, &salt_auth) {
+            return Err(Ctap2StatusCode::CTAP2_ERR_EXTENSION_FIRST);
+        }
+        let salts = shared_secret.decrypt(&salt_enc)?;
+        if salts.len() != 32 && salts.len() != 64 {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH);
         }
+        Ok(HmacSecretSalts {
+            shared_secret,
+            salts,
+        })
+    }
 
-        self.increment_global_signature_counter();
+    // Computes the encrypted output of the hmac-secret extension for a credential.
+    fn encrypt_hmac_secret_output(
+        rng: &mut R,
+        hmac_secret_salts: &HmacSecretSalts,
+        cred_random: &[u8],
+        has_uv: bool,
+    ) -> Result<Vec<u8>, Ctap2StatusCode> {
+        // The specification asks for different secrets with and without user verification. Both
+        // are derived from the credential's CredRandom.
+        let cred_random = hmac_256::<Sha256>(cred_random, &[has_uv as u8]);
+        let mut output = vec![];
+        for salt in hmac_secret_salts.salts.chunks(32) {
+            output.extend(&hmac_256::<Sha256>(&cred_random, salt));
+        }
+        hmac_secret_salts.shared_secret.encrypt(rng, &output)
+    }
 
-        let auth_data = self.generate_auth_data(&rp_id_hash, flags);
+    // Signs the assertion for a credential. Shared by GetAssertion and GetNextAssertion, which
+    // both increment the signature counter for each returned credential.
+    fn assertion_response(
+        &mut self,
+        credential: &PublicKeyCredentialSource,
+        rp_id_hash: &[u8],
+        flags: u8,
+        client_data_hash: &[u8],
+        hmac_secret_salts: Option<&HmacSecretSalts>,
+        number_of_credentials: Option<u64>,
+    ) -> Result<AuthenticatorGetAssertionResponse, Ctap2StatusCode> {
+        let signature_counter = self.increment_signature_counter(credential)?;
+
+        // Credentials created without the hmac-secret extension have no output.
+        let hmac_secret_output = match (hmac_secret_salts, &credential.cred_random) {
+            (Some(hmac_secret_salts), Some(cred_random)) => {
+                Some(
+                    CtapState::<R, CheckUserPresence, S, C>::encrypt_hmac_secret_output(
+                        self.rng,
+                        hmac_secret_salts,
+                        cred_random,
+                        flags & UV_FLAG != 0,
+                    )?,
+                )
+            }
+            _ => None,
+        };
+        let auth_data = match hmac_secret_output {
+            Some(hmac_secret_output) => {
+                let mut auth_data =
+                    self.generate_auth_data(rp_id_hash, flags | ED_FLAG, signature_counter);
+                let extensions_output = cbor_map! {
+                    "hmac-secret" => hmac_secret_output,
+                };
+                if !cbor::write(extensions_output, &mut auth_data) {
+                    return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR);
+                }
+                auth_data
+            }
+            None => self.generate_auth_data(rp_id_hash, flags, signature_counter),
+        };
         let mut signature_data = auth_data.clone();
         signature_data.extend(client_data_hash);
-        let signature = credential
-            .private_key
-            .sign_rfc6979::<crypto::sha256::Sha256>(&signature_data);
+        let signature = credential.private_key.sign_and_encode(&signature_data);
 
         let cred_desc = PublicKeyCredentialDescriptor {
             key_type: PublicKeyCredentialType::PublicKey,
@@ -612,15 +1110,13 @@ where
         } else {
             None
         };
-        Ok(ResponseData::AuthenticatorGetAssertion(
-            AuthenticatorGetAssertionResponse {
-                credential: Some(cred_desc),
-                auth_data,
-                signature: signature.to_asn1_der(),
-                user,
-                number_of_credentials: None,
-            },
-        ))
+        Ok(AuthenticatorGetAssertionResponse {
+            credential: Some(cred_desc),
+            auth_data,
+            signature,
+            user,
+            number_of_credentials,
+        })
     }
 
     fn process_get_info(&self) -> Result<ResponseData, Ctap2StatusCode> {
@@ -628,65 +1124,122 @@ where
         // TODO(kaczmarczyck) add FIDO 2.1 options
         options_map.insert(String::from("rk"), true);
         options_map.insert(String::from("up"), true);
+        options_map.insert(String::from("credMgmt"), true);
         options_map.insert(
             String::from("clientPin"),
             self.persistent_store.pin_hash().is_some(),
         );
+        options_map.insert(String::from("pinUvAuthToken"), true);
+        options_map.insert(String::from("authnrCfg"), true);
+        options_map.insert(String::from("setMinPINLength"), true);
+        options_map.insert(String::from("alwaysUv"), self.persistent_store.always_uv());
+        options_map.insert(
+            String::from("ep"),
+            self.persistent_store.enterprise_attestation(),
+        );
+        #[cfg(not(feature = "with_ctap1"))]
+        let versions = vec![String::from(FIDO2_VERSION_STRING)];
+        // U2F has no user verification, so it is disabled with alwaysUv.
+        #[cfg(feature = "with_ctap1")]
+        let versions = if self.persistent_store.always_uv() {
+            vec![String::from(FIDO2_VERSION_STRING)]
+        } else {
+            vec![
+                String::from(U2F_VERSION_STRING),
+                String::from(FIDO2_VERSION_STRING),
+            ]
+        };
         Ok(ResponseData::AuthenticatorGetInfo(
             AuthenticatorGetInfoResponse {
-                versions: vec![
-                    #[cfg(feature = "with_ctap1")]
-                    String::from(U2F_VERSION_STRING),
-                    String::from(FIDO2_VERSION_STRING),
-                ],
-                extensions: Some(vec![]),
+                versions,
+                extensions: Some(vec![
+                    String::from("hmac-secret"),
+                    String::from("credProtect"),
+                    String::from("minPinLength"),
+                ]),
                 aaguid: *AAGUID,
                 options: Some(options_map),
                 max_msg_size: Some(1024),
+                // Ordered by preference, the platform picks the first version it supports.
                 pin_protocols: Some(vec![
-                    CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION,
+                    u64::from(PinUvAuthProtocol::V2),
+                    u64::from(PinUvAuthProtocol::V1),
                 ]),
+                max_credential_count_in_list: Some(MAX_CREDENTIAL_COUNT_IN_LIST as u64),
+                force_pin_change: Some(self.persistent_store.force_pin_change()),
+                min_pin_length: Some(self.persistent_store.min_pin_length() as u64),
+                max_rp_ids_for_set_min_pin_length: Some(MAX_RP_IDS_LENGTH as u64),
+                remaining_discoverable_credentials: Some(
+                    self.persistent_store.remaining_credentials() as u64,
+                ),
             },
         ))
     }
 
-    fn check_and_store_new_pin(
-        &mut self,
-        aes_dec_key: &crypto::aes256::DecryptionKey,
-        new_pin_enc: Vec<u8>,
+    fn pin_protocol(&self, protocol: PinUvAuthProtocol) -> &PinProtocol {
+        match protocol {
+            PinUvAuthProtocol::V1 => &self.pin_protocol_v1,
+            PinUvAuthProtocol::V2 => &self.pin_protocol_v2,
+        }
+    }
+
+    // Checks a pinUvAuthParam against the pinUvAuthToken of the PIN protocol version it claims.
+    // The caller still has to check the token's permissions, and only then mark the token as used.
+    fn check_pin_uv_auth_param(
+        &self,
+        protocol: PinUvAuthProtocol,
+        message: &[u8],
+        pin_uv_auth_param: &[u8],
     ) -> bool {
-        if new_pin_enc.len() != PIN_PADDED_LENGTH {
+        if !self.pin_uv_auth_token_state.is_in_use()
+            || !verify_pin_uv_auth_token(
+                self.pin_protocol(protocol).pin_uv_auth_token(),
+                message,
+                pin_uv_auth_param,
+                protocol,
+            )
+        {
             return false;
         }
-        let iv = [0; 16];
-        // Assuming PIN_PADDED_LENGTH % block_size == 0 here.
-        let mut blocks = [[0u8; 16]; PIN_PADDED_LENGTH / 16];
-        for i in 0..PIN_PADDED_LENGTH / 16 {
-            blocks[i].copy_from_slice(&new_pin_enc[i * 16..(i + 1) * 16]);
+        true
+    }
+
+    fn check_and_store_new_pin(
+        &mut self,
+        shared_secret: &SharedSecret,
+        new_pin_enc: Vec<u8>,
+    ) -> Result<(), Ctap2StatusCode> {
+        let padded_pin = match shared_secret.decrypt(&new_pin_enc) {
+            Ok(padded_pin) => padded_pin,
+            Err(_) => return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+        };
+        if padded_pin.len() != PIN_PADDED_LENGTH {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
         }
-        cbc_decrypt(aes_dec_key, iv, &mut blocks);
-        let mut pin = vec![];
-        'pin_block_loop: for block in blocks.iter().take(PIN_PADDED_LENGTH / 16) {
-            for cur_char in block.iter() {
-                if *cur_char != 0 {
-                    pin.push(*cur_char);
-                } else {
-                    break 'pin_block_loop;
-                }
-            }
+        let pin: Vec<u8> = padded_pin
+            .into_iter()
+            .take_while(|cur_char| *cur_char != 0)
+            .collect();
+        if pin.len() == PIN_PADDED_LENGTH {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
         }
-        if pin.len() < 4 || pin.len() == PIN_PADDED_LENGTH {
-            return false;
+        // The minimum PIN length counts Unicode code points, not bytes.
+        let pin_length = match core::str::from_utf8(&pin) {
+            Ok(pin_str) => pin_str.chars().count(),
+            Err(_) => return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+        };
+        if pin_length < self.persistent_store.min_pin_length() as usize {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
         }
         let mut pin_hash = [0; 16];
         pin_hash.copy_from_slice(&Sha256::hash(&pin[..])[..16]);
-        self.persistent_store.set_pin_hash(&pin_hash);
-        true
+        self.persistent_store.set_pin_hash(&pin_hash)
     }
 
     fn check_pin_hash_enc(
         &mut self,
-        aes_dec_key: &crypto::aes256::DecryptionKey,
+        protocol: PinUvAuthProtocol,
+        shared_secret: &SharedSecret,
         pin_hash_enc: Vec<u8>,
     ) -> Result<(), Ctap2StatusCode> {
         match self.persistent_store.pin_hash() {
@@ -697,20 +1250,25 @@ where
                 // We need to copy the pin hash, because decrementing the pin retries below may
                 // invalidate the reference (if the page containing the pin hash is compacted).
                 let pin_hash = pin_hash.to_vec();
-                self.persistent_store.decr_pin_retries();
-                if pin_hash_enc.len() != PIN_AUTH_LENGTH {
+                self.persistent_store.decr_pin_retries()?;
+                let pin_hash_dec = shared_secret
+                    .decrypt(&pin_hash_enc)
+                    .map_err(|_| Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)?;
+                if pin_hash_dec.len() != PIN_AUTH_LENGTH {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID);
                 }
 
-                let iv = [0; 16];
-                let mut blocks = [[0u8; 16]; 1];
-                blocks[0].copy_from_slice(&pin_hash_enc[0..PIN_AUTH_LENGTH]);
-                cbc_decrypt(aes_dec_key, iv, &mut blocks);
-
-                let pin_comparison = array_ref![pin_hash, 0, PIN_AUTH_LENGTH].ct_eq(&blocks[0]);
+                let pin_comparison = array_ref![pin_hash, 0, PIN_AUTH_LENGTH].ct_eq(array_ref![
+                    pin_hash_dec,
+                    0,
+                    PIN_AUTH_LENGTH
+                ]);
                 if !bool::from(pin_comparison) {
-                    self.key_agreement_key = crypto::ecdh::SecKey::gensk(self.rng);
-                    if self.persistent_store.pin_retries() == 0 {
+                    match protocol {
+                        PinUvAuthProtocol::V1 => self.pin_protocol_v1.regenerate(self.rng),
+                        PinUvAuthProtocol::V2 => self.pin_protocol_v2.regenerate(self.rng),
+                    }
+                    if self.persistent_store.pin_retries()? == 0 {
                         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED);
                     }
                     self.consecutive_pin_mismatches += 1;
@@ -723,7 +1281,7 @@ where
             // This status code is not explicitly mentioned in the specification.
             None => return Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED),
         }
-        self.persistent_store.reset_pin_retries();
+        self.persistent_store.reset_pin_retries()?;
         self.consecutive_pin_mismatches = 0;
         Ok(())
     }
@@ -732,14 +1290,16 @@ where
         Ok(AuthenticatorClientPinResponse {
             key_agreement: None,
             pin_token: None,
-            retries: Some(self.persistent_store.pin_retries() as u64),
+            retries: Some(self.persistent_store.pin_retries()? as u64),
         })
     }
 
-    fn process_get_key_agreement(&self) -> Result<AuthenticatorClientPinResponse, Ctap2StatusCode> {
-        let pk = self.key_agreement_key.genpk();
+    fn process_get_key_agreement(
+        &self,
+        protocol: PinUvAuthProtocol,
+    ) -> Result<AuthenticatorClientPinResponse, Ctap2StatusCode> {
         Ok(AuthenticatorClientPinResponse {
-            key_agreement: Some(CoseKey::from(pk)),
+            key_agreement: Some(self.pin_protocol(protocol).get_public_key()),
             pin_token: None,
             retries: None,
         })
@@ -747,6 +1307,7 @@ where
 
     fn process_set_pin(
         &mut self,
+        protocol: PinUvAuthProtocol,
         key_agreement: CoseKey,
         pin_auth: Vec<u8>,
         new_pin_enc: Vec<u8>,
@@ -754,79 +1315,81 @@ where
         if self.persistent_store.pin_hash().is_some() {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
         }
-        let pk: crypto::ecdh::PubKey = CoseKey::try_into(key_agreement)?;
-        let shared_secret = self.key_agreement_key.exchange_x_sha256(&pk);
+        let shared_secret = self
+            .pin_protocol(protocol)
+            .decapsulate(key_agreement, protocol)?;
 
-        if !check_pin_auth(&shared_secret, &new_pin_enc, &pin_auth) {
+        if !shared_secret.verify(&new_pin_enc, &pin_auth) {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
         }
 
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&shared_secret);
-        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
-        if !self.check_and_store_new_pin(&aes_dec_key, new_pin_enc) {
-            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
-        }
-        self.persistent_store.reset_pin_retries();
-        Ok(())
+        self.check_and_store_new_pin(&shared_secret, new_pin_enc)
     }
 
     fn process_change_pin(
         &mut self,
+        protocol: PinUvAuthProtocol,
         key_agreement: CoseKey,
         pin_auth: Vec<u8>,
         new_pin_enc: Vec<u8>,
         pin_hash_enc: Vec<u8>,
     ) -> Result<(), Ctap2StatusCode> {
-        if self.persistent_store.pin_retries() == 0 {
+        if self.persistent_store.pin_retries()? == 0 {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED);
         }
-        let pk: crypto::ecdh::PubKey = CoseKey::try_into(key_agreement)?;
-        let shared_secret = self.key_agreement_key.exchange_x_sha256(&pk);
+        let shared_secret = self
+            .pin_protocol(protocol)
+            .decapsulate(key_agreement, protocol)?;
 
         let mut auth_param_data = new_pin_enc.clone();
         auth_param_data.extend(&pin_hash_enc);
-        if !check_pin_auth(&shared_secret, &auth_param_data, &pin_auth) {
+        if !shared_secret.verify(&auth_param_data, &pin_auth) {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
         }
 
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&shared_secret);
-        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
-        self.check_pin_hash_enc(&aes_dec_key, pin_hash_enc)?;
+        self.check_pin_hash_enc(protocol, &shared_secret, pin_hash_enc)?;
 
-        if !self.check_and_store_new_pin(&aes_dec_key, new_pin_enc) {
-            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
-        }
-        self.pin_uv_auth_token = self.rng.gen_uniform_u8x32();
+        self.check_and_store_new_pin(&shared_secret, new_pin_enc)?;
+        // Tokens of both versions were obtained with the old PIN.
+        self.pin_protocol_v1.reset_pin_uv_auth_token(self.rng);
+        self.pin_protocol_v2.reset_pin_uv_auth_token(self.rng);
+        self.pin_uv_auth_token_state.stop_using_pin_uv_auth_token();
         Ok(())
     }
 
     fn process_get_pin_uv_auth_token_using_pin(
         &mut self,
+        protocol: PinUvAuthProtocol,
         key_agreement: CoseKey,
         pin_hash_enc: Vec<u8>,
+        permissions: u8,
+        permissions_rp_id: Option<String>,
+        now: ClockValue,
     ) -> Result<AuthenticatorClientPinResponse, Ctap2StatusCode> {
-        if self.persistent_store.pin_retries() == 0 {
+        if self.persistent_store.pin_retries()? == 0 {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED);
         }
-        let pk: crypto::ecdh::PubKey = CoseKey::try_into(key_agreement)?;
-        let shared_secret = self.key_agreement_key.exchange_x_sha256(&pk);
-
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&shared_secret);
-        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
-        self.check_pin_hash_enc(&aes_dec_key, pin_hash_enc)?;
+        let shared_secret = self
+            .pin_protocol(protocol)
+            .decapsulate(key_agreement, protocol)?;
 
-        // Assuming PIN_TOKEN_LENGTH % block_size == 0 here.
-        let iv = [0; 16];
-        let mut blocks = [[0u8; 16]; PIN_TOKEN_LENGTH / 16];
-        for (i, item) in blocks.iter_mut().take(PIN_TOKEN_LENGTH / 16).enumerate() {
-            item.copy_from_slice(&self.pin_uv_auth_token[i * 16..(i + 1) * 16]);
-        }
-        cbc_encrypt(&aes_enc_key, iv, &mut blocks);
-        let mut pin_token = vec![];
-        for item in blocks.iter().take(PIN_TOKEN_LENGTH / 16) {
-            pin_token.extend(item);
+        self.check_pin_hash_enc(protocol, &shared_secret, pin_hash_enc)?;
+        // The PIN has to be changed first, for example after the minimum PIN length increased.
+        if self.persistent_store.force_pin_change() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
         }
 
+        // A new token replaces all earlier tokens, of both versions, and their permissions.
+        self.pin_protocol_v1.reset_pin_uv_auth_token(self.rng);
+        self.pin_protocol_v2.reset_pin_uv_auth_token(self.rng);
+        self.pin_uv_auth_token_state.begin_using_pin_uv_auth_token(
+            now,
+            permissions,
+            permissions_rp_id,
+        );
+        let pin_uv_auth_token = *self.pin_protocol(protocol).pin_uv_auth_token();
+        let pin_token = shared_secret.encrypt(self.rng, &pin_uv_auth_token)?;
+
         Ok(AuthenticatorClientPinResponse {
             key_agreement: None,
             pin_token: Some(pin_token),
@@ -858,6 +1421,8 @@ where
     fn process_client_pin(
         &mut self,
         client_pin_params: AuthenticatorClientPinParameters,
+        cid: ChannelID,
+        now: ClockValue,
     ) -> Result<ResponseData, Ctap2StatusCode> {
         let AuthenticatorClientPinParameters {
             pin_protocol,
@@ -866,17 +1431,22 @@ where
             pin_auth,
             new_pin_enc,
             pin_hash_enc,
+            permissions,
+            permissions_rp_id,
         } = client_pin_params;
 
-        if pin_protocol != 1 {
-            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+        let protocol = PinUvAuthProtocol::try_from(pin_protocol)?;
+        // The key agreement with the platform is the long operation of the PIN subcommands.
+        if key_agreement.is_some() {
+            self.check_user_presence.processing(cid)?;
         }
 
         let response = match sub_command {
             ClientPinSubCommand::GetPinRetries => Some(self.process_get_pin_retries()?),
-            ClientPinSubCommand::GetKeyAgreement => Some(self.process_get_key_agreement()?),
+            ClientPinSubCommand::GetKeyAgreement => Some(self.process_get_key_agreement(protocol)?),
             ClientPinSubCommand::SetPin => {
                 self.process_set_pin(
+                    protocol,
                     key_agreement.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                     pin_auth.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                     new_pin_enc.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
@@ -885,6 +1455,7 @@ where
             }
             ClientPinSubCommand::ChangePin => {
                 self.process_change_pin(
+                    protocol,
                     key_agreement.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                     pin_auth.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                     new_pin_enc.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
@@ -893,9 +1464,28 @@ where
                 None
             }
             ClientPinSubCommand::GetPinUvAuthTokenUsingPin => {
+                // Tokens of the subcommand without permissions can only be used to register and
+                // authenticate, as in FIDO 2.0.
+                Some(self.process_get_pin_uv_auth_token_using_pin(
+                    protocol,
+                    key_agreement.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
+                    pin_hash_enc.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
+                    PinPermission::MakeCredential as u8 | PinPermission::GetAssertion as u8,
+                    None,
+                    now,
+                )?)
+            }
+            ClientPinSubCommand::GetPinUvAuthTokenUsingPinWithPermissions => {
+                let permissions =
+                    permissions.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?;
+                check_requested_permissions(permissions)?;
                 Some(self.process_get_pin_uv_auth_token_using_pin(
+                    protocol,
                     key_agreement.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                     pin_hash_enc.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
+                    permissions,
+                    permissions_rp_id,
+                    now,
                 )?)
             }
             ClientPinSubCommand::GetPinUvAuthTokenUsingUv => {
@@ -908,16 +1498,24 @@ where
         Ok(ResponseData::AuthenticatorClientPin(response))
     }
 
+    // The platform sends this command to all connected authenticators, and uses the first one the
+    // user touches. Cancel and timeout errors are returned by the user presence check.
+    fn process_selection(&self, cid: ChannelID) -> Result<ResponseData, Ctap2StatusCode> {
+        self.check_user_presence.check(cid)?;
+        Ok(ResponseData::AuthenticatorSelection)
+    }
+
     fn process_reset(&mut self, cid: ChannelID) -> Result<ResponseData, Ctap2StatusCode> {
         // Resets are only possible in the first 10 seconds after booting.
         if !self.accepts_reset {
             return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
         }
-        (self.check_user_presence)(cid)?;
+        self.check_user_presence.check(cid)?;
 
-        self.persistent_store.reset(self.rng);
-        self.key_agreement_key = crypto::ecdh::SecKey::gensk(self.rng);
-        self.pin_uv_auth_token = self.rng.gen_uniform_u8x32();
+        self.persistent_store.reset(self.rng)?;
+        self.pin_protocol_v1 = PinProtocol::new(self.rng);
+        self.pin_protocol_v2 = PinProtocol::new(self.rng);
+        self.pin_uv_auth_token_state.stop_using_pin_uv_auth_token();
         self.consecutive_pin_mismatches = 0;
         #[cfg(feature = "with_ctap1")]
         {
@@ -929,18 +1527,19 @@ where
         Ok(ResponseData::AuthenticatorReset)
     }
 
-    pub fn generate_auth_data(&self, rp_id_hash: &[u8], flag_byte: u8) -> Vec<u8> {
+    pub fn generate_auth_data(
+        &self,
+        rp_id_hash: &[u8],
+        flag_byte: u8,
+        signature_counter: u32,
+    ) -> Vec<u8> {
         let mut auth_data = vec![];
         auth_data.extend(rp_id_hash);
         auth_data.push(flag_byte);
-        // The global counter is only increased if USE_SIGNATURE_COUNTER is true.
-        // It uses a big-endian representation.
-        let mut signature_counter = [0u8; 4];
-        BigEndian::write_u32(
-            &mut signature_counter,
-            self.persistent_store.global_signature_counter(),
-        );
-        auth_data.extend(&signature_counter);
+        // The signature counter uses a big-endian representation.
+        let mut signature_counter_bytes = [0u8; 4];
+        BigEndian::write_u32(&mut signature_counter_bytes, signature_counter);
+        auth_data.extend(&signature_counter_bytes);
         auth_data
     }
 }
@@ -948,10 +1547,12 @@ where
 #[cfg(test)]
 mod test {
     use super::data_formats::{
-        GetAssertionOptions, MakeCredentialOptions, PublicKeyCredentialRpEntity,
+        Extensions, GetAssertionOptions, MakeCredentialOptions, PublicKeyCredentialRpEntity,
         PublicKeyCredentialUserEntity,
     };
+    use super::platform::TestClock;
     use super::*;
+    use core::convert::TryInto;
     use crypto::rng256::ThreadRng256;
 
     // The keep-alive logic in the processing of some commands needs a channel ID to send
@@ -959,39 +1560,98 @@ mod test {
     // In tests where we define a dummy user-presence check that immediately returns, the channel
     // ID is irrelevant, so we pass this (dummy but valid) value.
     const DUMMY_CHANNEL_ID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+
+    // The user is present, but the host cancels the command once its long operations start.
+    struct CancelWhileProcessing;
+
+    impl UserPresence for CancelWhileProcessing {
+        fn check(&self, _cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+            Ok(())
+        }
+
+        fn processing(&self, _cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+            Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL)
+        }
+    }
 
     #[test]
     fn test_get_info() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
         let info_reponse = ctap_state.process_command(&[0x04], DUMMY_CHANNEL_ID);
 
-        let mut expected_response = vec![0x00, 0xA6, 0x01];
+        assert_eq!(info_reponse[0], 0x00);
+        let info_cbor = cbor::read(&info_reponse[1..]).unwrap();
+
         // The difference here is a longer array of supported versions.
         #[cfg(not(feature = "with_ctap1"))]
-        expected_response.extend(&[
-            0x81, 0x68, 0x46, 0x49, 0x44, 0x4F, 0x5F, 0x32, 0x5F, 0x30, 0x02, 0x80, 0x03, 0x50,
-        ]);
+        let versions = cbor_array_vec![vec![FIDO2_VERSION_STRING]];
         #[cfg(feature = "with_ctap1")]
-        expected_response.extend(&[
-            0x82, 0x66, 0x55, 0x32, 0x46, 0x5F, 0x56, 0x32, 0x68, 0x46, 0x49, 0x44, 0x4F, 0x5F,
-            0x32, 0x5F, 0x30, 0x02, 0x80, 0x03, 0x50,
-        ]);
-        expected_response.extend(AAGUID);
-        expected_response.extend(&[
-            0x04, 0xA3, 0x62, 0x72, 0x6B, 0xF5, 0x62, 0x75, 0x70, 0xF5, 0x69, 0x63, 0x6C, 0x69,
-            0x65, 0x6E, 0x74, 0x50, 0x69, 0x6E, 0xF4, 0x05, 0x19, 0x04, 0x00, 0x06, 0x81, 0x01,
-        ]);
-
-        assert_eq!(info_reponse, expected_response);
+        let versions = cbor_array_vec![vec![U2F_VERSION_STRING, FIDO2_VERSION_STRING]];
+        let expected_info_cbor = cbor_map_options! {
+            1 => versions,
+            2 => cbor_array!["hmac-secret", "credProtect", "minPinLength"],
+            3 => &AAGUID[..],
+            4 => cbor_map! {
+                "ep" => false,
+                "rk" => true,
+                "up" => true,
+                "alwaysUv" => false,
+                "credMgmt" => true,
+                "authnrCfg" => true,
+                "clientPin" => false,
+                "pinUvAuthToken" => true,
+                "setMinPINLength" => true,
+            },
+            5 => 1024,
+            6 => cbor_array![2, 1],
+            7 => MAX_CREDENTIAL_COUNT_IN_LIST as u64,
+            0x0C => false,
+            0x0D => 4,
+            0x10 => MAX_RP_IDS_LENGTH as u64,
+            0x14 => ctap_state.persistent_store.remaining_credentials() as u64,
+        };
+        assert_eq!(info_cbor, expected_info_cbor);
     }
 
-    fn create_minimal_make_credential_parameters() -> AuthenticatorMakeCredentialParameters {
-        let client_data_hash = vec![0xCD];
-        let rp = PublicKeyCredentialRpEntity {
-            rp_id: String::from("example.com"),
-            rp_name: None,
+    #[test]
+    fn test_get_info_always_uv() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state.persistent_store.toggle_always_uv().unwrap();
+        let info_reponse = ctap_state.process_get_info();
+
+        match info_reponse.unwrap() {
+            ResponseData::AuthenticatorGetInfo(info_response) => {
+                // U2F is disabled with alwaysUv.
+                assert_eq!(info_response.versions, vec![FIDO2_VERSION_STRING]);
+                assert_eq!(info_response.options.unwrap().get("alwaysUv"), Some(&true));
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    fn create_minimal_make_credential_parameters() -> AuthenticatorMakeCredentialParameters {
+        let client_data_hash = vec![0xCD];
+        let rp = PublicKeyCredentialRpEntity {
+            rp_id: String::from("example.com"),
+            rp_name: None,
             rp_icon: None,
         };
         let user = PublicKeyCredentialUserEntity {
@@ -1018,6 +1678,7 @@ mod test {
             options,
             pin_uv_auth_param: None,
             pin_uv_auth_protocol: None,
+            enterprise_attestation: None,
         }
     }
 
@@ -1025,7 +1686,13 @@ mod test {
     fn test_residential_process_make_credential() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let make_credential_params = create_minimal_make_credential_parameters();
         let make_credential_response =
@@ -1037,6 +1704,7 @@ mod test {
                     fmt,
                     auth_data,
                     att_stmt,
+                    ..
                 } = make_credential_response;
                 // The expected response is split to only assert the non-random parts.
                 assert_eq!(fmt, "packed");
@@ -1057,11 +1725,140 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_process_make_credential_hmac_secret() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let extensions = Extensions::try_from(&cbor_map! {
+            "hmac-secret" => true,
+        })
+        .unwrap();
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
+        make_credential_params.extensions = Some(extensions);
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                let auth_data = make_credential_response.auth_data;
+                assert_eq!(auth_data[32], UP_FLAG | AT_FLAG | ED_FLAG);
+                let credential_id_start = 37 + AAGUID.len();
+                assert_eq!(
+                    auth_data[credential_id_start..credential_id_start + 2],
+                    [0x00, ENCRYPTED_CREDENTIAL_ID_SIZE_WITH_CRED_RANDOM as u8]
+                );
+                let expected_extensions = [
+                    0xA1, 0x6B, 0x68, 0x6D, 0x61, 0x63, 0x2D, 0x73, 0x65, 0x63, 0x72, 0x65, 0x74,
+                    0xF5,
+                ];
+                assert!(auth_data.ends_with(&expected_extensions));
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_process_make_credential_min_pin_length() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state.persistent_store.set_min_pin_length(6).unwrap();
+
+        // The RP ID is not in the list, so the extension is ignored.
+        let extensions = Extensions::try_from(&cbor_map! {
+            "minPinLength" => true,
+        })
+        .unwrap();
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
+        make_credential_params.extensions = Some(extensions);
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                let auth_data = make_credential_response.auth_data;
+                assert_eq!(auth_data[32], UP_FLAG | AT_FLAG);
+            }
+            _ => panic!("Invalid response type"),
+        }
+
+        ctap_state
+            .persistent_store
+            .set_min_pin_length_rp_ids(vec![String::from("example.com")])
+            .unwrap();
+        let extensions = Extensions::try_from(&cbor_map! {
+            "minPinLength" => true,
+        })
+        .unwrap();
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
+        make_credential_params.extensions = Some(extensions);
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                let auth_data = make_credential_response.auth_data;
+                assert_eq!(auth_data[32], UP_FLAG | AT_FLAG | ED_FLAG);
+                let expected_extensions = [
+                    0xA1, 0x6C, 0x6D, 0x69, 0x6E, 0x50, 0x69, 0x6E, 0x4C, 0x65, 0x6E, 0x67, 0x74,
+                    0x68, 0x06,
+                ];
+                assert!(auth_data.ends_with(&expected_extensions));
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_process_make_credential_always_uv() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        ctap_state.persistent_store.toggle_always_uv().unwrap();
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET)
+        );
+    }
+
     #[test]
     fn test_non_residential_process_make_credential() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let mut make_credential_params = create_minimal_make_credential_parameters();
         make_credential_params.options.rk = false;
@@ -1074,6 +1871,7 @@ mod test {
                     fmt,
                     auth_data,
                     att_stmt,
+                    ..
                 } = make_credential_response;
                 // The expected response is split to only assert the non-random parts.
                 assert_eq!(fmt, "packed");
@@ -1098,7 +1896,13 @@ mod test {
     fn test_process_make_credential_unsupported_algorithm() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let pub_key_cred_params = vec![(
             PublicKeyCredentialType::PublicKey,
@@ -1115,12 +1919,85 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_process_make_credential_eddsa() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        // The first supported algorithm in the relying party's order is chosen.
+        let pub_key_cred_params = vec![
+            (PublicKeyCredentialType::PublicKey, -257),
+            (
+                PublicKeyCredentialType::PublicKey,
+                SignatureAlgorithm::EDDSA as i64,
+            ),
+            (
+                PublicKeyCredentialType::PublicKey,
+                SignatureAlgorithm::ES256 as i64,
+            ),
+        ];
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pub_key_cred_params = pub_key_cred_params;
+        make_credential_params.options.rk = false;
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                let AuthenticatorMakeCredentialResponse {
+                    auth_data,
+                    att_stmt,
+                    ..
+                } = make_credential_response;
+                let credential_id_size = ENCRYPTED_CREDENTIAL_ID_SIZE + ALGORITHM_BLOCK_SIZE;
+                let credential_id_start = 37 + AAGUID.len() + 2;
+                let credential_id_end = credential_id_start + credential_id_size;
+                assert_eq!(
+                    auth_data[credential_id_start - 2..credential_id_start],
+                    [0x00, credential_id_size as u8]
+                );
+                let rp_id_hash = Sha256::hash(b"example.com");
+                let credential_source = ctap_state
+                    .decrypt_credential_source(
+                        auth_data[credential_id_start..credential_id_end].to_vec(),
+                        &rp_id_hash,
+                    )
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(
+                    credential_source.private_key.algorithm(),
+                    SignatureAlgorithm::EDDSA
+                );
+                assert_eq!(
+                    auth_data[credential_id_end..],
+                    credential_source.private_key.to_cose_key().unwrap()[..]
+                );
+                assert_eq!(att_stmt.alg, SignatureAlgorithm::EDDSA as i64);
+                assert_eq!(att_stmt.sig.len(), 64);
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
     #[test]
     fn test_process_make_credential_credential_excluded() {
         let mut rng = ThreadRng256 {};
-        let excluded_private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let excluded_private_key = PrivateKey::new(&mut rng, SignatureAlgorithm::ES256);
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let excluded_credential_id = vec![0x01, 0x23, 0x45, 0x67];
         let excluded_credential_source = PublicKeyCredentialSource {
@@ -1130,10 +2007,14 @@ mod test {
             rp_id: String::from("example.com"),
             user_handle: vec![],
             other_ui: None,
+            cred_random: None,
+            cred_protect_policy: None,
+            signature_counter: None,
+            creation_order: 0,
         };
         assert!(ctap_state
             .persistent_store
-            .store_credential(excluded_credential_source)
+            .store_credential(ctap_state.rng, excluded_credential_source)
             .is_ok());
 
         let excluded_credential_descriptor = PublicKeyCredentialDescriptor {
@@ -1153,11 +2034,184 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_process_make_credential_cred_protect() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let extensions = Extensions::try_from(&cbor_map! {
+            "credProtect" => CredentialProtectionPolicy::UserVerificationRequired,
+        })
+        .unwrap();
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.extensions = Some(extensions);
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                let auth_data = make_credential_response.auth_data;
+                assert_eq!(auth_data[32], UP_FLAG | AT_FLAG | ED_FLAG);
+                let expected_extensions = [
+                    0xA1, 0x6B, 0x63, 0x72, 0x65, 0x64, 0x50, 0x72, 0x6F, 0x74, 0x65, 0x63, 0x74,
+                    0x03,
+                ];
+                assert!(auth_data.ends_with(&expected_extensions));
+            }
+            _ => panic!("Invalid response type"),
+        }
+        let stored_credentials = ctap_state.persistent_store.filter_credential("example.com");
+        assert_eq!(
+            stored_credentials[0].cred_protect_policy,
+            Some(CredentialProtectionPolicy::UserVerificationRequired)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_cred_protect_excluded() {
+        let mut rng = ThreadRng256 {};
+        let excluded_private_key = PrivateKey::new(&mut rng, SignatureAlgorithm::ES256);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let excluded_credential_id = vec![0x01, 0x23, 0x45, 0x67];
+        let excluded_credential_source = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: excluded_credential_id.clone(),
+            private_key: excluded_private_key,
+            rp_id: String::from("example.com"),
+            user_handle: vec![],
+            other_ui: None,
+            cred_random: None,
+            cred_protect_policy: Some(CredentialProtectionPolicy::UserVerificationRequired),
+            signature_counter: None,
+            creation_order: 0,
+        };
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, excluded_credential_source)
+            .is_ok());
+
+        let excluded_credential_descriptor = PublicKeyCredentialDescriptor {
+            key_type: PublicKeyCredentialType::PublicKey,
+            key_id: excluded_credential_id,
+            transports: None,
+        };
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.exclude_list = Some(vec![excluded_credential_descriptor]);
+        // Without user verification, the existing credential is invisible.
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+        assert!(make_credential_response.is_ok());
+    }
+
+    #[test]
+    fn test_process_make_credential_enterprise_attestation() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        // Enterprise attestation must be enabled first.
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.enterprise_attestation =
+            Some(ENTERPRISE_ATTESTATION_PLATFORM_MANAGED);
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+
+        ctap_state
+            .persistent_store
+            .enable_enterprise_attestation()
+            .unwrap();
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.enterprise_attestation = Some(3);
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION)
+        );
+
+        for &(enterprise_attestation, is_enterprise) in &[
+            (ENTERPRISE_ATTESTATION_VENDOR_FACILITATED, false),
+            (ENTERPRISE_ATTESTATION_PLATFORM_MANAGED, true),
+        ] {
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.enterprise_attestation = Some(enterprise_attestation);
+            let make_credential_response =
+                ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+            match make_credential_response.unwrap() {
+                ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                    let AuthenticatorMakeCredentialResponse {
+                        att_stmt, ep_att, ..
+                    } = make_credential_response;
+                    if is_enterprise {
+                        assert_eq!(ep_att, Some(true));
+                        assert_eq!(att_stmt.x5c, Some(vec![ATTESTATION_CERTIFICATE.to_vec()]));
+                    } else {
+                        assert_eq!(ep_att, None);
+                        assert_eq!(att_stmt.x5c, None);
+                    }
+                }
+                _ => panic!("Invalid response type"),
+            }
+        }
+    }
+
     #[test]
     fn test_process_make_credential_cancelled() {
         let mut rng = ThreadRng256 {};
         let user_presence_always_cancel = |_| Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
-        let mut ctap_state = CtapState::new(&mut rng, user_presence_always_cancel);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_presence_always_cancel,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_cancelled_while_processing() {
+        let mut rng = ThreadRng256 {};
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            CancelWhileProcessing,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let make_credential_params = create_minimal_make_credential_parameters();
         let make_credential_response =
@@ -1167,13 +2221,20 @@ mod test {
             make_credential_response,
             Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL)
         );
+        assert_eq!(ctap_state.persistent_store.count_credentials(), 0);
     }
 
     #[test]
     fn test_residential_process_get_assertion() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
 
         let make_credential_params = create_minimal_make_credential_parameters();
         assert!(ctap_state
@@ -1192,8 +2253,11 @@ mod test {
             pin_uv_auth_param: None,
             pin_uv_auth_protocol: None,
         };
-        let get_assertion_response =
-            ctap_state.process_get_assertion(get_assertion_params, DUMMY_CHANNEL_ID);
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
 
         match get_assertion_response.unwrap() {
             ResponseData::AuthenticatorGetAssertion(get_assertion_response) => {
@@ -1216,81 +2280,1400 @@ mod test {
         }
     }
 
+    // Returns the signature counter of the assertion.
+    fn get_assertion_signature_counter(
+        ctap_state: &mut CtapState<
+            ThreadRng256,
+            impl UserPresence,
+            StorageRegions<BufferStorage>,
+            TestClock,
+        >,
+        allow_list: Option<Vec<PublicKeyCredentialDescriptor>>,
+    ) -> u32 {
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            allow_list,
+            ..create_get_assertion_parameters_without_allow_list()
+        };
+        match ctap_state
+            .process_get_assertion(get_assertion_params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE)
+            .unwrap()
+        {
+            ResponseData::AuthenticatorGetAssertion(response) => {
+                BigEndian::read_u32(&response.auth_data[33..37])
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
     #[test]
-    fn test_process_reset() {
+    fn test_process_get_assertion_signature_counters() {
         let mut rng = ThreadRng256 {};
+        let private_key = PrivateKey::new(&mut rng, SignatureAlgorithm::ES256);
         let user_immediately_present = |_| Ok(());
-        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
 
-        let credential_id = vec![0x01, 0x23, 0x45, 0x67];
-        let credential_source = PublicKeyCredentialSource {
-            key_type: PublicKeyCredentialType::PublicKey,
-            credential_id,
-            private_key,
-            rp_id: String::from("example.com"),
-            user_handle: vec![],
-            other_ui: None,
-        };
+        let make_credential_params = create_minimal_make_credential_parameters();
         assert!(ctap_state
-            .persistent_store
-            .store_credential(credential_source)
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
             .is_ok());
-        assert!(ctap_state.persistent_store.count_credentials() > 0);
-
-        let reset_reponse = ctap_state.process_command(&[0x07], DUMMY_CHANNEL_ID);
-        let expected_response = vec![0x00];
-        assert_eq!(reset_reponse, expected_response);
-        assert!(ctap_state.persistent_store.count_credentials() == 0);
-    }
-
-    #[test]
-    fn test_process_reset_cancelled() {
-        let mut rng = ThreadRng256 {};
-        let user_presence_always_cancel = |_| Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
-        let mut ctap_state = CtapState::new(&mut rng, user_presence_always_cancel);
+        let rp_id_hash = Sha256::hash(b"example.com");
+        let key_handle = ctap_state
+            .encrypt_key_handle(private_key, &rp_id_hash, None, None)
+            .unwrap();
+        let allow_list = vec![PublicKeyCredentialDescriptor {
+            key_type: PublicKeyCredentialType::PublicKey,
+            key_id: key_handle,
+            transports: None,
+        }];
 
-        let reset_reponse = ctap_state.process_reset(DUMMY_CHANNEL_ID);
+        // The resident credential counts its own signatures.
+        assert_eq!(get_assertion_signature_counter(&mut ctap_state, None), 1);
+        assert_eq!(get_assertion_signature_counter(&mut ctap_state, None), 2);
+        assert_eq!(
+            ctap_state
+                .persistent_store
+                .global_signature_counter()
+                .unwrap(),
+            0
+        );
 
+        // Key handles share the global counter, which grows by random amounts.
+        let signature_counter =
+            get_assertion_signature_counter(&mut ctap_state, Some(allow_list.clone()));
+        assert!(signature_counter >= 1);
+        assert!(signature_counter <= MAX_GLOBAL_SIGNATURE_COUNTER_INCREMENT as u32);
         assert_eq!(
-            reset_reponse,
-            Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL)
+            ctap_state
+                .persistent_store
+                .global_signature_counter()
+                .unwrap(),
+            signature_counter
         );
+        let next_signature_counter =
+            get_assertion_signature_counter(&mut ctap_state, Some(allow_list));
+        assert!(next_signature_counter > signature_counter);
+        assert_eq!(get_assertion_signature_counter(&mut ctap_state, None), 3);
     }
 
     #[test]
-    fn test_encrypt_decrypt_credential() {
+    fn test_residential_process_get_assertion_eddsa() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
-
-        // Usually, the relying party ID or its hash is provided by the client.
-        // We are not testing the correctness of our SHA256 here, only if it is checked.
-        let rp_id_hash = [0x55; 32];
-        let encrypted_id = ctap_state.encrypt_key_handle(private_key.clone(), &rp_id_hash);
-        let decrypted_source = ctap_state
-            .decrypt_credential_source(encrypted_id, &rp_id_hash)
-            .unwrap();
-
-        assert_eq!(private_key, decrypted_source.private_key);
-    }
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
 
-    #[test]
-    fn test_encrypt_decrypt_bad_hmac() {
-        let mut rng = ThreadRng256 {};
-        let user_immediately_present = |_| Ok(());
-        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pub_key_cred_params = vec![(
+            PublicKeyCredentialType::PublicKey,
+            SignatureAlgorithm::EDDSA as i64,
+        )];
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
 
-        // Same as above.
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+
+        match get_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(get_assertion_response) => {
+                let AuthenticatorGetAssertionResponse {
+                    auth_data,
+                    signature,
+                    ..
+                } = get_assertion_response;
+                let credential = ctap_state
+                    .persistent_store
+                    .filter_credential("example.com")
+                    .pop()
+                    .unwrap();
+                let mut signature_data = auth_data;
+                signature_data.push(0xCD);
+                assert_eq!(
+                    signature,
+                    credential.private_key.sign_and_encode(&signature_data)
+                );
+                assert_eq!(signature.len(), 64);
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_process_get_assertion_always_uv() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        ctap_state.persistent_store.toggle_always_uv().unwrap();
+
+        let get_assertion_params = create_get_assertion_parameters_without_allow_list();
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET)
+        );
+
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0x88; 16])
+            .unwrap();
+        let get_assertion_params = create_get_assertion_parameters_without_allow_list();
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)
+        );
+    }
+
+    // Encrypts the PIN padded with zeros to PIN_PADDED_LENGTH, the way a platform would.
+    fn encrypt_pin(shared_secret: &SharedSecret, pin: &[u8]) -> Vec<u8> {
+        let mut rng = ThreadRng256 {};
+        let mut padded_pin = [0u8; PIN_PADDED_LENGTH];
+        padded_pin[..pin.len()].copy_from_slice(pin);
+        shared_secret.encrypt(&mut rng, &padded_pin).unwrap()
+    }
+
+    // Computes the shared secret with the authenticator's key agreement key, the way a platform
+    // would, and returns it with the platform's public key.
+    fn create_shared_secret(
+        rng: &mut ThreadRng256,
+        authenticator_key: CoseKey,
+        protocol: PinUvAuthProtocol,
+    ) -> (CoseKey, SharedSecret) {
+        let platform_key = crypto::ecdh::SecKey::gensk(rng);
+        let authenticator_pk: crypto::ecdh::PubKey = authenticator_key.try_into().unwrap();
+        let shared_secret =
+            SharedSecret::new(protocol, &platform_key.exchange_x(&authenticator_pk));
+        (CoseKey::from(platform_key.genpk()), shared_secret)
+    }
+
+    #[test]
+    fn test_check_and_store_new_pin_min_length() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let shared_secret = SharedSecret::new(PinUvAuthProtocol::V1, &[0xC2; 32]);
+
+        assert_eq!(
+            ctap_state.check_and_store_new_pin(&shared_secret, encrypt_pin(&shared_secret, b"123")),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)
+        );
+        assert!(ctap_state
+            .check_and_store_new_pin(&shared_secret, encrypt_pin(&shared_secret, b"1234"))
+            .is_ok());
+
+        // The length is counted in code points, "\u{00E4}" is 2 bytes long.
+        ctap_state.persistent_store.set_min_pin_length(6).unwrap();
+        ctap_state
+            .persistent_store
+            .set_force_pin_change(true)
+            .unwrap();
+        let short_pin = "1234\u{00E4}".as_bytes();
+        assert_eq!(
+            ctap_state
+                .check_and_store_new_pin(&shared_secret, encrypt_pin(&shared_secret, short_pin)),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)
+        );
+        assert!(ctap_state.persistent_store.force_pin_change());
+        let long_pin = "12345\u{00E4}".as_bytes();
+        assert!(ctap_state
+            .check_and_store_new_pin(&shared_secret, encrypt_pin(&shared_secret, long_pin))
+            .is_ok());
+        assert!(!ctap_state.persistent_store.force_pin_change());
+    }
+
+    fn create_client_pin_parameters(
+        protocol: PinUvAuthProtocol,
+        sub_command: ClientPinSubCommand,
+    ) -> AuthenticatorClientPinParameters {
+        AuthenticatorClientPinParameters {
+            pin_protocol: u64::from(protocol),
+            sub_command,
+            key_agreement: None,
+            pin_auth: None,
+            new_pin_enc: None,
+            pin_hash_enc: None,
+            permissions: None,
+            permissions_rp_id: None,
+        }
+    }
+
+    // Sets a PIN, gets a pinUvAuthToken and uses it for MakeCredential, all with one version.
+    fn check_process_client_pin(protocol: PinUvAuthProtocol) {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let params = create_client_pin_parameters(protocol, ClientPinSubCommand::GetKeyAgreement);
+        let authenticator_key =
+            match ctap_state.process_client_pin(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE) {
+                Ok(ResponseData::AuthenticatorClientPin(Some(response))) => {
+                    response.key_agreement.unwrap()
+                }
+                _ => panic!("Invalid response type"),
+            };
+        let mut rng = ThreadRng256 {};
+        let (platform_key, shared_secret) =
+            create_shared_secret(&mut rng, authenticator_key, protocol);
+
+        let mut params = create_client_pin_parameters(protocol, ClientPinSubCommand::SetPin);
+        let new_pin_enc = encrypt_pin(&shared_secret, b"1234");
+        params.key_agreement = Some(CoseKey(platform_key.0.clone()));
+        params.pin_auth = Some(shared_secret.authenticate(&new_pin_enc));
+        params.new_pin_enc = Some(new_pin_enc);
+        assert_eq!(
+            ctap_state.process_client_pin(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE),
+            Ok(ResponseData::AuthenticatorClientPin(None))
+        );
+
+        let mut params =
+            create_client_pin_parameters(protocol, ClientPinSubCommand::GetPinUvAuthTokenUsingPin);
+        params.key_agreement = Some(platform_key);
+        params.pin_hash_enc = Some(
+            shared_secret
+                .encrypt(&mut rng, &Sha256::hash(b"1234")[..16])
+                .unwrap(),
+        );
+        let pin_token_enc =
+            match ctap_state.process_client_pin(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE) {
+                Ok(ResponseData::AuthenticatorClientPin(Some(response))) => {
+                    response.pin_token.unwrap()
+                }
+                _ => panic!("Invalid response type"),
+            };
+        let pin_uv_auth_token = shared_secret.decrypt(&pin_token_enc).unwrap();
+        assert_eq!(
+            &pin_uv_auth_token[..],
+            &ctap_state.pin_protocol(protocol).pin_uv_auth_token()[..]
+        );
+
+        let client_data_hash = vec![0xCD];
+        let pin_uv_auth_param = match protocol {
+            PinUvAuthProtocol::V1 => {
+                hmac_256::<Sha256>(&pin_uv_auth_token, &client_data_hash)[..16].to_vec()
+            }
+            PinUvAuthProtocol::V2 => {
+                hmac_256::<Sha256>(&pin_uv_auth_token, &client_data_hash).to_vec()
+            }
+        };
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pin_uv_auth_param = Some(pin_uv_auth_param.clone());
+        make_credential_params.pin_uv_auth_protocol = Some(u64::from(protocol));
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+
+        // The token of one version is not accepted by the other.
+        let other_protocol = match protocol {
+            PinUvAuthProtocol::V1 => PinUvAuthProtocol::V2,
+            PinUvAuthProtocol::V2 => PinUvAuthProtocol::V1,
+        };
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pin_uv_auth_param = Some(pin_uv_auth_param);
+        make_credential_params.pin_uv_auth_protocol = Some(u64::from(other_protocol));
+        assert_eq!(
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
+    #[test]
+    fn test_process_client_pin_v1() {
+        check_process_client_pin(PinUvAuthProtocol::V1);
+    }
+
+    #[test]
+    fn test_process_client_pin_v2() {
+        check_process_client_pin(PinUvAuthProtocol::V2);
+    }
+
+    #[test]
+    fn test_process_client_pin_cancelled_while_processing() {
+        let mut rng = ThreadRng256 {};
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            CancelWhileProcessing,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let params = create_client_pin_parameters(
+            PinUvAuthProtocol::V2,
+            ClientPinSubCommand::GetKeyAgreement,
+        );
+        let authenticator_key =
+            match ctap_state.process_client_pin(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE) {
+                Ok(ResponseData::AuthenticatorClientPin(Some(response))) => {
+                    response.key_agreement.unwrap()
+                }
+                _ => panic!("Invalid response type"),
+            };
+        let mut rng = ThreadRng256 {};
+        let (key_agreement, shared_secret) =
+            create_shared_secret(&mut rng, authenticator_key, PinUvAuthProtocol::V2);
+        let new_pin_enc = encrypt_pin(&shared_secret, b"1234");
+        let mut params =
+            create_client_pin_parameters(PinUvAuthProtocol::V2, ClientPinSubCommand::SetPin);
+        params.key_agreement = Some(key_agreement);
+        params.pin_auth = Some(shared_secret.authenticate(&new_pin_enc));
+        params.new_pin_enc = Some(new_pin_enc);
+        assert_eq!(
+            ctap_state.process_client_pin(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE),
+            Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL)
+        );
+        assert!(ctap_state.persistent_store.pin_hash().is_none());
+    }
+
+    #[test]
+    fn test_process_client_pin_unsupported_protocol() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let mut params = create_client_pin_parameters(
+            PinUvAuthProtocol::V1,
+            ClientPinSubCommand::GetKeyAgreement,
+        );
+        params.pin_protocol = 3;
+        assert_eq!(
+            ctap_state.process_client_pin(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
+    // Requests a token with permissions for the PIN "1234", and returns the decrypted token.
+    fn get_pin_uv_auth_token_with_permissions(
+        ctap_state: &mut CtapState<
+            ThreadRng256,
+            impl UserPresence,
+            StorageRegions<BufferStorage>,
+            TestClock,
+        >,
+        permissions: Option<u8>,
+        permissions_rp_id: Option<String>,
+    ) -> Result<Vec<u8>, Ctap2StatusCode> {
+        let mut rng = ThreadRng256 {};
+        let protocol = PinUvAuthProtocol::V2;
+        let (platform_key, shared_secret) = create_shared_secret(
+            &mut rng,
+            ctap_state.pin_protocol(protocol).get_public_key(),
+            protocol,
+        );
+        let mut params = create_client_pin_parameters(
+            protocol,
+            ClientPinSubCommand::GetPinUvAuthTokenUsingPinWithPermissions,
+        );
+        params.key_agreement = Some(platform_key);
+        params.pin_hash_enc = Some(
+            shared_secret
+                .encrypt(&mut rng, &Sha256::hash(b"1234")[..16])
+                .unwrap(),
+        );
+        params.permissions = permissions;
+        params.permissions_rp_id = permissions_rp_id;
+        match ctap_state.process_client_pin(params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE)? {
+            ResponseData::AuthenticatorClientPin(Some(response)) => {
+                Ok(shared_secret.decrypt(&response.pin_token.unwrap()).unwrap())
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    fn create_make_credential_parameters_with_pin_uv_auth_token(
+        pin_uv_auth_token: &[u8],
+    ) -> AuthenticatorMakeCredentialParameters {
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pin_uv_auth_param = Some(
+            hmac_256::<Sha256>(pin_uv_auth_token, &make_credential_params.client_data_hash)
+                .to_vec(),
+        );
+        make_credential_params.pin_uv_auth_protocol = Some(2);
+        make_credential_params
+    }
+
+    #[test]
+    fn test_process_get_pin_uv_auth_token_invalid_permissions() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let pin_hash = Sha256::hash(b"1234");
+        ctap_state
+            .persistent_store
+            .set_pin_hash(array_ref![pin_hash, 0, 16])
+            .unwrap();
+
+        assert_eq!(
+            get_pin_uv_auth_token_with_permissions(&mut ctap_state, None, None),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+        assert_eq!(
+            get_pin_uv_auth_token_with_permissions(&mut ctap_state, Some(0x00), None),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        assert_eq!(
+            get_pin_uv_auth_token_with_permissions(
+                &mut ctap_state,
+                Some(PinPermission::BioEnrollment as u8),
+                None
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_UNAUTHORIZED_PERMISSION)
+        );
+        assert_eq!(
+            get_pin_uv_auth_token_with_permissions(
+                &mut ctap_state,
+                Some(PinPermission::LargeBlobWrite as u8),
+                None
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_UNAUTHORIZED_PERMISSION)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_permissions() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let pin_hash = Sha256::hash(b"1234");
+        ctap_state
+            .persistent_store
+            .set_pin_hash(array_ref![pin_hash, 0, 16])
+            .unwrap();
+
+        // A token without the MakeCredential permission is rejected.
+        let pin_uv_auth_token = get_pin_uv_auth_token_with_permissions(
+            &mut ctap_state,
+            Some(PinPermission::GetAssertion as u8),
+            None,
+        )
+        .unwrap();
+        let make_credential_params =
+            create_make_credential_parameters_with_pin_uv_auth_token(&pin_uv_auth_token);
+        assert_eq!(
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+
+        // A token for a different relying party is rejected.
+        let pin_uv_auth_token = get_pin_uv_auth_token_with_permissions(
+            &mut ctap_state,
+            Some(PinPermission::MakeCredential as u8),
+            Some(String::from("another.example.com")),
+        )
+        .unwrap();
+        let make_credential_params =
+            create_make_credential_parameters_with_pin_uv_auth_token(&pin_uv_auth_token);
+        assert_eq!(
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+
+        // A matching token is accepted once, its permission is cleared on use.
+        let pin_uv_auth_token = get_pin_uv_auth_token_with_permissions(
+            &mut ctap_state,
+            Some(PinPermission::MakeCredential as u8),
+            Some(String::from("example.com")),
+        )
+        .unwrap();
+        let make_credential_params =
+            create_make_credential_parameters_with_pin_uv_auth_token(&pin_uv_auth_token);
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        let make_credential_params =
+            create_make_credential_parameters_with_pin_uv_auth_token(&pin_uv_auth_token);
+        assert_eq!(
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
+    #[test]
+    fn test_pin_uv_auth_token_expires() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let pin_hash = Sha256::hash(b"1234");
+        ctap_state
+            .persistent_store
+            .set_pin_hash(array_ref![pin_hash, 0, 16])
+            .unwrap();
+
+        let pin_uv_auth_token = get_pin_uv_auth_token_with_permissions(
+            &mut ctap_state,
+            Some(PinPermission::MakeCredential as u8),
+            None,
+        )
+        .unwrap();
+        // Any command checks the usage timer, the token is not used within its time limit.
+        ctap_state
+            .clock
+            .set(DUMMY_CLOCK_VALUE.wrapping_add(Duration::from_ms(31_000)));
+        ctap_state.process_command(&[0x04], DUMMY_CHANNEL_ID);
+        let make_credential_params =
+            create_make_credential_parameters_with_pin_uv_auth_token(&pin_uv_auth_token);
+        assert_eq!(
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
+    #[test]
+    fn test_pin_uv_auth_token_not_used_without_permission() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+        let pin_hash = Sha256::hash(b"1234");
+        ctap_state
+            .persistent_store
+            .set_pin_hash(array_ref![pin_hash, 0, 16])
+            .unwrap();
+
+        let pin_uv_auth_token = get_pin_uv_auth_token_with_permissions(
+            &mut ctap_state,
+            Some(PinPermission::MakeCredential as u8),
+            None,
+        )
+        .unwrap();
+        // A valid pinUvAuthParam for a command the token has no permission for doesn't use it.
+        let mut get_assertion_params = create_get_assertion_parameters_without_allow_list();
+        get_assertion_params.pin_uv_auth_param = Some(
+            hmac_256::<Sha256>(&pin_uv_auth_token, &get_assertion_params.client_data_hash).to_vec(),
+        );
+        get_assertion_params.pin_uv_auth_protocol = Some(2);
+        assert_eq!(
+            ctap_state.process_get_assertion(
+                get_assertion_params,
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+        ctap_state
+            .clock
+            .set(DUMMY_CLOCK_VALUE.wrapping_add(Duration::from_ms(31_000)));
+        ctap_state.process_command(&[0x04], DUMMY_CHANNEL_ID);
+        let make_credential_params =
+            create_make_credential_parameters_with_pin_uv_auth_token(&pin_uv_auth_token);
+        assert_eq!(
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
+    fn create_get_assertion_parameters_without_allow_list() -> AuthenticatorGetAssertionParameters {
+        AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        }
+    }
+
+    // Returns the hmac-secret extension input for the given salt, and the shared secret.
+    fn create_hmac_secret_extensions(
+        rng: &mut ThreadRng256,
+        authenticator_key: CoseKey,
+        protocol: PinUvAuthProtocol,
+        salt: &[u8; 32],
+    ) -> (Extensions, SharedSecret) {
+        let (cose_key, shared_secret) = create_shared_secret(rng, authenticator_key, protocol);
+        let salt_enc = shared_secret.encrypt(rng, salt).unwrap();
+        let salt_auth = shared_secret.authenticate(&salt_enc);
+        let extensions = Extensions::try_from(&cbor_map! {
+            "hmac-secret" => cbor_map! {
+                1 => cbor::Value::Map(cose_key.0),
+                2 => salt_enc,
+                3 => salt_auth,
+                4 => u64::from(protocol),
+            },
+        })
+        .unwrap();
+        (extensions, shared_secret)
+    }
+
+    fn check_process_get_assertion_hmac_secret(protocol: PinUvAuthProtocol) {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let make_extensions = Extensions::try_from(&cbor_map! {
+            "hmac-secret" => true,
+        })
+        .unwrap();
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.extensions = Some(make_extensions);
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        let cred_random = ctap_state.persistent_store.filter_credential("example.com")[0]
+            .cred_random
+            .clone()
+            .unwrap();
+
+        let salt = [0x5A; 32];
+        let mut rng = ThreadRng256 {};
+        let (extensions, shared_secret) = create_hmac_secret_extensions(
+            &mut rng,
+            ctap_state.pin_protocol(protocol).get_public_key(),
+            protocol,
+            &salt,
+        );
+        let mut get_assertion_params = create_get_assertion_parameters_without_allow_list();
+        get_assertion_params.extensions = Some(extensions);
+        get_assertion_params.options.up = true;
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+
+        let auth_data = match get_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(response) => response.auth_data,
+            _ => panic!("Invalid response type"),
+        };
+        assert_eq!(auth_data[32], UP_FLAG | ED_FLAG);
+        let extensions_output = cbor::read(&auth_data[37..]).unwrap();
+        let output_enc = match extensions_output {
+            cbor::Value::Map(map) => match map.get(&cbor_text!("hmac-secret")) {
+                Some(cbor::Value::KeyValue(cbor::KeyType::ByteString(output))) => output.clone(),
+                _ => panic!("Invalid extension output"),
+            },
+            _ => panic!("Invalid extension output"),
+        };
+        let output = shared_secret.decrypt(&output_enc).unwrap();
+        let cred_random_without_uv = hmac_256::<Sha256>(&cred_random, &[0x00]);
+        assert_eq!(
+            output,
+            hmac_256::<Sha256>(&cred_random_without_uv, &salt).to_vec()
+        );
+    }
+
+    #[test]
+    fn test_process_get_assertion_hmac_secret_v1() {
+        check_process_get_assertion_hmac_secret(PinUvAuthProtocol::V1);
+    }
+
+    #[test]
+    fn test_process_get_assertion_hmac_secret_v2() {
+        check_process_get_assertion_hmac_secret(PinUvAuthProtocol::V2);
+    }
+
+    #[test]
+    fn test_process_get_assertion_hmac_secret_bad_salt_auth() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+
+        let mut rng = ThreadRng256 {};
+        // The platform uses a different key than the authenticator's key agreement key.
+        let wrong_key = CoseKey::from(crypto::ecdh::SecKey::gensk(&mut rng).genpk());
+        let (extensions, _) =
+            create_hmac_secret_extensions(&mut rng, wrong_key, PinUvAuthProtocol::V1, &[0x5A; 32]);
+        let mut get_assertion_params = create_get_assertion_parameters_without_allow_list();
+        get_assertion_params.extensions = Some(extensions);
+        get_assertion_params.options.up = true;
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_EXTENSION_FIRST)
+        );
+    }
+
+    #[test]
+    fn test_process_get_assertion_hmac_secret_without_up() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let make_extensions = Extensions::try_from(&cbor_map! {
+            "hmac-secret" => true,
+        })
+        .unwrap();
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.extensions = Some(make_extensions);
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+
+        let mut rng = ThreadRng256 {};
+        let (extensions, _) = create_hmac_secret_extensions(
+            &mut rng,
+            ctap_state
+                .pin_protocol(PinUvAuthProtocol::V2)
+                .get_public_key(),
+            PinUvAuthProtocol::V2,
+            &[0x5A; 32],
+        );
+        let mut get_assertion_params = create_get_assertion_parameters_without_allow_list();
+        get_assertion_params.extensions = Some(extensions);
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION)
+        );
+    }
+
+    // Stores a credential for "example.com" with the given credProtect policy.
+    fn store_cred_protect_credential(
+        persistent_store: &mut PersistentStore<BufferStorage>,
+        credential_id: Vec<u8>,
+        cred_protect_policy: Option<CredentialProtectionPolicy>,
+    ) {
+        let mut rng = ThreadRng256 {};
+        let credential_source = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: credential_id.clone(),
+            private_key: PrivateKey::new(&mut rng, SignatureAlgorithm::ES256),
+            rp_id: String::from("example.com"),
+            user_handle: credential_id,
+            other_ui: None,
+            cred_random: None,
+            cred_protect_policy,
+            signature_counter: None,
+            creation_order: 0,
+        };
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_process_get_assertion_cred_protect() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        store_cred_protect_credential(
+            &mut ctap_state.persistent_store,
+            vec![0x02],
+            Some(CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList),
+        );
+        store_cred_protect_credential(
+            &mut ctap_state.persistent_store,
+            vec![0x03],
+            Some(CredentialProtectionPolicy::UserVerificationRequired),
+        );
+
+        // Without user verification, no credential is discoverable.
+        let get_assertion_params = create_get_assertion_parameters_without_allow_list();
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
+        );
+
+        // Only the credential with policy level 2 is found in an allow list.
+        for (key_id, is_found) in vec![(vec![0x02], true), (vec![0x03], false)] {
+            let mut get_assertion_params = create_get_assertion_parameters_without_allow_list();
+            get_assertion_params.allow_list = Some(vec![PublicKeyCredentialDescriptor {
+                key_type: PublicKeyCredentialType::PublicKey,
+                key_id,
+                transports: None,
+            }]);
+            let get_assertion_response = ctap_state.process_get_assertion(
+                get_assertion_params,
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            );
+            assert_eq!(get_assertion_response.is_ok(), is_found);
+        }
+
+        // A credential without policy stays discoverable.
+        store_cred_protect_credential(&mut ctap_state.persistent_store, vec![0x01], None);
+        let get_assertion_params = create_get_assertion_parameters_without_allow_list();
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        match get_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(response) => {
+                assert_eq!(response.credential.unwrap().key_id, vec![0x01]);
+                assert_eq!(response.number_of_credentials, None);
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_process_get_assertion_cred_protect_non_resident() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        for &(policy, is_found) in &[
+            (
+                CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList,
+                true,
+            ),
+            (CredentialProtectionPolicy::UserVerificationRequired, false),
+        ] {
+            let extensions = Extensions::try_from(&cbor_map! {
+                "credProtect" => policy,
+            })
+            .unwrap();
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.options.rk = false;
+            make_credential_params.extensions = Some(extensions);
+            let make_credential_response =
+                ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+            let auth_data = match make_credential_response.unwrap() {
+                ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                    make_credential_response.auth_data
+                }
+                _ => panic!("Invalid response type"),
+            };
+            // The policy is kept in the key handle, along with the algorithm.
+            let credential_id_size = ENCRYPTED_CREDENTIAL_ID_SIZE + ALGORITHM_BLOCK_SIZE;
+            let credential_id_start = 37 + AAGUID.len() + 2;
+            assert_eq!(
+                auth_data[credential_id_start - 2..credential_id_start],
+                [0x00, credential_id_size as u8]
+            );
+            let key_id =
+                auth_data[credential_id_start..credential_id_start + credential_id_size].to_vec();
+
+            let mut get_assertion_params = create_get_assertion_parameters_without_allow_list();
+            get_assertion_params.allow_list = Some(vec![PublicKeyCredentialDescriptor {
+                key_type: PublicKeyCredentialType::PublicKey,
+                key_id,
+                transports: None,
+            }]);
+            let get_assertion_response = ctap_state.process_get_assertion(
+                get_assertion_params,
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            );
+            if is_found {
+                assert!(get_assertion_response.is_ok());
+            } else {
+                assert_eq!(
+                    get_assertion_response,
+                    Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_process_get_next_assertion() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.user.user_id = vec![0x01];
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.user.user_id = vec![0x02];
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+
+        let get_assertion_response = ctap_state.process_get_assertion(
+            create_get_assertion_parameters_without_allow_list(),
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        // The most recent credential comes first.
+        let stored_credentials = ctap_state.persistent_store.filter_credential("example.com");
+        let latest_credential = stored_credentials
+            .iter()
+            .find(|credential| credential.user_handle == vec![0x02])
+            .unwrap();
+        let first_credential = match get_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(get_assertion_response) => {
+                assert_eq!(get_assertion_response.number_of_credentials, Some(2));
+                let credential = get_assertion_response.credential.unwrap();
+                assert_eq!(credential.key_id, latest_credential.credential_id);
+                credential
+            }
+            _ => panic!("Invalid response type"),
+        };
+
+        let get_next_assertion_response =
+            ctap_state.process_get_next_assertion(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        match get_next_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetNextAssertion(get_assertion_response) => {
+                let AuthenticatorGetAssertionResponse {
+                    credential,
+                    auth_data,
+                    number_of_credentials,
+                    ..
+                } = get_assertion_response;
+                assert_ne!(credential.unwrap().key_id, first_credential.key_id);
+                // Each credential has its own signature counter.
+                assert_eq!(auth_data[33..], [0x00, 0x00, 0x00, 0x01]);
+                assert!(number_of_credentials.is_none());
+            }
+            _ => panic!("Invalid response type"),
+        }
+
+        let get_next_assertion_response =
+            ctap_state.process_get_next_assertion(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(
+            get_next_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+    }
+
+    #[test]
+    fn test_process_get_next_assertion_invalidated() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        // Without a previous GetAssertion, there is no state to continue.
+        let get_next_assertion_response = ctap_state.process_command(&[0x08], DUMMY_CHANNEL_ID);
+        assert_eq!(
+            get_next_assertion_response,
+            vec![Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED as u8]
+        );
+
+        for user_id in 0..3 {
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.user.user_id = vec![user_id];
+            assert!(ctap_state
+                .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+                .is_ok());
+        }
+
+        // Another command discards the state.
+        assert!(ctap_state
+            .process_get_assertion(
+                create_get_assertion_parameters_without_allow_list(),
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            )
+            .is_ok());
+        assert_eq!(
+            ctap_state.process_command(&[0x04], DUMMY_CHANNEL_ID)[0],
+            0x00
+        );
+        assert_eq!(
+            ctap_state.process_get_next_assertion(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+
+        // Another channel can't continue the state.
+        assert!(ctap_state
+            .process_get_assertion(
+                create_get_assertion_parameters_without_allow_list(),
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            )
+            .is_ok());
+        assert_eq!(
+            ctap_state.process_get_next_assertion([0x9A, 0xBC, 0xDE, 0xF0], DUMMY_CLOCK_VALUE),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+
+        // The state expires after 30 seconds.
+        assert!(ctap_state
+            .process_get_assertion(
+                create_get_assertion_parameters_without_allow_list(),
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            )
+            .is_ok());
+        let timeout_clock_value = DUMMY_CLOCK_VALUE.wrapping_add(STATEFUL_COMMAND_TIMEOUT);
+        assert_eq!(
+            ctap_state.process_get_next_assertion(DUMMY_CHANNEL_ID, timeout_clock_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+    }
+
+    #[test]
+    fn test_process_reset() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let private_key = PrivateKey::new(&mut rng, SignatureAlgorithm::ES256);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let credential_id = vec![0x01, 0x23, 0x45, 0x67];
+        let credential_source = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id,
+            private_key,
+            rp_id: String::from("example.com"),
+            user_handle: vec![],
+            other_ui: None,
+            cred_random: None,
+            cred_protect_policy: None,
+            signature_counter: None,
+            creation_order: 0,
+        };
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(ctap_state.rng, credential_source)
+            .is_ok());
+        assert!(ctap_state.persistent_store.count_credentials() > 0);
+
+        let reset_reponse = ctap_state.process_command(&[0x07], DUMMY_CHANNEL_ID);
+        let expected_response = vec![0x00];
+        assert_eq!(reset_reponse, expected_response);
+        assert!(ctap_state.persistent_store.count_credentials() == 0);
+    }
+
+    #[test]
+    fn test_process_reset_cancelled() {
+        let mut rng = ThreadRng256 {};
+        let user_presence_always_cancel = |_| Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_presence_always_cancel,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let reset_reponse = ctap_state.process_reset(DUMMY_CHANNEL_ID);
+
+        assert_eq!(
+            reset_reponse,
+            Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL)
+        );
+    }
+
+    #[test]
+    fn test_process_selection() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let selection_response = ctap_state.process_command(&[0xB0], DUMMY_CHANNEL_ID);
+        assert_eq!(selection_response, vec![0x00]);
+    }
+
+    #[test]
+    fn test_process_selection_cancelled() {
+        let mut rng = ThreadRng256 {};
+        let user_presence_always_cancel = |_| Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_presence_always_cancel,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let selection_response = ctap_state.process_command(&[0xB0], DUMMY_CHANNEL_ID);
+        assert_eq!(
+            selection_response,
+            vec![Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL as u8]
+        );
+    }
+
+    #[test]
+    fn test_process_selection_timeout() {
+        let mut rng = ThreadRng256 {};
+        let user_presence_timeout = |_| Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT);
+        let ctap_state = CtapState::new(
+            &mut rng,
+            user_presence_timeout,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let selection_response = ctap_state.process_selection(DUMMY_CHANNEL_ID);
+        assert_eq!(
+            selection_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_credential() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let private_key = PrivateKey::new(&mut rng, SignatureAlgorithm::ES256);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        // Usually, the relying party ID or its hash is provided by the client.
+        // We are not testing the correctness of our SHA256 here, only if it is checked.
+        let rp_id_hash = [0x55; 32];
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key.clone(), &rp_id_hash, None, None)
+            .unwrap();
+        let decrypted_source = ctap_state
+            .decrypt_credential_source(encrypted_id, &rp_id_hash)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(private_key, decrypted_source.private_key);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_credential_with_cred_random() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let private_key = PrivateKey::new(&mut rng, SignatureAlgorithm::ES256);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let rp_id_hash = [0x55; 32];
+        let cred_random = [0xC4; 32];
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key.clone(), &rp_id_hash, Some(&cred_random), None)
+            .unwrap();
+        assert_eq!(
+            encrypted_id.len(),
+            ENCRYPTED_CREDENTIAL_ID_SIZE_WITH_CRED_RANDOM
+        );
+        let decrypted_source = ctap_state
+            .decrypt_credential_source(encrypted_id, &rp_id_hash)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(private_key, decrypted_source.private_key);
+        assert_eq!(decrypted_source.cred_random, Some(cred_random.to_vec()));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_credential_eddsa() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let private_key = PrivateKey::new(&mut rng, SignatureAlgorithm::EDDSA);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        let rp_id_hash = [0x55; 32];
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key.clone(), &rp_id_hash, None, None)
+            .unwrap();
+        assert_eq!(
+            encrypted_id.len(),
+            ENCRYPTED_CREDENTIAL_ID_SIZE + ALGORITHM_BLOCK_SIZE
+        );
+        let decrypted_source = ctap_state
+            .decrypt_credential_source(encrypted_id, &rp_id_hash)
+            .unwrap()
+            .unwrap();
+        assert_eq!(private_key, decrypted_source.private_key);
+        assert_eq!(decrypted_source.cred_random, None);
+
+        let cred_random = [0xC4; 32];
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key.clone(), &rp_id_hash, Some(&cred_random), None)
+            .unwrap();
+        assert_eq!(
+            encrypted_id.len(),
+            ENCRYPTED_CREDENTIAL_ID_SIZE_WITH_CRED_RANDOM + ALGORITHM_BLOCK_SIZE
+        );
+        let decrypted_source = ctap_state
+            .decrypt_credential_source(encrypted_id, &rp_id_hash)
+            .unwrap()
+            .unwrap();
+        assert_eq!(private_key, decrypted_source.private_key);
+        assert_eq!(decrypted_source.cred_random, Some(cred_random.to_vec()));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_bad_hmac() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let private_key = PrivateKey::new(&mut rng, SignatureAlgorithm::ES256);
+        let mut ctap_state = CtapState::new(
+            &mut rng,
+            user_immediately_present,
+            new_buffer_storage(),
+            TestClock::new(DUMMY_CLOCK_VALUE),
+        )
+        .unwrap();
+
+        // Same as above.
         let rp_id_hash = [0x55; 32];
-        let encrypted_id = ctap_state.encrypt_key_handle(private_key, &rp_id_hash);
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key, &rp_id_hash, None, None)
+            .unwrap();
         for i in 0..encrypted_id.len() {
             let mut modified_id = encrypted_id.clone();
             modified_id[i] ^= 0x01;
             assert!(ctap_state
                 .decrypt_credential_source(modified_id, &rp_id_hash)
+                .unwrap()
                 .is_none());
         }
     }
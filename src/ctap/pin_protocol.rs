@@ -0,0 +1,412 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::data_formats::CoseKey;
+use super::status_code::Ctap2StatusCode;
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+use crypto::cbc::{cbc_decrypt, cbc_encrypt};
+use crypto::hkdf::hkdf_256;
+use crypto::hmac::{verify_hmac_256, verify_hmac_256_first_128bits};
+use crypto::rng256::Rng256;
+use crypto::sha256::Sha256;
+use crypto::Hash256;
+
+pub const PIN_TOKEN_LENGTH: usize = 32;
+const BLOCK_SIZE: usize = 16;
+// Protocol 1 truncates its authentication tags, protocol 2 uses the full HMAC.
+const PIN_AUTH_LENGTH_V1: usize = 16;
+const PIN_AUTH_LENGTH_V2: usize = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum PinUvAuthProtocol {
+    V1 = 1,
+    V2 = 2,
+}
+
+impl From<PinUvAuthProtocol> for u64 {
+    fn from(protocol: PinUvAuthProtocol) -> Self {
+        protocol as u64
+    }
+}
+
+impl TryFrom<u64> for PinUvAuthProtocol {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(protocol: u64) -> Result<Self, Ctap2StatusCode> {
+        match protocol {
+            1 => Ok(PinUvAuthProtocol::V1),
+            2 => Ok(PinUvAuthProtocol::V2),
+            // The specification is unclear, we keep the error code of the first version.
+            _ => Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID),
+        }
+    }
+}
+
+fn to_blocks(data: &[u8]) -> Vec<[u8; BLOCK_SIZE]> {
+    data.chunks(BLOCK_SIZE)
+        .map(|chunk| *array_ref![chunk, 0, BLOCK_SIZE])
+        .collect()
+}
+
+// Checks a signature made with the pinUvAuthToken, truncated for the first protocol version.
+pub fn verify_pin_uv_auth_token(
+    token: &[u8; PIN_TOKEN_LENGTH],
+    message: &[u8],
+    signature: &[u8],
+    protocol: PinUvAuthProtocol,
+) -> bool {
+    match protocol {
+        PinUvAuthProtocol::V1 => {
+            signature.len() == PIN_AUTH_LENGTH_V1
+                && verify_hmac_256_first_128bits::<Sha256>(
+                    token,
+                    message,
+                    array_ref![signature, 0, PIN_AUTH_LENGTH_V1],
+                )
+        }
+        PinUvAuthProtocol::V2 => {
+            signature.len() == PIN_AUTH_LENGTH_V2
+                && verify_hmac_256::<Sha256>(
+                    token,
+                    message,
+                    array_ref![signature, 0, PIN_AUTH_LENGTH_V2],
+                )
+        }
+    }
+}
+
+// The keys derived from the ECDH shared point, as specified for each protocol version.
+pub enum SharedSecret {
+    // The SHA256 hash of the shared point is used for both encryption and authentication. The
+    // IV is always zero.
+    V1 {
+        key: [u8; 32],
+    },
+    // Separate keys are derived with HKDF. Ciphertexts are prefixed with a random IV.
+    V2 {
+        hmac_key: [u8; 32],
+        aes_key: [u8; 32],
+    },
+}
+
+impl SharedSecret {
+    pub fn new(protocol: PinUvAuthProtocol, shared_point_x: &[u8; 32]) -> SharedSecret {
+        match protocol {
+            PinUvAuthProtocol::V1 => SharedSecret::V1 {
+                key: Sha256::hash(shared_point_x),
+            },
+            PinUvAuthProtocol::V2 => SharedSecret::V2 {
+                hmac_key: hkdf_256::<Sha256>(shared_point_x, &[0; 32], b"CTAP2 HMAC key"),
+                aes_key: hkdf_256::<Sha256>(shared_point_x, &[0; 32], b"CTAP2 AES key"),
+            },
+        }
+    }
+
+    // Encrypts a plaintext whose length is a multiple of the block size.
+    pub fn encrypt(
+        &self,
+        rng: &mut impl Rng256,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, Ctap2StatusCode> {
+        if plaintext.len() % BLOCK_SIZE != 0 {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH);
+        }
+        let mut blocks = to_blocks(plaintext);
+        match self {
+            SharedSecret::V1 { key } => {
+                let aes_enc_key = crypto::aes256::EncryptionKey::new(key);
+                cbc_encrypt(&aes_enc_key, [0; BLOCK_SIZE], &mut blocks);
+                Ok(blocks.iter().flatten().cloned().collect())
+            }
+            SharedSecret::V2 { aes_key, .. } => {
+                let aes_enc_key = crypto::aes256::EncryptionKey::new(aes_key);
+                let random_bytes = rng.gen_uniform_u8x32();
+                let iv = *array_ref![random_bytes, 0, BLOCK_SIZE];
+                cbc_encrypt(&aes_enc_key, iv, &mut blocks);
+                let mut ciphertext = iv.to_vec();
+                ciphertext.extend(blocks.iter().flatten());
+                Ok(ciphertext)
+            }
+        }
+    }
+
+    // Decrypts a ciphertext, returning an error if its length is not valid for the protocol.
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Ctap2StatusCode> {
+        let (aes_key, iv, ciphertext) = match self {
+            SharedSecret::V1 { key } => (key, [0; BLOCK_SIZE], ciphertext),
+            SharedSecret::V2 { aes_key, .. } => {
+                if ciphertext.len() < BLOCK_SIZE {
+                    return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH);
+                }
+                let (iv, ciphertext) = ciphertext.split_at(BLOCK_SIZE);
+                (aes_key, *array_ref![iv, 0, BLOCK_SIZE], ciphertext)
+            }
+        };
+        if ciphertext.is_empty() || ciphertext.len() % BLOCK_SIZE != 0 {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH);
+        }
+        let aes_enc_key = crypto::aes256::EncryptionKey::new(aes_key);
+        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
+        let mut blocks = to_blocks(ciphertext);
+        cbc_decrypt(&aes_dec_key, iv, &mut blocks);
+        Ok(blocks.iter().flatten().cloned().collect())
+    }
+
+    // Only the platform authenticates messages with the shared secret, tests act as one.
+    #[cfg(test)]
+    pub fn authenticate(&self, message: &[u8]) -> Vec<u8> {
+        match self {
+            SharedSecret::V1 { key } => {
+                crypto::hmac::hmac_256::<Sha256>(key, message)[..PIN_AUTH_LENGTH_V1].to_vec()
+            }
+            SharedSecret::V2 { hmac_key, .. } => {
+                crypto::hmac::hmac_256::<Sha256>(hmac_key, message).to_vec()
+            }
+        }
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            SharedSecret::V1 { key } => {
+                signature.len() == PIN_AUTH_LENGTH_V1
+                    && verify_hmac_256_first_128bits::<Sha256>(
+                        key,
+                        message,
+                        array_ref![signature, 0, PIN_AUTH_LENGTH_V1],
+                    )
+            }
+            SharedSecret::V2 { hmac_key, .. } => {
+                signature.len() == PIN_AUTH_LENGTH_V2
+                    && verify_hmac_256::<Sha256>(
+                        hmac_key,
+                        message,
+                        array_ref![signature, 0, PIN_AUTH_LENGTH_V2],
+                    )
+            }
+        }
+    }
+}
+
+// The volatile state of one PIN protocol version. Each version has its own key agreement key and
+// pinUvAuthToken, so that a token obtained with one version can't be used with the other.
+pub struct PinProtocol {
+    key_agreement_key: crypto::ecdh::SecKey,
+    pin_uv_auth_token: [u8; PIN_TOKEN_LENGTH],
+}
+
+impl PinProtocol {
+    pub fn new(rng: &mut impl Rng256) -> PinProtocol {
+        PinProtocol {
+            key_agreement_key: crypto::ecdh::SecKey::gensk(rng),
+            pin_uv_auth_token: rng.gen_uniform_u8x32(),
+        }
+    }
+
+    // Generates a new key agreement key, invalidating all shared secrets.
+    pub fn regenerate(&mut self, rng: &mut impl Rng256) {
+        self.key_agreement_key = crypto::ecdh::SecKey::gensk(rng);
+    }
+
+    pub fn reset_pin_uv_auth_token(&mut self, rng: &mut impl Rng256) {
+        self.pin_uv_auth_token = rng.gen_uniform_u8x32();
+    }
+
+    pub fn get_public_key(&self) -> CoseKey {
+        CoseKey::from(self.key_agreement_key.genpk())
+    }
+
+    // Computes the shared secret with the platform's key agreement key.
+    pub fn decapsulate(
+        &self,
+        key_agreement: CoseKey,
+        protocol: PinUvAuthProtocol,
+    ) -> Result<SharedSecret, Ctap2StatusCode> {
+        let pk: crypto::ecdh::PubKey = key_agreement.try_into()?;
+        Ok(SharedSecret::new(
+            protocol,
+            &self.key_agreement_key.exchange_x(&pk),
+        ))
+    }
+
+    pub fn pin_uv_auth_token(&self) -> &[u8; PIN_TOKEN_LENGTH] {
+        &self.pin_uv_auth_token
+    }
+
+    #[cfg(test)]
+    pub fn set_pin_uv_auth_token(&mut self, pin_uv_auth_token: [u8; PIN_TOKEN_LENGTH]) {
+        self.pin_uv_auth_token = pin_uv_auth_token;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::hmac::hmac_256;
+    use crypto::rng256::ThreadRng256;
+
+    // Computes the shared secret the way a platform would.
+    fn platform_shared_secret(
+        rng: &mut ThreadRng256,
+        pin_protocol: &PinProtocol,
+        protocol: PinUvAuthProtocol,
+    ) -> (CoseKey, SharedSecret) {
+        let platform_key = crypto::ecdh::SecKey::gensk(rng);
+        let authenticator_pk: crypto::ecdh::PubKey =
+            pin_protocol.get_public_key().try_into().unwrap();
+        let shared_secret =
+            SharedSecret::new(protocol, &platform_key.exchange_x(&authenticator_pk));
+        (CoseKey::from(platform_key.genpk()), shared_secret)
+    }
+
+    #[test]
+    fn test_pin_uv_auth_protocol_try_from() {
+        assert_eq!(PinUvAuthProtocol::try_from(1), Ok(PinUvAuthProtocol::V1));
+        assert_eq!(PinUvAuthProtocol::try_from(2), Ok(PinUvAuthProtocol::V2));
+        assert_eq!(
+            PinUvAuthProtocol::try_from(3),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+        assert_eq!(u64::from(PinUvAuthProtocol::V2), 2);
+    }
+
+    #[test]
+    fn test_decapsulate_is_symmetric() {
+        let mut rng = ThreadRng256 {};
+        let pin_protocol = PinProtocol::new(&mut rng);
+        for &protocol in &[PinUvAuthProtocol::V1, PinUvAuthProtocol::V2] {
+            let (platform_pk, platform_secret) =
+                platform_shared_secret(&mut rng, &pin_protocol, protocol);
+            let shared_secret = pin_protocol.decapsulate(platform_pk, protocol).unwrap();
+            let message = [0x55; 32];
+            let ciphertext = platform_secret.encrypt(&mut rng, &message).unwrap();
+            assert_eq!(shared_secret.decrypt(&ciphertext), Ok(message.to_vec()));
+            let signature = platform_secret.authenticate(&message);
+            assert!(shared_secret.verify(&message, &signature));
+        }
+    }
+
+    #[test]
+    fn test_shared_secret_v1_encrypt_decrypt() {
+        let mut rng = ThreadRng256 {};
+        let shared_secret = SharedSecret::new(PinUvAuthProtocol::V1, &[0x11; 32]);
+        let plaintext = [0x22; 64];
+        let ciphertext = shared_secret.encrypt(&mut rng, &plaintext).unwrap();
+        assert_eq!(ciphertext.len(), 64);
+        // Protocol 1 is deterministic, since its IV is always zero.
+        assert_eq!(
+            shared_secret.encrypt(&mut rng, &plaintext),
+            Ok(ciphertext.clone())
+        );
+        assert_eq!(shared_secret.decrypt(&ciphertext), Ok(plaintext.to_vec()));
+        assert_eq!(
+            shared_secret.encrypt(&mut rng, &[0x22; 15]),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH)
+        );
+        assert_eq!(
+            shared_secret.decrypt(&[]),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH)
+        );
+    }
+
+    #[test]
+    fn test_shared_secret_v2_encrypt_decrypt() {
+        let mut rng = ThreadRng256 {};
+        let shared_secret = SharedSecret::new(PinUvAuthProtocol::V2, &[0x11; 32]);
+        let plaintext = [0x22; 64];
+        let ciphertext = shared_secret.encrypt(&mut rng, &plaintext).unwrap();
+        assert_eq!(ciphertext.len(), 80);
+        assert_ne!(
+            shared_secret.encrypt(&mut rng, &plaintext),
+            Ok(ciphertext.clone())
+        );
+        assert_eq!(shared_secret.decrypt(&ciphertext), Ok(plaintext.to_vec()));
+        assert_eq!(
+            shared_secret.decrypt(&ciphertext[..16]),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH)
+        );
+        assert_eq!(
+            shared_secret.decrypt(&ciphertext[..40]),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH)
+        );
+    }
+
+    #[test]
+    fn test_shared_secret_v2_keys() {
+        // The keys are derived with an all-zero salt, as the specification requires.
+        let shared_point_x = [0x11; 32];
+        match SharedSecret::new(PinUvAuthProtocol::V2, &shared_point_x) {
+            SharedSecret::V2 { hmac_key, aes_key } => {
+                assert_eq!(
+                    hmac_key,
+                    hkdf_256::<Sha256>(&shared_point_x, &[], b"CTAP2 HMAC key")
+                );
+                assert_eq!(
+                    aes_key,
+                    hkdf_256::<Sha256>(&shared_point_x, &[], b"CTAP2 AES key")
+                );
+                assert_ne!(hmac_key, aes_key);
+            }
+            SharedSecret::V1 { .. } => panic!("Expected a shared secret of version 2."),
+        }
+    }
+
+    #[test]
+    fn test_shared_secret_authenticate_verify() {
+        let message = [0x33; 20];
+        let shared_secret = SharedSecret::new(PinUvAuthProtocol::V1, &[0x11; 32]);
+        let signature = shared_secret.authenticate(&message);
+        assert_eq!(signature.len(), 16);
+        assert!(shared_secret.verify(&message, &signature));
+        assert!(!shared_secret.verify(&[0x44; 20], &signature));
+
+        let shared_secret = SharedSecret::new(PinUvAuthProtocol::V2, &[0x11; 32]);
+        let signature = shared_secret.authenticate(&message);
+        assert_eq!(signature.len(), 32);
+        assert!(shared_secret.verify(&message, &signature));
+        assert!(!shared_secret.verify(&message, &signature[..16]));
+    }
+
+    #[test]
+    fn test_verify_pin_uv_auth_token() {
+        let token = [0x55; PIN_TOKEN_LENGTH];
+        let message = [0x66; 32];
+        let signature = hmac_256::<Sha256>(&token, &message);
+        assert!(verify_pin_uv_auth_token(
+            &token,
+            &message,
+            &signature[..16],
+            PinUvAuthProtocol::V1
+        ));
+        assert!(!verify_pin_uv_auth_token(
+            &token,
+            &message,
+            &signature,
+            PinUvAuthProtocol::V1
+        ));
+        assert!(verify_pin_uv_auth_token(
+            &token,
+            &message,
+            &signature,
+            PinUvAuthProtocol::V2
+        ));
+        assert!(!verify_pin_uv_auth_token(
+            &token,
+            &message,
+            &signature[..16],
+            PinUvAuthProtocol::V2
+        ));
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The interfaces between the CTAP implementation and the platform it runs on. The Tock
+// implementations live in the application, which wires them into CtapState and CtapHid. Other
+// firmwares and test harnesses provide their own.
+
+use super::hid::{ChannelID, HidPacket};
+use super::status_code::Ctap2StatusCode;
+use super::time::{ClockValue, Duration};
+use super::StorageRegions;
+use crate::embedded_flash::Storage;
+#[cfg(test)]
+use core::cell::Cell;
+#[cfg(feature = "debug_ctap")]
+use core::fmt;
+#[cfg(feature = "debug_ctap")]
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+// The flash that holds the persistent store, see PersistentStore::new for its requirements. It is
+// split in the regions of the store entries and of the counters. The regions themselves are a
+// persistent storage, e.g. of embedded_flash::BufferStorage in RAM.
+pub trait PersistentStorage {
+    type Flash: Storage;
+
+    fn into_regions(self) -> StorageRegions<Self::Flash>;
+}
+
+impl<S: Storage> PersistentStorage for StorageRegions<S> {
+    type Flash = S;
+
+    fn into_regions(self) -> StorageRegions<S> {
+        self
+    }
+}
+
+// A free running clock. CtapState reads it once per command, and CtapHid once per packet.
+pub trait Clock {
+    fn now(&self) -> ClockValue;
+}
+
+// A clock that only moves when tests set it.
+#[cfg(test)]
+pub struct TestClock {
+    now: Cell<ClockValue>,
+}
+
+#[cfg(test)]
+impl TestClock {
+    pub fn new(now: ClockValue) -> TestClock {
+        TestClock {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn set(&self, now: ClockValue) {
+        self.now.set(now);
+    }
+}
+
+#[cfg(test)]
+impl Clock for TestClock {
+    fn now(&self) -> ClockValue {
+        self.now.get()
+    }
+}
+
+// Waits for the user to confirm their presence, e.g. by touching a button. Implementations should
+// send UPNEEDED keepalives on the channel while waiting, see CtapHid::send_keepalive, and queue
+// the packets received meanwhile in CtapHid::pending_packets.
+pub trait UserPresence {
+    fn check(&self, cid: ChannelID) -> Result<(), Ctap2StatusCode>;
+
+    // Called before the long operations of a command, e.g. key generation or key agreement.
+    // Implementations should send a PROCESSING keepalive on the channel, and return
+    // CTAP2_ERR_KEEPALIVE_CANCEL if the host cancelled the command in the meantime.
+    fn processing(&self, _cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        Ok(())
+    }
+}
+
+impl<F> UserPresence for F
+where
+    F: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+{
+    fn check(&self, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        self(cid)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum SendOrRecvStatus {
+    Error,
+    Sent,
+    Received,
+}
+
+// Exchanges HID packets with the host, e.g. over USB.
+pub trait Transport {
+    // Waits for a packet from the host. Returns None if the timeout elapsed.
+    fn recv_with_timeout(
+        &self,
+        packet: &mut HidPacket,
+        timeout: Duration<isize>,
+    ) -> Option<SendOrRecvStatus>;
+
+    // Sends the packet, unless a packet from the host arrives first. In that case, the received
+    // packet overwrites the given one. Returns None if the timeout elapsed.
+    fn send_or_recv_with_timeout(
+        &self,
+        packet: &mut HidPacket,
+        timeout: Duration<isize>,
+    ) -> Option<SendOrRecvStatus>;
+}
+
+// Writes a line of debug output, e.g. to the console on Tock.
+#[cfg(feature = "debug_ctap")]
+pub type DebugWriter = fn(fmt::Arguments);
+
+#[cfg(feature = "debug_ctap")]
+static DEBUG_WRITER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+// The debug output of the CTAP implementation is dropped until the platform sets its writer.
+#[cfg(feature = "debug_ctap")]
+pub fn set_debug_writer(writer: DebugWriter) {
+    DEBUG_WRITER.store(writer as *mut (), Ordering::Relaxed);
+}
+
+#[cfg(feature = "debug_ctap")]
+pub(crate) fn debug_writeln(args: fmt::Arguments) {
+    let writer = DEBUG_WRITER.load(Ordering::Relaxed);
+    if !writer.is_null() {
+        // Safety: Only set_debug_writer stores non-null pointers, and they are DebugWriters.
+        let writer = unsafe { core::mem::transmute::<*mut (), DebugWriter>(writer) };
+        writer(args);
+    }
+}
@@ -14,7 +14,7 @@
 
 use super::data_formats::{
     CoseKey, PackedAttestationStatement, PublicKeyCredentialDescriptor,
-    PublicKeyCredentialUserEntity,
+    PublicKeyCredentialRpEntity, PublicKeyCredentialUserEntity,
 };
 use alloc::collections::BTreeMap;
 use alloc::string::String;
@@ -29,6 +29,9 @@ pub enum ResponseData {
     AuthenticatorGetInfo(AuthenticatorGetInfoResponse),
     AuthenticatorClientPin(Option<AuthenticatorClientPinResponse>),
     AuthenticatorReset,
+    AuthenticatorCredentialManagement(Option<AuthenticatorCredentialManagementResponse>),
+    AuthenticatorSelection,
+    AuthenticatorConfig,
 }
 
 impl From<ResponseData> for Option<cbor::Value> {
@@ -41,6 +44,10 @@ impl From<ResponseData> for Option<cbor::Value> {
             ResponseData::AuthenticatorClientPin(Some(data)) => Some(data.into()),
             ResponseData::AuthenticatorClientPin(None) => None,
             ResponseData::AuthenticatorReset => None,
+            ResponseData::AuthenticatorCredentialManagement(Some(data)) => Some(data.into()),
+            ResponseData::AuthenticatorCredentialManagement(None) => None,
+            ResponseData::AuthenticatorSelection => None,
+            ResponseData::AuthenticatorConfig => None,
         }
     }
 }
@@ -51,6 +58,8 @@ pub struct AuthenticatorMakeCredentialResponse {
     pub fmt: String,
     pub auth_data: Vec<u8>,
     pub att_stmt: PackedAttestationStatement,
+    // Only present if an enterprise attestation was returned.
+    pub ep_att: Option<bool>,
 }
 
 impl From<AuthenticatorMakeCredentialResponse> for cbor::Value {
@@ -59,12 +68,14 @@ impl From<AuthenticatorMakeCredentialResponse> for cbor::Value {
             fmt,
             auth_data,
             att_stmt,
+            ep_att,
         } = make_credential_response;
 
         cbor_map_options! {
             1 => fmt,
             2 => auth_data,
             3 => att_stmt,
+            4 => ep_att,
         }
     }
 }
@@ -109,6 +120,11 @@ pub struct AuthenticatorGetInfoResponse {
     pub options: Option<BTreeMap<String, bool>>,
     pub max_msg_size: Option<u64>,
     pub pin_protocols: Option<Vec<u64>>,
+    pub max_credential_count_in_list: Option<u64>,
+    pub force_pin_change: Option<bool>,
+    pub min_pin_length: Option<u64>,
+    pub max_rp_ids_for_set_min_pin_length: Option<u64>,
+    pub remaining_discoverable_credentials: Option<u64>,
 }
 
 impl From<AuthenticatorGetInfoResponse> for cbor::Value {
@@ -120,6 +136,11 @@ impl From<AuthenticatorGetInfoResponse> for cbor::Value {
             options,
             max_msg_size,
             pin_protocols,
+            max_credential_count_in_list,
+            force_pin_change,
+            min_pin_length,
+            max_rp_ids_for_set_min_pin_length,
+            remaining_discoverable_credentials,
         } = get_info_response;
 
         let options_cbor: Option<cbor::Value> = options.map(|options| {
@@ -137,6 +158,11 @@ impl From<AuthenticatorGetInfoResponse> for cbor::Value {
             4 => options_cbor,
             5 => max_msg_size,
             6 => pin_protocols.map(|vec| cbor_array_vec!(vec)),
+            7 => max_credential_count_in_list,
+            0x0C => force_pin_change,
+            0x0D => min_pin_length,
+            0x10 => max_rp_ids_for_set_min_pin_length,
+            0x14 => remaining_discoverable_credentials,
         }
     }
 }
@@ -165,6 +191,49 @@ impl From<AuthenticatorClientPinResponse> for cbor::Value {
     }
 }
 
+#[derive(Default)]
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct AuthenticatorCredentialManagementResponse {
+    pub existing_resident_credentials_count: Option<u64>,
+    pub max_possible_remaining_resident_credentials_count: Option<u64>,
+    pub rp: Option<PublicKeyCredentialRpEntity>,
+    pub rp_id_hash: Option<Vec<u8>>,
+    pub total_rps: Option<u64>,
+    pub user: Option<PublicKeyCredentialUserEntity>,
+    pub credential_id: Option<PublicKeyCredentialDescriptor>,
+    pub public_key: Option<CoseKey>,
+    pub total_credentials: Option<u64>,
+}
+
+impl From<AuthenticatorCredentialManagementResponse> for cbor::Value {
+    fn from(credential_management_response: AuthenticatorCredentialManagementResponse) -> Self {
+        let AuthenticatorCredentialManagementResponse {
+            existing_resident_credentials_count,
+            max_possible_remaining_resident_credentials_count,
+            rp,
+            rp_id_hash,
+            total_rps,
+            user,
+            credential_id,
+            public_key,
+            total_credentials,
+        } = credential_management_response;
+
+        cbor_map_options! {
+            0x01 => existing_resident_credentials_count,
+            0x02 => max_possible_remaining_resident_credentials_count,
+            0x03 => rp,
+            0x04 => rp_id_hash,
+            0x05 => total_rps,
+            0x06 => user,
+            0x07 => credential_id,
+            0x08 => public_key.map(|cose_key| cbor_map_btree!(cose_key.0)),
+            0x09 => total_credentials,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::super::data_formats::PackedAttestationStatement;
@@ -190,6 +259,7 @@ mod test {
             fmt: "packed".to_string(),
             auth_data: vec![0xAD],
             att_stmt,
+            ep_att: Some(true),
         };
         let response_cbor: Option<cbor::Value> =
             ResponseData::AuthenticatorMakeCredential(make_credential_response).into();
@@ -197,6 +267,7 @@ mod test {
             1 => "packed",
             2 => vec![0xAD],
             3 => cbor_packed_attestation_statement,
+            4 => true,
         };
         assert_eq!(response_cbor, Some(expected_cbor));
     }
@@ -228,6 +299,11 @@ mod test {
             options: None,
             max_msg_size: None,
             pin_protocols: None,
+            max_credential_count_in_list: None,
+            force_pin_change: None,
+            min_pin_length: None,
+            max_rp_ids_for_set_min_pin_length: None,
+            remaining_discoverable_credentials: None,
         };
         let response_cbor: Option<cbor::Value> =
             ResponseData::AuthenticatorGetInfo(get_info_response).into();
@@ -264,4 +340,40 @@ mod test {
         let response_cbor: Option<cbor::Value> = ResponseData::AuthenticatorReset.into();
         assert_eq!(response_cbor, None);
     }
+
+    #[test]
+    fn test_used_credential_management_into_cbor() {
+        let credential_management_response = AuthenticatorCredentialManagementResponse {
+            existing_resident_credentials_count: Some(1),
+            max_possible_remaining_resident_credentials_count: Some(149),
+            ..Default::default()
+        };
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorCredentialManagement(Some(credential_management_response))
+                .into();
+        let expected_cbor = cbor_map_options! {
+            1 => 1,
+            2 => 149,
+        };
+        assert_eq!(response_cbor, Some(expected_cbor));
+    }
+
+    #[test]
+    fn test_empty_credential_management_into_cbor() {
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorCredentialManagement(None).into();
+        assert_eq!(response_cbor, None);
+    }
+
+    #[test]
+    fn test_selection_into_cbor() {
+        let response_cbor: Option<cbor::Value> = ResponseData::AuthenticatorSelection.into();
+        assert_eq!(response_cbor, None);
+    }
+
+    #[test]
+    fn test_config_into_cbor() {
+        let response_cbor: Option<cbor::Value> = ResponseData::AuthenticatorConfig.into();
+        assert_eq!(response_cbor, None);
+    }
 }
@@ -60,6 +60,8 @@ pub enum Ctap2StatusCode {
     CTAP2_ERR_ACTION_TIMEOUT = 0x3A,
     CTAP2_ERR_UP_REQUIRED = 0x3B,
     CTAP2_ERR_UV_BLOCKED = 0x3C,
+    CTAP2_ERR_INVALID_SUBCOMMAND = 0x3E,
+    CTAP2_ERR_UNAUTHORIZED_PERMISSION = 0x40,
     CTAP1_ERR_OTHER = 0x7F,
     CTAP2_ERR_SPEC_LAST = 0xDF,
     CTAP2_ERR_EXTENSION_FIRST = 0xE0,
@@ -67,5 +69,7 @@ pub enum Ctap2StatusCode {
     // CTAP2_ERR_VENDOR_FIRST = 0xF0,
     CTAP2_ERR_VENDOR_RESPONSE_TOO_LONG = 0xF0,
     CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR = 0xF1,
+    CTAP2_ERR_VENDOR_INTERNAL_ERROR = 0xF2,
+    CTAP2_ERR_VENDOR_HARDWARE_FAILURE = 0xF3,
     CTAP2_ERR_VENDOR_LAST = 0xFF,
 }
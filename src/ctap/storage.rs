@@ -12,21 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::crypto::aes256::{DecryptionKey, EncryptionKey};
+use crate::crypto::cbc::{cbc_decrypt, cbc_encrypt};
+use crate::crypto::hkdf::hkdf_256;
+use crate::crypto::hmac::{hmac_256, verify_hmac_256};
 use crate::crypto::rng256::Rng256;
-use crate::ctap::data_formats::PublicKeyCredentialSource;
+use crate::crypto::sha256::Sha256;
+use crate::crypto::Hash256;
+use crate::ctap::data_formats::{
+    read_byte_string, read_text_string, read_unsigned, PrivateKey, PublicKeyCredentialSource,
+    PublicKeyCredentialSourceField, SignatureAlgorithm,
+};
 use crate::ctap::status_code::Ctap2StatusCode;
 use crate::ctap::PIN_AUTH_LENGTH;
+#[cfg(feature = "std")]
+use crate::embedded_flash::BufferStorage;
+use crate::embedded_flash::{
+    self, Storage, StoreConfig, StoreEntry, StoreError, StoreIndex, StoreOperation,
+};
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::string::String;
 use alloc::vec::Vec;
-use core::convert::TryInto;
-use ctap2::embedded_flash::{self, StoreConfig, StoreEntry, StoreError, StoreIndex};
+use byteorder::{BigEndian, ByteOrder};
+use core::convert::{TryFrom, TryInto};
 
-#[cfg(test)]
-type Storage = embedded_flash::BufferStorage;
-#[cfg(not(test))]
-type Storage = embedded_flash::SyscallStorage;
-
-// Those constants may be modified before compilation to tune the behavior of the key.
+// Those constants are set at build time to tune the behavior of the key. They default to 20 pages
+// and 150 residential keys and are overridden with the OPENSK_NUM_PAGES and
+// OPENSK_MAX_RESIDENTIAL_KEYS environment variables, see build.rs.
 //
 // The number of pages should be at least 2 and at most what the flash can hold. There should be no
 // reason to put a small number here, except that the latency of flash operations depends on the
@@ -34,28 +46,72 @@ type Storage = embedded_flash::SyscallStorage;
 // operation. The rule of thumb is 3.5ms per additional page.
 //
 // Limiting the number of residential keys permits to ensure a minimum number of counter increments.
-// Let:
+// The global signature counter and the PIN retries are counters in their own pages, see below, so
+// only the signature counters of resident credentials wear the store. Let:
 // - P the number of pages (NUM_PAGES)
 // - K the maximum number of residential keys (MAX_SUPPORTED_RESIDENTIAL_KEYS)
 // - S the maximum size of a residential key (about 500)
 // - C the number of erase cycles (10000)
 // - I the minimum number of counter increments
 //
-// We have: I = ((P - 1) * 4092 - K * S) / 12 * C
+// We have: I = ((P - 1) * 4092 - K * S) / S * C
 //
-// With P=20 and K=150, we have I > 2M which is enough for 500 increments per day for 10 years.
-const NUM_PAGES: usize = 20;
-const MAX_SUPPORTED_RESIDENTIAL_KEYS: usize = 150;
+// With P=20 and K=150, we have I > 50K for a full store. Each key that is not stored leaves room
+// for 200K more increments.
+include!(concat!(env!("OUT_DIR"), "/storage_config.rs"));
 
 // List of tags. They should all be unique. And there should be less than NUM_TAGS.
 const TAG_CREDENTIAL: usize = 0;
+// Only found in stores before schema version 3, which are migrated to counters.
 const GLOBAL_SIGNATURE_COUNTER: usize = 1;
 const MASTER_KEYS: usize = 2;
 const PIN_HASH: usize = 3;
 const PIN_RETRIES: usize = 4;
-const NUM_TAGS: usize = 5;
+const MIN_PIN_LENGTH: usize = 5;
+const MIN_PIN_LENGTH_RP_IDS: usize = 6;
+const CONFIG_FLAGS: usize = 7;
+const TAG_SCHEMA_VERSION: usize = 8;
+const NUM_TAGS: usize = 9;
+// Stores before schema version 4 had 8 tags. Their pages keep tags of 3 bits until compacted.
+const NUM_LEGACY_TAGS: usize = 8;
+
+// The counters live in the pages after the store, 2 pages each, see embedded_flash::Counters. A
+// counter page takes about 2000 increments per erase, so each counter takes more than 40M
+// increments. The global signature counter increases by 8.5 on average per signature.
+const SIGNATURE_COUNTER: usize = 0;
+// Counts the wrong PINs. The PinRetries entry holds its value when the retries were last reset.
+const PIN_FAILURES: usize = 1;
+const NUM_COUNTERS: usize = 2;
+const NUM_COUNTER_PAGES: usize = 2 * NUM_COUNTERS;
 
 const MAX_PIN_RETRIES: u8 = 6;
+const DEFAULT_MIN_PIN_LENGTH: u8 = 4;
+// The maximum number of RP IDs that can see the minimum PIN length. The RP IDs are stored in a
+// single entry, so this also bounds its size.
+pub const MAX_RP_IDS_LENGTH: usize = 8;
+
+// The boolean settings of authenticatorConfig share a single entry, to keep the number of tags low.
+const ALWAYS_UV_FLAG: u8 = 0x01;
+const ENTERPRISE_ATTESTATION_FLAG: u8 = 0x02;
+const FORCE_PIN_CHANGE_FLAG: u8 = 0x04;
+
+// The version of the layout of the store entries. Stores of older versions are migrated when the
+// persistent store is opened, see PersistentStore::migrate. The versions are:
+// 0. Credentials are CBOR arrays, whose secrets may be sealed.
+// 1. Credentials are CBOR maps with sealed secrets.
+// 2. Credentials have their own signature counter.
+// 3. The global signature counter and the PIN retries are counters, outside of the store entries.
+// 4. The schema version has its own entry, instead of following the master keys.
+const SCHEMA_VERSION: u8 = 4;
+// The master keys entry holds the encryption key and the hmac key. From version 1 to 3, they were
+// followed by the schema version. Stores of version 0 have no schema version.
+const MASTER_KEYS_LENGTH: usize = 64;
+
+// The private key and CredRandom of stored credentials are sealed, see seal_credential. The sealed
+// secrets consist of a 16 byte IV, the encrypted blocks and a 32 byte HMAC. There are 3 blocks for
+// the private key and its algorithm, and 2 more if the credential has a CredRandom.
+const SEALED_SECRETS_SIZE: usize = 96;
+const SEALED_SECRETS_SIZE_WITH_CRED_RANDOM: usize = 128;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 enum Key {
@@ -70,6 +126,10 @@ enum Key {
     MasterKeys,
     PinHash,
     PinRetries,
+    MinPinLength,
+    MinPinLengthRpIds,
+    ConfigFlags,
+    SchemaVersion,
 }
 
 pub struct MasterKeys<'a> {
@@ -77,6 +137,27 @@ pub struct MasterKeys<'a> {
     pub hmac: &'a [u8; 32],
 }
 
+// The keys sealing the secrets of stored credentials. They are derived from the master keys, but
+// differ from the keys of credential IDs, so that sealed secrets are never accepted as key handles.
+//
+// The seal is not bound to the PIN. The authenticator only sees a hash of the PIN, which is kept in
+// the same flash, so it would not help against a dump. And credentials that don't require user
+// verification must be usable without the PIN.
+struct SealingKeys {
+    encryption: EncryptionKey,
+    hmac: [u8; 32],
+}
+
+impl SealingKeys {
+    fn new(master_keys: &MasterKeys) -> SealingKeys {
+        let encryption = hkdf_256::<Sha256>(master_keys.encryption, &[], b"credential encryption");
+        SealingKeys {
+            encryption: EncryptionKey::new(&encryption),
+            hmac: hkdf_256::<Sha256>(master_keys.hmac, &[], b"credential authentication"),
+        }
+    }
+}
+
 struct Config;
 
 impl StoreConfig for Config {
@@ -86,10 +167,14 @@ impl StoreConfig for Config {
         NUM_TAGS
     }
 
+    fn num_legacy_tags(&self) -> usize {
+        NUM_LEGACY_TAGS
+    }
+
     fn keys(&self, entry: StoreEntry, mut add: impl FnMut(Key)) {
         match entry.tag {
             TAG_CREDENTIAL => {
-                let credential = match deserialize_credential(entry.data) {
+                let credential = match deserialize_credential_header(entry.data) {
                     None => {
                         debug_assert!(false);
                         return;
@@ -98,6 +183,11 @@ impl StoreConfig for Config {
                 };
                 add(Key::Credential {
                     rp_id: Some(credential.rp_id.clone()),
+                    credential_id: Some(credential.credential_id.clone()),
+                    user_handle: None,
+                });
+                add(Key::Credential {
+                    rp_id: None,
                     credential_id: Some(credential.credential_id),
                     user_handle: None,
                 });
@@ -121,87 +211,323 @@ impl StoreConfig for Config {
             MASTER_KEYS => add(Key::MasterKeys),
             PIN_HASH => add(Key::PinHash),
             PIN_RETRIES => add(Key::PinRetries),
+            MIN_PIN_LENGTH => add(Key::MinPinLength),
+            MIN_PIN_LENGTH_RP_IDS => add(Key::MinPinLengthRpIds),
+            CONFIG_FLAGS => add(Key::ConfigFlags),
+            TAG_SCHEMA_VERSION => add(Key::SchemaVersion),
             _ => debug_assert!(false),
         }
     }
+
+    // Deleted credentials could still be unsealed with the master keys, and deleted PIN hashes give
+    // away old PINs.
+    fn is_sensitive(&self, tag: usize) -> bool {
+        match tag {
+            TAG_CREDENTIAL | MASTER_KEYS | PIN_HASH => true,
+            _ => false,
+        }
+    }
+}
+
+pub struct PersistentStore<S: Storage> {
+    store: embedded_flash::Store<S, Config>,
+    counters: embedded_flash::Counters<S>,
+    // Greater than the creation order of all stored credentials.
+    next_creation_order: u64,
 }
 
-pub struct PersistentStore {
-    store: embedded_flash::Store<Storage, Config>,
+/// The flash of the persistent store, split in the pages of the store entries and the pages of the
+/// counters that follow them.
+pub struct StorageRegions<S: Storage> {
+    pub store: S,
+    pub counters: S,
 }
 
 const PAGE_SIZE: usize = 0x1000;
-const STORE_SIZE: usize = NUM_PAGES * PAGE_SIZE;
+pub const STORE_SIZE: usize = (NUM_PAGES + NUM_COUNTER_PAGES) * PAGE_SIZE;
+// The size of the counters at the end of the persistent store.
+pub const COUNTERS_SIZE: usize = NUM_COUNTER_PAGES * PAGE_SIZE;
 
-#[cfg(not(test))]
-#[link_section = ".app_state"]
-static STORE: [u8; STORE_SIZE] = [0xff; STORE_SIZE];
+/// Returns a fresh storage in RAM for the persistent store.
+#[cfg(feature = "std")]
+pub fn new_buffer_storage() -> StorageRegions<BufferStorage> {
+    new_buffer_storage_with_content(vec![0xff; STORE_SIZE].into_boxed_slice())
+}
 
-impl PersistentStore {
-    /// Gives access to the persistent store.
-    ///
-    /// # Safety
-    ///
-    /// This should be at most one instance of persistent store per program lifetime.
-    pub fn new(rng: &mut impl Rng256) -> PersistentStore {
-        #[cfg(not(test))]
-        let storage = PersistentStore::new_prod_storage();
-        #[cfg(test)]
-        let storage = PersistentStore::new_test_storage();
-        let mut store = PersistentStore {
-            store: embedded_flash::Store::new(storage, Config).unwrap(),
-        };
-        store.init(rng);
-        store
+/// Returns a storage in RAM for the persistent store, initialized with the given content.
+///
+/// The content is typically what `PersistentStore::content` returned in a previous run. It must be
+/// STORE_SIZE bytes long, a fresh store is all 0xff.
+#[cfg(feature = "std")]
+pub fn new_buffer_storage_with_content(content: Box<[u8]>) -> StorageRegions<BufferStorage> {
+    assert_eq!(
+        content.len(),
+        STORE_SIZE,
+        "The store content does not match the store size of this build"
+    );
+    let options = embedded_flash::BufferOptions {
+        word_size: 4,
+        page_size: PAGE_SIZE,
+        max_word_writes: 2,
+        max_page_erases: 10000,
+        strict_write: true,
+    };
+    let mut store = content.into_vec();
+    let counters = store.split_off(STORE_SIZE - COUNTERS_SIZE);
+    StorageRegions {
+        store: BufferStorage::new(store.into_boxed_slice(), options),
+        counters: BufferStorage::new(counters.into_boxed_slice(), options),
     }
+}
 
-    #[cfg(not(test))]
-    fn new_prod_storage() -> Storage {
-        let store = unsafe {
-            // Safety: The store cannot alias because this function is called only once.
-            core::slice::from_raw_parts_mut(STORE.as_ptr() as *mut u8, STORE_SIZE)
-        };
-        unsafe {
-            // Safety: The store is in a writeable flash region.
-            Storage::new(store).unwrap()
-        }
+#[cfg(feature = "std")]
+impl PersistentStore<BufferStorage> {
+    /// Returns a copy of the content of the store, to restore it with
+    /// `new_buffer_storage_with_content`.
+    pub fn content(&self) -> Box<[u8]> {
+        let mut content = self.store.take_snapshot().into_vec();
+        content.extend_from_slice(&self.counters.take_snapshot());
+        content.into_boxed_slice()
     }
+}
 
-    #[cfg(test)]
-    fn new_test_storage() -> Storage {
-        let store = vec![0xff; STORE_SIZE].into_boxed_slice();
-        let options = embedded_flash::BufferOptions {
-            word_size: 4,
-            page_size: PAGE_SIZE,
-            max_word_writes: 2,
-            max_page_erases: 10000,
-            strict_write: true,
+impl<S: Storage> PersistentStore<S> {
+    /// Gives access to the persistent store in the given storage.
+    ///
+    /// The storage must be STORE_SIZE bytes long, of which the last COUNTERS_SIZE bytes are the
+    /// counters. There should be at most one instance of persistent store per storage.
+    ///
+    /// Fails if the storage is not supported or fails, or if the store can't be initialized or
+    /// migrated, e.g. because it is full.
+    pub fn new(
+        rng: &mut impl Rng256,
+        storage: StorageRegions<S>,
+    ) -> Result<PersistentStore<S>, Ctap2StatusCode> {
+        let mut store = PersistentStore {
+            store: embedded_flash::Store::new(storage.store, Config)
+                .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)?,
+            counters: embedded_flash::Counters::new(storage.counters, NUM_COUNTERS)
+                .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)?,
+            next_creation_order: 0,
         };
-        Storage::new(store, options)
+        // Without master keys, no credential can be created or used.
+        store.init(rng)?;
+        store.next_creation_order = store.max_creation_order().map_or(0, |order| order + 1);
+        Ok(store)
     }
 
-    fn init(&mut self, rng: &mut impl Rng256) {
+    fn init(&mut self, rng: &mut impl Rng256) -> Result<(), Ctap2StatusCode> {
         if self.store.find_one(&Key::MasterKeys).is_none() {
             let master_encryption_key = rng.gen_uniform_u8x32();
             let master_hmac_key = rng.gen_uniform_u8x32();
-            let mut master_keys = Vec::with_capacity(64);
+            let mut master_keys = Vec::with_capacity(MASTER_KEYS_LENGTH);
             master_keys.extend_from_slice(&master_encryption_key);
             master_keys.extend_from_slice(&master_hmac_key);
-            self.store
-                .insert(StoreEntry {
+            let schema_version = [SCHEMA_VERSION];
+            self.store.transaction(&[
+                StoreOperation::Insert(StoreEntry {
                     tag: MASTER_KEYS,
                     data: &master_keys,
-                })
-                .unwrap();
+                }),
+                self.set_entry_operation(&Key::SchemaVersion, TAG_SCHEMA_VERSION, &schema_version),
+            ])?;
         }
         if self.store.find_one(&Key::PinRetries).is_none() {
-            self.store
-                .insert(StoreEntry {
+            let pin_failures = self.counters.get(PIN_FAILURES)?;
+            self.store.insert(StoreEntry {
+                tag: PIN_RETRIES,
+                data: &pin_failures.to_be_bytes(),
+            })?;
+        }
+        self.migrate(rng)
+    }
+
+    // Brings a store written by an older firmware to the current schema version. A power loss may
+    // interrupt the migration before the new version is written, so that it starts over at the
+    // next boot. Each step must therefore accept its own output. Stores of a newer version are
+    // left as they are: their credentials can still be read, because unknown fields are ignored.
+    fn migrate(&mut self, rng: &mut impl Rng256) -> Result<(), Ctap2StatusCode> {
+        let schema_version = self.schema_version()?;
+        if schema_version < 1 {
+            self.migrate_credentials_to_maps(rng)?;
+        }
+        if schema_version < 2 {
+            self.migrate_credential_signature_counters()?;
+        }
+        if schema_version < 3 {
+            self.migrate_counters()?;
+        }
+        if schema_version < SCHEMA_VERSION {
+            self.set_schema_version(SCHEMA_VERSION)?;
+            // Older versions may have left unsealed private keys in deleted entries.
+            self.store.shred()?;
+        }
+        Ok(())
+    }
+
+    // Rewrites credentials stored as arrays to sealed maps. The replaced entries are shredded once
+    // the migration is done.
+    fn migrate_credentials_to_maps(
+        &mut self,
+        rng: &mut impl Rng256,
+    ) -> Result<(), Ctap2StatusCode> {
+        let keys = self.sealing_keys()?;
+        self.update_credentials(
+            |credential| credential.is_legacy,
+            |data| match deserialize_credential(&keys, data) {
+                None => Ok(None),
+                Some(credential) => seal_credential(rng, &keys, credential).map(Some),
+            },
+        )
+    }
+
+    // Resident credentials used to share the global signature counter. They start their own
+    // counter at its value, so that relying parties still see it increase.
+    fn migrate_credential_signature_counters(&mut self) -> Result<(), Ctap2StatusCode> {
+        let signature_counter = match self.legacy_global_signature_counter() {
+            Some((_, signature_counter)) => signature_counter,
+            None => self.global_signature_counter()?,
+        };
+        self.update_credentials(
+            |credential| !credential.is_legacy && credential.signature_counter.is_none(),
+            |data| Ok(set_signature_counter(data, signature_counter)),
+        )
+    }
+
+    // Moves the global signature counter and the PIN retries from their entries to the counters.
+    // Each counter is only incremented up to the value of its entry, so that the migration can
+    // start over.
+    fn migrate_counters(&mut self) -> Result<(), Ctap2StatusCode> {
+        if let Some((index, signature_counter)) = self.legacy_global_signature_counter() {
+            let counter = self.counters.get(SIGNATURE_COUNTER)?;
+            if counter < signature_counter {
+                self.counters
+                    .incr(SIGNATURE_COUNTER, signature_counter - counter)?;
+            }
+            self.store.delete(index)?;
+        }
+        let legacy_pin_retries = match self.store.find_one(&Key::PinRetries) {
+            Some((index, entry)) if entry.data.len() == 1 => Some((index, entry.data[0])),
+            _ => None,
+        };
+        if let Some((index, pin_retries)) = legacy_pin_retries {
+            let wrong_pins = u32::from(MAX_PIN_RETRIES.saturating_sub(pin_retries));
+            let pin_failures = self.counters.get(PIN_FAILURES)?;
+            if pin_failures < wrong_pins {
+                self.counters
+                    .incr(PIN_FAILURES, wrong_pins - pin_failures)?;
+            }
+            let reset_pin_failures = core::cmp::max(pin_failures, wrong_pins) - wrong_pins;
+            self.store.replace(
+                index,
+                StoreEntry {
                     tag: PIN_RETRIES,
-                    data: &[MAX_PIN_RETRIES],
-                })
-                .unwrap();
+                    data: &reset_pin_failures.to_be_bytes(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    // Returns the global signature counter of stores before schema version 3, which kept it in an
+    // entry, and the index of that entry.
+    fn legacy_global_signature_counter(&self) -> Option<(StoreIndex, u32)> {
+        self.store
+            .find_one(&Key::GlobalSignatureCounter)
+            .map(|(index, entry)| (index, u32::from_ne_bytes(*array_ref!(entry.data, 0, 4))))
+    }
+
+    // Replaces each credential selected by the filter with the output of the update, unless it
+    // returns None.
+    fn update_credentials(
+        &mut self,
+        filter: impl Fn(&CredentialHeader) -> bool,
+        mut update: impl FnMut(&[u8]) -> Result<Option<Vec<u8>>, Ctap2StatusCode>,
+    ) -> Result<(), Ctap2StatusCode> {
+        let credential_ids: Vec<Vec<u8>> = self
+            .store
+            .find_all(&Key::Credential {
+                rp_id: None,
+                credential_id: None,
+                user_handle: None,
+            })
+            .filter_map(|(_, entry)| deserialize_credential_header(entry.data))
+            .filter(filter)
+            .map(|credential| credential.credential_id)
+            .collect();
+        for credential_id in credential_ids {
+            let key = Key::Credential {
+                rp_id: None,
+                credential_id: Some(credential_id),
+                user_handle: None,
+            };
+            let (index, data) = match self.store.find_one(&key) {
+                None => continue,
+                Some((index, entry)) => match update(entry.data)? {
+                    None => continue,
+                    Some(data) => (index, data),
+                },
+            };
+            self.store.replace(
+                index,
+                StoreEntry {
+                    tag: TAG_CREDENTIAL,
+                    data: &data,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn schema_version(&self) -> Result<u8, Ctap2StatusCode> {
+        if let Some((_, entry)) = self.store.find_one(&Key::SchemaVersion) {
+            return entry
+                .data
+                .first()
+                .cloned()
+                .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
+        }
+        // Before version 4, the schema version followed the master keys.
+        let (_, entry) = self
+            .store
+            .find_one(&Key::MasterKeys)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        Ok(entry.data.get(MASTER_KEYS_LENGTH).cloned().unwrap_or(0))
+    }
+
+    // Also drops the schema version following the master keys in older stores, in the same
+    // transaction, so that a power loss can't lose the version.
+    fn set_schema_version(&mut self, schema_version: u8) -> Result<(), Ctap2StatusCode> {
+        let (index, entry) = self
+            .store
+            .find_one(&Key::MasterKeys)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        if entry.data.len() < MASTER_KEYS_LENGTH {
+            return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
+        }
+        let has_legacy_version = entry.data.len() > MASTER_KEYS_LENGTH;
+        let master_keys = entry.data[..MASTER_KEYS_LENGTH].to_vec();
+        let schema_version = [schema_version];
+        let mut operations = vec![self.set_entry_operation(
+            &Key::SchemaVersion,
+            TAG_SCHEMA_VERSION,
+            &schema_version,
+        )];
+        if has_legacy_version {
+            operations.push(StoreOperation::Replace(
+                index,
+                StoreEntry {
+                    tag: MASTER_KEYS,
+                    data: &master_keys,
+                },
+            ));
         }
+        self.store.transaction(&operations)?;
+        Ok(())
+    }
+
+    fn sealing_keys(&self) -> Result<SealingKeys, Ctap2StatusCode> {
+        Ok(SealingKeys::new(&self.master_keys()?))
     }
 
     pub fn find_credential(
@@ -216,14 +542,31 @@ impl PersistentStore {
         };
         let (_, entry) = self.store.find_one(&key)?;
         debug_assert_eq!(entry.tag, TAG_CREDENTIAL);
-        let result = deserialize_credential(entry.data);
+        let keys = self.sealing_keys().ok()?;
+        let result = deserialize_credential(&keys, entry.data);
+        debug_assert!(result.is_some());
+        result
+    }
+
+    pub fn find_credential_by_id(&self, credential_id: &[u8]) -> Option<PublicKeyCredentialSource> {
+        let key = Key::Credential {
+            rp_id: None,
+            credential_id: Some(credential_id.into()),
+            user_handle: None,
+        };
+        let (_, entry) = self.store.find_one(&key)?;
+        debug_assert_eq!(entry.tag, TAG_CREDENTIAL);
+        let keys = self.sealing_keys().ok()?;
+        let result = deserialize_credential(&keys, entry.data);
         debug_assert!(result.is_some());
         result
     }
 
+    // Sets the creation order of the credential, so that it comes after all stored credentials.
     pub fn store_credential(
         &mut self,
-        credential: PublicKeyCredentialSource,
+        rng: &mut impl Rng256,
+        mut credential: PublicKeyCredentialSource,
     ) -> Result<(), Ctap2StatusCode> {
         let key = Key::Credential {
             rp_id: Some(credential.rp_id.clone()),
@@ -234,7 +577,8 @@ impl PersistentStore {
         if old_entry.is_none() && self.count_credentials() >= MAX_SUPPORTED_RESIDENTIAL_KEYS {
             return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
         }
-        let credential = serialize_credential(credential)?;
+        credential.creation_order = self.next_creation_order;
+        let credential = seal_credential(rng, &self.sealing_keys()?, credential)?;
         let new_entry = StoreEntry {
             tag: TAG_CREDENTIAL,
             data: &credential,
@@ -243,13 +587,64 @@ impl PersistentStore {
             None => self.store.insert(new_entry)?,
             Some((index, old_entry)) => {
                 debug_assert_eq!(old_entry.tag, TAG_CREDENTIAL);
-                self.store.replace(index, new_entry)?
+                self.store.replace(index, new_entry)?;
+                self.store.shred_key(&key)?;
             }
         };
+        self.next_creation_order += 1;
+        Ok(())
+    }
+
+    // Replaces the stored credential with the same ID, e.g. to update its user information. Keeps
+    // its creation order, so that the order of the credentials doesn't change.
+    pub fn update_credential(
+        &mut self,
+        rng: &mut impl Rng256,
+        mut credential: PublicKeyCredentialSource,
+    ) -> Result<(), Ctap2StatusCode> {
+        let key = Key::Credential {
+            rp_id: None,
+            credential_id: Some(credential.credential_id.clone()),
+            user_handle: None,
+        };
+        let (index, old_entry) = self
+            .store
+            .find_one(&key)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?;
+        debug_assert_eq!(old_entry.tag, TAG_CREDENTIAL);
+        let old_header = deserialize_credential_header(old_entry.data)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        credential.creation_order = old_header.creation_order;
+        let credential = seal_credential(rng, &self.sealing_keys()?, credential)?;
+        self.store.replace(
+            index,
+            StoreEntry {
+                tag: TAG_CREDENTIAL,
+                data: &credential,
+            },
+        )?;
+        self.store.shred_key(&key)?;
         Ok(())
     }
 
+    // Returns the greatest creation order of the stored credentials, if any.
+    fn max_creation_order(&self) -> Option<u64> {
+        self.store
+            .find_all(&Key::Credential {
+                rp_id: None,
+                credential_id: None,
+                user_handle: None,
+            })
+            .filter_map(|(_, entry)| deserialize_credential_header(entry.data))
+            .map(|credential| credential.creation_order)
+            .max()
+    }
+
     pub fn filter_credential(&self, rp_id: &str) -> Vec<PublicKeyCredentialSource> {
+        let keys = match self.sealing_keys() {
+            Ok(keys) => keys,
+            Err(_) => return Vec::new(),
+        };
         self.store
             .find_all(&Key::Credential {
                 rp_id: Some(rp_id.into()),
@@ -258,13 +653,51 @@ impl PersistentStore {
             })
             .filter_map(|(_, entry)| {
                 debug_assert_eq!(entry.tag, TAG_CREDENTIAL);
-                let credential = deserialize_credential(entry.data);
+                let credential = deserialize_credential(&keys, entry.data);
                 debug_assert!(credential.is_some());
                 credential
             })
             .collect()
     }
 
+    pub fn delete_credential(&mut self, credential_id: &[u8]) -> Result<(), Ctap2StatusCode> {
+        let key = Key::Credential {
+            rp_id: None,
+            credential_id: Some(credential_id.into()),
+            user_handle: None,
+        };
+        let (index, entry) = self
+            .store
+            .find_one(&key)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?;
+        debug_assert_eq!(entry.tag, TAG_CREDENTIAL);
+        self.store.delete(index)?;
+        // Also shreds the copies left by signature counter updates, but not those of other
+        // credentials, which are still alive.
+        self.store.shred_key(&key)?;
+        Ok(())
+    }
+
+    // Returns the relying party IDs of all stored credentials, each one only once.
+    pub fn list_rp_ids(&self) -> Vec<String> {
+        let rp_ids: BTreeSet<String> = self
+            .store
+            .find_all(&Key::Credential {
+                rp_id: None,
+                credential_id: None,
+                user_handle: None,
+            })
+            .filter_map(|(_, entry)| {
+                debug_assert_eq!(entry.tag, TAG_CREDENTIAL);
+                let credential = deserialize_credential_header(entry.data);
+                debug_assert!(credential.is_some());
+                credential
+            })
+            .map(|credential| credential.rp_id)
+            .collect();
+        rp_ids.into_iter().collect()
+    }
+
     pub fn count_credentials(&self) -> usize {
         self.store
             .find_all(&Key::Credential {
@@ -275,51 +708,72 @@ impl PersistentStore {
             .count()
     }
 
-    pub fn global_signature_counter(&self) -> u32 {
-        self.store
-            .find_one(&Key::GlobalSignatureCounter)
-            .map_or(0, |(_, entry)| {
-                u32::from_ne_bytes(*array_ref!(entry.data, 0, 4))
-            })
+    pub fn remaining_credentials(&self) -> usize {
+        MAX_SUPPORTED_RESIDENTIAL_KEYS.saturating_sub(self.count_credentials())
     }
 
-    pub fn incr_global_signature_counter(&mut self) {
-        let mut buffer = [0; core::mem::size_of::<u32>()];
-        match self.store.find_one(&Key::GlobalSignatureCounter) {
-            None => {
-                buffer.copy_from_slice(&1u32.to_ne_bytes());
-                self.store
-                    .insert(StoreEntry {
-                        tag: GLOBAL_SIGNATURE_COUNTER,
-                        data: &buffer,
-                    })
-                    .unwrap();
-            }
-            Some((index, entry)) => {
-                let value = u32::from_ne_bytes(*array_ref!(entry.data, 0, 4));
-                // In hopes that servers handle the wrapping gracefully.
-                buffer.copy_from_slice(&value.wrapping_add(1).to_ne_bytes());
-                self.store
-                    .replace(
-                        index,
-                        StoreEntry {
-                            tag: GLOBAL_SIGNATURE_COUNTER,
-                            data: &buffer,
-                        },
-                    )
-                    .unwrap();
-            }
-        }
+    pub fn global_signature_counter(&self) -> Result<u32, Ctap2StatusCode> {
+        Ok(self.counters.get(SIGNATURE_COUNTER)?)
     }
 
-    pub fn master_keys(&self) -> MasterKeys {
+    // Increments the global signature counter by the given amount and returns its new value.
+    pub fn incr_global_signature_counter(
+        &mut self,
+        increment: u32,
+    ) -> Result<u32, Ctap2StatusCode> {
+        Ok(self.counters.incr(SIGNATURE_COUNTER, increment)?)
+    }
+
+    // Increments the signature counter of a resident credential and returns its new value. The
+    // counter is part of the credential entry, so a single replace updates both.
+    pub fn incr_credential_signature_counter(
+        &mut self,
+        credential_id: &[u8],
+    ) -> Result<u32, Ctap2StatusCode> {
+        let key = Key::Credential {
+            rp_id: None,
+            credential_id: Some(credential_id.into()),
+            user_handle: None,
+        };
+        let (index, signature_counter, data) = {
+            let (index, entry) = self
+                .store
+                .find_one(&key)
+                .ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?;
+            debug_assert_eq!(entry.tag, TAG_CREDENTIAL);
+            let credential = deserialize_credential_header(entry.data)
+                .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+            // In hopes that servers handle the wrapping gracefully.
+            let signature_counter = credential.signature_counter.unwrap_or(0).wrapping_add(1);
+            let data = set_signature_counter(entry.data, signature_counter)
+                .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+            (index, signature_counter, data)
+        };
+        self.store.replace(
+            index,
+            StoreEntry {
+                tag: TAG_CREDENTIAL,
+                data: &data,
+            },
+        )?;
+        Ok(signature_counter)
+    }
+
+    pub fn master_keys(&self) -> Result<MasterKeys, Ctap2StatusCode> {
         // We have as invariant that there is always exactly one MasterKeys entry in the store.
-        let (_, entry) = self.store.find_one(&Key::MasterKeys).unwrap();
+        let (_, entry) = self
+            .store
+            .find_one(&Key::MasterKeys)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
         let data = entry.data;
-        // And this entry is well formed: the encryption key followed by the hmac key.
+        // And this entry is well formed: the encryption key followed by the hmac key, and the
+        // schema version from version 1 to 3.
+        if data.len() < MASTER_KEYS_LENGTH {
+            return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
+        }
         let encryption = array_ref!(data, 0, 32);
         let hmac = array_ref!(data, 32, 32);
-        MasterKeys { encryption, hmac }
+        Ok(MasterKeys { encryption, hmac })
     }
 
     pub fn pin_hash(&self) -> Option<&[u8; PIN_AUTH_LENGTH]> {
@@ -328,69 +782,196 @@ impl PersistentStore {
             .map(|(_, entry)| array_ref!(entry.data, 0, PIN_AUTH_LENGTH))
     }
 
-    pub fn set_pin_hash(&mut self, pin_hash: &[u8; PIN_AUTH_LENGTH]) {
-        let entry = StoreEntry {
-            tag: PIN_HASH,
-            data: pin_hash,
-        };
-        match self.store.find_one(&Key::PinHash) {
-            None => self.store.insert(entry).unwrap(),
-            Some((index, _)) => {
-                self.store.replace(index, entry).unwrap();
-            }
+    // Also resets the PIN retries and clears the force PIN change flag. All of it is written in one
+    // transaction, so a power loss can't leave a new PIN with stale retries or a pending change.
+    pub fn set_pin_hash(
+        &mut self,
+        pin_hash: &[u8; PIN_AUTH_LENGTH],
+    ) -> Result<(), Ctap2StatusCode> {
+        let pin_failures = self.counters.get(PIN_FAILURES)?.to_be_bytes();
+        let config_flags = [self.config_flags() & !FORCE_PIN_CHANGE_FLAG];
+        let mut operations = vec![self.set_entry_operation(&Key::PinHash, PIN_HASH, pin_hash)];
+        if self.pin_retries()? < MAX_PIN_RETRIES {
+            operations.push(self.set_entry_operation(&Key::PinRetries, PIN_RETRIES, &pin_failures));
+        }
+        if config_flags[0] != self.config_flags() {
+            operations.push(self.set_entry_operation(
+                &Key::ConfigFlags,
+                CONFIG_FLAGS,
+                &config_flags,
+            ));
         }
+        self.store.transaction(&operations)?;
+        self.store.shred_key(&Key::PinHash)?;
+        Ok(())
     }
 
-    fn pin_retries_entry(&self) -> (StoreIndex, u8) {
-        let (index, entry) = self.store.find_one(&Key::PinRetries).unwrap();
-        let data = entry.data;
-        debug_assert_eq!(data.len(), 1);
-        (index, data[0])
+    // Returns the value of the PIN failures counter when the PIN retries were last reset.
+    fn reset_pin_failures(&self) -> Result<u32, Ctap2StatusCode> {
+        // We have as invariant that there is always exactly one PinRetries entry in the store.
+        let (_, entry) = self
+            .store
+            .find_one(&Key::PinRetries)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        if entry.data.len() != 4 {
+            return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
+        }
+        Ok(BigEndian::read_u32(entry.data))
+    }
+
+    pub fn pin_retries(&self) -> Result<u8, Ctap2StatusCode> {
+        let wrong_pins = self
+            .counters
+            .get(PIN_FAILURES)?
+            .saturating_sub(self.reset_pin_failures()?);
+        let wrong_pins = u8::try_from(wrong_pins).unwrap_or(MAX_PIN_RETRIES);
+        Ok(MAX_PIN_RETRIES.saturating_sub(wrong_pins))
+    }
+
+    pub fn decr_pin_retries(&mut self) -> Result<(), Ctap2StatusCode> {
+        if self.pin_retries()? > 0 {
+            self.counters.incr(PIN_FAILURES, 1)?;
+        }
+        Ok(())
     }
 
-    pub fn pin_retries(&self) -> u8 {
-        self.pin_retries_entry().1
+    // Only writes to the store if a wrong PIN was entered since the last reset, which keeps the
+    // correct PIN entries from wearing the store.
+    pub fn reset_pin_retries(&mut self) -> Result<(), Ctap2StatusCode> {
+        if self.pin_retries()? < MAX_PIN_RETRIES {
+            let pin_failures = self.counters.get(PIN_FAILURES)?;
+            self.set_entry(&Key::PinRetries, PIN_RETRIES, &pin_failures.to_be_bytes())?;
+        }
+        Ok(())
     }
 
-    pub fn decr_pin_retries(&mut self) {
-        let (index, old_value) = self.pin_retries_entry();
-        let new_value = old_value.saturating_sub(1);
+    pub fn min_pin_length(&self) -> u8 {
         self.store
-            .replace(
-                index,
-                StoreEntry {
-                    tag: PIN_RETRIES,
-                    data: &[new_value],
-                },
-            )
-            .unwrap();
+            .find_one(&Key::MinPinLength)
+            .map_or(DEFAULT_MIN_PIN_LENGTH, |(_, entry)| {
+                debug_assert_eq!(entry.data.len(), 1);
+                entry.data[0]
+            })
+    }
+
+    pub fn set_min_pin_length(&mut self, min_pin_length: u8) -> Result<(), Ctap2StatusCode> {
+        self.set_entry(&Key::MinPinLength, MIN_PIN_LENGTH, &[min_pin_length])?;
+        Ok(())
     }
 
-    pub fn reset_pin_retries(&mut self) {
-        let (index, _) = self.pin_retries_entry();
+    pub fn min_pin_length_rp_ids(&self) -> Vec<String> {
         self.store
-            .replace(
-                index,
-                StoreEntry {
-                    tag: PIN_RETRIES,
-                    data: &[MAX_PIN_RETRIES],
-                },
-            )
-            .unwrap();
+            .find_one(&Key::MinPinLengthRpIds)
+            .and_then(|(_, entry)| {
+                let rp_ids = deserialize_rp_ids(entry.data);
+                debug_assert!(rp_ids.is_some());
+                rp_ids
+            })
+            .unwrap_or_default()
     }
 
-    pub fn reset(&mut self, rng: &mut impl Rng256) {
-        loop {
-            let index = {
-                let mut iter = self.store.iter();
-                match iter.next() {
-                    None => break,
-                    Some((index, _)) => index,
-                }
-            };
-            self.store.delete(index).unwrap();
+    pub fn set_min_pin_length_rp_ids(
+        &mut self,
+        min_pin_length_rp_ids: Vec<String>,
+    ) -> Result<(), Ctap2StatusCode> {
+        if min_pin_length_rp_ids.len() > MAX_RP_IDS_LENGTH {
+            return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
+        }
+        let rp_ids = serialize_rp_ids(min_pin_length_rp_ids)?;
+        self.set_entry(&Key::MinPinLengthRpIds, MIN_PIN_LENGTH_RP_IDS, &rp_ids)?;
+        Ok(())
+    }
+
+    pub fn always_uv(&self) -> bool {
+        self.config_flags() & ALWAYS_UV_FLAG != 0
+    }
+
+    pub fn toggle_always_uv(&mut self) -> Result<(), Ctap2StatusCode> {
+        let config_flags = self.config_flags() ^ ALWAYS_UV_FLAG;
+        self.set_config_flags(config_flags)
+    }
+
+    pub fn enterprise_attestation(&self) -> bool {
+        self.config_flags() & ENTERPRISE_ATTESTATION_FLAG != 0
+    }
+
+    pub fn enable_enterprise_attestation(&mut self) -> Result<(), Ctap2StatusCode> {
+        let config_flags = self.config_flags() | ENTERPRISE_ATTESTATION_FLAG;
+        self.set_config_flags(config_flags)
+    }
+
+    pub fn force_pin_change(&self) -> bool {
+        self.config_flags() & FORCE_PIN_CHANGE_FLAG != 0
+    }
+
+    pub fn set_force_pin_change(&mut self, force_pin_change: bool) -> Result<(), Ctap2StatusCode> {
+        let config_flags = if force_pin_change {
+            self.config_flags() | FORCE_PIN_CHANGE_FLAG
+        } else {
+            self.config_flags() & !FORCE_PIN_CHANGE_FLAG
+        };
+        self.set_config_flags(config_flags)
+    }
+
+    fn config_flags(&self) -> u8 {
+        self.store
+            .find_one(&Key::ConfigFlags)
+            .map_or(0, |(_, entry)| {
+                debug_assert_eq!(entry.data.len(), 1);
+                entry.data[0]
+            })
+    }
+
+    fn set_config_flags(&mut self, config_flags: u8) -> Result<(), Ctap2StatusCode> {
+        if config_flags != self.config_flags() {
+            self.set_entry(&Key::ConfigFlags, CONFIG_FLAGS, &[config_flags])?;
+        }
+        Ok(())
+    }
+
+    // Inserts the entry, or replaces the existing entry with the same key.
+    fn set_entry(&mut self, key: &Key, tag: usize, data: &[u8]) -> Result<(), StoreError> {
+        let entry = StoreEntry { tag, data };
+        match self.store.find_one(key) {
+            None => self.store.insert(entry),
+            Some((index, _)) => self.store.replace(index, entry),
+        }
+    }
+
+    // Returns the operation inserting the entry, or replacing the existing entry with the same key.
+    fn set_entry_operation<'a>(&self, key: &Key, tag: usize, data: &'a [u8]) -> StoreOperation<'a> {
+        let entry = StoreEntry { tag, data };
+        match self.store.find_one(key) {
+            None => StoreOperation::Insert(entry),
+            Some((index, _)) => StoreOperation::Replace(index, entry),
+        }
+    }
+
+    // The counters are not reset: the global signature counter must keep increasing, and init resets
+    // the PIN retries. All secrets are shredded before init writes new master keys.
+    //
+    // Entries are deleted one at a time, because a full store may have no room for a transaction.
+    // Credentials go first and the master keys last, so that a power loss never leaves credentials
+    // without their PIN, or sealed with lost master keys. The host can then reset again.
+    pub fn reset(&mut self, rng: &mut impl Rng256) -> Result<(), Ctap2StatusCode> {
+        while let Some(index) = self.next_entry_to_reset() {
+            self.store.delete(index)?;
         }
-        self.init(rng);
+        self.store.shred()?;
+        self.init(rng)
+    }
+
+    // Returns the next entry that reset deletes.
+    fn next_entry_to_reset(&self) -> Option<StoreIndex> {
+        let reset_order = |tag| match tag {
+            TAG_CREDENTIAL => 0,
+            MASTER_KEYS => 2,
+            _ => 1,
+        };
+        self.store
+            .iter()
+            .min_by_key(|(_, entry)| reset_order(entry.tag))
+            .map(|(index, _)| index)
     }
 }
 
@@ -398,39 +979,333 @@ impl From<StoreError> for Ctap2StatusCode {
     fn from(error: StoreError) -> Ctap2StatusCode {
         match error {
             StoreError::StoreFull => Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL,
-            StoreError::InvalidTag => unreachable!(),
-            StoreError::InvalidPrecondition => unreachable!(),
+            // Those errors are bugs in the usage of the store.
+            StoreError::InvalidTag | StoreError::InvalidPrecondition => {
+                Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR
+            }
+            StoreError::StorageError(_) => Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE,
         }
     }
 }
 
-fn deserialize_credential(data: &[u8]) -> Option<PublicKeyCredentialSource> {
-    let cbor = cbor::read(data).ok()?;
-    cbor.try_into().ok()
+// The fields of a stored credential that are not sealed. They are enough to find it in the store.
+struct CredentialHeader {
+    credential_id: Vec<u8>,
+    rp_id: String,
+    user_handle: Vec<u8>,
+    signature_counter: Option<u32>,
+    creation_order: u64,
+    // Whether the credential is an array of schema version 0.
+    is_legacy: bool,
 }
 
-fn serialize_credential(credential: PublicKeyCredentialSource) -> Result<Vec<u8>, Ctap2StatusCode> {
-    let mut data = Vec::new();
-    if cbor::write(credential.into(), &mut data) {
-        Ok(data)
-    } else {
-        Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CREDENTIAL)
-    }
+fn deserialize_credential_header(data: &[u8]) -> Option<CredentialHeader> {
+    use PublicKeyCredentialSourceField as Field;
+    match cbor::read(data).ok()? {
+        cbor::Value::Map(fields) => {
+            let field = |field: Field| fields.get(&cbor::KeyType::from(field));
+            Some(CredentialHeader {
+                credential_id: read_byte_string(field(Field::CredentialId)?).ok()?,
+                rp_id: read_text_string(field(Field::RpId)?).ok()?,
+                user_handle: read_byte_string(field(Field::UserHandle)?).ok()?,
+                signature_counter: match field(Field::SignatureCounter) {
+                    None => None,
+                    Some(cbor_value) => Some(u32::try_from(read_unsigned(cbor_value).ok()?).ok()?),
+                },
+                creation_order: match field(Field::CreationOrder) {
+                    None => 0,
+                    Some(cbor_value) => read_unsigned(cbor_value).ok()?,
+                },
+                is_legacy: false,
+            })
+        }
+        cbor::Value::Array(fields) if fields.len() >= 5 => Some(CredentialHeader {
+            credential_id: read_byte_string(&fields[0]).ok()?,
+            rp_id: read_text_string(&fields[2]).ok()?,
+            user_handle: read_byte_string(&fields[3]).ok()?,
+            signature_counter: None,
+            creation_order: 0,
+            is_legacy: true,
+        }),
+        _ => None,
+    }
+}
+
+// Deserializes a stored credential and unseals its secrets. Returns None if the credential is
+// malformed or the seal is broken.
+fn deserialize_credential(keys: &SealingKeys, data: &[u8]) -> Option<PublicKeyCredentialSource> {
+    match cbor::read(data).ok()? {
+        cbor::Value::Map(fields) => unseal_credential_map(keys, fields),
+        cbor::Value::Array(fields) => unseal_credential_array(keys, fields),
+        _ => None,
+    }
+}
+
+fn unseal_credential_map(
+    keys: &SealingKeys,
+    mut fields: BTreeMap<cbor::KeyType, cbor::Value>,
+) -> Option<PublicKeyCredentialSource> {
+    use PublicKeyCredentialSourceField as Field;
+    if let Some(sealed_secrets) = fields.remove(&cbor::KeyType::from(Field::SealedSecrets)) {
+        let sealed_secrets = read_byte_string(&sealed_secrets).ok()?;
+        let credential_id =
+            read_byte_string(fields.get(&cbor::KeyType::from(Field::CredentialId))?).ok()?;
+        let rp_id = read_text_string(fields.get(&cbor::KeyType::from(Field::RpId))?).ok()?;
+        let (private_key, cred_random) =
+            unseal_secrets(keys, &sealed_secrets, &rp_id, &credential_id)?;
+        fields.insert(Field::PrivateKey.into(), private_key.into());
+        if let Some(cred_random) = cred_random {
+            fields.insert(Field::CredRandom.into(), cbor_bytes!(cred_random));
+        }
+    }
+    cbor::Value::Map(fields).try_into().ok()
+}
+
+// Credentials of schema version 0 are arrays. Their private key is either an array or a 32 byte
+// string, and was replaced by a longer byte string when sealed. Only used for migration.
+fn unseal_credential_array(
+    keys: &SealingKeys,
+    mut fields: Vec<cbor::Value>,
+) -> Option<PublicKeyCredentialSource> {
+    let sealed_secrets = match fields.get(1).map(read_byte_string) {
+        Some(Ok(sealed_secrets)) if sealed_secrets.len() != 32 => sealed_secrets,
+        _ => return cbor::Value::Array(fields).try_into().ok(),
+    };
+    if fields.len() < 6 {
+        return None;
+    }
+    let credential_id = read_byte_string(&fields[0]).ok()?;
+    let rp_id = read_text_string(&fields[2]).ok()?;
+    let (private_key, cred_random) = unseal_secrets(keys, &sealed_secrets, &rp_id, &credential_id)?;
+    fields[1] = private_key.into();
+    fields[5] = match cred_random {
+        None => cbor_null!(),
+        Some(cred_random) => cbor_bytes!(cred_random),
+    };
+    cbor::Value::Array(fields).try_into().ok()
+}
+
+// Serializes the credential as a CBOR map, with its private key and CredRandom sealed. The other
+// fields are stored in plaintext, so that the store can find the credential by its keys.
+//
+// The secrets are encrypted with AES-256-CBC and a random IV, then authenticated with HMAC-SHA256.
+// The HMAC also covers the relying party ID and the credential ID, so sealed secrets can't be
+// moved to another credential.
+fn seal_credential(
+    rng: &mut impl Rng256,
+    keys: &SealingKeys,
+    credential: PublicKeyCredentialSource,
+) -> Result<Vec<u8>, Ctap2StatusCode> {
+    let sk_bytes = credential.private_key.to_bytes();
+    let mut blocks = vec![[0u8; 16]; 3];
+    blocks[0].copy_from_slice(&sk_bytes[..16]);
+    blocks[1].copy_from_slice(&sk_bytes[16..]);
+    BigEndian::write_i64(
+        &mut blocks[2][..8],
+        credential.private_key.algorithm() as i64,
+    );
+    if let Some(cred_random) = &credential.cred_random {
+        if cred_random.len() != 32 {
+            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CREDENTIAL);
+        }
+        blocks.push(*array_ref![cred_random, 0, 16]);
+        blocks.push(*array_ref![cred_random, 16, 16]);
+    }
+    let mut iv = [0; 16];
+    iv.copy_from_slice(&rng.gen_uniform_u8x32()[..16]);
+    cbc_encrypt(&keys.encryption, iv, &mut blocks);
+
+    let mut sealed_secrets = Vec::with_capacity(SEALED_SECRETS_SIZE_WITH_CRED_RANDOM);
+    sealed_secrets.extend(&iv);
+    for block in &blocks {
+        sealed_secrets.extend(block);
+    }
+    let mac = hmac_256::<Sha256>(
+        &keys.hmac,
+        &sealed_mac_contents(
+            &sealed_secrets,
+            &credential.rp_id,
+            &credential.credential_id,
+        ),
+    );
+    sealed_secrets.extend(&mac);
+
+    let mut fields = match credential.into() {
+        cbor::Value::Map(fields) => fields,
+        _ => return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
+    };
+    fields.remove(&cbor::KeyType::from(
+        PublicKeyCredentialSourceField::PrivateKey,
+    ));
+    fields.remove(&cbor::KeyType::from(
+        PublicKeyCredentialSourceField::CredRandom,
+    ));
+    fields.insert(
+        PublicKeyCredentialSourceField::SealedSecrets.into(),
+        cbor_bytes!(sealed_secrets),
+    );
+    let mut data = Vec::new();
+    if cbor::write(cbor::Value::Map(fields), &mut data) {
+        Ok(data)
+    } else {
+        Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CREDENTIAL)
+    }
+}
+
+// Sets the signature counter of a stored credential, which is not sealed.
+fn set_signature_counter(data: &[u8], signature_counter: u32) -> Option<Vec<u8>> {
+    let mut fields = match cbor::read(data).ok()? {
+        cbor::Value::Map(fields) => fields,
+        _ => return None,
+    };
+    fields.insert(
+        PublicKeyCredentialSourceField::SignatureCounter.into(),
+        cbor_unsigned!(u64::from(signature_counter)),
+    );
+    let mut data = Vec::new();
+    if cbor::write(cbor::Value::Map(fields), &mut data) {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+// Returns the private key and the CredRandom, or None if the HMAC doesn't match.
+fn unseal_secrets(
+    keys: &SealingKeys,
+    sealed_secrets: &[u8],
+    rp_id: &str,
+    credential_id: &[u8],
+) -> Option<(PrivateKey, Option<Vec<u8>>)> {
+    match sealed_secrets.len() {
+        SEALED_SECRETS_SIZE | SEALED_SECRETS_SIZE_WITH_CRED_RANDOM => (),
+        _ => return None,
+    }
+    let mac_start = sealed_secrets.len() - 32;
+    if !verify_hmac_256::<Sha256>(
+        &keys.hmac,
+        &sealed_mac_contents(&sealed_secrets[..mac_start], rp_id, credential_id),
+        array_ref![sealed_secrets, mac_start, 32],
+    ) {
+        return None;
+    }
+    let iv = *array_ref![sealed_secrets, 0, 16];
+    let mut blocks: Vec<[u8; 16]> = sealed_secrets[16..mac_start]
+        .chunks_exact(16)
+        .map(|block| *array_ref![block, 0, 16])
+        .collect();
+    cbc_decrypt(&DecryptionKey::new(&keys.encryption), iv, &mut blocks);
+
+    let mut sk_bytes = [0; 32];
+    sk_bytes[..16].copy_from_slice(&blocks[0]);
+    sk_bytes[16..].copy_from_slice(&blocks[1]);
+    let algorithm = SignatureAlgorithm::try_from(BigEndian::read_i64(&blocks[2][..8])).ok()?;
+    let private_key = PrivateKey::from_bytes(algorithm, &sk_bytes)?;
+    let cred_random = if blocks.len() == 5 {
+        let mut cred_random = Vec::with_capacity(32);
+        cred_random.extend(&blocks[3]);
+        cred_random.extend(&blocks[4]);
+        Some(cred_random)
+    } else {
+        None
+    };
+    Some((private_key, cred_random))
+}
+
+// The relying party ID is hashed to a fixed length, so that it can't run into the credential ID.
+fn sealed_mac_contents(sealed_secrets: &[u8], rp_id: &str, credential_id: &[u8]) -> Vec<u8> {
+    let mut contents = sealed_secrets.to_vec();
+    contents.extend(&Sha256::hash(rp_id.as_bytes()));
+    contents.extend(credential_id);
+    contents
+}
+
+fn deserialize_rp_ids(data: &[u8]) -> Option<Vec<String>> {
+    let cbor = cbor::read(data).ok()?;
+    match cbor {
+        cbor::Value::Array(array) => array
+            .into_iter()
+            .map(|value| match value {
+                cbor::Value::KeyValue(cbor::KeyType::TextString(rp_id)) => Some(rp_id),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn serialize_rp_ids(rp_ids: Vec<String>) -> Result<Vec<u8>, Ctap2StatusCode> {
+    let mut data = Vec::new();
+    if cbor::write(cbor_array_vec!(rp_ids), &mut data) {
+        Ok(data)
+    } else {
+        Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::crypto;
     use crate::crypto::rng256::{Rng256, ThreadRng256};
-    use crate::ctap::data_formats::{PublicKeyCredentialSource, PublicKeyCredentialType};
+    use crate::ctap::data_formats::{
+        PrivateKey, PublicKeyCredentialSource, PublicKeyCredentialType, SignatureAlgorithm,
+    };
+    use crate::embedded_flash::{Index, StorageError, StorageResult};
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    // A storage whose writes and erases fail once broken, like a worn-out flash.
+    struct BreakableStorage {
+        storage: BufferStorage,
+        broken: Rc<Cell<bool>>,
+    }
+
+    impl Storage for BreakableStorage {
+        fn word_size(&self) -> usize {
+            self.storage.word_size()
+        }
+
+        fn page_size(&self) -> usize {
+            self.storage.page_size()
+        }
+
+        fn num_pages(&self) -> usize {
+            self.storage.num_pages()
+        }
+
+        fn max_word_writes(&self) -> usize {
+            self.storage.max_word_writes()
+        }
+
+        fn max_page_erases(&self) -> usize {
+            self.storage.max_page_erases()
+        }
+
+        fn read_slice(&self, index: Index, length: usize) -> StorageResult<&[u8]> {
+            self.storage.read_slice(index, length)
+        }
+
+        fn write_slice(&mut self, index: Index, value: &[u8]) -> StorageResult<()> {
+            if self.broken.get() {
+                return Err(StorageError::BadFlash);
+            }
+            self.storage.write_slice(index, value)
+        }
+
+        fn erase_page(&mut self, page: usize) -> StorageResult<()> {
+            if self.broken.get() {
+                return Err(StorageError::BadFlash);
+            }
+            self.storage.erase_page(page)
+        }
+    }
 
     fn create_credential_source(
         rng: &mut ThreadRng256,
         rp_id: &str,
         user_handle: Vec<u8>,
     ) -> PublicKeyCredentialSource {
-        let private_key = crypto::ecdsa::SecKey::gensk(rng);
+        let private_key = PrivateKey::new(rng, SignatureAlgorithm::ES256);
         PublicKeyCredentialSource {
             key_type: PublicKeyCredentialType::PublicKey,
             credential_id: rng.gen_uniform_u8x32().to_vec(),
@@ -438,9 +1313,74 @@ mod test {
             rp_id: String::from(rp_id),
             user_handle,
             other_ui: None,
+            cred_random: None,
+            cred_protect_policy: None,
+            signature_counter: None,
+            creation_order: 0,
         }
     }
 
+    #[test]
+    fn test_storage_error() {
+        let mut rng = ThreadRng256 {};
+        let broken = Rc::new(Cell::new(false));
+        let regions = new_buffer_storage();
+        let storage = StorageRegions {
+            store: BreakableStorage {
+                storage: regions.store,
+                broken: broken.clone(),
+            },
+            counters: BreakableStorage {
+                storage: regions.counters,
+                broken: broken.clone(),
+            },
+        };
+        let mut persistent_store = PersistentStore::new(&mut rng, storage).unwrap();
+        broken.set(true);
+
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![]);
+        assert_eq!(
+            persistent_store.store_credential(&mut rng, credential_source),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+        assert_eq!(
+            persistent_store.set_pin_hash(&[0x88; PIN_AUTH_LENGTH]),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+        assert_eq!(
+            persistent_store.decr_pin_retries(),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+        assert_eq!(
+            persistent_store.reset(&mut rng),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+        // Reads still work.
+        assert!(persistent_store.master_keys().is_ok());
+        assert_eq!(persistent_store.pin_retries(), Ok(MAX_PIN_RETRIES));
+        assert_eq!(persistent_store.count_credentials(), 0);
+    }
+
+    #[test]
+    fn test_new_storage_error() {
+        let mut rng = ThreadRng256 {};
+        let regions = new_buffer_storage();
+        let storage = StorageRegions {
+            store: BreakableStorage {
+                storage: regions.store,
+                broken: Rc::new(Cell::new(true)),
+            },
+            counters: BreakableStorage {
+                storage: regions.counters,
+                broken: Rc::new(Cell::new(true)),
+            },
+        };
+        assert_eq!(
+            PersistentStore::new(&mut rng, storage).err(),
+            Some(Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE)
+        );
+    }
+
     #[test]
     fn format_overhead() {
         // nRF52840 NVMC
@@ -455,21 +1395,23 @@ mod test {
             max_page_erases: 10000,
             strict_write: true,
         };
-        let storage = Storage::new(store, options);
+        let storage = BufferStorage::new(store, options);
         let store = embedded_flash::Store::new(storage, Config).unwrap();
-        // We can replace 3 bytes with minimal overhead.
+        // We can replace 1 byte with minimal overhead (the checksum takes 2 bytes).
         assert_eq!(store.replace_len(0), 2 * WORD_SIZE);
-        assert_eq!(store.replace_len(3), 2 * WORD_SIZE);
-        assert_eq!(store.replace_len(4), 3 * WORD_SIZE);
+        assert_eq!(store.replace_len(1), 2 * WORD_SIZE);
+        assert_eq!(store.replace_len(2), 3 * WORD_SIZE);
     }
 
     #[test]
     fn test_store() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
         assert_eq!(persistent_store.count_credentials(), 0);
         let credential_source = create_credential_source(&mut rng, "example.com", vec![]);
-        assert!(persistent_store.store_credential(credential_source).is_ok());
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source)
+            .is_ok());
         assert!(persistent_store.count_credentials() > 0);
     }
 
@@ -477,7 +1419,7 @@ mod test {
     #[allow(clippy::assertions_on_constants)]
     fn test_fill_store() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
         assert_eq!(persistent_store.count_credentials(), 0);
 
         // To make this test work for bigger storages, implement better int -> Vec conversion.
@@ -485,7 +1427,9 @@ mod test {
         for i in 0..MAX_SUPPORTED_RESIDENTIAL_KEYS {
             let credential_source =
                 create_credential_source(&mut rng, "example.com", vec![i as u8]);
-            assert!(persistent_store.store_credential(credential_source).is_ok());
+            assert!(persistent_store
+                .store_credential(&mut rng, credential_source)
+                .is_ok());
             assert_eq!(persistent_store.count_credentials(), i + 1);
         }
         let credential_source = create_credential_source(
@@ -494,7 +1438,7 @@ mod test {
             vec![MAX_SUPPORTED_RESIDENTIAL_KEYS as u8],
         );
         assert_eq!(
-            persistent_store.store_credential(credential_source),
+            persistent_store.store_credential(&mut rng, credential_source),
             Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
         );
         assert_eq!(
@@ -507,18 +1451,19 @@ mod test {
     #[allow(clippy::assertions_on_constants)]
     fn test_overwrite() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
         assert_eq!(persistent_store.count_credentials(), 0);
         // These should have different IDs.
         let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
         let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x00]);
-        let expected_credential = credential_source1.clone();
+        let mut expected_credential = credential_source1.clone();
+        expected_credential.creation_order = 1;
 
         assert!(persistent_store
-            .store_credential(credential_source0)
+            .store_credential(&mut rng, credential_source0)
             .is_ok());
         assert!(persistent_store
-            .store_credential(credential_source1)
+            .store_credential(&mut rng, credential_source1)
             .is_ok());
         assert_eq!(persistent_store.count_credentials(), 1);
         assert_eq!(
@@ -531,7 +1476,9 @@ mod test {
         for i in 0..MAX_SUPPORTED_RESIDENTIAL_KEYS {
             let credential_source =
                 create_credential_source(&mut rng, "example.com", vec![i as u8]);
-            assert!(persistent_store.store_credential(credential_source).is_ok());
+            assert!(persistent_store
+                .store_credential(&mut rng, credential_source)
+                .is_ok());
             assert_eq!(persistent_store.count_credentials(), i + 1);
         }
         let credential_source = create_credential_source(
@@ -540,7 +1487,7 @@ mod test {
             vec![MAX_SUPPORTED_RESIDENTIAL_KEYS as u8],
         );
         assert_eq!(
-            persistent_store.store_credential(credential_source),
+            persistent_store.store_credential(&mut rng, credential_source),
             Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
         );
         assert_eq!(
@@ -552,7 +1499,7 @@ mod test {
     #[test]
     fn test_filter() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
         assert_eq!(persistent_store.count_credentials(), 0);
         let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
         let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
@@ -561,13 +1508,13 @@ mod test {
         let id0 = credential_source0.credential_id.clone();
         let id1 = credential_source1.credential_id.clone();
         assert!(persistent_store
-            .store_credential(credential_source0)
+            .store_credential(&mut rng, credential_source0)
             .is_ok());
         assert!(persistent_store
-            .store_credential(credential_source1)
+            .store_credential(&mut rng, credential_source1)
             .is_ok());
         assert!(persistent_store
-            .store_credential(credential_source2)
+            .store_credential(&mut rng, credential_source2)
             .is_ok());
 
         let filtered_credentials = persistent_store.filter_credential("example.com");
@@ -580,20 +1527,112 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_creation_order() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        for user_handle in 0..3 {
+            let credential_source =
+                create_credential_source(&mut rng, "example.com", vec![user_handle]);
+            assert!(persistent_store
+                .store_credential(&mut rng, credential_source)
+                .is_ok());
+        }
+        // Replacing a credential makes it the most recent one.
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source)
+            .is_ok());
+
+        let mut credentials = persistent_store.filter_credential("example.com");
+        credentials.sort_by_key(|credential| credential.creation_order);
+        let user_handles: Vec<Vec<u8>> = credentials
+            .into_iter()
+            .map(|credential| credential.user_handle)
+            .collect();
+        assert_eq!(user_handles, vec![vec![0x01], vec![0x02], vec![0x00]]);
+    }
+
+    #[test]
+    fn test_update_credential_keeps_creation_order() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let id0 = credential_source0.credential_id.clone();
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source0)
+            .is_ok());
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source1)
+            .is_ok());
+
+        let mut credential = persistent_store.find_credential_by_id(&id0).unwrap();
+        credential.other_ui = Some(String::from("bar"));
+        assert!(persistent_store
+            .update_credential(&mut rng, credential)
+            .is_ok());
+        let credential = persistent_store.find_credential_by_id(&id0).unwrap();
+        assert_eq!(credential.other_ui, Some(String::from("bar")));
+        assert_eq!(credential.creation_order, 0);
+        assert_eq!(persistent_store.count_credentials(), 2);
+
+        // A new credential still comes after both.
+        let credential_source2 = create_credential_source(&mut rng, "example.com", vec![0x02]);
+        let id2 = credential_source2.credential_id.clone();
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source2)
+            .is_ok());
+        let credential = persistent_store.find_credential_by_id(&id2).unwrap();
+        assert_eq!(credential.creation_order, 2);
+
+        // Updating an unknown credential fails.
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x03]);
+        assert_eq!(
+            persistent_store.update_credential(&mut rng, credential_source),
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
+        );
+    }
+
+    #[test]
+    fn test_creation_order_after_restart() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        for user_handle in 0..2 {
+            let credential_source =
+                create_credential_source(&mut rng, "example.com", vec![user_handle]);
+            assert!(persistent_store
+                .store_credential(&mut rng, credential_source)
+                .is_ok());
+        }
+        let content = persistent_store.content();
+        let mut persistent_store =
+            PersistentStore::new(&mut rng, new_buffer_storage_with_content(content)).unwrap();
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x02]);
+        let credential_id = credential_source.credential_id.clone();
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source)
+            .is_ok());
+        let credential = persistent_store
+            .find_credential_by_id(&credential_id)
+            .unwrap();
+        assert_eq!(credential.creation_order, 2);
+    }
+
     #[test]
     fn test_find() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
         assert_eq!(persistent_store.count_credentials(), 0);
         let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
         let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
         let id0 = credential_source0.credential_id.clone();
         let key0 = credential_source0.private_key.clone();
         assert!(persistent_store
-            .store_credential(credential_source0)
+            .store_credential(&mut rng, credential_source0)
             .is_ok());
         assert!(persistent_store
-            .store_credential(credential_source1)
+            .store_credential(&mut rng, credential_source1)
             .is_ok());
 
         let no_credential = persistent_store.find_credential("another.example.com", &id0);
@@ -606,18 +1645,405 @@ mod test {
             rp_id: String::from("example.com"),
             user_handle: vec![0x00],
             other_ui: None,
+            cred_random: None,
+            cred_protect_policy: None,
+            signature_counter: None,
+            creation_order: 0,
         };
         assert_eq!(found_credential, Some(expected_credential));
     }
 
+    #[test]
+    fn test_find_by_id() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let credential_id = credential_source.credential_id.clone();
+        let expected_credential = credential_source.clone();
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source)
+            .is_ok());
+
+        assert_eq!(persistent_store.find_credential_by_id(&[0x00]), None);
+        assert_eq!(
+            persistent_store.find_credential_by_id(&credential_id),
+            Some(expected_credential)
+        );
+    }
+
+    #[test]
+    fn test_credential_secrets_sealed() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        let mut credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        credential_source.cred_random = Some(vec![0xC9; 32]);
+        let sk_bytes = credential_source.private_key.to_bytes();
+        let expected_credential = credential_source.clone();
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source)
+            .is_ok());
+
+        let content = persistent_store.content();
+        assert!(!content.windows(32).any(|window| window == &sk_bytes[..]));
+        assert!(!content.windows(32).any(|window| window == &[0xC9; 32][..]));
+        assert_eq!(
+            persistent_store.filter_credential("example.com"),
+            vec![expected_credential]
+        );
+    }
+
+    fn find_credential_entry(
+        persistent_store: &PersistentStore<BufferStorage>,
+        credential_id: &[u8],
+    ) -> (StoreIndex, Vec<u8>) {
+        let key = Key::Credential {
+            rp_id: None,
+            credential_id: Some(credential_id.to_vec()),
+            user_handle: None,
+        };
+        let (index, entry) = persistent_store.store.find_one(&key).unwrap();
+        (index, entry.data.to_vec())
+    }
+
+    // Writes the schema version like stores before version 4, after the master keys.
+    fn set_legacy_schema_version(
+        persistent_store: &mut PersistentStore<BufferStorage>,
+        schema_version: u8,
+    ) {
+        let (index, _) = persistent_store
+            .store
+            .find_one(&Key::SchemaVersion)
+            .unwrap();
+        persistent_store.store.delete(index).unwrap();
+        let (index, entry) = persistent_store.store.find_one(&Key::MasterKeys).unwrap();
+        let mut data = entry.data.to_vec();
+        // Version 0 is the absence of a version.
+        if schema_version > 0 {
+            data.push(schema_version);
+        }
+        persistent_store
+            .store
+            .replace(
+                index,
+                StoreEntry {
+                    tag: MASTER_KEYS,
+                    data: &data,
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_sealed_secrets_bound_to_credential() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let credential_id = credential_source.credential_id.clone();
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source)
+            .is_ok());
+
+        let (_, data) = find_credential_entry(&persistent_store, &credential_id);
+        let mut fields = match cbor::read(&data).unwrap() {
+            cbor::Value::Map(fields) => fields,
+            _ => panic!("Credentials are stored as maps"),
+        };
+        fields.insert(
+            PublicKeyCredentialSourceField::RpId.into(),
+            cbor_text!("another.example.com"),
+        );
+        let mut data = Vec::new();
+        assert!(cbor::write(cbor::Value::Map(fields), &mut data));
+        let keys = persistent_store.sealing_keys().unwrap();
+        assert!(deserialize_credential(&keys, &data).is_none());
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        assert_eq!(persistent_store.schema_version(), Ok(SCHEMA_VERSION));
+        let master_keys = persistent_store.master_keys().unwrap().encryption.to_vec();
+
+        set_legacy_schema_version(&mut persistent_store, 0);
+        assert_eq!(persistent_store.schema_version(), Ok(0));
+        let persistent_store = PersistentStore::new(
+            &mut rng,
+            new_buffer_storage_with_content(persistent_store.content()),
+        )
+        .unwrap();
+        assert_eq!(persistent_store.schema_version(), Ok(SCHEMA_VERSION));
+        assert_eq!(
+            persistent_store.master_keys().unwrap().encryption.to_vec(),
+            master_keys
+        );
+    }
+
+    #[test]
+    fn test_migrate_schema_version_entry() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        let (_, entry) = persistent_store.store.find_one(&Key::MasterKeys).unwrap();
+        assert_eq!(entry.data.len(), MASTER_KEYS_LENGTH);
+        let master_keys = entry.data.to_vec();
+
+        // Before schema version 4, the schema version followed the master keys.
+        set_legacy_schema_version(&mut persistent_store, 3);
+        assert_eq!(persistent_store.schema_version(), Ok(3));
+
+        let persistent_store = PersistentStore::new(
+            &mut rng,
+            new_buffer_storage_with_content(persistent_store.content()),
+        )
+        .unwrap();
+        let (_, entry) = persistent_store
+            .store
+            .find_one(&Key::SchemaVersion)
+            .unwrap();
+        assert_eq!(entry.data, [SCHEMA_VERSION]);
+        let (_, entry) = persistent_store.store.find_one(&Key::MasterKeys).unwrap();
+        assert_eq!(entry.data, &master_keys[..]);
+    }
+
+    #[test]
+    fn test_migrate_legacy_credentials() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        assert!(persistent_store.incr_global_signature_counter(7).is_ok());
+
+        // The oldest credentials are arrays of 5 fields, in plaintext.
+        let plaintext_credential = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let data = {
+            let credential = plaintext_credential.clone();
+            let cbor_value = cbor_array! {
+                credential.credential_id,
+                credential.private_key,
+                credential.rp_id,
+                credential.user_handle,
+                cbor_null!(),
+            };
+            let mut data = Vec::new();
+            assert!(cbor::write(cbor_value, &mut data));
+            data
+        };
+        persistent_store
+            .store
+            .insert(StoreEntry {
+                tag: TAG_CREDENTIAL,
+                data: &data,
+            })
+            .unwrap();
+
+        // Later, arrays of 7 fields had their private key and CredRandom sealed.
+        let mut sealed_credential = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        sealed_credential.cred_random = Some(vec![0xC9; 32]);
+        let sealed_credential_id = sealed_credential.credential_id.clone();
+        assert!(persistent_store
+            .store_credential(&mut rng, sealed_credential.clone())
+            .is_ok());
+        let (index, data) = find_credential_entry(&persistent_store, &sealed_credential_id);
+        let data = match cbor::read(&data).unwrap() {
+            cbor::Value::Map(fields) => {
+                let field = |field: PublicKeyCredentialSourceField| {
+                    fields.get(&cbor::KeyType::from(field)).unwrap().clone()
+                };
+                let cbor_value = cbor_array! {
+                    field(PublicKeyCredentialSourceField::CredentialId),
+                    field(PublicKeyCredentialSourceField::SealedSecrets),
+                    field(PublicKeyCredentialSourceField::RpId),
+                    field(PublicKeyCredentialSourceField::UserHandle),
+                    cbor_null!(),
+                    cbor_null!(),
+                    cbor_null!(),
+                };
+                let mut data = Vec::new();
+                assert!(cbor::write(cbor_value, &mut data));
+                data
+            }
+            _ => panic!("Credentials are stored as maps"),
+        };
+        persistent_store
+            .store
+            .replace(
+                index,
+                StoreEntry {
+                    tag: TAG_CREDENTIAL,
+                    data: &data,
+                },
+            )
+            .unwrap();
+        set_legacy_schema_version(&mut persistent_store, 0);
+
+        let persistent_store = PersistentStore::new(
+            &mut rng,
+            new_buffer_storage_with_content(persistent_store.content()),
+        )
+        .unwrap();
+        assert_eq!(persistent_store.schema_version(), Ok(SCHEMA_VERSION));
+        for credential in &[plaintext_credential, sealed_credential] {
+            let (_, data) = find_credential_entry(&persistent_store, &credential.credential_id);
+            assert!(!deserialize_credential_header(&data).unwrap().is_legacy);
+            // The credentials continue from the global signature counter.
+            let expected_credential = PublicKeyCredentialSource {
+                signature_counter: Some(7),
+                ..credential.clone()
+            };
+            assert_eq!(
+                persistent_store.find_credential("example.com", &credential.credential_id),
+                Some(expected_credential)
+            );
+        }
+    }
+
+    #[test]
+    fn test_migrate_counters() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+
+        // Before schema version 3, the counters were entries.
+        assert!(persistent_store
+            .store
+            .insert(StoreEntry {
+                tag: GLOBAL_SIGNATURE_COUNTER,
+                data: &42u32.to_ne_bytes(),
+            })
+            .is_ok());
+        let (index, _) = persistent_store.store.find_one(&Key::PinRetries).unwrap();
+        assert!(persistent_store
+            .store
+            .replace(
+                index,
+                StoreEntry {
+                    tag: PIN_RETRIES,
+                    data: &[4],
+                },
+            )
+            .is_ok());
+        set_legacy_schema_version(&mut persistent_store, 2);
+
+        let mut persistent_store = PersistentStore::new(
+            &mut rng,
+            new_buffer_storage_with_content(persistent_store.content()),
+        )
+        .unwrap();
+        assert_eq!(persistent_store.schema_version(), Ok(SCHEMA_VERSION));
+        assert!(persistent_store
+            .store
+            .find_one(&Key::GlobalSignatureCounter)
+            .is_none());
+        assert_eq!(persistent_store.global_signature_counter(), Ok(42));
+        assert_eq!(persistent_store.incr_global_signature_counter(1), Ok(43));
+        assert_eq!(persistent_store.pin_retries(), Ok(4));
+        assert!(persistent_store.decr_pin_retries().is_ok());
+        assert_eq!(persistent_store.pin_retries(), Ok(3));
+        assert!(persistent_store.reset_pin_retries().is_ok());
+        assert_eq!(persistent_store.pin_retries(), Ok(MAX_PIN_RETRIES));
+    }
+
+    #[test]
+    fn test_signature_counters() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        assert_eq!(persistent_store.global_signature_counter(), Ok(0));
+        assert_eq!(persistent_store.incr_global_signature_counter(5), Ok(5));
+        assert_eq!(persistent_store.incr_global_signature_counter(3), Ok(8));
+
+        let mut credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        credential_source.signature_counter = Some(0);
+        let credential_id = credential_source.credential_id.clone();
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source)
+            .is_ok());
+        assert_eq!(
+            persistent_store.incr_credential_signature_counter(&credential_id),
+            Ok(1)
+        );
+        assert_eq!(
+            persistent_store.incr_credential_signature_counter(&credential_id),
+            Ok(2)
+        );
+        assert_eq!(
+            persistent_store
+                .find_credential_by_id(&credential_id)
+                .unwrap()
+                .signature_counter,
+            Some(2)
+        );
+        assert_eq!(persistent_store.global_signature_counter(), Ok(8));
+        assert_eq!(
+            persistent_store.incr_credential_signature_counter(&[0x00]),
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
+        );
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let id0 = credential_source0.credential_id.clone();
+        let mut expected_credential = credential_source1.clone();
+        expected_credential.creation_order = 1;
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source0)
+            .is_ok());
+        assert!(persistent_store
+            .store_credential(&mut rng, credential_source1)
+            .is_ok());
+        assert_eq!(
+            persistent_store.remaining_credentials(),
+            MAX_SUPPORTED_RESIDENTIAL_KEYS - 2
+        );
+
+        assert!(persistent_store.delete_credential(&id0).is_ok());
+        assert_eq!(persistent_store.count_credentials(), 1);
+        assert_eq!(
+            persistent_store.remaining_credentials(),
+            MAX_SUPPORTED_RESIDENTIAL_KEYS - 1
+        );
+        assert_eq!(
+            &persistent_store.filter_credential("example.com"),
+            &[expected_credential]
+        );
+        assert_eq!(
+            persistent_store.delete_credential(&id0),
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
+        );
+    }
+
+    #[test]
+    fn test_list_rp_ids() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        assert!(persistent_store.list_rp_ids().is_empty());
+        for (rp_id, user_handle) in &[
+            ("example.com", 0x00),
+            ("another.example.com", 0x01),
+            ("example.com", 0x02),
+        ] {
+            let credential_source = create_credential_source(&mut rng, rp_id, vec![*user_handle]);
+            assert!(persistent_store
+                .store_credential(&mut rng, credential_source)
+                .is_ok());
+        }
+        assert_eq!(
+            persistent_store.list_rp_ids(),
+            vec![
+                String::from("another.example.com"),
+                String::from("example.com")
+            ]
+        );
+    }
+
     #[test]
     fn test_master_keys() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
 
         // Master keys stay the same between resets.
-        let master_keys_1 = persistent_store.master_keys();
-        let master_keys_2 = persistent_store.master_keys();
+        let master_keys_1 = persistent_store.master_keys().unwrap();
+        let master_keys_2 = persistent_store.master_keys().unwrap();
         assert_eq!(master_keys_2.encryption, master_keys_1.encryption);
         assert_eq!(master_keys_2.hmac, master_keys_1.hmac);
 
@@ -625,8 +2051,8 @@ mod test {
         // same keys.
         let master_encryption_key = master_keys_1.encryption.to_vec();
         let master_hmac_key = master_keys_1.hmac.to_vec();
-        persistent_store.reset(&mut rng);
-        let master_keys_3 = persistent_store.master_keys();
+        persistent_store.reset(&mut rng).unwrap();
+        let master_keys_3 = persistent_store.master_keys().unwrap();
         assert!(master_keys_3.encryption as &[u8] != &master_encryption_key[..]);
         assert!(master_keys_3.hmac as &[u8] != &master_hmac_key[..]);
     }
@@ -635,7 +2061,7 @@ mod test {
     fn test_pin_hash() {
         use crate::ctap::PIN_AUTH_LENGTH;
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
 
         // Pin hash is initially not set.
         assert!(persistent_store.pin_hash().is_none());
@@ -645,38 +2071,291 @@ mod test {
         assert_eq!(random_data.len(), 2 * PIN_AUTH_LENGTH);
         let pin_hash_1 = array_ref!(random_data, 0, PIN_AUTH_LENGTH);
         let pin_hash_2 = array_ref!(random_data, PIN_AUTH_LENGTH, PIN_AUTH_LENGTH);
-        persistent_store.set_pin_hash(&pin_hash_1);
+        persistent_store.set_pin_hash(&pin_hash_1).unwrap();
         assert_eq!(persistent_store.pin_hash(), Some(pin_hash_1));
         assert_eq!(persistent_store.pin_hash(), Some(pin_hash_1));
-        persistent_store.set_pin_hash(&pin_hash_2);
+        persistent_store.set_pin_hash(&pin_hash_2).unwrap();
         assert_eq!(persistent_store.pin_hash(), Some(pin_hash_2));
         assert_eq!(persistent_store.pin_hash(), Some(pin_hash_2));
 
+        // Setting the pin hash resets the pin retries and the force pin change flag.
+        persistent_store.decr_pin_retries().unwrap();
+        persistent_store.set_force_pin_change(true).unwrap();
+        persistent_store.set_pin_hash(&pin_hash_1).unwrap();
+        assert_eq!(persistent_store.pin_hash(), Some(pin_hash_1));
+        assert_eq!(persistent_store.pin_retries().unwrap(), MAX_PIN_RETRIES);
+        assert!(!persistent_store.force_pin_change());
+
         // Resetting the storage resets the pin hash.
-        persistent_store.reset(&mut rng);
+        persistent_store.reset(&mut rng).unwrap();
         assert!(persistent_store.pin_hash().is_none());
     }
 
     #[test]
     fn test_pin_retries() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
 
         // The pin retries is initially at the maximum.
-        assert_eq!(persistent_store.pin_retries(), MAX_PIN_RETRIES);
+        assert_eq!(persistent_store.pin_retries().unwrap(), MAX_PIN_RETRIES);
 
         // Decrementing the pin retries decrements the pin retries.
         for pin_retries in (0..MAX_PIN_RETRIES).rev() {
-            persistent_store.decr_pin_retries();
-            assert_eq!(persistent_store.pin_retries(), pin_retries);
+            persistent_store.decr_pin_retries().unwrap();
+            assert_eq!(persistent_store.pin_retries().unwrap(), pin_retries);
         }
 
         // Decrementing the pin retries after zero does not modify the pin retries.
-        persistent_store.decr_pin_retries();
-        assert_eq!(persistent_store.pin_retries(), 0);
+        persistent_store.decr_pin_retries().unwrap();
+        assert_eq!(persistent_store.pin_retries().unwrap(), 0);
 
         // Resetting the pin retries resets the pin retries.
-        persistent_store.reset_pin_retries();
-        assert_eq!(persistent_store.pin_retries(), MAX_PIN_RETRIES);
+        persistent_store.reset_pin_retries().unwrap();
+        assert_eq!(persistent_store.pin_retries().unwrap(), MAX_PIN_RETRIES);
+        persistent_store.decr_pin_retries().unwrap();
+        assert_eq!(persistent_store.pin_retries().unwrap(), MAX_PIN_RETRIES - 1);
+
+        // The pin retries survive a reboot.
+        let persistent_store = PersistentStore::new(
+            &mut rng,
+            new_buffer_storage_with_content(persistent_store.content()),
+        )
+        .unwrap();
+        assert_eq!(persistent_store.pin_retries().unwrap(), MAX_PIN_RETRIES - 1);
+    }
+
+    #[test]
+    fn test_reset_keeps_signature_counter() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        assert!(persistent_store.incr_global_signature_counter(5).is_ok());
+        persistent_store.decr_pin_retries().unwrap();
+        assert!(persistent_store.reset(&mut rng).is_ok());
+        assert_eq!(persistent_store.global_signature_counter(), Ok(5));
+        assert_eq!(persistent_store.pin_retries(), Ok(MAX_PIN_RETRIES));
+    }
+
+    #[test]
+    fn test_reset_power_loss() {
+        let mut rng = ThreadRng256 {};
+        let mut delay = 0;
+        loop {
+            let mut persistent_store =
+                PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+            let credential_source = create_credential_source(&mut rng, "example.com", vec![]);
+            let credential_id = credential_source.credential_id.clone();
+            persistent_store
+                .store_credential(&mut rng, credential_source)
+                .unwrap();
+            persistent_store
+                .set_pin_hash(&[0x88; PIN_AUTH_LENGTH])
+                .unwrap();
+            let master_encryption_key = *persistent_store.master_keys().unwrap().encryption;
+
+            persistent_store.store.arm_snapshot(delay);
+            persistent_store.reset(&mut rng).unwrap();
+            let (complete, store) = match persistent_store.store.get_snapshot() {
+                Err(_) => (true, persistent_store.store.take_snapshot()),
+                Ok(store) => (false, store),
+            };
+            let mut content = store.into_vec();
+            content.extend_from_slice(&persistent_store.counters.take_snapshot());
+            let persistent_store = PersistentStore::new(
+                &mut rng,
+                new_buffer_storage_with_content(content.into_boxed_slice()),
+            )
+            .unwrap();
+
+            // A remaining credential is still protected by the PIN, and can still be unsealed.
+            if persistent_store.count_credentials() > 0 {
+                assert_eq!(persistent_store.pin_hash(), Some(&[0x88; PIN_AUTH_LENGTH]));
+                assert_eq!(
+                    persistent_store.master_keys().unwrap().encryption,
+                    &master_encryption_key
+                );
+                assert!(persistent_store
+                    .find_credential_by_id(&credential_id)
+                    .is_some());
+            }
+            if complete {
+                assert_eq!(persistent_store.count_credentials(), 0);
+                assert!(persistent_store.pin_hash().is_none());
+                break;
+            }
+            delay += 1;
+        }
+    }
+
+    #[test]
+    fn test_secrets_shredded() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        let contains = |persistent_store: &PersistentStore<BufferStorage>, secret: &[u8]| {
+            persistent_store
+                .content()
+                .windows(secret.len())
+                .any(|window| window == secret)
+        };
+
+        // Deleted credentials are shredded.
+        let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let id0 = credential_source0.credential_id.clone();
+        let id1 = credential_source1.credential_id.clone();
+        persistent_store
+            .store_credential(&mut rng, credential_source0)
+            .unwrap();
+        persistent_store
+            .store_credential(&mut rng, credential_source1)
+            .unwrap();
+        // The copies left by signature counter updates are shredded too.
+        for _ in 0..30 {
+            persistent_store
+                .incr_credential_signature_counter(&id0)
+                .unwrap();
+        }
+        assert!(contains(&persistent_store, &id0));
+        persistent_store.delete_credential(&id0).unwrap();
+        assert!(!contains(&persistent_store, &id0));
+        assert!(contains(&persistent_store, &id1));
+
+        // Old PIN hashes are shredded.
+        let random_data = rng.gen_uniform_u8x32();
+        let pin_hash_1 = array_ref!(random_data, 0, PIN_AUTH_LENGTH);
+        let pin_hash_2 = array_ref!(random_data, PIN_AUTH_LENGTH, PIN_AUTH_LENGTH);
+        persistent_store.set_pin_hash(pin_hash_1).unwrap();
+        persistent_store.set_pin_hash(pin_hash_2).unwrap();
+        assert!(!contains(&persistent_store, pin_hash_1));
+        assert!(contains(&persistent_store, pin_hash_2));
+
+        // No secret survives a reset.
+        let master_encryption_key = *persistent_store.master_keys().unwrap().encryption;
+        let master_hmac_key = *persistent_store.master_keys().unwrap().hmac;
+        persistent_store.reset(&mut rng).unwrap();
+        assert!(!contains(&persistent_store, &id1));
+        assert!(!contains(&persistent_store, pin_hash_2));
+        assert!(!contains(&persistent_store, &master_encryption_key));
+        assert!(!contains(&persistent_store, &master_hmac_key));
+    }
+
+    #[test]
+    fn test_delete_credential_shreds_only_its_pages() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+        let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
+        let id0 = credential_source0.credential_id.clone();
+        let id1 = credential_source1.credential_id.clone();
+        persistent_store
+            .store_credential(&mut rng, credential_source0)
+            .unwrap();
+        persistent_store
+            .store_credential(&mut rng, credential_source1)
+            .unwrap();
+        // Signature counter updates leave deleted copies of the first credential in many pages.
+        for _ in 0..100 {
+            persistent_store
+                .incr_credential_signature_counter(&id0)
+                .unwrap();
+        }
+
+        let erase_count = |persistent_store: &PersistentStore<BufferStorage>| -> usize {
+            persistent_store.store.compaction_info().iter().sum()
+        };
+        let old_erase_count = erase_count(&persistent_store);
+        persistent_store.delete_credential(&id1).unwrap();
+        assert!(erase_count(&persistent_store) <= old_erase_count + 1);
+        assert!(persistent_store.find_credential_by_id(&id0).is_some());
+    }
+
+    #[test]
+    fn test_min_pin_length() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+
+        // The minimum PIN length is initially at the default.
+        assert_eq!(persistent_store.min_pin_length(), DEFAULT_MIN_PIN_LENGTH);
+
+        // Changes of the minimum PIN length persist.
+        persistent_store.set_min_pin_length(8).unwrap();
+        assert_eq!(persistent_store.min_pin_length(), 8);
+        persistent_store.set_min_pin_length(10).unwrap();
+        assert_eq!(persistent_store.min_pin_length(), 10);
+
+        // Resetting the storage resets the minimum PIN length.
+        persistent_store.reset(&mut rng).unwrap();
+        assert_eq!(persistent_store.min_pin_length(), DEFAULT_MIN_PIN_LENGTH);
+    }
+
+    #[test]
+    fn test_min_pin_length_rp_ids() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+
+        // The list of RP IDs is initially empty.
+        assert!(persistent_store.min_pin_length_rp_ids().is_empty());
+
+        // Setting the list replaces it.
+        let rp_ids = vec![String::from("example.com")];
+        assert!(persistent_store
+            .set_min_pin_length_rp_ids(rp_ids.clone())
+            .is_ok());
+        assert_eq!(persistent_store.min_pin_length_rp_ids(), rp_ids);
+        let rp_ids = vec![
+            String::from("example.com"),
+            String::from("another.example.com"),
+        ];
+        assert!(persistent_store
+            .set_min_pin_length_rp_ids(rp_ids.clone())
+            .is_ok());
+        assert_eq!(persistent_store.min_pin_length_rp_ids(), rp_ids);
+
+        // The list has a maximum length.
+        let too_many_rp_ids = vec![String::from("example.com"); MAX_RP_IDS_LENGTH + 1];
+        assert_eq!(
+            persistent_store.set_min_pin_length_rp_ids(too_many_rp_ids),
+            Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
+        );
+        assert_eq!(persistent_store.min_pin_length_rp_ids(), rp_ids);
+
+        // Resetting the storage empties the list.
+        persistent_store.reset(&mut rng).unwrap();
+        assert!(persistent_store.min_pin_length_rp_ids().is_empty());
+    }
+
+    #[test]
+    fn test_config_flags() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng, new_buffer_storage()).unwrap();
+
+        // All flags are initially unset.
+        assert!(!persistent_store.always_uv());
+        assert!(!persistent_store.enterprise_attestation());
+        assert!(!persistent_store.force_pin_change());
+
+        persistent_store.toggle_always_uv().unwrap();
+        assert!(persistent_store.always_uv());
+        persistent_store.toggle_always_uv().unwrap();
+        assert!(!persistent_store.always_uv());
+        persistent_store.toggle_always_uv().unwrap();
+
+        persistent_store.enable_enterprise_attestation().unwrap();
+        assert!(persistent_store.enterprise_attestation());
+        persistent_store.enable_enterprise_attestation().unwrap();
+        assert!(persistent_store.enterprise_attestation());
+
+        persistent_store.set_force_pin_change(true).unwrap();
+        assert!(persistent_store.force_pin_change());
+        persistent_store.set_force_pin_change(true).unwrap();
+        assert!(persistent_store.force_pin_change());
+        persistent_store.set_force_pin_change(false).unwrap();
+        assert!(!persistent_store.force_pin_change());
+        persistent_store.set_force_pin_change(true).unwrap();
+
+        // Resetting the storage resets all flags.
+        persistent_store.reset(&mut rng).unwrap();
+        assert!(!persistent_store.always_uv());
+        assert!(!persistent_store.enterprise_attestation());
+        assert!(!persistent_store.force_pin_change());
     }
 }
@@ -0,0 +1,208 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Time as seen by the CTAP implementation. Platforms convert their timer values into ClockValues,
+// see the Clock trait. The API follows the timer of libtock, which was used before.
+
+use core::ops::{Add, AddAssign, Sub};
+
+// A reading of a free running clock, in ticks of the clock frequency. Clock values wrap around
+// eventually, so they should only be compared through durations.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClockValue {
+    num_ticks: isize,
+    clock_hz: usize,
+}
+
+impl ClockValue {
+    pub const fn new(num_ticks: isize, clock_hz: usize) -> ClockValue {
+        ClockValue {
+            num_ticks,
+            clock_hz,
+        }
+    }
+
+    pub fn num_ticks(&self) -> isize {
+        self.num_ticks
+    }
+
+    pub fn clock_hz(&self) -> usize {
+        self.clock_hz
+    }
+
+    // Computes (value * factor) / divisor, even when value * factor >= isize::MAX.
+    fn scale_int(value: isize, factor: isize, divisor: isize) -> isize {
+        ((value as i64 * factor as i64) / divisor as i64) as isize
+    }
+
+    pub fn ms(&self) -> isize {
+        ClockValue::scale_int(self.num_ticks, 1000, self.clock_hz as isize)
+    }
+
+    pub fn wrapping_add(self, duration: Duration<isize>) -> ClockValue {
+        let duration_ticks = ClockValue::scale_int(duration.ms, self.clock_hz as isize, 1000);
+        ClockValue {
+            num_ticks: self.num_ticks.wrapping_add(duration_ticks),
+            clock_hz: self.clock_hz,
+        }
+    }
+
+    // Returns None for clock values of different frequencies.
+    pub fn wrapping_sub(self, other: ClockValue) -> Option<Duration<isize>> {
+        if self.clock_hz == other.clock_hz {
+            let clock_duration = ClockValue {
+                num_ticks: self.num_ticks.wrapping_sub(other.num_ticks),
+                clock_hz: self.clock_hz,
+            };
+            Some(Duration::from_ms(clock_duration.ms()))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd)]
+pub struct Duration<T> {
+    ms: T,
+}
+
+impl<T> Duration<T> {
+    pub const fn from_ms(ms: T) -> Duration<T> {
+        Duration { ms }
+    }
+}
+
+impl<T: Copy> Duration<T> {
+    pub fn ms(&self) -> T {
+        self.ms
+    }
+}
+
+impl<T: Sub<Output = T>> Sub for Duration<T> {
+    type Output = Duration<T>;
+
+    fn sub(self, other: Duration<T>) -> Duration<T> {
+        Duration {
+            ms: self.ms - other.ms,
+        }
+    }
+}
+
+impl<T: Add<Output = T>> Add for Duration<T> {
+    type Output = Duration<T>;
+
+    fn add(self, other: Duration<T>) -> Duration<T> {
+        Duration {
+            ms: self.ms + other.ms,
+        }
+    }
+}
+
+// A point in time in milliseconds since boot.
+#[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd)]
+pub struct Timestamp<T> {
+    ms: T,
+}
+
+impl<T> Timestamp<T> {
+    pub const fn from_ms(ms: T) -> Timestamp<T> {
+        Timestamp { ms }
+    }
+}
+
+impl<T: Copy> Timestamp<T> {
+    pub fn ms(&self) -> T {
+        self.ms
+    }
+}
+
+impl Timestamp<isize> {
+    pub fn from_clock_value(value: ClockValue) -> Timestamp<isize> {
+        Timestamp { ms: value.ms() }
+    }
+}
+
+impl<T: Sub<Output = T>> Sub for Timestamp<T> {
+    type Output = Duration<T>;
+
+    fn sub(self, other: Timestamp<T>) -> Duration<T> {
+        Duration {
+            ms: self.ms - other.ms,
+        }
+    }
+}
+
+impl<T: Add<Output = T>> Add<Duration<T>> for Timestamp<T> {
+    type Output = Timestamp<T>;
+
+    fn add(self, duration: Duration<T>) -> Timestamp<T> {
+        Timestamp {
+            ms: self.ms + duration.ms,
+        }
+    }
+}
+
+impl<T: AddAssign> AddAssign<Duration<T>> for Timestamp<T> {
+    fn add_assign(&mut self, duration: Duration<T>) {
+        self.ms += duration.ms;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+
+    #[test]
+    fn test_clock_value_ms() {
+        assert_eq!(ClockValue::new(32768, CLOCK_FREQUENCY_HZ).ms(), 1000);
+        assert_eq!(ClockValue::new(16384, CLOCK_FREQUENCY_HZ).ms(), 500);
+    }
+
+    #[test]
+    fn test_clock_value_wrapping_add_sub() {
+        let start = ClockValue::new(1000, CLOCK_FREQUENCY_HZ);
+        let later = start.wrapping_add(Duration::from_ms(1500));
+        assert_eq!(later.wrapping_sub(start), Some(Duration::from_ms(1500)));
+        assert_eq!(
+            later.wrapping_sub(ClockValue::new(0, 1000)),
+            None,
+            "Clock values with different frequencies can't be compared"
+        );
+    }
+
+    #[test]
+    fn test_clock_value_wraps() {
+        let start = ClockValue::new(core::isize::MAX - 10, CLOCK_FREQUENCY_HZ);
+        let later = start.wrapping_add(Duration::from_ms(1000));
+        assert!(later.num_ticks() < 0);
+        assert_eq!(later.wrapping_sub(start), Some(Duration::from_ms(1000)));
+    }
+
+    #[test]
+    fn test_timestamp_arithmetic() {
+        let mut timestamp = Timestamp::<isize>::from_clock_value(ClockValue::new(
+            2 * CLOCK_FREQUENCY_HZ as isize,
+            CLOCK_FREQUENCY_HZ,
+        ));
+        assert_eq!(timestamp.ms(), 2000);
+        timestamp += Duration::from_ms(500);
+        assert_eq!(
+            timestamp - Timestamp::from_ms(1000),
+            Duration::from_ms(1500)
+        );
+        assert!(timestamp + Duration::from_ms(1) > timestamp);
+    }
+}
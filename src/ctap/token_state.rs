@@ -0,0 +1,237 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::status_code::Ctap2StatusCode;
+use super::time::{ClockValue, Duration};
+use super::timed_permission::TimedPermission;
+use alloc::string::String;
+use crypto::sha256::Sha256;
+use crypto::Hash256;
+
+// CTAP specification (version 20210615) section 6.5.2.1
+// A token that is not used within this time after it was issued expires.
+const INITIAL_USAGE_TIME_LIMIT: Duration<isize> = Duration::from_ms(30000);
+// No token is usable for longer than this time after it was issued.
+const MAX_USAGE_TIME_PERIOD: Duration<isize> = Duration::from_ms(600_000);
+
+// The bits of the permissions parameter of getPinUvAuthTokenUsingPinWithPermissions.
+#[derive(Clone, Copy)]
+pub enum PinPermission {
+    MakeCredential = 0x01,
+    GetAssertion = 0x02,
+    CredentialManagement = 0x04,
+    BioEnrollment = 0x08,
+    LargeBlobWrite = 0x10,
+    AuthenticatorConfiguration = 0x20,
+}
+
+// The state of the pinUvAuthToken, shared by all PIN protocol versions. Only one token is in use
+// at any time, issuing a new token replaces the state of the last one.
+pub struct PinUvAuthTokenState {
+    in_use: bool,
+    permissions: u8,
+    permissions_rp_id: Option<String>,
+    // Extended to the maximum usage time on the first use of the token.
+    usage_timer: TimedPermission,
+    max_usage_timer: TimedPermission,
+}
+
+impl PinUvAuthTokenState {
+    pub fn new() -> PinUvAuthTokenState {
+        PinUvAuthTokenState {
+            in_use: false,
+            permissions: 0,
+            permissions_rp_id: None,
+            usage_timer: TimedPermission::waiting(),
+            max_usage_timer: TimedPermission::waiting(),
+        }
+    }
+
+    pub fn is_in_use(&self) -> bool {
+        self.in_use
+    }
+
+    pub fn begin_using_pin_uv_auth_token(
+        &mut self,
+        now: ClockValue,
+        permissions: u8,
+        permissions_rp_id: Option<String>,
+    ) {
+        self.in_use = true;
+        self.permissions = permissions;
+        self.permissions_rp_id = permissions_rp_id;
+        self.usage_timer = TimedPermission::granted(now, INITIAL_USAGE_TIME_LIMIT);
+        self.max_usage_timer = TimedPermission::granted(now, MAX_USAGE_TIME_PERIOD);
+    }
+
+    // Called after the token authenticated a command, it stays usable until its maximum usage time.
+    pub fn mark_used(&mut self) {
+        self.usage_timer = self.max_usage_timer;
+    }
+
+    // Expires the token when its usage timer runs out. Called before processing each command.
+    pub fn pin_uv_auth_token_usage_timer_observer(&mut self, now: ClockValue) {
+        if self.in_use && !self.usage_timer.is_granted(now) {
+            self.stop_using_pin_uv_auth_token();
+        }
+    }
+
+    pub fn stop_using_pin_uv_auth_token(&mut self) {
+        *self = PinUvAuthTokenState::new();
+    }
+
+    pub fn has_permission(&self, permission: PinPermission) -> Result<(), Ctap2StatusCode> {
+        if self.permissions & permission as u8 != 0 {
+            Ok(())
+        } else {
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        }
+    }
+
+    // Only the large blob write permission survives a MakeCredential or GetAssertion.
+    pub fn clear_permissions_except_lbw(&mut self) {
+        self.permissions &= PinPermission::LargeBlobWrite as u8;
+    }
+
+    // Commands that are not scoped to a relying party need a token without permissions RP ID.
+    pub fn has_no_permissions_rp_id(&self) -> Result<(), Ctap2StatusCode> {
+        if self.permissions_rp_id.is_some() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+        }
+        Ok(())
+    }
+
+    // Tokens without permissions RP ID are valid for all relying parties.
+    pub fn has_permissions_rp_id(&self, rp_id: &str) -> Result<(), Ctap2StatusCode> {
+        match &self.permissions_rp_id {
+            Some(permissions_rp_id) if permissions_rp_id != rp_id => {
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn has_permissions_rp_id_hash(&self, rp_id_hash: &[u8]) -> Result<(), Ctap2StatusCode> {
+        match &self.permissions_rp_id {
+            Some(permissions_rp_id)
+                if Sha256::hash(permissions_rp_id.as_bytes())[..] != rp_id_hash[..] =>
+            {
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Like has_permissions_rp_id, but binds tokens without permissions RP ID to the given one.
+    pub fn ensure_permissions_rp_id(&mut self, rp_id: &str) -> Result<(), Ctap2StatusCode> {
+        self.has_permissions_rp_id(rp_id)?;
+        self.permissions_rp_id = Some(String::from(rp_id));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const START_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+
+    #[test]
+    fn test_observer_initial_usage_time_limit() {
+        let mut token_state = PinUvAuthTokenState::new();
+        assert!(!token_state.is_in_use());
+        token_state.begin_using_pin_uv_auth_token(START_CLOCK_VALUE, 0x01, None);
+        assert!(token_state.is_in_use());
+        token_state.pin_uv_auth_token_usage_timer_observer(
+            START_CLOCK_VALUE.wrapping_add(Duration::from_ms(29000)),
+        );
+        assert!(token_state.is_in_use());
+        token_state.pin_uv_auth_token_usage_timer_observer(
+            START_CLOCK_VALUE.wrapping_add(Duration::from_ms(31000)),
+        );
+        assert!(!token_state.is_in_use());
+        assert_eq!(
+            token_state.has_permission(PinPermission::MakeCredential),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
+    #[test]
+    fn test_observer_max_usage_time_period() {
+        let mut token_state = PinUvAuthTokenState::new();
+        token_state.begin_using_pin_uv_auth_token(START_CLOCK_VALUE, 0x01, None);
+        token_state.mark_used();
+        token_state.pin_uv_auth_token_usage_timer_observer(
+            START_CLOCK_VALUE.wrapping_add(Duration::from_ms(599_000)),
+        );
+        assert!(token_state.is_in_use());
+        token_state.pin_uv_auth_token_usage_timer_observer(
+            START_CLOCK_VALUE.wrapping_add(Duration::from_ms(601_000)),
+        );
+        assert!(!token_state.is_in_use());
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut token_state = PinUvAuthTokenState::new();
+        token_state.begin_using_pin_uv_auth_token(START_CLOCK_VALUE, 0x13, None);
+        assert_eq!(
+            token_state.has_permission(PinPermission::MakeCredential),
+            Ok(())
+        );
+        assert_eq!(
+            token_state.has_permission(PinPermission::GetAssertion),
+            Ok(())
+        );
+        assert_eq!(
+            token_state.has_permission(PinPermission::CredentialManagement),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+        token_state.clear_permissions_except_lbw();
+        assert_eq!(
+            token_state.has_permission(PinPermission::MakeCredential),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+        assert_eq!(
+            token_state.has_permission(PinPermission::LargeBlobWrite),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_permissions_rp_id() {
+        let mut token_state = PinUvAuthTokenState::new();
+        token_state.begin_using_pin_uv_auth_token(START_CLOCK_VALUE, 0x01, None);
+        assert_eq!(token_state.has_no_permissions_rp_id(), Ok(()));
+        assert_eq!(token_state.has_permissions_rp_id("example.com"), Ok(()));
+        assert_eq!(token_state.ensure_permissions_rp_id("example.com"), Ok(()));
+        assert_eq!(
+            token_state.has_no_permissions_rp_id(),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+        assert_eq!(
+            token_state.ensure_permissions_rp_id("another.example.com"),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+        assert_eq!(
+            token_state.has_permissions_rp_id_hash(&Sha256::hash(b"example.com")),
+            Ok(())
+        );
+        assert_eq!(
+            token_state.has_permissions_rp_id_hash(&Sha256::hash(b"another.example.com")),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+}
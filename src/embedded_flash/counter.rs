@@ -0,0 +1,475 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Provides flash-efficient monotonic counters.
+//!
+//! # Description
+//!
+//! Incrementing a counter kept in a `Store` entry rewrites the whole entry, which fills pages and
+//! regularly costs a compaction. The `Counters` data-structure counts in unary instead: an
+//! increment clears bits in the current page of the counter, and only moving to the next page
+//! needs an erase.
+//!
+//! Counters never decrease, even if power is lost during an increment. An interrupted increment by
+//! `n` leaves the counter anywhere between its old value and its old value plus `n`, in particular
+//! an interrupted increment by 1 is atomic.
+//!
+//! # Implementation
+//!
+//! The storage is split in as many regions of consecutive pages as there are counters. Each region
+//! matches the following grammar:
+//!
+//! ```text
+//! Counter := Page*  // at least 2 pages
+//! Page :=
+//!     rotation:word  // blank if the page is unused
+//!     base:word  // blank if the page is unused
+//!     Unit*
+//! Unit := word  // counts up to `units_per_word` increments
+//! ```
+//!
+//! The header words hold a little-endian `u32` in their first 4 bytes. The _rotation_ counts how
+//! many times the counter moved to a new page, so rotation `r` uses the page `r % num_pages` of
+//! the region. The _base_ is the value of the counter when it moved to the page. It is written
+//! after the rotation, so a page is only valid once both are written. The current page of a counter
+//! is the valid page with the highest rotation and the value of the counter is its base plus the
+//! number of increments in its units. A counter without valid page is zero.
+//!
+//! Each write to a unit word clears more bits, from the least significant bit of the first byte.
+//! A word can be written `max_word_writes` times between erasures, so it counts that many
+//! increments (at most one per bit) by clearing the same number of bits each time. When the units
+//! of the page are all used, the counter moves to the next page of its region: the page is erased
+//! if needed, then the header is written with the new value as base.
+
+#[cfg(feature = "std")]
+use super::BufferStorage;
+use super::{Index, Storage, StoreError};
+use alloc::vec::Vec;
+
+const BLANK_VALUE: u32 = 0xffff_ffff;
+const ROTATION_WORD: usize = 0;
+const BASE_WORD: usize = 1;
+const HEADER_WORDS: usize = 2;
+
+/// A fixed number of monotonic counters on top of any storage.
+pub struct Counters<S: Storage> {
+    storage: S,
+    num_counters: usize,
+
+    /// The number of pages of each counter.
+    num_pages: usize,
+
+    /// The number of increments a unit word can count.
+    units_per_word: usize,
+
+    /// The number of bits cleared by each increment in a unit word.
+    bits_per_unit: usize,
+}
+
+/// The current page of a counter.
+#[derive(Copy, Clone)]
+struct PageInfo {
+    page: usize,
+    rotation: u32,
+    base: u32,
+    units: usize,
+}
+
+impl<S: Storage> Counters<S> {
+    /// Creates counters in a storage.
+    ///
+    /// Counters of a fresh storage (filled with `0xff`) are zero. There is nothing to recover after
+    /// a power loss, since an interrupted operation leaves the counter in a valid state.
+    ///
+    /// # Errors
+    ///
+    /// Returns `None` if the storage does not have at least 2 pages per counter, or if its words
+    /// are smaller than 4 bytes.
+    pub fn new(storage: S, num_counters: usize) -> Option<Counters<S>> {
+        let word_size = storage.word_size();
+        let word_bits = 8 * word_size;
+        let units_per_word = core::cmp::min(storage.max_word_writes(), word_bits);
+        if num_counters == 0
+            || storage.num_pages() / num_counters < 2
+            || word_size < 4
+            || storage.page_size() / word_size <= HEADER_WORDS
+            || units_per_word == 0
+        {
+            return None;
+        }
+        Some(Counters {
+            num_pages: storage.num_pages() / num_counters,
+            storage,
+            num_counters,
+            units_per_word,
+            bits_per_unit: word_bits / units_per_word,
+        })
+    }
+
+    /// Returns the value of a counter.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidPrecondition` if the counter does not exist.
+    pub fn get(&self, counter: usize) -> Result<u32, StoreError> {
+        self.check_counter(counter)?;
+        Ok(self
+            .current_page(counter)
+            .map_or(0, |info| self.value(info)))
+    }
+
+    /// Increments a counter and returns its new value.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidPrecondition` if the counter does not exist or would overflow, and
+    /// `StoreFull` if its pages are worn out.
+    pub fn incr(&mut self, counter: usize, increment: u32) -> Result<u32, StoreError> {
+        self.check_counter(counter)?;
+        let current_page = self.current_page(counter);
+        let old_value = current_page.map_or(0, |info| self.value(info));
+        let value = old_value
+            .checked_add(increment)
+            .filter(|&value| value != BLANK_VALUE)
+            .ok_or(StoreError::InvalidPrecondition)?;
+        if increment == 0 {
+            return Ok(value);
+        }
+        match current_page {
+            Some(info) if info.units + increment as usize <= self.page_units() => {
+                self.write_units(info, increment as usize)?
+            }
+            _ => {
+                let rotation = current_page.map_or(0, |info| info.rotation + 1);
+                self.rotate(counter, rotation, value)?
+            }
+        }
+        Ok(value)
+    }
+
+    fn check_counter(&self, counter: usize) -> Result<(), StoreError> {
+        if counter < self.num_counters {
+            Ok(())
+        } else {
+            Err(StoreError::InvalidPrecondition)
+        }
+    }
+
+    fn word_size(&self) -> usize {
+        self.storage.word_size()
+    }
+
+    /// Returns the number of increments a page can count.
+    fn page_units(&self) -> usize {
+        (self.storage.page_size() / self.word_size() - HEADER_WORDS) * self.units_per_word
+    }
+
+    fn value(&self, info: PageInfo) -> u32 {
+        // Increments never overflow, see incr.
+        info.base.saturating_add(info.units as u32)
+    }
+
+    fn read_slice(&self, index: Index, length: usize) -> &[u8] {
+        self.storage.read_slice(index, length).unwrap()
+    }
+
+    /// Reads a header word of a page.
+    ///
+    /// Returns `None` if the word is blank.
+    fn read_header(&self, page: usize, word: usize) -> Option<u32> {
+        let index = Index {
+            page,
+            byte: word * self.word_size(),
+        };
+        let value = u32::from_le_bytes(*array_ref!(self.read_slice(index, 4), 0, 4));
+        if value == BLANK_VALUE {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    /// Returns the number of increments in a unit word.
+    ///
+    /// A partially written word counts as if the write was complete, so that the counter never
+    /// goes back.
+    fn read_units(&self, word: &[u8]) -> usize {
+        let cleared_bits = word
+            .iter()
+            .map(|byte| byte.count_zeros() as usize)
+            .sum::<usize>();
+        let units = (cleared_bits + self.bits_per_unit - 1) / self.bits_per_unit;
+        core::cmp::min(units, self.units_per_word)
+    }
+
+    /// Returns the value of a unit word that counts the given number of increments.
+    fn unit_word(&self, units: usize) -> Vec<u8> {
+        let cleared_bits = units * self.bits_per_unit;
+        let mut word = vec![0xff; self.word_size()];
+        for (i, byte) in word.iter_mut().enumerate() {
+            let byte_cleared_bits = core::cmp::min(cleared_bits.saturating_sub(8 * i), 8);
+            *byte = (0xffu16 << byte_cleared_bits) as u8;
+        }
+        word
+    }
+
+    /// Returns the current page of a counter, if any.
+    fn current_page(&self, counter: usize) -> Option<PageInfo> {
+        let first_page = counter * self.num_pages;
+        let (page, rotation, base) = (first_page..first_page + self.num_pages)
+            .filter_map(|page| {
+                let rotation = self.read_header(page, ROTATION_WORD)?;
+                let base = self.read_header(page, BASE_WORD)?;
+                Some((page, rotation, base))
+            })
+            .max_by_key(|&(_, rotation, _)| rotation)?;
+        let word_size = self.word_size();
+        let units_index = Index {
+            page,
+            byte: HEADER_WORDS * word_size,
+        };
+        let units_length = self.storage.page_size() - units_index.byte;
+        let units = self
+            .read_slice(units_index, units_length)
+            .chunks(word_size)
+            .map(|word| self.read_units(word))
+            .sum();
+        Some(PageInfo {
+            page,
+            rotation,
+            base,
+            units,
+        })
+    }
+
+    /// Adds increments to the current page of a counter.
+    ///
+    /// The increments must fit in the page.
+    fn write_units(&mut self, info: PageInfo, increment: usize) -> Result<(), StoreError> {
+        let units_per_word = self.units_per_word;
+        let first_word = info.units / units_per_word;
+        let last_word = (info.units + increment - 1) / units_per_word;
+        let total_units = info.units + increment;
+        let mut words = Vec::with_capacity((last_word - first_word + 1) * self.word_size());
+        for word in first_word..=last_word {
+            let units = core::cmp::min(total_units - word * units_per_word, units_per_word);
+            words.extend_from_slice(&self.unit_word(units));
+        }
+        let index = Index {
+            page: info.page,
+            byte: (HEADER_WORDS + first_word) * self.word_size(),
+        };
+        self.storage.write_slice(index, &words)?;
+        Ok(())
+    }
+
+    /// Moves a counter to the page of the given rotation, with the given value.
+    ///
+    /// If the page is not erased, it is first erased.
+    fn rotate(&mut self, counter: usize, rotation: u32, value: u32) -> Result<(), StoreError> {
+        // The first round of rotations uses fresh pages, each following round erases each page.
+        if rotation as usize / self.num_pages > self.storage.max_page_erases() {
+            return Err(StoreError::StoreFull);
+        }
+        let page = counter * self.num_pages + rotation as usize % self.num_pages;
+        let index = Index { page, byte: 0 };
+        let content = self.read_slice(index, self.storage.page_size());
+        if !content.iter().all(|&byte| byte == 0xff) {
+            self.storage.erase_page(page)?;
+        }
+        let word_size = self.word_size();
+        let mut header = vec![0x00; HEADER_WORDS * word_size];
+        header[ROTATION_WORD * word_size..][..4].copy_from_slice(&rotation.to_le_bytes());
+        header[BASE_WORD * word_size..][..4].copy_from_slice(&value.to_le_bytes());
+        self.storage.write_slice(index, &header)?;
+        Ok(())
+    }
+}
+
+// Those functions are not meant for production.
+#[cfg(feature = "std")]
+impl Counters<BufferStorage> {
+    /// Takes a snapshot of the storage after a given amount of word operations.
+    pub fn arm_snapshot(&mut self, delay: usize) {
+        self.storage.arm_snapshot(delay);
+    }
+
+    /// Unarms and returns the snapshot or the delay remaining.
+    pub fn get_snapshot(&mut self) -> Result<Box<[u8]>, usize> {
+        self.storage.get_snapshot()
+    }
+
+    /// Takes a snapshot of the storage.
+    pub fn take_snapshot(&self) -> Box<[u8]> {
+        self.storage.take_snapshot()
+    }
+
+    /// Returns the storage.
+    pub fn get_storage(self) -> Box<[u8]> {
+        self.storage.get_storage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::BufferOptions;
+    use super::*;
+
+    const WORD_SIZE: usize = 4;
+    // Each page counts 6 * 2 = 12 increments.
+    const PAGE_SIZE: usize = 8 * WORD_SIZE;
+    const NUM_PAGES: usize = 4;
+    const NUM_COUNTERS: usize = 2;
+    const MAX_PAGE_ERASES: usize = 3;
+
+    fn new_buffer(storage: Box<[u8]>) -> BufferStorage {
+        let options = BufferOptions {
+            word_size: WORD_SIZE,
+            page_size: PAGE_SIZE,
+            max_word_writes: 2,
+            max_page_erases: MAX_PAGE_ERASES,
+            strict_write: true,
+        };
+        BufferStorage::new(storage, options)
+    }
+
+    fn new_counters() -> Counters<BufferStorage> {
+        let storage = vec![0xff; NUM_PAGES * PAGE_SIZE].into_boxed_slice();
+        Counters::new(new_buffer(storage), NUM_COUNTERS).unwrap()
+    }
+
+    fn reboot(counters: Box<[u8]>) -> Counters<BufferStorage> {
+        Counters::new(new_buffer(counters), NUM_COUNTERS).unwrap()
+    }
+
+    #[test]
+    fn incr_ok() {
+        let mut counters = new_counters();
+        assert_eq!(counters.get(0), Ok(0));
+        assert_eq!(counters.incr(0, 1), Ok(1));
+        assert_eq!(counters.incr(0, 5), Ok(6));
+        assert_eq!(counters.incr(0, 0), Ok(6));
+        assert_eq!(counters.get(0), Ok(6));
+        assert_eq!(counters.get(1), Ok(0));
+        assert_eq!(counters.incr(1, 2), Ok(2));
+        assert_eq!(counters.get(0), Ok(6));
+    }
+
+    #[test]
+    fn unit_words_ok() {
+        let counters = new_counters();
+        assert_eq!(counters.unit_word(0), [0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(counters.unit_word(1), [0x00, 0x00, 0xff, 0xff]);
+        assert_eq!(counters.unit_word(2), [0x00, 0x00, 0x00, 0x00]);
+        for units in 0..=2 {
+            assert_eq!(counters.read_units(&counters.unit_word(units)), units);
+        }
+        // Partially written words count as written.
+        assert_eq!(counters.read_units(&[0xfe, 0xff, 0xff, 0xff]), 1);
+    }
+
+    #[test]
+    fn rotate_ok() {
+        let mut counters = new_counters();
+        for value in 1..=30 {
+            assert_eq!(counters.incr(0, 1), Ok(value));
+        }
+        assert_eq!(counters.get(0), Ok(30));
+        // Large increments move to the next page at once.
+        assert_eq!(counters.incr(0, 1000), Ok(1030));
+        assert_eq!(counters.incr(0, 1), Ok(1031));
+        assert_eq!(counters.get(1), Ok(0));
+    }
+
+    #[test]
+    fn reboot_ok() {
+        let mut counters = new_counters();
+        counters.incr(0, 20).unwrap();
+        counters.incr(0, 3).unwrap();
+        counters.incr(1, 7).unwrap();
+        let counters = reboot(counters.get_storage());
+        assert_eq!(counters.get(0), Ok(23));
+        assert_eq!(counters.get(1), Ok(7));
+    }
+
+    #[test]
+    fn wear_out() {
+        let mut counters = new_counters();
+        let mut value = 0;
+        // This would panic if a word or a page was written or erased too many times.
+        while let Ok(new_value) = counters.incr(0, 1) {
+            value += 1;
+            assert_eq!(new_value, value);
+        }
+        assert_eq!(counters.incr(0, 1), Err(StoreError::StoreFull));
+        assert_eq!(counters.get(0), Ok(value));
+        // Each of the 2 pages is used once fresh and once after each erase. It counts the increment
+        // that moved to it and 12 more.
+        assert_eq!(value, 13 * 2 * (MAX_PAGE_ERASES as u32 + 1));
+    }
+
+    #[test]
+    fn invalid_incr() {
+        let mut counters = new_counters();
+        assert_eq!(counters.get(2), Err(StoreError::InvalidPrecondition));
+        assert_eq!(counters.incr(2, 1), Err(StoreError::InvalidPrecondition));
+        counters.incr(0, BLANK_VALUE - 1).unwrap();
+        assert_eq!(counters.incr(0, 1), Err(StoreError::InvalidPrecondition));
+        assert_eq!(counters.get(0), Ok(BLANK_VALUE - 1));
+    }
+
+    #[test]
+    fn invalid_storage() {
+        let storage = vec![0xff; NUM_PAGES * PAGE_SIZE].into_boxed_slice();
+        assert!(Counters::new(new_buffer(storage), 3).is_none());
+    }
+
+    #[test]
+    fn incr_atomic() {
+        let increments = [1, 5, 1, 11, 2, 1, 1, 40, 1, 12, 1];
+        for (i, &increment) in increments.iter().enumerate() {
+            let mut delay = 0;
+            loop {
+                let mut counters = new_counters();
+                for &increment in &increments[..i] {
+                    counters.incr(0, increment).unwrap();
+                }
+                let old_value = counters.get(0).unwrap();
+                counters.arm_snapshot(delay);
+                counters.incr(0, increment).unwrap();
+                let (complete, storage) = match counters.get_snapshot() {
+                    Err(_) => (true, counters.get_storage()),
+                    Ok(storage) => (false, storage),
+                };
+                let mut counters = reboot(storage);
+                let value = counters.get(0).unwrap();
+                assert!(value >= old_value && value <= old_value + increment);
+                if increment == 1 {
+                    assert!(value == old_value || value == old_value + 1);
+                }
+                if complete {
+                    assert_eq!(value, old_value + increment);
+                }
+                // The counter keeps working after the power loss.
+                assert_eq!(counters.incr(0, 1), Ok(value + 1));
+                assert_eq!(counters.incr(0, 20), Ok(value + 21));
+                assert_eq!(counters.get(1), Ok(0));
+                if complete {
+                    break;
+                }
+                delay += 1;
+            }
+        }
+    }
+}
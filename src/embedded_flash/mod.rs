@@ -14,12 +14,12 @@
 
 #[cfg(feature = "std")]
 mod buffer;
+mod counter;
 mod storage;
 mod store;
-mod syscall;
 
 #[cfg(feature = "std")]
 pub use self::buffer::{BufferOptions, BufferStorage};
+pub use self::counter::Counters;
 pub use self::storage::{Index, Storage, StorageError, StorageResult};
-pub use self::store::{Store, StoreConfig, StoreEntry, StoreError, StoreIndex};
-pub use self::syscall::SyscallStorage;
+pub use self::store::{Store, StoreConfig, StoreEntry, StoreError, StoreIndex, StoreOperation};
@@ -19,7 +19,7 @@ pub struct Index {
     pub byte: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum StorageError {
     BadFlash,
     NotAligned,
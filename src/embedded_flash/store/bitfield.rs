@@ -77,6 +77,17 @@ pub fn set_zero(bit: usize, data: &mut [u8], gap: ByteGap) {
     data[bit / 8] &= !(1 << (bit % 8));
 }
 
+/// Sets a bit to one in a sequence of bits.
+///
+/// Flash can't set bits to one, so this is only used on copies, e.g. to ignore bits in a checksum.
+/// The sequence of bits is little-endian (both for bytes and bits) and defined by the bits that
+/// are in `data` but not in `gap`.
+pub fn set_one(bit: usize, data: &mut [u8], gap: ByteGap) {
+    let bit = gap.shift(bit);
+    debug_assert!(bit < 8 * data.len());
+    data[bit / 8] |= 1 << (bit % 8);
+}
+
 /// Returns a little-endian value in a sequence of bits.
 ///
 /// The sequence of bits is little-endian (both for bytes and bits) and defined by the bits that
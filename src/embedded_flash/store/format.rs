@@ -25,6 +25,86 @@ pub enum IsReplace {
     Insert,
 }
 
+/// Whether the user entries of a page have a checksum.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum HasChecksum {
+    /// The page was initialized by a store with checksums.
+    Yes,
+
+    /// The page was initialized by an older store, its entries have no checksum.
+    No,
+}
+
+/// The kind of an internal entry.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub enum InternalKind {
+    /// Erases a page once it is compacted.
+    Erase,
+
+    /// Starts a transaction.
+    Begin,
+
+    /// Deletes an entry when its transaction commits.
+    Delete,
+
+    /// Commits a transaction.
+    Commit,
+}
+
+/// The number of bits of the checksum of a user entry.
+const CHECKSUM_BITS: usize = 16;
+
+/// The fields of a user entry following its length.
+///
+/// Their position depends on the width of the tag, which is larger in pages with checksums if the
+/// number of tags grew since older stores. Pages with checksums also store the byte index of
+/// replace entries in words, since entries are word-aligned.
+#[derive(Copy, Clone)]
+struct EntryFields {
+    /// The tag of a user entry.
+    tag_range: bitfield::BitRange,
+
+    /// The page index of a replace entry.
+    replace_page_range: bitfield::BitRange,
+
+    /// The byte index of a replace entry, shifted right by `replace_byte_shift`.
+    replace_byte_range: bitfield::BitRange,
+
+    /// The number of low bits of the byte index that are not stored.
+    replace_byte_shift: usize,
+}
+
+impl EntryFields {
+    fn new(
+        start: usize,
+        tag_bits: usize,
+        page_bits: usize,
+        byte_bits: usize,
+        replace_byte_shift: usize,
+    ) -> EntryFields {
+        let tag_range = bitfield::BitRange {
+            start,
+            length: tag_bits,
+        };
+        let replace_page_range = bitfield::BitRange {
+            start: tag_range.end(),
+            length: page_bits,
+        };
+        let replace_byte_range = bitfield::BitRange {
+            start: replace_page_range.end(),
+            length: byte_bits - replace_byte_shift,
+        };
+        EntryFields {
+            tag_range,
+            replace_page_range,
+            replace_byte_range,
+            replace_byte_shift,
+        }
+    }
+}
+
 /// Helpers to parse the store format.
 ///
 /// See the store module-level documentation for information about the format.
@@ -62,14 +142,13 @@ pub struct Format {
     /// The data length of a user entry.
     length_range: bitfield::BitRange,
 
-    /// The tag of a user entry.
-    tag_range: bitfield::BitRange,
+    /// The fields of user entries in pages with checksums.
+    entry_fields: EntryFields,
 
-    /// The page index of a replace entry.
-    replace_page_range: bitfield::BitRange,
-
-    /// The byte index of a replace entry.
-    replace_byte_range: bitfield::BitRange,
+    /// The fields of user entries in pages initialized by older stores.
+    ///
+    /// Their tags have the width given by the number of tags of older stores.
+    legacy_entry_fields: EntryFields,
 
     /// The index of the page to erase.
     ///
@@ -81,6 +160,26 @@ pub struct Format {
     /// This is only present for internal entries.
     saved_erase_count_range: bitfield::BitRange,
 
+    /// The page index of the entry to delete.
+    ///
+    /// This is only present for internal entries.
+    delete_page_range: bitfield::BitRange,
+
+    /// The byte index of the entry to delete.
+    ///
+    /// This is only present for internal entries.
+    delete_byte_range: bitfield::BitRange,
+
+    /// The kind of an internal entry.
+    ///
+    /// It takes the last 2 bits of the entry, which were padding in stores without transactions.
+    ///
+    /// - 3 for erase entries.
+    /// - 2 for begin entries.
+    /// - 1 for delete entries.
+    /// - 0 for commit entries.
+    internal_kind_range: bitfield::BitRange,
+
     /// Whether a page is initialized.
     ///
     /// - 0 for initialized pages.
@@ -98,6 +197,12 @@ pub struct Format {
 
     /// The page index to which a page is being compacted.
     new_page_range: bitfield::BitRange,
+
+    /// Whether the user entries of a page have a checksum.
+    ///
+    /// - 0 for pages initialized by a store with checksums.
+    /// - 1 for pages initialized by an older store.
+    checksum_bit: usize,
 }
 
 impl Format {
@@ -112,6 +217,7 @@ impl Format {
     /// - It should be possible to write a word at least twice.
     /// - It should be possible to erase a page at least once.
     /// - There should be at least 1 tag.
+    /// - Older stores should have had at least 1 tag and not more than now.
     pub fn new<S: Storage, C: StoreConfig>(storage: &S, config: &C) -> Option<Format> {
         let word_size = storage.word_size();
         let page_size = storage.page_size();
@@ -119,12 +225,15 @@ impl Format {
         let max_word_writes = storage.max_word_writes();
         let max_page_erases = storage.max_page_erases();
         let num_tags = config.num_tags();
+        let num_legacy_tags = config.num_legacy_tags();
         if !(word_size.is_power_of_two()
             && page_size.is_power_of_two()
             && num_pages > 1
             && max_word_writes >= 2
             && max_page_erases > 0
-            && num_tags > 0)
+            && num_tags > 0
+            && num_legacy_tags > 0
+            && num_legacy_tags <= num_tags)
         {
             return None;
         }
@@ -132,6 +241,7 @@ impl Format {
         let page_bits = num_bits(num_pages);
         let byte_bits = num_bits(page_size);
         let tag_bits = num_bits(num_tags);
+        let legacy_tag_bits = num_bits(num_legacy_tags);
         let erase_bits = num_bits(max_page_erases + 1);
         // Compute the bit position of the fields.
         let present_bit = 0;
@@ -142,18 +252,15 @@ impl Format {
             start: replace_bit + 1,
             length: byte_bits,
         };
-        let tag_range = bitfield::BitRange {
-            start: length_range.end(),
-            length: tag_bits,
-        };
-        let replace_page_range = bitfield::BitRange {
-            start: tag_range.end(),
-            length: page_bits,
-        };
-        let replace_byte_range = bitfield::BitRange {
-            start: replace_page_range.end(),
-            length: byte_bits,
-        };
+        let entry_fields = EntryFields::new(
+            length_range.end(),
+            tag_bits,
+            page_bits,
+            byte_bits,
+            num_bits(word_size),
+        );
+        let legacy_entry_fields =
+            EntryFields::new(length_range.end(), legacy_tag_bits, page_bits, byte_bits, 0);
         let old_page_range = bitfield::BitRange {
             start: internal_bit + 1,
             length: page_bits,
@@ -162,6 +269,18 @@ impl Format {
             start: old_page_range.end(),
             length: erase_bits,
         };
+        let delete_page_range = bitfield::BitRange {
+            start: internal_bit + 1,
+            length: page_bits,
+        };
+        let delete_byte_range = bitfield::BitRange {
+            start: delete_page_range.end(),
+            length: byte_bits,
+        };
+        let internal_kind_range = bitfield::BitRange {
+            start: 8 * word_size - 2,
+            length: 2,
+        };
         let initialized_bit = 0;
         let erase_count_range = bitfield::BitRange {
             start: initialized_bit + 1,
@@ -172,6 +291,7 @@ impl Format {
             start: compacting_bit + 1,
             length: page_bits,
         };
+        let checksum_bit = new_page_range.end();
         let format = Format {
             word_size,
             page_size,
@@ -183,22 +303,28 @@ impl Format {
             internal_bit,
             replace_bit,
             length_range,
-            tag_range,
-            replace_page_range,
-            replace_byte_range,
+            entry_fields,
+            legacy_entry_fields,
             old_page_range,
             saved_erase_count_range,
+            delete_page_range,
+            delete_byte_range,
+            internal_kind_range,
             initialized_bit,
             erase_count_range,
             compacting_bit,
             new_page_range,
+            checksum_bit,
         };
         // Make sure all the following conditions hold:
         // - The page header is one word.
-        // - The internal entry is one word.
+        // - The internal entry is one word, with its kind in the last bits.
         // - The entry header fits in one word.
         if format.page_header_size() != word_size
+            || format.checksum_bit >= 8 * word_size
             || format.internal_entry_size() != word_size
+            || format.saved_erase_count_range.end() > format.internal_kind_range.start
+            || format.delete_byte_range.end() > format.internal_kind_range.start
             || format.header_size() > word_size
         {
             return None;
@@ -229,28 +355,33 @@ impl Format {
     ///
     /// This is the number of bytes necessary to store all fields of the entry info. This also
     /// includes the internal padding to protect the `committed` bit from the `deleted` bit.
-    fn info_size(&self, is_replace: IsReplace) -> usize {
-        let suffix_bits = 2; // committed + complete
-        let info_bits = match is_replace {
-            IsReplace::Replace => self.replace_byte_range.end() + suffix_bits,
-            IsReplace::Insert => self.tag_range.end() + suffix_bits,
+    fn info_size(&self, is_replace: IsReplace, has_checksum: HasChecksum) -> usize {
+        let suffix_bits = match has_checksum {
+            HasChecksum::Yes => CHECKSUM_BITS + 2, // checksum + committed + complete
+            HasChecksum::No => 2,                  // committed + complete
         };
-        let info_size = self.bits_to_bytes(info_bits);
-        // If the suffix bits would end up in the header, we need to add one byte for them.
-        if info_size == self.header_size() {
-            info_size + 1
-        } else {
-            info_size
-        }
+        let fields = self.entry_fields(has_checksum);
+        let fields_bits = match is_replace {
+            IsReplace::Replace => fields.replace_byte_range.end(),
+            IsReplace::Insert => fields.tag_range.end(),
+        };
+        // The suffix bits are at the end of the entry, so they can't use the rest of the header.
+        let fields_bits = core::cmp::max(fields_bits, 8 * self.header_size());
+        self.bits_to_bytes(fields_bits + suffix_bits)
     }
 
     /// Returns the length in bytes of an entry.
     ///
-    /// This depends on the length of the user data and whether the entry replaces an old entry or
-    /// is an insertion. This also includes the internal padding to protect the `committed` bit from
-    /// the `deleted` bit.
-    pub fn entry_size(&self, is_replace: IsReplace, length: usize) -> usize {
-        let mut entry_size = length + self.info_size(is_replace);
+    /// This depends on the length of the user data, whether the entry replaces an old entry or is
+    /// an insertion, and whether it has a checksum. This also includes the internal padding to
+    /// protect the `committed` bit from the `deleted` bit.
+    pub fn entry_size(
+        &self,
+        is_replace: IsReplace,
+        length: usize,
+        has_checksum: HasChecksum,
+    ) -> usize {
+        let mut entry_size = length + self.info_size(is_replace, has_checksum);
         let word_size = self.word_size;
         entry_size = self.align_word(entry_size);
         // The entry must be at least 2 words such that the `committed` and `deleted` bits are on
@@ -330,27 +461,47 @@ impl Format {
         bitfield::ByteGap { start, length }
     }
 
-    pub fn get_tag(&self, entry: &[u8]) -> usize {
-        bitfield::get_range(self.tag_range, entry, self.entry_gap(entry))
+    /// Returns the fields of the user entries of a page.
+    fn entry_fields(&self, has_checksum: HasChecksum) -> EntryFields {
+        match has_checksum {
+            HasChecksum::Yes => self.entry_fields,
+            HasChecksum::No => self.legacy_entry_fields,
+        }
     }
 
-    fn set_tag(&self, entry: &mut [u8], tag: usize) {
-        bitfield::set_range(self.tag_range, entry, self.entry_gap(entry), tag)
+    /// Returns whether a tag fits in the user entries of a page.
+    ///
+    /// Pages initialized by older stores may only hold the tags of older stores.
+    pub fn fits_tag(&self, tag: usize, has_checksum: HasChecksum) -> bool {
+        tag < 1 << self.entry_fields(has_checksum).tag_range.length
     }
 
-    pub fn get_replace_index(&self, entry: &[u8]) -> Index {
-        let gap = self.entry_gap(entry);
-        let page = bitfield::get_range(self.replace_page_range, entry, gap);
-        let byte = bitfield::get_range(self.replace_byte_range, entry, gap);
-        Index { page, byte }
+    pub fn get_tag(&self, entry: &[u8], has_checksum: HasChecksum) -> usize {
+        let tag_range = self.entry_fields(has_checksum).tag_range;
+        bitfield::get_range(tag_range, entry, self.entry_gap(entry))
     }
 
-    fn set_replace_page(&self, entry: &mut [u8], page: usize) {
-        bitfield::set_range(self.replace_page_range, entry, self.entry_gap(entry), page)
+    fn set_tag(&self, entry: &mut [u8], tag: usize, has_checksum: HasChecksum) {
+        let tag_range = self.entry_fields(has_checksum).tag_range;
+        bitfield::set_range(tag_range, entry, self.entry_gap(entry), tag)
     }
 
-    fn set_replace_byte(&self, entry: &mut [u8], byte: usize) {
-        bitfield::set_range(self.replace_byte_range, entry, self.entry_gap(entry), byte)
+    pub fn get_replace_index(&self, entry: &[u8], has_checksum: HasChecksum) -> Index {
+        let fields = self.entry_fields(has_checksum);
+        let gap = self.entry_gap(entry);
+        let page = bitfield::get_range(fields.replace_page_range, entry, gap);
+        let byte = bitfield::get_range(fields.replace_byte_range, entry, gap);
+        let byte = byte << fields.replace_byte_shift;
+        Index { page, byte }
+    }
+
+    fn set_replace_index(&self, entry: &mut [u8], index: Index, has_checksum: HasChecksum) {
+        let fields = self.entry_fields(has_checksum);
+        let gap = self.entry_gap(entry);
+        bitfield::set_range(fields.replace_page_range, entry, gap, index.page);
+        let byte = index.byte >> fields.replace_byte_shift;
+        debug_assert_eq!(byte << fields.replace_byte_shift, index.byte);
+        bitfield::set_range(fields.replace_byte_range, entry, gap, byte);
     }
 
     /// Returns the bit position of the `committed` bit.
@@ -383,6 +534,52 @@ impl Format {
         bitfield::set_zero(self.complete_bit(entry), entry, bitfield::NO_GAP)
     }
 
+    /// Returns the bit range of the checksum of a user entry.
+    ///
+    /// The checksum is just before the `committed` bit, so its position only depends on the length
+    /// of the entry.
+    fn checksum_range(&self, entry: &[u8]) -> bitfield::BitRange {
+        bitfield::BitRange {
+            start: self.committed_bit(entry) - CHECKSUM_BITS,
+            length: CHECKSUM_BITS,
+        }
+    }
+
+    /// Computes the checksum of a user entry.
+    ///
+    /// The checksum covers the whole entry except the bits written after the entry: `deleted` and
+    /// `committed`. The `complete` bit is not covered either, since incomplete entries are rolled
+    /// back without looking at their checksum.
+    fn compute_checksum(&self, entry: &[u8]) -> usize {
+        let mut entry = entry.to_vec();
+        bitfield::set_one(self.deleted_bit, &mut entry, bitfield::NO_GAP);
+        bitfield::set_one(self.committed_bit(&entry), &mut entry, bitfield::NO_GAP);
+        bitfield::set_one(self.complete_bit(&entry), &mut entry, bitfield::NO_GAP);
+        let checksum_range = self.checksum_range(&entry);
+        for bit in checksum_range.start..checksum_range.end() {
+            bitfield::set_one(bit, &mut entry, bitfield::NO_GAP);
+        }
+        crc16(&entry) as usize
+    }
+
+    /// Returns whether the checksum of a user entry matches its content.
+    pub fn has_valid_checksum(&self, entry: &[u8]) -> bool {
+        // An entry is at least 2 words, so its checksum can't overlap its header.
+        entry.len() >= 2 * self.word_size
+            && bitfield::get_range(self.checksum_range(entry), entry, bitfield::NO_GAP)
+                == self.compute_checksum(entry)
+    }
+
+    fn set_checksum(&self, entry: &mut [u8]) {
+        let checksum = self.compute_checksum(entry);
+        bitfield::set_range(
+            self.checksum_range(entry),
+            entry,
+            bitfield::NO_GAP,
+            checksum,
+        )
+    }
+
     pub fn get_old_page(&self, header: &[u8]) -> usize {
         bitfield::get_range(self.old_page_range, header, bitfield::NO_GAP)
     }
@@ -404,14 +601,19 @@ impl Format {
         )
     }
 
-    /// Builds an entry for replace or insert operations.
-    pub fn build_entry(&self, replace: Option<Index>, user_entry: StoreEntry) -> Vec<u8> {
+    /// Builds an uncommitted entry for replace or insert operations.
+    pub fn build_entry(
+        &self,
+        replace: Option<Index>,
+        user_entry: StoreEntry,
+        has_checksum: HasChecksum,
+    ) -> Vec<u8> {
         let StoreEntry { tag, data } = user_entry;
         let is_replace = match replace {
             None => IsReplace::Insert,
             Some(_) => IsReplace::Replace,
         };
-        let entry_len = self.entry_size(is_replace, data.len());
+        let entry_len = self.entry_size(is_replace, data.len(), has_checksum);
         let mut entry = Vec::with_capacity(entry_len);
         // Build the header.
         entry.resize(self.header_size(), 0xff);
@@ -421,29 +623,114 @@ impl Format {
         entry.extend_from_slice(data);
         // Build the footer.
         entry.resize(entry_len, 0xff);
-        self.set_tag(&mut entry[..], tag);
+        debug_assert!(self.fits_tag(tag, has_checksum));
+        self.set_tag(&mut entry[..], tag, has_checksum);
         self.set_complete(&mut entry[..]);
-        match replace {
-            None => self.set_committed(&mut entry[..]),
-            Some(Index { page, byte }) => {
-                self.set_replace(&mut entry[..]);
-                self.set_replace_page(&mut entry[..], page);
-                self.set_replace_byte(&mut entry[..], byte);
-            }
+        if let Some(index) = replace {
+            self.set_replace(&mut entry[..]);
+            self.set_replace_index(&mut entry[..], index, has_checksum);
+        }
+        if has_checksum == HasChecksum::Yes {
+            self.set_checksum(&mut entry[..]);
         }
         entry
     }
 
-    /// Builds an entry for replace or insert operations.
+    /// Rebuilds a complete user entry for a page of another format.
+    ///
+    /// This is used to compact pages of older stores to pages with checksums, whose tags may be
+    /// wider. The entry keeps its tag, data, replaced index, and `committed` bit.
+    pub fn convert_entry(&self, entry: &[u8], from: HasChecksum, to: HasChecksum) -> Vec<u8> {
+        if from == to {
+            return entry.to_vec();
+        }
+        debug_assert!(self.is_complete(entry));
+        let user_entry = StoreEntry {
+            tag: self.get_tag(entry, from),
+            data: self.get_data(entry),
+        };
+        let replace = match self.is_replace(entry) {
+            IsReplace::Insert => None,
+            IsReplace::Replace => Some(self.get_replace_index(entry, from)),
+        };
+        let mut new_entry = self.build_entry(replace, user_entry, to);
+        if self.is_committed(entry) {
+            self.set_committed(&mut new_entry[..]);
+        }
+        new_entry
+    }
+
+    /// Builds an entry to erase a page.
     pub fn build_erase_entry(&self, old_page: usize, saved_erase_count: usize) -> Vec<u8> {
+        let mut entry = self.build_internal_entry(InternalKind::Erase);
+        self.set_old_page(&mut entry[..], old_page);
+        self.set_saved_erase_count(&mut entry[..], saved_erase_count);
+        entry
+    }
+
+    /// Builds an entry starting a transaction.
+    pub fn build_begin_entry(&self) -> Vec<u8> {
+        self.build_internal_entry(InternalKind::Begin)
+    }
+
+    /// Builds an entry deleting an entry when its transaction commits.
+    pub fn build_delete_entry(&self, index: Index) -> Vec<u8> {
+        let mut entry = self.build_internal_entry(InternalKind::Delete);
+        bitfield::set_range(
+            self.delete_page_range,
+            &mut entry[..],
+            bitfield::NO_GAP,
+            index.page,
+        );
+        bitfield::set_range(
+            self.delete_byte_range,
+            &mut entry[..],
+            bitfield::NO_GAP,
+            index.byte,
+        );
+        entry
+    }
+
+    /// Builds an entry committing a transaction.
+    pub fn build_commit_entry(&self) -> Vec<u8> {
+        self.build_internal_entry(InternalKind::Commit)
+    }
+
+    /// Builds an internal entry of a given kind, with its other fields left erased.
+    fn build_internal_entry(&self, kind: InternalKind) -> Vec<u8> {
         let mut entry = vec![0xff; self.internal_entry_size()];
         self.set_present(&mut entry[..]);
         self.set_internal(&mut entry[..]);
-        self.set_old_page(&mut entry[..], old_page);
-        self.set_saved_erase_count(&mut entry[..], saved_erase_count);
+        let kind = match kind {
+            InternalKind::Erase => 3,
+            InternalKind::Begin => 2,
+            InternalKind::Delete => 1,
+            InternalKind::Commit => 0,
+        };
+        bitfield::set_range(
+            self.internal_kind_range,
+            &mut entry[..],
+            bitfield::NO_GAP,
+            kind,
+        );
         entry
     }
 
+    pub fn get_internal_kind(&self, header: &[u8]) -> InternalKind {
+        match bitfield::get_range(self.internal_kind_range, header, bitfield::NO_GAP) {
+            3 => InternalKind::Erase,
+            2 => InternalKind::Begin,
+            1 => InternalKind::Delete,
+            _ => InternalKind::Commit,
+        }
+    }
+
+    pub fn get_delete_index(&self, header: &[u8]) -> Index {
+        let page = bitfield::get_range(self.delete_page_range, header, bitfield::NO_GAP);
+        let byte = bitfield::get_range(self.delete_byte_range, header, bitfield::NO_GAP);
+        Index { page, byte }
+    }
+
     /// Returns the length in bytes of a page header entry.
     ///
     /// This includes the word padding.
@@ -483,6 +770,18 @@ impl Format {
         bitfield::set_range(self.new_page_range, header, bitfield::NO_GAP, new_page)
     }
 
+    pub fn has_checksum(&self, header: &[u8]) -> HasChecksum {
+        if bitfield::is_zero(self.checksum_bit, header, bitfield::NO_GAP) {
+            HasChecksum::Yes
+        } else {
+            HasChecksum::No
+        }
+    }
+
+    pub fn set_has_checksum(&self, header: &mut [u8]) {
+        bitfield::set_zero(self.checksum_bit, header, bitfield::NO_GAP)
+    }
+
     /// Returns the smallest word boundary greater or equal to a value.
     fn align_word(&self, value: usize) -> usize {
         let word_size = self.word_size;
@@ -495,11 +794,36 @@ impl Format {
     }
 }
 
+/// Computes the CRC-16/CCITT-FALSE of a slice.
+///
+/// It detects all errors of up to 3 bits and all bursts of up to 16 bits in an entry.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
 /// Returns the number of bits necessary to write numbers smaller than `x`.
 fn num_bits(x: usize) -> usize {
     x.next_power_of_two().trailing_zeros() as usize
 }
 
+#[test]
+fn crc16_ok() {
+    // The check value of the CRC-16/CCITT-FALSE catalog entry.
+    assert_eq!(crc16(b"123456789"), 0x29b1);
+    assert_eq!(crc16(&[]), 0xffff);
+}
+
 #[test]
 fn num_bits_ok() {
     assert_eq!(num_bits(0), 0);
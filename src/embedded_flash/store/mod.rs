@@ -31,6 +31,9 @@
 //! entries associated to a given key. The same key can be associated to multiple entries and the
 //! same entry can be associated to multiple keys.
 //!
+//! Deleted entries stay in the storage until their page is compacted. Entries whose tag is
+//! _sensitive_ can be erased from the storage by shredding, which compacts their page.
+//!
 //! # Storage
 //!
 //! The data-structure is parametric over its storage which must implement the `Storage` trait.
@@ -55,6 +58,7 @@
 //!     erase_count:erase_bits
 //!     compacting:1
 //!     new_page:page_bits
+//!     checksum:1  // whether the entries of the page have a checksum
 //!     Padding(word)
 //! Entry := Header Data Footer
 //! // Let X be the byte following `length` in `Info`.
@@ -66,21 +70,32 @@
 //!     internal=1
 //!     replace:1
 //!     length:byte_bits
-//!     tag:tag_bits
+//!     tag:tag_bits  // legacy_tag_bits if the page has no checksums
 //!     [  // present if `replace` is 0
 //!         replace_page:page_bits
-//!         replace_byte:byte_bits
+//!         replace_byte:byte_bits  // in words if the page has checksums
 //!     ]
 //!     [Padding(bit)]  // until `complete` is the last bit of a different word than `present`
+//!     [checksum:16]  // present if the page has checksums
 //!     committed:1
 //!     complete=0
-//! InternalEntry :=
+//! InternalEntry :=  // must fit in one word
 //!     present=0
 //!     deleted:1
 //!     internal=0
+//!     (EraseEntry | BeginEntry | DeleteEntry | CommitEntry)
+//! EraseEntry :=
 //!     old_page:page_bits
 //!     saved_erase_count:erase_bits
-//!     Padding(word)
+//!     Padding(bit)  // until `kind` is the last 2 bits of the word
+//!     kind=3
+//! BeginEntry := Padding(bit) kind=2
+//! DeleteEntry :=
+//!     delete_page:page_bits
+//!     delete_byte:byte_bits
+//!     Padding(bit)
+//!     kind=1
+//! CommitEntry := Padding(bit) kind=0
 //! Padding(X) := 1* until X-aligned
 //! ```
 //!
@@ -88,8 +103,17 @@
 //! false. They can be set to true by writing 0.
 //!
 //! The `Entry` rule is for user entries and the `InternalEntry` rule is for internal entries of the
-//! store. Currently, there is only one kind of internal entry: an entry to erase the page being
-//! compacted.
+//! store. An erase entry erases the page being compacted. The other internal entries are for
+//! transactions, which are written in the free space of a single page as follows:
+//!
+//! ```text
+//! Transaction := BeginEntry (Entry | DeleteEntry)* CommitEntry
+//! ```
+//!
+//! The entries of a transaction are written uncommitted. Once the commit entry is written, the
+//! transaction is committed: the entries pointed by delete entries are deleted and the new entries
+//! are committed. Then the delete entries, the commit entry, and the begin entry are deleted. If
+//! power is lost before the commit entry is written, the transaction is rolled-back instead.
 //!
 //! The `Header` and `Footer` rules are computed from the `Info` rule. An entry could simply be the
 //! concatenation of internal metadata and the user data. However, to optimize the size in flash, we
@@ -97,7 +121,7 @@
 //! the same word and for replace entries we need to write the deleted bit and the committed bit
 //! independently. Also, this is important for the complete bit to be the last written bit (since
 //! slices are written to flash from low to high addresses). Here is the representation of a
-//! specific replace entry for a specific configuration:
+//! specific replace entry without checksum for a specific configuration:
 //!
 //! ```text
 //! page_bits=6
@@ -123,6 +147,16 @@
 //!    8.0   <end> (word-aligned)
 //! ```
 //!
+//! The `checksum` of an entry is a CRC-16 of the entry where the `deleted`, `checksum`,
+//! `committed`, and `complete` bits are set to 1. Pages initialized by older stores have no
+//! checksums and may have narrower tags. Their entries get one (and the current tag width) when the
+//! page is compacted. Entries with tags too large for a page are written to other pages. When the store is created, a
+//! corrupted entry is dropped with the entries following it in the same page (since its length
+//! can't be trusted) by compacting the page. Corruption of the bits deciding how an entry is
+//! parsed (`present` and `internal`) or written after the entry (`deleted`, `committed`, and
+//! `complete`) can't be detected. Neither can a corrupted `length` moving the `complete` bit to
+//! an erased bit, in which case the entry is rolled-back like an incomplete entry.
+//!
 //! The store should always contain at least one blank page, so that it is always possible to
 //! compact.
 
@@ -130,18 +164,14 @@
 //   InsertEntry | ReplaceEntry | InternalEntry (maybe rename to EraseEntry)
 //   InsertEntry padding is until `complete` is the last bit of a word.
 //   ReplaceEntry padding is until `complete` is the last bit of a different word than `present`.
-// TODO(cretin): Add checksum (may play the same role as the completed bit) and recovery strategy?
-// TODO(cretin): Add corruption (deterministic but undetermined reads) to fuzzing.
-// TODO(cretin): Add more complex transactions? (this does not seem necessary yet)
-// TODO(cretin): Add possibility to shred an entry (force compact page after delete)?
 
 mod bitfield;
 mod format;
 
-use self::format::{Format, IsReplace};
+use self::format::{Format, HasChecksum, InternalKind, IsReplace};
 #[cfg(feature = "std")]
 use super::BufferStorage;
-use super::{Index, Storage};
+use super::{Index, Storage, StorageError};
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 
@@ -159,11 +189,27 @@ pub trait StoreConfig {
     /// To disable tags, this function should return `1`. The only valid tag would then be `0`.
     fn num_tags(&self) -> usize;
 
+    /// Number of entry tags of older stores, whose pages have no checksums.
+    ///
+    /// A tag takes as many bits as needed for the number of tags. The pages of older stores keep
+    /// narrower tags if the number of tags grew since then, so entries with larger tags are only
+    /// written to pages with checksums. By default, the number of tags didn't change.
+    fn num_legacy_tags(&self) -> usize {
+        self.num_tags()
+    }
+
     /// Specifies the set of keys of an entry.
     ///
     /// If keys are not used, this function can immediately return. Otherwise, it should call
     /// `associate_key` for each key that should be associated to `entry`.
     fn keys(&self, entry: StoreEntry, associate_key: impl FnMut(Self::Key));
+
+    /// Whether entries with a given tag must not survive in the storage once deleted.
+    ///
+    /// Deleted sensitive entries are erased by `Store::shred`. By default, no tag is sensitive.
+    fn is_sensitive(&self, _tag: usize) -> bool {
+        false
+    }
 }
 
 /// Errors returned by store operations.
@@ -177,6 +223,18 @@ pub enum StoreError {
 
     /// The operation could not proceed because the preconditions do not hold.
     InvalidPrecondition,
+
+    /// The operation could not proceed because the storage failed.
+    ///
+    /// The operation may be partially written. It is rolled-back or completed the next time the
+    /// store is created, like when power is lost.
+    StorageError(StorageError),
+}
+
+impl From<StorageError> for StoreError {
+    fn from(error: StorageError) -> StoreError {
+        StoreError::StorageError(error)
+    }
 }
 
 /// The position of an entry in the store.
@@ -205,6 +263,19 @@ pub struct StoreEntry<'a> {
     pub data: &'a [u8],
 }
 
+/// An operation of a transaction.
+#[derive(Copy, Clone)]
+pub enum StoreOperation<'a> {
+    /// Inserts an entry.
+    Insert(StoreEntry<'a>),
+
+    /// Replaces an entry with another with the same tag.
+    Replace(StoreIndex, StoreEntry<'a>),
+
+    /// Deletes an entry.
+    Delete(StoreIndex),
+}
+
 /// Implements a configurable multi-set on top of any storage.
 pub struct Store<S: Storage, C: StoreConfig> {
     storage: S,
@@ -219,6 +290,9 @@ pub struct Store<S: Storage, C: StoreConfig> {
     /// A `StoreIndex` is valid only if they originate from the same generation. This is checked by
     /// operations that take a `StoreIndex` as argument.
     generation: usize,
+
+    /// Counts the number of corrupted entries dropped since the store creation.
+    dropped_entries: usize,
 }
 
 impl<S: Storage, C: StoreConfig> Store<S, C> {
@@ -226,11 +300,12 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     ///
     /// Initializes the storage if it is fresh (filled with `0xff`). Rolls-back or completes an
     /// operation if the store was powered off in the middle of that operation. In other words,
-    /// operations are atomic.
+    /// operations are atomic. Drops corrupted entries, see `dropped_entries`.
     ///
     /// # Errors
     ///
-    /// Returns `None` if `storage` and/or `config` are not supported.
+    /// Returns `None` if `storage` and/or `config` are not supported, or if the storage fails
+    /// while recovering or initializing.
     pub fn new(storage: S, config: C) -> Option<Store<S, C>> {
         let format = Format::new(&storage, &config)?;
         let blank_page = format.num_pages;
@@ -240,27 +315,42 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             format,
             blank_page,
             generation: 0,
+            dropped_entries: 0,
         };
         // Finish any ongoing page compaction.
-        store.recover_compact_page();
+        store.recover_compact_page().ok()?;
+        // Commit or roll-back any ongoing transaction.
+        store.recover_transaction().ok()?;
         // Finish or roll-back any other entry-level operations.
-        store.recover_entry_operations();
+        let corrupted_pages = store.recover_entry_operations().ok()?;
         // Initialize uninitialized pages.
-        store.initialize_storage();
+        store.initialize_storage().ok()?;
+        // Drop corrupted entries.
+        store.compact_corrupted_pages(corrupted_pages).ok()?;
         Some(store)
     }
 
+    /// Returns the number of corrupted entries dropped since the store creation.
+    ///
+    /// An entry is corrupted if its checksum does not match. Since its length can't be trusted,
+    /// the entries following it in the same page are dropped too but they are not counted. Entries
+    /// written before the store had checksums are not checked until their page is compacted.
+    pub fn dropped_entries(&self) -> usize {
+        self.dropped_entries
+    }
+
     /// Iterates over all entries in the store.
     pub fn iter(&self) -> impl Iterator<Item = (StoreIndex, StoreEntry)> {
         Iter::new(self).filter_map(move |(index, entry)| {
-            if self.format.is_alive(entry) {
+            if self.format.is_alive(entry) && !self.format.is_internal(entry) {
+                let has_checksum = self.page_has_checksum(index.page);
                 Some((
                     StoreIndex {
                         index,
                         generation: self.generation,
                     },
                     StoreEntry {
-                        tag: self.format.get_tag(entry),
+                        tag: self.format.get_tag(entry, has_checksum),
                         data: self.format.get_data(entry),
                     },
                 ))
@@ -302,8 +392,7 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         if self.generation != index.generation {
             return Err(StoreError::InvalidPrecondition);
         }
-        self.delete_index(index.index);
-        Ok(())
+        self.delete_index(index.index)
     }
 
     /// Replaces an entry with another with the same tag in the store.
@@ -319,6 +408,7 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     /// - `StoreFull` if the new entry does not fit in the store.
     /// - `InvalidTag` if the tag of the new entry is not smaller than the configured number of
     ///   tags.
+    /// - `StorageError` if the storage fails.
     pub fn replace(&mut self, old: StoreIndex, new: StoreEntry) -> Result<(), StoreError> {
         if self.generation != old.generation {
             return Err(StoreError::InvalidPrecondition);
@@ -327,15 +417,16 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         let mut old_index = old.index;
         // Find a slot.
         let entry_len = self.replace_len(new.data.len());
-        let index = self.find_slot_for_write(entry_len, Some(&mut old_index))?;
+        let index =
+            self.find_slot_for_write(entry_len, new.tag, core::slice::from_mut(&mut old_index))?;
         // Build a new entry replacing the old one.
-        let entry = self.format.build_entry(Some(old_index), new);
-        debug_assert_eq!(entry.len(), entry_len);
+        let has_checksum = self.page_has_checksum(index.page);
+        let entry = self.format.build_entry(Some(old_index), new, has_checksum);
+        debug_assert!(entry.len() <= entry_len);
         // Write the new entry.
-        self.write_entry(index, &entry);
+        self.write_entry(index, &entry)?;
         // Commit the new entry, which both deletes the old entry and commits the new one.
-        self.commit_index(index);
-        Ok(())
+        self.commit_index(index)
     }
 
     /// Inserts an entry in the store.
@@ -346,31 +437,168 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     /// - `StoreFull` if the new entry does not fit in the store.
     /// - `InvalidTag` if the tag of the new entry is not smaller than the configured number of
     ///   tags.
+    /// - `StorageError` if the storage fails.
     pub fn insert(&mut self, entry: StoreEntry) -> Result<(), StoreError> {
         self.format.validate_entry(entry)?;
-        // Build entry.
-        let entry = self.format.build_entry(None, entry);
         // Find a slot.
-        let index = self.find_slot_for_write(entry.len(), None)?;
+        let entry_len = self.insert_len(entry.data.len());
+        let index = self.find_slot_for_write(entry_len, entry.tag, &mut [])?;
+        // Build entry.
+        let has_checksum = self.page_has_checksum(index.page);
+        let mut entry = self.format.build_entry(None, entry, has_checksum);
+        self.format.set_committed(&mut entry);
+        debug_assert!(entry.len() <= entry_len);
         // Write entry.
-        self.write_entry(index, &entry);
-        Ok(())
+        self.write_entry(index, &entry)
+    }
+
+    /// Applies a sequence of operations atomically.
+    ///
+    /// If it returns successfully, then all operations are applied. If it fails, then none is. If
+    /// power is lost during the transaction, during next startup, the transaction is either
+    /// rolled-back (like in case of failure) or completed (like in case of success).
+    ///
+    /// The operations must be on distinct entries. The transaction is written in a single page, so
+    /// all its entries must fit together in the free space of a page.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// - `StoreFull` if the new entries do not fit in a page.
+    /// - `InvalidTag` if the tag of a new entry is not smaller than the configured number of tags.
+    /// - `InvalidPrecondition` if an index is outdated or used by more than one operation.
+    /// - `StorageError` if the storage fails.
+    pub fn transaction(&mut self, operations: &[StoreOperation]) -> Result<(), StoreError> {
+        // Validate the operations and compute the length of the transaction, which starts with a
+        // begin entry and ends with a commit entry.
+        let internal_len = self.format.internal_entry_size();
+        let mut length = 2 * internal_len;
+        let mut max_tag = 0;
+        let mut old_indices = Vec::new();
+        for &operation in operations {
+            let old = match operation {
+                StoreOperation::Insert(entry) => {
+                    self.format.validate_entry(entry)?;
+                    length += self.insert_len(entry.data.len());
+                    max_tag = core::cmp::max(max_tag, entry.tag);
+                    continue;
+                }
+                StoreOperation::Replace(old, entry) => {
+                    self.format.validate_entry(entry)?;
+                    length += self.replace_len(entry.data.len());
+                    max_tag = core::cmp::max(max_tag, entry.tag);
+                    old
+                }
+                StoreOperation::Delete(old) => {
+                    length += internal_len;
+                    old
+                }
+            };
+            if self.generation != old.generation || old_indices.contains(&old.index) {
+                return Err(StoreError::InvalidPrecondition);
+            }
+            old_indices.push(old.index);
+        }
+        // Find a slot for the whole transaction.
+        let mut index = self.find_slot_for_write(length, max_tag, &mut old_indices)?;
+        // Build the entries. New entries are uncommitted until the transaction is committed.
+        let has_checksum = self.page_has_checksum(index.page);
+        let mut old_indices = old_indices.into_iter();
+        let mut entries = Vec::with_capacity(operations.len() + 2);
+        entries.push(self.format.build_begin_entry());
+        for &operation in operations {
+            entries.push(match operation {
+                StoreOperation::Insert(entry) => self.format.build_entry(None, entry, has_checksum),
+                StoreOperation::Replace(_, entry) => {
+                    self.format
+                        .build_entry(old_indices.next(), entry, has_checksum)
+                }
+                StoreOperation::Delete(_) => {
+                    let old_index = old_indices.next().unwrap();
+                    self.format.build_delete_entry(old_index)
+                }
+            });
+        }
+        let begin = index;
+        let commit = Index {
+            page: index.page,
+            byte: index.byte + entries.iter().map(Vec::len).sum::<usize>(),
+        };
+        entries.push(self.format.build_commit_entry());
+        // Write the entries. The transaction is committed once the commit entry is written.
+        for entry in entries {
+            self.write_entry(index, &entry)?;
+            index.byte += entry.len();
+        }
+        self.finish_transaction(begin, Some(commit))
+    }
+
+    /// Erases deleted sensitive entries from the storage.
+    ///
+    /// Deleting an entry (directly or by replacing it) only marks it as deleted: its data stays in
+    /// the storage until its page is compacted. This function compacts all pages holding deleted
+    /// entries that are sensitive (see `StoreConfig::is_sensitive`) or incomplete (since their tag
+    /// can't be trusted).
+    ///
+    /// Compacting a page costs an erase, so this function should only be called when a secret was
+    /// deleted, see also `shred_key`. If power is lost before it returns, the remaining secrets are
+    /// only erased by the next call.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StoreFull` if a page to compact reached its erase limit, or if its entries would
+    /// not fit the blank page. Its secrets are left in the storage, the other pages are compacted.
+    pub fn shred(&mut self) -> Result<(), StoreError> {
+        self.shred_pages(None)
+    }
+
+    /// Erases deleted sensitive entries matching a key from the storage.
+    ///
+    /// This is like `shred`, but only compacts the pages holding deleted sensitive entries that
+    /// match the key, or incomplete entries. Pages holding other deleted secrets are left as they
+    /// are, which saves erases when those secrets are still alive in a newer entry.
+    ///
+    /// # Errors
+    ///
+    /// Same as `shred`.
+    pub fn shred_key(&mut self, key: &C::Key) -> Result<(), StoreError> {
+        self.shred_pages(Some(key))
+    }
+
+    /// Compacts the pages holding deleted secrets, see `shred` and `shred_key`.
+    fn shred_pages(&mut self, key: Option<&C::Key>) -> Result<(), StoreError> {
+        let mut result = Ok(());
+        for page in 0..self.format.num_pages {
+            if page == self.blank_page || !self.has_deleted_secrets(page, key) {
+                continue;
+            }
+            if self.can_compact(&self.page_info(page)) {
+                self.compact_page(page, self.blank_page)?;
+            } else {
+                result = Err(StoreError::StoreFull);
+            }
+        }
+        result
     }
 
     /// Returns the byte cost of a replace operation.
     ///
     /// Computes the length in bytes that would be used in the storage if a replace operation is
-    /// executed provided the data of the new entry has `length` bytes.
+    /// executed provided the data of the new entry has `length` bytes. This is an upper bound since
+    /// entries written to pages of older stores have no checksum.
     pub fn replace_len(&self, length: usize) -> usize {
-        self.format.entry_size(IsReplace::Replace, length)
+        self.format
+            .entry_size(IsReplace::Replace, length, HasChecksum::Yes)
     }
 
     /// Returns the byte cost of an insert operation.
     ///
     /// Computes the length in bytes that would be used in the storage if an insert operation is
-    /// executed provided the data of the inserted entry has `length` bytes.
+    /// executed provided the data of the inserted entry has `length` bytes. This is an upper bound
+    /// since entries written to pages of older stores have no checksum.
     pub fn insert_len(&self, length: usize) -> usize {
-        self.format.entry_size(IsReplace::Insert, length)
+        self.format
+            .entry_size(IsReplace::Insert, length, HasChecksum::Yes)
     }
 
     /// Returns the erase count of all pages.
@@ -388,46 +616,227 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     }
 
     /// Completes any ongoing page compaction.
-    fn recover_compact_page(&mut self) {
+    fn recover_compact_page(&mut self) -> Result<(), StoreError> {
         for page in 0..self.format.num_pages {
             let (page_header, _) = self.read_page_header(page);
             if self.format.is_compacting(page_header) {
                 let new_page = self.format.get_new_page(page_header);
-                self.compact_page(page, new_page);
+                self.compact_page(page, new_page)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Commits or rolls-back any ongoing transaction.
+    ///
+    /// A transaction is ongoing if a begin entry is alive. It is committed if a commit entry is
+    /// alive too.
+    fn recover_transaction(&mut self) -> Result<(), StoreError> {
+        let mut begin = None;
+        let mut commit = None;
+        for (index, entry) in Iter::new(self) {
+            if !self.format.is_alive(entry) || !self.format.is_internal(entry) {
+                continue;
+            }
+            match self.format.get_internal_kind(entry) {
+                InternalKind::Begin => begin = Some(index),
+                InternalKind::Commit => commit = Some(index),
+                InternalKind::Erase | InternalKind::Delete => (),
+            }
+        }
+        match begin {
+            None => Ok(()),
+            Some(begin) => self.finish_transaction(begin, commit),
+        }
+    }
+
+    /// Commits or rolls-back a transaction.
+    ///
+    /// The entries of a transaction follow its begin entry in the same page. If the transaction is
+    /// committed, its delete entries delete their entry and its new entries are committed.
+    /// Otherwise, they are all deleted. Finally, the commit entry (if any) and then the begin entry
+    /// are deleted, so this can start over if power is lost.
+    fn finish_transaction(
+        &mut self,
+        begin: Index,
+        commit: Option<Index>,
+    ) -> Result<(), StoreError> {
+        let has_checksum = self.page_has_checksum(begin.page);
+        let mut index = begin;
+        index.byte += self.format.internal_entry_size();
+        while index.byte < self.format.page_size {
+            let entry_index = index;
+            let entry = self.read_entry(index);
+            index.byte += entry.len();
+            if self.is_corrupted(has_checksum, entry) {
+                // The rest of the page is dropped when the store is created.
+                break;
             }
+            if !self.format.is_alive(entry) {
+                // Skip deleted entries (or the page padding).
+            } else if self.format.is_internal(entry) {
+                if self.format.get_internal_kind(entry) == InternalKind::Delete {
+                    if commit.is_some() {
+                        let delete_index = self.format.get_delete_index(entry);
+                        self.delete_index(delete_index)?;
+                    }
+                    self.delete_index(entry_index)?;
+                }
+            } else if !self.format.is_committed(entry) {
+                if commit.is_some() {
+                    self.commit_index(entry_index)?;
+                } else {
+                    self.delete_index(entry_index)?;
+                }
+            }
+        }
+        if let Some(commit) = commit {
+            self.delete_index(commit)?;
         }
+        self.delete_index(begin)
     }
 
     /// Rolls-back or completes any ongoing operation.
-    fn recover_entry_operations(&mut self) {
+    ///
+    /// Returns the pages with corrupted entries. The entries following a corrupted entry are not
+    /// recovered since their position can't be trusted.
+    fn recover_entry_operations(&mut self) -> Result<Vec<usize>, StoreError> {
+        let mut corrupted_pages = Vec::new();
         for page in 0..self.format.num_pages {
             let (page_header, mut index) = self.read_page_header(page);
             if !self.format.is_initialized(page_header) {
                 // Skip uninitialized pages.
                 continue;
             }
+            let has_checksum = self.format.has_checksum(page_header);
             while index.byte < self.format.page_size {
                 let entry_index = index;
                 let entry = self.read_entry(index);
                 index.byte += entry.len();
+                if self.is_corrupted(has_checksum, entry) {
+                    corrupted_pages.push(page);
+                    break;
+                }
                 if !self.format.is_alive(entry) {
                     // Skip deleted entries (or the page padding).
                 } else if self.format.is_internal(entry) {
-                    // Finish page compaction.
-                    self.erase_page(entry_index);
+                    // Finish page compaction. Transactions are already recovered.
+                    if self.format.get_internal_kind(entry) == InternalKind::Erase {
+                        self.erase_page(entry_index)?;
+                    }
                 } else if !self.format.is_complete(entry) {
                     // Roll-back incomplete operations.
-                    self.delete_index(entry_index);
+                    self.delete_index(entry_index)?;
                 } else if !self.format.is_committed(entry) {
                     // Finish complete but uncommitted operations.
-                    self.commit_index(entry_index)
+                    self.commit_index(entry_index)?;
                 }
             }
         }
+        Ok(corrupted_pages)
+    }
+
+    /// Compacts pages with corrupted entries.
+    ///
+    /// The corrupted entries and the entries following them are dropped by compaction.
+    fn compact_corrupted_pages(&mut self, pages: Vec<usize>) -> Result<(), StoreError> {
+        for page in pages {
+            // The page may have been erased since it was found corrupted.
+            if page != self.blank_page && self.find_corruption(page).is_some() {
+                self.compact_page(page, self.blank_page)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether a page holds deleted entries that must be shredded.
+    ///
+    /// If a key is given, only sensitive entries matching it are considered.
+    fn has_deleted_secrets(&self, page: usize, key: Option<&C::Key>) -> bool {
+        let (page_header, mut index) = self.read_page_header(page);
+        let has_checksum = self.format.has_checksum(page_header);
+        while index.byte < self.format.page_size {
+            let entry = self.read_entry(index);
+            index.byte += entry.len();
+            if !self.format.is_present(entry)
+                || !self.format.is_deleted(entry)
+                || self.format.is_internal(entry)
+            {
+                continue;
+            }
+            if !self.format.is_complete(entry) || self.is_truncated(has_checksum, entry) {
+                return true;
+            }
+            let tag = self.format.get_tag(entry, has_checksum);
+            if !self.config.is_sensitive(tag) {
+                continue;
+            }
+            let has_match = match key {
+                None => true,
+                Some(key) => {
+                    let mut has_match = false;
+                    let entry = StoreEntry {
+                        tag,
+                        data: self.format.get_data(entry),
+                    };
+                    self.config.keys(entry, |k| has_match |= key == &k);
+                    has_match
+                }
+            };
+            if has_match {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns whether a user entry is truncated to fit its page.
+    ///
+    /// Entries are written in the free space of a page, so a truncated entry has a corrupted
+    /// length.
+    fn is_truncated(&self, has_checksum: HasChecksum, entry: &[u8]) -> bool {
+        let is_replace = self.format.is_replace(entry);
+        let length = self.format.get_length(entry);
+        self.format.entry_size(is_replace, length, has_checksum) != entry.len()
+    }
+
+    /// Returns whether a page has checksums.
+    fn page_has_checksum(&self, page: usize) -> HasChecksum {
+        let (page_header, _) = self.read_page_header(page);
+        self.format.has_checksum(page_header)
+    }
+
+    /// Returns whether an entry is corrupted.
+    ///
+    /// Only user entries of pages with checksums can be detected as corrupted. Incomplete entries
+    /// are rolled-back anyway, unless they are truncated.
+    fn is_corrupted(&self, has_checksum: HasChecksum, entry: &[u8]) -> bool {
+        if has_checksum == HasChecksum::No
+            || !self.format.is_present(entry)
+            || self.format.is_internal(entry)
+        {
+            return false;
+        }
+        self.is_truncated(has_checksum, entry)
+            || (self.format.is_complete(entry) && !self.format.has_valid_checksum(entry))
+    }
+
+    /// Returns the index of the first corrupted entry of a page, if any.
+    fn find_corruption(&self, page: usize) -> Option<Index> {
+        let (page_header, mut index) = self.read_page_header(page);
+        let has_checksum = self.format.has_checksum(page_header);
+        while index.byte < self.format.page_size {
+            let entry = self.read_entry(index);
+            if self.is_corrupted(has_checksum, entry) {
+                return Some(index);
+            }
+            index.byte += entry.len();
+        }
+        None
     }
 
     /// Initializes uninitialized pages.
-    fn initialize_storage(&mut self) {
+    fn initialize_storage(&mut self) -> Result<(), StoreError> {
         for page in 0..self.format.num_pages {
             let (header, index) = self.read_page_header(page);
             if self.format.is_initialized(header) {
@@ -438,43 +847,47 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
                 }
             } else {
                 // We set the erase count to zero the very first time we initialize a page.
-                self.initialize_page(page, 0);
+                self.initialize_page(page, 0)?;
             }
         }
         debug_assert!(self.blank_page != self.format.num_pages);
+        Ok(())
     }
 
     /// Marks an entry as deleted.
     ///
     /// The provided index must point to the beginning of an entry.
-    fn delete_index(&mut self, index: Index) {
-        self.update_word(index, |format, word| format.set_deleted(word));
+    fn delete_index(&mut self, index: Index) -> Result<(), StoreError> {
+        self.update_word(index, |format, word| format.set_deleted(word))
     }
 
     /// Finds a page with enough free space.
     ///
-    /// Returns an index to the free space of a page which can hold an entry of `length` bytes. If
-    /// necessary, pages may be compacted to free space. In that case, if provided, the `old_index`
-    /// is updated according to compaction.
+    /// Returns an index to the free space of a page which can hold an entry of `length` bytes with
+    /// tags up to `max_tag`. If necessary, pages may be compacted to free space. In that case, the
+    /// `old_indices` are updated according to compaction.
     fn find_slot_for_write(
         &mut self,
         length: usize,
-        mut old_index: Option<&mut Index>,
+        max_tag: usize,
+        old_indices: &mut [Index],
     ) -> Result<Index, StoreError> {
         loop {
-            if let Some(index) = self.choose_slot_for_write(length) {
+            if let Some(index) = self.choose_slot_for_write(length, max_tag) {
                 return Ok(index);
             }
-            match self.choose_page_for_compact() {
+            match self.choose_page_for_compact(max_tag) {
                 None => return Err(StoreError::StoreFull),
                 Some(page) => {
                     let blank_page = self.blank_page;
-                    // Compact the chosen page and update the old index to point to the entry in the
-                    // new page if it happened to be in the old page. This is essentially a way to
-                    // avoid index invalidation due to compaction.
-                    let map = self.compact_page(page, blank_page);
-                    if let Some(old_index) = &mut old_index {
-                        map_index(page, blank_page, &map, old_index);
+                    // Compact the chosen page and update the old indices to point to the entries in
+                    // the new page if they happened to be in the old page. This is essentially a way
+                    // to avoid index invalidation due to compaction.
+                    let map = self.compact_page(page, blank_page)?;
+                    for old_index in old_indices.iter_mut() {
+                        // The old entry may have been dropped if corrupted.
+                        map_index(page, blank_page, &map, old_index)
+                            .ok_or(StoreError::InvalidPrecondition)?;
                     }
                 }
             }
@@ -484,20 +897,23 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     /// Returns whether a page has enough free space.
     ///
     /// Returns an index to the free space of a page with smallest free space that may hold `length`
-    /// bytes.
-    fn choose_slot_for_write(&self, length: usize) -> Option<Index> {
+    /// bytes with tags up to `max_tag`.
+    fn choose_slot_for_write(&self, length: usize, max_tag: usize) -> Option<Index> {
         Iter::new(self)
             .filter(|(index, entry)| {
                 index.page != self.blank_page
                     && !self.format.is_present(entry)
                     && length <= entry.len()
+                    && self
+                        .format
+                        .fits_tag(max_tag, self.page_has_checksum(index.page))
             })
             .min_by_key(|(_, entry)| entry.len())
             .map(|(index, _)| index)
     }
 
     /// Returns the page that should be compacted.
-    fn choose_page_for_compact(&self) -> Option<usize> {
+    fn choose_page_for_compact(&self, max_tag: usize) -> Option<usize> {
         // TODO(cretin): This could be optimized by using some cost function depending on:
         // - the erase count
         // - the length of the free space
@@ -512,19 +928,32 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             .map(|page| (page, self.page_info(page)))
             .filter(|&(page, ref info)| {
                 page != self.blank_page
-                    && info.erase_count < self.format.max_page_erases
-                    && info.deleted_length > self.format.internal_entry_size()
+                    && (info.deleted_length > self.format.internal_entry_size()
+                        // Pages of older stores may be too narrow for the tag.
+                        || !self.format.fits_tag(max_tag, self.page_has_checksum(page)))
+                    && self.can_compact(info)
             })
             .min_by(|(_, lhs_info), (_, rhs_info)| lhs_info.compare_for_compaction(rhs_info))
             .map(|(page, _)| page)
     }
 
+    /// Returns whether a page can be compacted to the blank page.
+    fn can_compact(&self, info: &PageInfo) -> bool {
+        info.erase_count < self.format.max_page_erases
+            // Entries of older stores grow when compacted to a page with checksums.
+            && info.compacted_length + self.format.internal_entry_size()
+                <= self.format.page_size - self.format.page_header_size()
+    }
+
     fn page_info(&self, page: usize) -> PageInfo {
         let (page_header, mut index) = self.read_page_header(page);
+        let has_checksum = self.format.has_checksum(page_header);
+        let blank_has_checksum = self.page_has_checksum(self.blank_page);
         let mut info = PageInfo {
             erase_count: self.format.get_erase_count(page_header),
             deleted_length: 0,
             free_length: 0,
+            compacted_length: 0,
         };
         while index.byte < self.format.page_size {
             let entry = self.read_entry(index);
@@ -534,6 +963,14 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
                 info.free_length = entry.len();
             } else if self.format.is_deleted(entry) {
                 info.deleted_length += entry.len();
+            } else if has_checksum == blank_has_checksum || self.format.is_internal(entry) {
+                info.compacted_length += entry.len();
+            } else {
+                let is_replace = self.format.is_replace(entry);
+                let length = self.format.get_length(entry);
+                info.compacted_length +=
+                    self.format
+                        .entry_size(is_replace, length, blank_has_checksum);
             }
         }
         debug_assert_eq!(index.page, page);
@@ -558,7 +995,8 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             let header = self.read_slice(index, self.format.header_size());
             let replace = self.format.is_replace(header);
             let length = self.format.get_length(header);
-            self.format.entry_size(replace, length)
+            let has_checksum = self.page_has_checksum(index.page);
+            self.format.entry_size(replace, length, has_checksum)
         };
         // Truncate the length to fit the page. This can only happen in case of corruption or
         // partial writes.
@@ -581,56 +1019,72 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     /// The `update` function is called with the word at `index`. The input value is the current
     /// value of the word. The output value is the value that will be written. It should only change
     /// bits from 1 to 0.
-    fn update_word(&mut self, index: Index, update: impl FnOnce(&Format, &mut [u8])) {
+    fn update_word(
+        &mut self,
+        index: Index,
+        update: impl FnOnce(&Format, &mut [u8]),
+    ) -> Result<(), StoreError> {
         let word_size = self.format.word_size;
         let mut word = self.read_slice(index, word_size).to_vec();
         update(&self.format, &mut word);
-        self.storage.write_slice(index, &word).unwrap();
+        self.storage.write_slice(index, &word)?;
+        Ok(())
     }
 
-    fn write_entry(&mut self, index: Index, entry: &[u8]) {
-        self.storage.write_slice(index, entry).unwrap();
+    fn write_entry(&mut self, index: Index, entry: &[u8]) -> Result<(), StoreError> {
+        self.storage.write_slice(index, entry)?;
+        Ok(())
     }
 
     /// Initializes a page by writing the page header.
     ///
-    /// If the page is not erased, it is first erased.
-    fn initialize_page(&mut self, page: usize, erase_count: usize) {
+    /// If the page is not erased, it is first erased. The entries of the page will have checksums.
+    fn initialize_page(&mut self, page: usize, erase_count: usize) -> Result<(), StoreError> {
         let index = Index { page, byte: 0 };
         let page = self.read_slice(index, self.format.page_size);
         if !page.iter().all(|&byte| byte == 0xff) {
-            self.storage.erase_page(index.page).unwrap();
+            self.storage.erase_page(index.page)?;
         }
         self.update_word(index, |format, header| {
             format.set_initialized(header);
             format.set_erase_count(header, erase_count);
-        });
+            format.set_has_checksum(header);
+        })?;
         self.blank_page = index.page;
+        Ok(())
     }
 
-    /// Commits a replace entry.
+    /// Commits an entry.
     ///
-    /// Deletes the old entry and commits the new entry.
-    fn commit_index(&mut self, mut index: Index) {
+    /// For replace entries, also deletes the old entry.
+    fn commit_index(&mut self, mut index: Index) -> Result<(), StoreError> {
         let entry = self.read_entry(index);
         index.byte += entry.len();
         let word_size = self.format.word_size;
         debug_assert!(entry.len() >= 2 * word_size);
         match self.format.is_replace(entry) {
             IsReplace::Replace => {
-                let delete_index = self.format.get_replace_index(entry);
-                self.delete_index(delete_index);
+                let has_checksum = self.page_has_checksum(index.page);
+                let delete_index = self.format.get_replace_index(entry, has_checksum);
+                self.delete_index(delete_index)?;
             }
-            IsReplace::Insert => debug_assert!(false),
+            IsReplace::Insert => (),
         };
         index.byte -= word_size;
-        self.update_word(index, |format, word| format.set_committed(word));
+        self.update_word(index, |format, word| format.set_committed(word))
     }
 
     /// Compacts a page to an other.
     ///
+    /// Entries are converted to the format of the new page. Copying stops at the first corrupted
+    /// entry, which is counted as dropped.
+    ///
     /// Returns the mapping from the alive entries in the old page to their index in the new page.
-    fn compact_page(&mut self, old_page: usize, new_page: usize) -> BTreeMap<usize, usize> {
+    fn compact_page(
+        &mut self,
+        old_page: usize,
+        new_page: usize,
+    ) -> Result<BTreeMap<usize, usize>, StoreError> {
         // Write the old page as being compacted to the new page.
         let mut erase_count = 0;
         self.update_word(
@@ -643,7 +1097,7 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
                 format.set_compacting(header);
                 format.set_new_page(header, new_page);
             },
-        );
+        )?;
         // Copy alive entries from the old page to the new page.
         let page_header_size = self.format.page_header_size();
         let mut old_index = Index {
@@ -654,11 +1108,17 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             page: new_page,
             byte: page_header_size,
         };
+        let old_has_checksum = self.page_has_checksum(old_page);
+        let new_has_checksum = self.page_has_checksum(new_page);
         let mut map = BTreeMap::new();
         while old_index.byte < self.format.page_size {
             let old_entry = self.read_entry(old_index);
             let old_entry_index = old_index.byte;
             old_index.byte += old_entry.len();
+            if self.is_corrupted(old_has_checksum, old_entry) {
+                self.dropped_entries += 1;
+                break;
+            }
             if !self.format.is_alive(old_entry) {
                 continue;
             }
@@ -666,26 +1126,31 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             debug_assert!(previous_mapping.is_none());
             // We need to copy the old entry because it is in the storage and we are going to write
             // to the storage. Rust cannot tell that both entries don't overlap.
-            let old_entry = old_entry.to_vec();
-            self.write_entry(new_index, &old_entry);
-            new_index.byte += old_entry.len();
+            let new_entry = if self.format.is_internal(old_entry) {
+                old_entry.to_vec()
+            } else {
+                self.format
+                    .convert_entry(old_entry, old_has_checksum, new_has_checksum)
+            };
+            self.write_entry(new_index, &new_entry)?;
+            new_index.byte += new_entry.len();
         }
         // Save the old page index and erase count to the new page.
         let erase_index = new_index;
         let erase_entry = self.format.build_erase_entry(old_page, erase_count);
-        self.storage.write_slice(new_index, &erase_entry).unwrap();
+        self.write_entry(new_index, &erase_entry)?;
         // Erase the page.
-        self.erase_page(erase_index);
+        self.erase_page(erase_index)?;
         // Increase generation.
         self.generation += 1;
-        map
+        Ok(map)
     }
 
     /// Commits an internal entry.
     ///
     /// The only kind of internal entry is to erase a page, which first erases the page, then
     /// initializes it with the saved erase count, and finally deletes the internal entry.
-    fn erase_page(&mut self, erase_index: Index) {
+    fn erase_page(&mut self, erase_index: Index) -> Result<(), StoreError> {
         let erase_entry = self.read_entry(erase_index);
         debug_assert!(self.format.is_present(erase_entry));
         debug_assert!(!self.format.is_deleted(erase_entry));
@@ -693,11 +1158,11 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         let old_page = self.format.get_old_page(erase_entry);
         let erase_count = self.format.get_saved_erase_count(erase_entry) + 1;
         // Erase the page.
-        self.storage.erase_page(old_page).unwrap();
+        self.storage.erase_page(old_page)?;
         // Initialize the page.
-        self.initialize_page(old_page, erase_count);
+        self.initialize_page(old_page, erase_count)?;
         // Delete the internal entry.
-        self.delete_index(erase_index);
+        self.delete_index(erase_index)
     }
 }
 
@@ -726,16 +1191,24 @@ impl<C: StoreConfig> Store<BufferStorage, C> {
 
     /// Erases and initializes a page with a given erase count.
     pub fn set_erase_count(&mut self, page: usize, erase_count: usize) {
-        self.initialize_page(page, erase_count);
+        self.initialize_page(page, erase_count).unwrap();
     }
 }
 
 /// Maps an index from an old page to a new page if needed.
-fn map_index(old_page: usize, new_page: usize, map: &BTreeMap<usize, usize>, index: &mut Index) {
+///
+/// Returns `None` if the index was not copied to the new page.
+fn map_index(
+    old_page: usize,
+    new_page: usize,
+    map: &BTreeMap<usize, usize>,
+    index: &mut Index,
+) -> Option<()> {
     if index.page == old_page {
         index.page = new_page;
-        index.byte = *map.get(&index.byte).unwrap();
+        index.byte = *map.get(&index.byte)?;
     }
+    Some(())
 }
 
 /// Page information for compaction.
@@ -748,6 +1221,9 @@ struct PageInfo {
 
     /// Length of the free space.
     free_length: usize,
+
+    /// Cumulative length of alive entries once compacted to the blank page.
+    compacted_length: usize,
 }
 
 impl PageInfo {
@@ -818,6 +1294,10 @@ mod tests {
                 add(entry.data[0]);
             }
         }
+
+        fn is_sensitive(&self, _tag: usize) -> bool {
+            true
+        }
     }
 
     fn new_buffer(storage: Box<[u8]>) -> BufferStorage {
@@ -950,7 +1430,15 @@ mod tests {
                 Err(_) => (true, store.get_storage()),
                 Ok(store) => (false, store),
             };
+            // Corrupt the last data byte of the old entry, which is the first entry of the first
+            // page. It is dropped with the new entry following it.
+            let mut corrupted = store.clone();
+            corrupted[WORD_SIZE + 2 + old_entry.data.len() - 1] ^= 0x10;
+            let corrupted = Store::new(new_buffer(corrupted), Config).unwrap();
+            assert_eq!(corrupted.dropped_entries(), 1);
+            assert_eq!(corrupted.iter().count(), 0);
             let store = Store::new(new_buffer(store), Config).unwrap();
+            assert_eq!(store.dropped_entries(), 0);
             assert_eq!(store.iter().count(), 1);
             assert_eq!(store.find_all(&key).count(), 1);
             let (_, cur_entry) = store.find_one(&key).unwrap();
@@ -1009,6 +1497,315 @@ mod tests {
         }
     }
 
+    /// Inserts 2 entries with keys 1 and 2, and returns the operations of a transaction replacing
+    /// the first, deleting the second, and inserting one with key 3.
+    fn prepare_transaction(
+        store: &mut Store<BufferStorage, Config>,
+    ) -> Vec<StoreOperation<'static>> {
+        let tag = 0;
+        store.insert(StoreEntry { tag, data: &[1, 2] }).unwrap();
+        store.insert(StoreEntry { tag, data: &[2, 2] }).unwrap();
+        let (index1, _) = store.find_one(&1).unwrap();
+        let (index2, _) = store.find_one(&2).unwrap();
+        vec![
+            StoreOperation::Replace(index1, StoreEntry { tag, data: &[1, 9] }),
+            StoreOperation::Delete(index2),
+            StoreOperation::Insert(StoreEntry { tag, data: &[3, 3] }),
+        ]
+    }
+
+    /// Returns the data of the entries with keys 1, 2, and 3.
+    fn transaction_state(store: &Store<BufferStorage, Config>) -> Vec<Option<Vec<u8>>> {
+        (1..=3)
+            .map(|key| store.find_one(&key).map(|(_, entry)| entry.data.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn transaction_ok() {
+        let mut store = new_store();
+        let operations = prepare_transaction(&mut store);
+        store.transaction(&operations).unwrap();
+        let committed = vec![Some(vec![1, 9]), None, Some(vec![3, 3])];
+        assert_eq!(transaction_state(&store), committed);
+        assert_eq!(store.iter().count(), 2);
+
+        // Reboot the store.
+        let store = store.get_storage();
+        let store = Store::new(new_buffer(store), Config).unwrap();
+        assert_eq!(transaction_state(&store), committed);
+        assert_eq!(store.iter().count(), 2);
+    }
+
+    #[test]
+    fn transaction_atomic() {
+        let initial = vec![Some(vec![1, 2]), Some(vec![2, 2]), None];
+        let committed = vec![Some(vec![1, 9]), None, Some(vec![3, 3])];
+        let mut delay = 0;
+        loop {
+            let mut store = new_store();
+            let operations = prepare_transaction(&mut store);
+            store.arm_snapshot(delay);
+            store.transaction(&operations).unwrap();
+            let (complete, store) = match store.get_snapshot() {
+                Err(_) => (true, store.get_storage()),
+                Ok(store) => (false, store),
+            };
+            let mut store = Store::new(new_buffer(store), Config).unwrap();
+            let state = transaction_state(&store);
+            assert!((state == initial && !complete) || state == committed);
+            assert_eq!(store.iter().count(), 2);
+            // The store is still usable.
+            store
+                .insert(StoreEntry {
+                    tag: 0,
+                    data: &[4, 4],
+                })
+                .unwrap();
+            assert_eq!(store.iter().count(), 3);
+            if complete {
+                break;
+            }
+            delay += 1;
+        }
+    }
+
+    #[test]
+    fn invalid_transaction() {
+        let mut store = new_store();
+        let tag = 0;
+        store.insert(StoreEntry { tag, data: &[1] }).unwrap();
+        let (index, _) = store.find_one(&1).unwrap();
+        // The same entry can't be used twice.
+        let operations = [StoreOperation::Delete(index), StoreOperation::Delete(index)];
+        assert_eq!(
+            store.transaction(&operations),
+            Err(StoreError::InvalidPrecondition)
+        );
+        // The transaction must fit in a page.
+        let entry = StoreEntry { tag, data: &[2; 8] };
+        let operations = [StoreOperation::Insert(entry), StoreOperation::Insert(entry)];
+        assert_eq!(store.transaction(&operations), Err(StoreError::StoreFull));
+        assert_eq!(store.iter().count(), 1);
+    }
+
+    #[test]
+    fn corruption_dropped() {
+        let tag = 0;
+        let mut store = new_store();
+        let mut key = 0;
+        while store
+            .insert(StoreEntry {
+                tag,
+                data: &[key, 0],
+            })
+            .is_ok()
+        {
+            key += 1;
+        }
+        let length = store.insert_len(2);
+        let entries: Vec<(Index, u8)> = store
+            .iter()
+            .map(|(index, entry)| (index.index, entry.data[0]))
+            .collect();
+        let storage = store.get_storage();
+        for &(index, key) in &entries {
+            // Flip all bits covered by the checksum.
+            for bit in 3..8 * length - 2 {
+                let mut storage = storage.clone();
+                storage[index.page * PAGE_SIZE + index.byte + bit / 8] ^= 1 << (bit % 8);
+                let store = Store::new(new_buffer(storage), Config).unwrap();
+                // The `length` field takes 5 bits after the first 4 flags. If it moves the
+                // `complete` bit to an erased bit, the entry is rolled-back instead of dropped.
+                let is_length = 4 <= bit && bit < 9;
+                let dropped_entries = store.dropped_entries();
+                assert!(dropped_entries == 1 || (is_length && dropped_entries == 0));
+                assert_eq!(store.find_all(&key).count(), 0);
+                // Only the entries following the corrupted entry in its page may be lost.
+                for &(other_index, other_key) in &entries {
+                    if other_key == key {
+                        continue;
+                    }
+                    let data = [other_key, 0];
+                    let expected = StoreEntry { tag, data: &data };
+                    let other_entry = store.find_one(&other_key).map(|(_, entry)| entry);
+                    if other_index.page == index.page && other_index.byte > index.byte {
+                        assert!(
+                            other_entry.is_none() || (is_length && other_entry == Some(expected))
+                        );
+                    } else {
+                        assert_eq!(other_entry, Some(expected));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_ok() {
+        // Build a store where pages have no checksums, with one entry.
+        let storage = vec![0xff; NUM_PAGES * PAGE_SIZE].into_boxed_slice();
+        let format = Format::new(&new_buffer(storage.clone()), &Config).unwrap();
+        let mut storage = storage.into_vec();
+        for page in 0..NUM_PAGES {
+            let header = &mut storage[page * PAGE_SIZE..][..WORD_SIZE];
+            format.set_initialized(header);
+            format.set_erase_count(header, 0);
+        }
+        let entry = StoreEntry {
+            tag: 0,
+            data: &[0, 0],
+        };
+        let mut entry = format.build_entry(None, entry, HasChecksum::No);
+        format.set_committed(&mut entry);
+        storage[WORD_SIZE..][..entry.len()].copy_from_slice(&entry);
+        let mut store = Store::new(new_buffer(storage.into_boxed_slice()), Config).unwrap();
+        assert_eq!(store.page_has_checksum(0), HasChecksum::No);
+
+        // Replace the entry until all pages have been compacted.
+        let mut value = 0;
+        while (0..NUM_PAGES).any(|page| store.page_has_checksum(page) == HasChecksum::No) {
+            let (index, entry) = store.find_one(&0).unwrap();
+            assert_eq!(entry.data, [0, value]);
+            value += 1;
+            store
+                .replace(
+                    index,
+                    StoreEntry {
+                        tag: 0,
+                        data: &[0, value],
+                    },
+                )
+                .unwrap();
+        }
+
+        // Reboot the store.
+        let store = store.get_storage();
+        let store = Store::new(new_buffer(store), Config).unwrap();
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(store.find_one(&0).unwrap().1.data, [0, value]);
+        assert_eq!(store.dropped_entries(), 0);
+    }
+
+    #[test]
+    fn legacy_wide_tag_ok() {
+        struct WideConfig;
+
+        impl StoreConfig for WideConfig {
+            type Key = u8;
+
+            fn num_tags(&self) -> usize {
+                4
+            }
+
+            fn num_legacy_tags(&self) -> usize {
+                2
+            }
+
+            fn keys(&self, entry: StoreEntry, mut add: impl FnMut(u8)) {
+                add(entry.tag as u8);
+            }
+        }
+
+        // Build a store where pages have no checksums and narrow tags, with one entry.
+        let storage = vec![0xff; NUM_PAGES * PAGE_SIZE].into_boxed_slice();
+        let format = Format::new(&new_buffer(storage.clone()), &WideConfig).unwrap();
+        assert!(format.fits_tag(1, HasChecksum::No));
+        assert!(!format.fits_tag(3, HasChecksum::No));
+        assert!(format.fits_tag(3, HasChecksum::Yes));
+        let mut storage = storage.into_vec();
+        for page in 0..NUM_PAGES {
+            let header = &mut storage[page * PAGE_SIZE..][..WORD_SIZE];
+            format.set_initialized(header);
+            format.set_erase_count(header, 0);
+        }
+        let entry = StoreEntry { tag: 1, data: &[1] };
+        let mut entry = format.build_entry(None, entry, HasChecksum::No);
+        format.set_committed(&mut entry);
+        storage[WORD_SIZE..][..entry.len()].copy_from_slice(&entry);
+        let mut store = Store::new(new_buffer(storage.into_boxed_slice()), WideConfig).unwrap();
+        assert_eq!(store.find_one(&1).unwrap().1.data, [1]);
+
+        // Insert an entry with a wide tag.
+        store.insert(StoreEntry { tag: 3, data: &[3] }).unwrap();
+        let (index, entry) = store.find_one(&3).unwrap();
+        assert_eq!(entry.data, [3]);
+        assert_eq!(store.page_has_checksum(index.index.page), HasChecksum::Yes);
+
+        // Reboot the store.
+        let store = store.get_storage();
+        let store = Store::new(new_buffer(store), WideConfig).unwrap();
+        assert_eq!(store.iter().count(), 2);
+        assert_eq!(store.find_one(&1).unwrap().1.data, [1]);
+        assert_eq!(store.find_one(&3).unwrap().1.data, [3]);
+        assert_eq!(store.dropped_entries(), 0);
+    }
+
+    #[test]
+    fn shred_ok() {
+        let mut store = new_store();
+        let tag = 0;
+        let secret = [1, 0x5a, 0x5a, 0x5a, 0x5a, 0x5a];
+        store.insert(StoreEntry { tag, data: &[0, 0] }).unwrap();
+        store.insert(StoreEntry { tag, data: &secret }).unwrap();
+        let (index, _) = store.find_one(&1).unwrap();
+        store.delete(index).unwrap();
+        let contains_secret = |storage: &[u8]| storage.windows(secret.len()).any(|w| w == secret);
+        assert!(contains_secret(&store.take_snapshot()));
+
+        store.shred().unwrap();
+        assert!(!contains_secret(&store.take_snapshot()));
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(store.find_one(&0).unwrap().1.data, [0, 0]);
+
+        // Nothing is left to shred.
+        let compaction_info = store.compaction_info();
+        store.shred().unwrap();
+        assert_eq!(store.compaction_info(), compaction_info);
+    }
+
+    #[test]
+    fn shred_key_ok() {
+        let mut store = new_store();
+        let tag = 0;
+        let secret = [1, 0x5a, 0x5a, 0x5a, 0x5a, 0x5a];
+        store.insert(StoreEntry { tag, data: &secret }).unwrap();
+        let (index, _) = store.find_one(&1).unwrap();
+        store.delete(index).unwrap();
+        let contains_secret = |storage: &[u8]| storage.windows(secret.len()).any(|w| w == secret);
+
+        // Other keys are left as they are.
+        let compaction_info = store.compaction_info();
+        store.shred_key(&2).unwrap();
+        assert_eq!(store.compaction_info(), compaction_info);
+        assert!(contains_secret(&store.take_snapshot()));
+
+        store.shred_key(&1).unwrap();
+        assert!(!contains_secret(&store.take_snapshot()));
+    }
+
+    #[test]
+    fn shred_erase_limit() {
+        let mut store = new_store();
+        let tag = 0;
+        let secret = [1, 0x5a, 0x5a, 0x5a, 0x5a, 0x5a];
+        let contains_secret = |storage: &[u8]| storage.windows(secret.len()).any(|w| w == secret);
+        loop {
+            store.insert(StoreEntry { tag, data: &secret }).unwrap();
+            let (index, _) = store.find_one(&1).unwrap();
+            store.delete(index).unwrap();
+            match store.shred() {
+                Ok(()) => assert!(!contains_secret(&store.take_snapshot())),
+                Err(error) => {
+                    // The page can't be erased anymore, and the secret is still there.
+                    assert_eq!(error, StoreError::StoreFull);
+                    assert!(contains_secret(&store.take_snapshot()));
+                    break;
+                }
+            }
+        }
+    }
+
     #[test]
     fn invalid_tag() {
         let mut store = new_store();
@@ -16,6 +16,13 @@
 
 #[macro_use]
 extern crate alloc;
-extern crate libtock;
+#[macro_use]
+extern crate arrayref;
+extern crate byteorder;
+#[macro_use]
+extern crate cbor;
+extern crate crypto;
+extern crate subtle;
 
+pub mod ctap;
 pub mod embedded_flash;
@@ -14,30 +14,29 @@
 
 #![cfg_attr(not(feature = "std"), no_std)]
 
-#[macro_use]
-extern crate alloc;
-#[macro_use]
-extern crate arrayref;
-extern crate byteorder;
 #[cfg(feature = "std")]
 extern crate core;
+extern crate crypto;
 extern crate ctap2;
 extern crate libtock;
-extern crate subtle;
-#[macro_use]
-extern crate cbor;
-extern crate crypto;
 
-mod ctap;
+mod syscall_storage;
 mod usb_ctap_hid;
 
 use core::cell::Cell;
 #[cfg(feature = "debug_ctap")]
+use core::fmt;
+#[cfg(feature = "debug_ctap")]
 use core::fmt::Write;
 use crypto::rng256::TockRng256;
-use ctap::hid::{ChannelID, CtapHid, KeepaliveStatus, ProcessedPacket};
-use ctap::status_code::Ctap2StatusCode;
-use ctap::CtapState;
+use ctap2::ctap;
+use ctap2::ctap::hid::{ChannelID, CtapHid, KeepaliveStatus, PendingPackets, TransportError};
+#[cfg(feature = "debug_ctap")]
+use ctap2::ctap::platform;
+use ctap2::ctap::platform::{Clock, SendOrRecvStatus, UserPresence};
+use ctap2::ctap::status_code::Ctap2StatusCode;
+use ctap2::ctap::time::{ClockValue, Duration};
+use ctap2::ctap::{CtapState, StorageRegions};
 use libtock::buttons;
 use libtock::buttons::ButtonState;
 #[cfg(feature = "debug_ctap")]
@@ -46,19 +45,72 @@ use libtock::led;
 use libtock::result::TockValue;
 use libtock::syscalls;
 use libtock::timer;
-#[cfg(feature = "debug_ctap")]
-use libtock::timer::Timer;
-use libtock::timer::{Duration, StopAlarmError, Timestamp};
+use libtock::timer::{StopAlarmError, Timer};
+use syscall_storage::SyscallStorage;
+use usb_ctap_hid::UsbTransport;
 
 const KEEPALIVE_DELAY_MS: isize = 100;
 const KEEPALIVE_DELAY: Duration<isize> = Duration::from_ms(KEEPALIVE_DELAY_MS);
 const SEND_TIMEOUT: Duration<isize> = Duration::from_ms(1000);
 
+// The persistent store lives in a flash region reserved for the application. The counters take
+// its last pages.
+#[cfg_attr(not(feature = "std"), link_section = ".app_state")]
+static STORE: [u8; ctap::STORE_SIZE] = [0xff; ctap::STORE_SIZE];
+
+// The USB driver is recovered after it fails, instead of panicking.
+#[derive(Clone, Copy, PartialEq)]
+enum UsbState {
+    Connected,
+    Failed,
+}
+
+// Reads the clock of the timer driver.
+#[derive(Clone, Copy)]
+struct TockClock<'a> {
+    timer: &'a Timer<'a>,
+}
+
+impl Clock for TockClock<'_> {
+    fn now(&self) -> ClockValue {
+        ClockValue::new(
+            self.timer.get_current_clock().num_ticks(),
+            self.timer.clock_frequency().hz(),
+        )
+    }
+}
+
+// Checks user presence with the buttons, and sends keepalives over USB while processing commands.
+// Packets received in the meantime are queued for the main loop.
+struct TockUserPresence {
+    pending_packets: PendingPackets,
+}
+
+impl UserPresence for TockUserPresence {
+    fn check(&self, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        check_user_presence(&self.pending_packets, cid)
+    }
+
+    fn processing(&self, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        CtapHid::send_keepalive(
+            &UsbTransport,
+            &self.pending_packets,
+            cid,
+            KeepaliveStatus::Processing,
+            KEEPALIVE_DELAY,
+        )
+    }
+}
+
 fn main() {
+    #[cfg(feature = "debug_ctap")]
+    platform::set_debug_writer(write_console_line);
+
     // Setup the timer with a dummy callback (we only care about reading the current time, but the
     // API forces us to set an alarm callback too).
     let mut with_callback = timer::with_callback(|_, _| {});
     let timer = with_callback.init().unwrap();
+    let clock = TockClock { timer: &timer };
 
     // Setup USB driver.
     if !usb_ctap_hid::setup() {
@@ -66,16 +118,37 @@ fn main() {
     }
 
     let mut rng = TockRng256 {};
-    let mut ctap_state = CtapState::new(&mut rng, check_user_presence);
     let mut ctap_hid = CtapHid::new();
+    let user_presence = TockUserPresence {
+        pending_packets: ctap_hid.pending_packets(),
+    };
+    let mut ctap_state = match CtapState::new(&mut rng, user_presence, new_storage(), clock) {
+        Ok(ctap_state) => ctap_state,
+        Err(e) => panic!("Cannot setup persistent store: {:?}", e),
+    };
+
+    let mut usb_state = UsbState::Connected;
 
     let mut led_counter = 0;
-    let mut last_led_increment = timer.get_current_clock();
+    let mut last_led_increment = clock.now();
 
     // Main loop. If CTAP1 is used, we register button presses for U2F while receiving and waiting.
     // The way TockOS and apps currently interact, callbacks need a yield syscall to execute,
     // making consistent blinking patterns and sending keepalives harder.
     loop {
+        if usb_state == UsbState::Failed {
+            // Messages in flight are lost with the connection, the host will retry them.
+            ctap_hid.reset();
+            if usb_ctap_hid::recover() {
+                #[cfg(feature = "debug_ctap")]
+                print_packet_notice("Recovered USB", &timer);
+                usb_state = UsbState::Connected;
+            } else {
+                wait(KEEPALIVE_DELAY);
+                continue;
+            }
+        }
+
         // Create the button callback, used for CTAP1.
         #[cfg(feature = "with_ctap1")]
         let button_touched = Cell::new(false);
@@ -94,18 +167,31 @@ fn main() {
             button.enable().unwrap();
         }
 
-        let mut pkt_request = [0; 64];
-        let has_packet = match usb_ctap_hid::recv_with_timeout(&mut pkt_request, KEEPALIVE_DELAY) {
-            Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
-                #[cfg(feature = "debug_ctap")]
-                print_packet_notice("Received packet", &timer);
-                true
+        // Packets received while sending the last reply come first.
+        let (pkt_request, has_packet) = match ctap_hid.next_pending_packet() {
+            Some(packet) => (packet, true),
+            None => {
+                let mut pkt_request = [0; 64];
+                let status = usb_ctap_hid::recv_with_timeout(&mut pkt_request, KEEPALIVE_DELAY);
+                let has_packet = match status {
+                    Some(SendOrRecvStatus::Received) => {
+                        #[cfg(feature = "debug_ctap")]
+                        print_packet_notice("Received packet", &timer);
+                        true
+                    }
+                    Some(_) => {
+                        #[cfg(feature = "debug_ctap")]
+                        print_packet_notice("Error receiving packet", &timer);
+                        usb_state = UsbState::Failed;
+                        false
+                    }
+                    None => false,
+                };
+                (pkt_request, has_packet)
             }
-            Some(_) => panic!("Error receiving packet"),
-            None => false,
         };
 
-        let now = timer.get_current_clock();
+        let now = clock.now();
         #[cfg(feature = "with_ctap1")]
         {
             if button_touched.get() {
@@ -123,37 +209,31 @@ fn main() {
 
         // These calls are making sure that even for long inactivity, wrapping clock values
         // never randomly wink or grant user presence for U2F.
-        ctap_state.check_disable_reset(Timestamp::<isize>::from_clock_value(now));
+        ctap_state.check_disable_reset();
         ctap_hid.wink_permission = ctap_hid.wink_permission.check_expiration(now);
 
         if has_packet {
-            let reply = ctap_hid.process_hid_packet(&pkt_request, now, &mut ctap_state);
-            // This block handles sending packets.
-            for mut pkt_reply in reply {
-                let status = usb_ctap_hid::send_or_recv_with_timeout(&mut pkt_reply, SEND_TIMEOUT);
-                match status {
-                    None => {
-                        #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Sending packet timed out", &timer);
-                        // TODO: reset the ctap_hid state.
-                        // Since sending the packet timed out, we cancel this reply.
-                        break;
-                    }
-                    Some(usb_ctap_hid::SendOrRecvStatus::Error) => panic!("Error sending packet"),
-                    Some(usb_ctap_hid::SendOrRecvStatus::Sent) => {
-                        #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Sent packet", &timer);
-                    }
-                    Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
-                        #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Received an UNEXPECTED packet", &timer);
-                        // TODO: handle this unexpected packet.
-                    }
+            let reply = ctap_hid.process_hid_packet(&pkt_request, &mut ctap_state);
+            match ctap_hid.send_reply(&UsbTransport, reply, SEND_TIMEOUT) {
+                Ok(()) => {
+                    #[cfg(feature = "debug_ctap")]
+                    print_packet_notice("Sent reply", &timer);
+                }
+                Err(TransportError::Timeout) => {
+                    #[cfg(feature = "debug_ctap")]
+                    print_packet_notice("Sending reply timed out", &timer);
+                    // The rest of the reply was dropped. Packets received in the meantime are
+                    // still processed, the host may be sending new requests.
+                }
+                Err(TransportError::Failure) => {
+                    #[cfg(feature = "debug_ctap")]
+                    print_packet_notice("Error sending reply", &timer);
+                    usb_state = UsbState::Failed;
                 }
             }
         }
 
-        let now = timer.get_current_clock();
+        let now = clock.now();
         if let Some(wait_duration) = now.wrapping_sub(last_led_increment) {
             if wait_duration > KEEPALIVE_DELAY {
                 // Loops quickly when waiting for U2F user presence, so the next LED blink
@@ -186,10 +266,15 @@ fn main() {
     }
 }
 
+#[cfg(feature = "debug_ctap")]
+fn write_console_line(args: fmt::Arguments) {
+    writeln!(Console::new(), "{}", args).unwrap();
+}
+
 #[cfg(feature = "debug_ctap")]
 fn print_packet_notice(notice_text: &str, timer: &Timer) {
     let now_us =
-        (Timestamp::<f64>::from_clock_value(timer.get_current_clock()).ms() * 1000.0) as u64;
+        (timer::Timestamp::<f64>::from_clock_value(timer.get_current_clock()).ms() * 1000.0) as u64;
     writeln!(
         Console::new(),
         "{} at {}.{:06} s",
@@ -200,68 +285,34 @@ fn print_packet_notice(notice_text: &str, timer: &Timer) {
     .unwrap();
 }
 
-// Returns whether the keepalive was sent, or false if cancelled.
-fn send_keepalive_up_needed(
-    cid: ChannelID,
-    timeout: Duration<isize>,
-) -> Result<(), Ctap2StatusCode> {
-    let keepalive_msg = CtapHid::keepalive(cid, KeepaliveStatus::UpNeeded);
-    for mut pkt in keepalive_msg {
-        let status = usb_ctap_hid::send_or_recv_with_timeout(&mut pkt, timeout);
-        match status {
-            None => {
-                #[cfg(feature = "debug_ctap")]
-                writeln!(Console::new(), "Sending a KEEPALIVE packet timed out").unwrap();
-                // TODO: abort user presence test?
-            }
-            Some(usb_ctap_hid::SendOrRecvStatus::Error) => panic!("Error sending KEEPALIVE packet"),
-            Some(usb_ctap_hid::SendOrRecvStatus::Sent) => {
-                #[cfg(feature = "debug_ctap")]
-                writeln!(Console::new(), "Sent KEEPALIVE packet").unwrap();
-            }
-            Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
-                // We only parse one packet, because we only care about CANCEL.
-                let (received_cid, processed_packet) = CtapHid::process_single_packet(&pkt);
-                if received_cid != &cid {
-                    #[cfg(feature = "debug_ctap")]
-                    writeln!(
-                        Console::new(),
-                        "Received a packet on channel ID {:?} while sending a KEEPALIVE packet",
-                        received_cid,
-                    )
-                    .unwrap();
-                    return Ok(());
-                }
-                match processed_packet {
-                    ProcessedPacket::InitPacket { cmd, .. } => {
-                        if cmd == CtapHid::COMMAND_CANCEL {
-                            // We ignore the payload, we can't answer with an error code anyway.
-                            #[cfg(feature = "debug_ctap")]
-                            writeln!(Console::new(), "User presence check cancelled").unwrap();
-                            return Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
-                        } else {
-                            #[cfg(feature = "debug_ctap")]
-                            writeln!(
-                                Console::new(),
-                                "Discarded packet with command {} received while sending a KEEPALIVE packet",
-                                cmd,
-                            )
-                            .unwrap();
-                        }
-                    }
-                    ProcessedPacket::ContinuationPacket { .. } => {
-                        #[cfg(feature = "debug_ctap")]
-                        writeln!(
-                            Console::new(),
-                            "Discarded continuation packet received while sending a KEEPALIVE packet",
-                        )
-                        .unwrap();
-                    }
-                }
-            }
+// Blocks for the given duration.
+fn wait(duration: Duration<isize>) {
+    let expired = Cell::new(false);
+    let mut callback = timer::with_callback(|_, _| expired.set(true));
+    let mut alarm_timer = callback.init().unwrap();
+    let alarm = alarm_timer
+        .set_alarm(timer::Duration::from_ms(duration.ms()))
+        .unwrap();
+    syscalls::yieldk_for(|| expired.get());
+    match alarm_timer.stop_alarm(alarm) {
+        Ok(()) | Err(TockValue::Expected(StopAlarmError::AlreadyDisabled)) => (),
+        Err(e) => panic!("Unexpected error when stopping alarm: {:?}", e),
+    }
+}
+
+fn new_storage() -> StorageRegions<SyscallStorage> {
+    let store = unsafe {
+        // Safety: The store cannot alias because this function is called only once.
+        core::slice::from_raw_parts_mut(STORE.as_ptr() as *mut u8, ctap::STORE_SIZE)
+    };
+    let (store, counters) = store.split_at_mut(ctap::STORE_SIZE - ctap::COUNTERS_SIZE);
+    unsafe {
+        // Safety: The store is in a writeable flash region.
+        StorageRegions {
+            store: SyscallStorage::new(store).unwrap(),
+            counters: SyscallStorage::new(counters).unwrap(),
         }
     }
-    Ok(())
 }
 
 fn blink_leds(pattern_seed: isize) {
@@ -313,12 +364,21 @@ fn switch_off_leds() {
     }
 }
 
-fn check_user_presence(cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+fn check_user_presence(
+    pending_packets: &PendingPackets,
+    cid: ChannelID,
+) -> Result<(), Ctap2StatusCode> {
     // The timeout is N times the keepalive delay.
     const TIMEOUT_ITERATIONS: isize = ctap::TOUCH_TIMEOUT_MS / KEEPALIVE_DELAY_MS;
 
     // First, send a keep-alive packet to notify that the keep-alive status has changed.
-    send_keepalive_up_needed(cid, KEEPALIVE_DELAY)?;
+    CtapHid::send_keepalive(
+        &UsbTransport,
+        pending_packets,
+        cid,
+        KeepaliveStatus::UpNeeded,
+        KEEPALIVE_DELAY,
+    )?;
 
     // Listen to the button presses.
     let button_touched = Cell::new(false);
@@ -344,7 +404,9 @@ fn check_user_presence(cid: ChannelID) -> Result<(), Ctap2StatusCode> {
             keepalive_expired.set(true);
         });
         let mut keepalive = keepalive_callback.init().unwrap();
-        let keepalive_alarm = keepalive.set_alarm(KEEPALIVE_DELAY).unwrap();
+        let keepalive_alarm = keepalive
+            .set_alarm(timer::Duration::from_ms(KEEPALIVE_DELAY_MS))
+            .unwrap();
 
         // Wait for a button touch or an alarm.
         syscalls::yieldk_for(|| button_touched.get() || keepalive_expired.get());
@@ -362,7 +424,13 @@ fn check_user_presence(cid: ChannelID) -> Result<(), Ctap2StatusCode> {
         // so that LEDs blink with a consistent pattern.
         if keepalive_expired.get() {
             // Do not return immediately, because we must clean up still.
-            keepalive_response = send_keepalive_up_needed(cid, KEEPALIVE_DELAY);
+            keepalive_response = CtapHid::send_keepalive(
+                &UsbTransport,
+                pending_packets,
+                cid,
+                KeepaliveStatus::UpNeeded,
+                KEEPALIVE_DELAY,
+            );
         }
 
         if button_touched.get() || keepalive_response.is_err() {
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{Index, Storage, StorageError, StorageResult};
+use ctap2::embedded_flash::{Index, Storage, StorageError, StorageResult};
 use libtock::syscalls;
 
 const DRIVER_NUMBER: usize = 0x50003;
@@ -70,19 +70,12 @@ impl SyscallStorage {
     ///
     /// # Examples
     ///
-    /// ```rust
-    /// # extern crate ctap2;
-    /// # use ctap2::embedded_flash::SyscallStorage;
-    /// # use ctap2::embedded_flash::StorageResult;
-    /// # const NUM_PAGES: usize = 1;
-    /// # const PAGE_SIZE: usize = 1;
+    /// ```ignore
     /// #[link_section = ".app_state"]
     /// static mut STORAGE: [u8; NUM_PAGES * PAGE_SIZE] = [0xff; NUM_PAGES * PAGE_SIZE];
-    /// # fn foo() -> StorageResult<SyscallStorage> {
     /// // This is safe because this is the only use of `STORAGE` in the whole program and this is
     /// // called only once.
     /// unsafe { SyscallStorage::new(&mut STORAGE) }
-    /// # }
     /// ```
     pub unsafe fn new(storage: &'static mut [u8]) -> StorageResult<SyscallStorage> {
         let word_size = get_info(command_nr::get_info_nr::WORD_SIZE)?;
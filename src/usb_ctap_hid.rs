@@ -15,13 +15,16 @@
 use core::cell::Cell;
 #[cfg(feature = "debug_ctap")]
 use core::fmt::Write;
+use ctap2::ctap::hid::HidPacket;
+use ctap2::ctap::platform::{SendOrRecvStatus, Transport};
+use ctap2::ctap::time::Duration;
 #[cfg(feature = "debug_ctap")]
 use libtock::console::Console;
 use libtock::result::TockValue;
 use libtock::result::{EALREADY, EBUSY, SUCCESS};
 use libtock::syscalls;
 use libtock::timer;
-use libtock::timer::{Duration, StopAlarmError};
+use libtock::timer::StopAlarmError;
 
 const DRIVER_NUMBER: usize = 0x20009;
 
@@ -64,6 +67,23 @@ pub fn setup() -> bool {
     true
 }
 
+// Recovers the driver after an error: cancels any pending transaction and checks that the app is
+// still connected to the driver. The device stays attached to the bus, so the host doesn't
+// enumerate it again.
+pub fn recover() -> bool {
+    let result = unsafe { syscalls::command(DRIVER_NUMBER, command_nr::CANCEL, 0, 0) };
+    match result {
+        SUCCESS | EALREADY => (),
+        _ => return false,
+    }
+    // The driver answers EALREADY if the app is connected, e.g. after a failed transaction.
+    let result = unsafe { syscalls::command(DRIVER_NUMBER, command_nr::CONNECT, 0, 0) };
+    match result {
+        SUCCESS | EALREADY => true,
+        _ => false,
+    }
+}
+
 #[allow(dead_code)]
 pub fn recv(buf: &mut [u8; 64]) -> bool {
     let result = syscalls::allow(DRIVER_NUMBER, allow_nr::RECEIVE, buf);
@@ -110,13 +130,6 @@ pub fn send(buf: &mut [u8; 64]) -> bool {
     true
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum SendOrRecvStatus {
-    Error,
-    Sent,
-    Received,
-}
-
 // Either sends or receive a packet.
 // Because USB transactions are initiated by the host, we don't decide whether an IN transaction
 // (send for us), an OUT transaction (receive for us), or no transaction at all will happen next.
@@ -194,7 +207,7 @@ pub fn recv_with_timeout(
         Ok(x) => x,
         Err(_) => return Some(SendOrRecvStatus::Error),
     };
-    let timeout_alarm = match timeout.set_alarm(timeout_delay) {
+    let timeout_alarm = match timeout.set_alarm(timer::Duration::from_ms(timeout_delay.ms())) {
         Ok(x) => x,
         Err(_) => return Some(SendOrRecvStatus::Error),
     };
@@ -239,10 +252,17 @@ pub fn recv_with_timeout(
                 #[cfg(feature = "debug_ctap")]
                 writeln!(Console::new(), "Couldn't cancel the USB receive").unwrap();
             }
-            _ => panic!(
-                "Unexpected error when cancelling USB receive: {:?}",
-                result_code
-            ),
+            _ => {
+                // The driver is in an unknown state, let the caller recover it.
+                #[cfg(feature = "debug_ctap")]
+                writeln!(
+                    Console::new(),
+                    "Unexpected error when cancelling USB receive: {:?}",
+                    result_code
+                )
+                .unwrap();
+                return Some(SendOrRecvStatus::Error);
+            }
         }
     }
 
@@ -285,7 +305,7 @@ pub fn send_or_recv_with_timeout(
         Ok(x) => x,
         Err(_) => return Some(SendOrRecvStatus::Error),
     };
-    let timeout_alarm = match timeout.set_alarm(timeout_delay) {
+    let timeout_alarm = match timeout.set_alarm(timer::Duration::from_ms(timeout_delay.ms())) {
         Ok(x) => x,
         Err(_) => return Some(SendOrRecvStatus::Error),
     };
@@ -331,10 +351,17 @@ pub fn send_or_recv_with_timeout(
                 #[cfg(feature = "debug_ctap")]
                 writeln!(Console::new(), "Couldn't cancel the transaction").unwrap();
             }
-            _ => panic!(
-                "Unexpected error when cancelling USB transaction: {:?}",
-                result_code
-            ),
+            _ => {
+                // The driver is in an unknown state, let the caller recover it.
+                #[cfg(feature = "debug_ctap")]
+                writeln!(
+                    Console::new(),
+                    "Unexpected error when cancelling USB transaction: {:?}",
+                    result_code
+                )
+                .unwrap();
+                return Some(SendOrRecvStatus::Error);
+            }
         }
         #[cfg(feature = "debug_ctap")]
         writeln!(Console::new(), "Cancelled USB transaction!").unwrap();
@@ -342,3 +369,24 @@ pub fn send_or_recv_with_timeout(
 
     status.get()
 }
+
+// The CTAPHID transport over the USB driver of Tock.
+pub struct UsbTransport;
+
+impl Transport for UsbTransport {
+    fn recv_with_timeout(
+        &self,
+        packet: &mut HidPacket,
+        timeout: Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        recv_with_timeout(packet, timeout)
+    }
+
+    fn send_or_recv_with_timeout(
+        &self,
+        packet: &mut HidPacket,
+        timeout: Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        send_or_recv_with_timeout(packet, timeout)
+    }
+}